@@ -1,6 +1,6 @@
 use alsa;
 use librespot::playback::mixer::{AudioFilter, Mixer, MixerConfig};
-use log::error;
+use log::{error, info};
 use std::error::Error;
 
 pub struct AlsaMixer {
@@ -17,16 +17,54 @@ impl AlsaMixer {
         let elem = mixer.find_selem(&selem_id).ok_or("Couldn't find selem.")?;
 
         let (min, max) = elem.get_playback_volume_range();
+        elem.set_playback_volume_all(scale_volume(volume, min, max, self.linear_scaling))?;
+        Ok(())
+    }
 
-        let volume_steps = (max - min) as f64;
-        let normalised_volume = if self.linear_scaling {
-            ((f64::from(volume) / f64::from(u16::max_value())) * volume_steps) as i64 + min
-        } else {
-            (f64::from(volume).log(f64::from(u16::max_value())) * volume_steps).floor() as i64 + min
-        };
+    /// Sets the mixer control to `percent` of its range, bypassing Spotify's volume. The
+    /// percentage is scaled like Spotify's volume is.
+    pub fn set_volume_percent(&self, percent: u8) -> Result<(), Box<dyn Error>> {
+        self.set_volume_with_err(percent_to_volume(percent))
+    }
+}
 
-        elem.set_playback_volume_all(normalised_volume)?;
-        Ok(())
+/// Maps a volume onto the range of a mixer control, linearly or logarithmically.
+fn scale_volume(volume: u16, min: i64, max: i64, linear_scaling: bool) -> i64 {
+    let volume_steps = (max - min) as f64;
+    let scaled = if linear_scaling {
+        (f64::from(volume) / f64::from(u16::max_value())) * volume_steps
+    } else if volume == 0 {
+        0.0
+    } else {
+        (f64::from(volume).log(f64::from(u16::max_value())) * volume_steps).floor()
+    };
+    min + (scaled as i64).max(0).min(max - min)
+}
+
+/// Maps a percentage onto Spotify's volume range, rounded and clamped to 100%.
+fn percent_to_volume(percent: u8) -> u16 {
+    ((u32::from(percent.min(100)) * u32::from(u16::max_value()) + 50) / 100) as u16
+}
+
+/// Sets the mixer to the startup volume the first time it's opened, and leaves the
+/// mixers opened for later sessions alone, so a reconnect doesn't reset the volume.
+pub(crate) struct StartupVolume(Option<u8>);
+
+impl StartupVolume {
+    pub(crate) fn new(percent: Option<u8>) -> StartupVolume {
+        StartupVolume(percent)
+    }
+
+    pub(crate) fn apply<F>(&mut self, set_volume_percent: F)
+    where
+        F: FnOnce(u8) -> Result<(), Box<dyn Error>>,
+    {
+        if let Some(percent) = self.0.take() {
+            info!("Setting alsa mixer to {}% of its range.", percent);
+            if let Err(e) = set_volume_percent(percent) {
+                error!("Couldn't set startup mixer volume: {:?}", e);
+            }
+        }
     }
 }
 
@@ -80,3 +118,55 @@ impl Mixer for AlsaMixer {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn percent_of_range(percent: u8, min: i64, max: i64, linear_scaling: bool) -> i64 {
+        scale_volume(percent_to_volume(percent), min, max, linear_scaling)
+    }
+
+    #[test]
+    fn percent_to_control_range() {
+        assert_eq!(percent_of_range(0, 0, 255, true), 0);
+        assert_eq!(percent_of_range(50, 0, 255, true), 127);
+        assert_eq!(percent_of_range(100, 0, 255, true), 255);
+        assert_eq!(percent_of_range(25, -100, 0, true), -75);
+        assert_eq!(percent_of_range(200, 10, 20, true), 20);
+
+        // Like Spotify's volume, most of the range is in the upper percentages.
+        assert_eq!(percent_of_range(0, 0, 255, false), 0);
+        assert_eq!(percent_of_range(50, 0, 255, false), 239);
+        assert_eq!(percent_of_range(100, 0, 255, false), 255);
+        assert_eq!(percent_of_range(200, 10, 20, false), 20);
+        assert!(percent_of_range(10, -100, 0, false) > percent_of_range(10, -100, 0, true));
+    }
+
+    #[test]
+    fn startup_volume_once() {
+        let mut startup_volume = StartupVolume::new(Some(40));
+        let mut applied = Vec::new();
+        // The mixer is opened again for every session.
+        for _ in 0..3 {
+            startup_volume.apply(|percent| {
+                applied.push(percent);
+                Ok(())
+            });
+        }
+        assert_eq!(applied, vec![40]);
+
+        // A failure is logged, and not retried either.
+        let mut startup_volume = StartupVolume::new(Some(40));
+        let mut attempts = 0;
+        for _ in 0..2 {
+            startup_volume.apply(|_| {
+                attempts += 1;
+                Err("Couldn't find selem.".into())
+            });
+        }
+        assert_eq!(attempts, 1);
+
+        StartupVolume::new(None).apply(|_| panic!("there's no startup volume"));
+    }
+}
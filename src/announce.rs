@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+/// How long playback has to stay started or stopped before the advertisement follows,
+/// so that skipping between tracks doesn't withdraw and re-advertise the device.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// What to do with the Connect discovery advertisement.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Change {
+    Advertise,
+    Withdraw,
+}
+
+/// Decides when to advertise the device for Connect discovery, so that it's only
+/// announced to the local network while nothing is playing.
+pub(crate) struct Announcer {
+    advertised: bool,
+    /// When playback last changed to the state that calls for the other advertisement.
+    pending_since: Option<Instant>,
+}
+
+impl Announcer {
+    /// Creates an `Announcer` for a device that's being advertised.
+    pub(crate) fn new() -> Announcer {
+        Announcer {
+            advertised: true,
+            pending_since: None,
+        }
+    }
+
+    /// Records the playback state at `now`, returning a change once the state has
+    /// settled.
+    pub(crate) fn update(&mut self, playing: bool, now: Instant) -> Option<Change> {
+        if self.advertised != playing {
+            self.pending_since = None;
+            return None;
+        }
+        let since = *self.pending_since.get_or_insert(now);
+        if now.duration_since(since) < SETTLE_TIME {
+            return None;
+        }
+        self.pending_since = None;
+        self.advertised = !playing;
+        Some(if self.advertised {
+            Change::Advertise
+        } else {
+            Change::Withdraw
+        })
+    }
+
+    /// Returns when the pending change is due, if there is one.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.pending_since.map(|since| since + SETTLE_TIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn withdraw_while_playing() {
+        let start = Instant::now();
+        let mut announcer = Announcer::new();
+        assert_eq!(announcer.update(false, start), None);
+        assert_eq!(announcer.deadline(), None);
+
+        assert_eq!(announcer.update(true, start), None);
+        assert_eq!(announcer.deadline(), Some(start + SETTLE_TIME));
+        assert_eq!(announcer.update(true, start + Duration::from_secs(1)), None);
+        assert_eq!(
+            announcer.update(true, start + SETTLE_TIME),
+            Some(Change::Withdraw)
+        );
+        assert_eq!(announcer.deadline(), None);
+        assert_eq!(announcer.update(true, start + 2 * SETTLE_TIME), None);
+
+        let stopped = start + 3 * SETTLE_TIME;
+        assert_eq!(announcer.update(false, stopped), None);
+        assert_eq!(
+            announcer.update(false, stopped + SETTLE_TIME),
+            Some(Change::Advertise)
+        );
+        assert_eq!(announcer.update(false, stopped + 2 * SETTLE_TIME), None);
+    }
+
+    #[test]
+    fn no_flapping() {
+        let start = Instant::now();
+        let mut announcer = Announcer::new();
+        // Pausing briefly, e.g. between tracks, doesn't change anything...
+        for i in 0..10 {
+            let now = start + Duration::from_secs(i * 2);
+            assert_eq!(announcer.update(i % 2 == 0, now), None);
+        }
+        // ...and restarts the settle time.
+        let now = start + Duration::from_secs(20);
+        assert_eq!(announcer.update(true, now), None);
+        assert_eq!(announcer.deadline(), Some(now + SETTLE_TIME));
+        assert_eq!(
+            announcer.update(true, now + SETTLE_TIME),
+            Some(Change::Withdraw)
+        );
+    }
+}
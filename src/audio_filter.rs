@@ -0,0 +1,690 @@
+use librespot::playback::mixer::AudioFilter;
+use std::{
+    f64::consts::PI,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// librespot always decodes to interleaved stereo at 44.1 kHz.
+pub(crate) const CHANNELS: usize = 2;
+pub(crate) const SAMPLE_RATE: f64 = 44100.0;
+
+/// The range of gains in dB that `Gain` accepts. Boosting is capped at a few dB, as
+/// more than that clips most tracks.
+const MIN_GAIN_DB: f32 = -60.0;
+const MAX_GAIN_DB: f32 = 6.0;
+
+/// The range of peak limiter thresholds in dB below full scale.
+const MIN_THRESHOLD_DB: f32 = -20.0;
+const MAX_THRESHOLD_DB: f32 = 0.0;
+
+/// The ranges of the parameters an equalizer band accepts.
+const MIN_BAND_FREQUENCY: f64 = 20.0;
+const MAX_BAND_FREQUENCY: f64 = 20000.0;
+const MIN_BAND_GAIN_DB: f64 = -24.0;
+const MAX_BAND_GAIN_DB: f64 = 12.0;
+const MIN_BAND_Q: f64 = 0.1;
+const MAX_BAND_Q: f64 = 10.0;
+
+/// The player only hands over samples while playing, so a gap this long in the stream
+/// means playback was paused or stopped in between.
+const FADE_IN_GAP: Duration = Duration::from_millis(500);
+
+/// Filters spotifyd applies to the sample stream, after the mixer's own filter
+/// and before the samples reach the sink. Samples are interleaved stereo.
+#[derive(Clone, Default)]
+pub struct FilterConfig {
+    pub fade_in: Option<Duration>,
+    pub eq_bands: Vec<EqBand>,
+    pub gain: Option<Gain>,
+    pub peak_limiter: Option<PeakLimiter>,
+    pub mono: bool,
+    pub channel_map: Option<ChannelMap>,
+}
+
+impl FilterConfig {
+    /// Builds the filters to hand to a new player, chained behind the mixer's filter.
+    pub fn build(
+        &self,
+        mixer_filter: Option<Box<dyn AudioFilter + Send>>,
+    ) -> Option<Box<dyn AudioFilter + Send>> {
+        let mut filters: Vec<Box<dyn AudioFilter + Send>> = mixer_filter.into_iter().collect();
+        if let Some(duration) = self.fade_in {
+            filters.push(Box::new(FadeIn::new(duration)));
+        }
+        if !self.eq_bands.is_empty() {
+            filters.push(Box::new(Equalizer::new(&self.eq_bands)));
+        }
+        if self.gain.is_some() || self.peak_limiter.is_some() {
+            filters.push(Box::new(Amplifier {
+                factor: self.gain.map_or(1.0, Gain::factor),
+                limiter: self.peak_limiter,
+            }));
+        }
+        if let Some(channel_map) = self.channel_map {
+            filters.push(Box::new(channel_map));
+        }
+        if self.mono {
+            filters.push(Box::new(MonoDownmix));
+        }
+        match filters.len() {
+            0 => None,
+            1 => filters.pop(),
+            _ => Some(Box::new(FilterChain(filters))),
+        }
+    }
+}
+
+/// Applies several filters one after another.
+struct FilterChain(Vec<Box<dyn AudioFilter + Send>>);
+
+impl AudioFilter for FilterChain {
+    fn modify_stream(&self, data: &mut [i16]) {
+        for filter in &self.0 {
+            filter.modify_stream(data);
+        }
+    }
+}
+
+/// Assigns each output channel one of the input channels, e.g. `R,L` swaps them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelMap([usize; CHANNELS]);
+
+impl FromStr for ChannelMap {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = [0; CHANNELS];
+        let mut channels = s.split(',');
+        for output in map.iter_mut() {
+            *output = match channels.next().map(|c| c.trim().to_uppercase()) {
+                Some(ref c) if c == "L" => 0,
+                Some(ref c) if c == "R" => 1,
+                _ => return Err(()),
+            };
+        }
+        if channels.next().is_some() {
+            return Err(());
+        }
+        Ok(ChannelMap(map))
+    }
+}
+
+impl fmt::Display for ChannelMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let channels: Vec<_> = self
+            .0
+            .iter()
+            .map(|&c| if c == 0 { "L" } else { "R" })
+            .collect();
+        write!(f, "{}", channels.join(","))
+    }
+}
+
+impl AudioFilter for ChannelMap {
+    fn modify_stream(&self, data: &mut [i16]) {
+        for frame in data.chunks_exact_mut(CHANNELS) {
+            let input = [frame[0], frame[1]];
+            for (output, &channel) in frame.iter_mut().zip(self.0.iter()) {
+                *output = input[channel];
+            }
+        }
+    }
+}
+
+/// A constant gain in dB, applied on top of the volume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gain {
+    db: f32,
+}
+
+impl Gain {
+    /// The factor samples are multiplied with.
+    fn factor(self) -> f32 {
+        10f32.powf(self.db / 20.0)
+    }
+}
+
+impl FromStr for Gain {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let db = s
+            .trim_end_matches("dB")
+            .trim_end()
+            .parse::<f32>()
+            .map_err(|_| ())?;
+        if (MIN_GAIN_DB..=MAX_GAIN_DB).contains(&db) {
+            Ok(Gain { db })
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl fmt::Display for Gain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.db)
+    }
+}
+
+/// Compresses peaks above a threshold smoothly instead of letting them clip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeakLimiter {
+    /// The threshold as a fraction of full scale.
+    threshold: f32,
+}
+
+impl PeakLimiter {
+    /// Passes a sample between -1 and 1 through unchanged if it's below the threshold,
+    /// and otherwise bends it towards full scale, which it never reaches.
+    fn limit(self, sample: f32) -> f32 {
+        let t = self.threshold;
+        let level = sample.abs();
+        if level <= t || t >= 1.0 {
+            return sample;
+        }
+        let limited = t + (1.0 - t) * ((level - t) / (1.0 - t)).tanh();
+        limited.copysign(sample)
+    }
+}
+
+impl Default for PeakLimiter {
+    fn default() -> Self {
+        PeakLimiter::from_str("-1").unwrap()
+    }
+}
+
+impl FromStr for PeakLimiter {
+    type Err = ();
+
+    /// Parses the threshold in dB below full scale.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let db = s
+            .trim_end_matches("dB")
+            .trim_end()
+            .parse::<f32>()
+            .map_err(|_| ())?;
+        if (MIN_THRESHOLD_DB..=MAX_THRESHOLD_DB).contains(&db) {
+            Ok(PeakLimiter {
+                threshold: 10f32.powf(db / 20.0),
+            })
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl fmt::Display for PeakLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            (20.0 * self.threshold.log10() * 10.0).round() / 10.0
+        )
+    }
+}
+
+/// Applies the master gain, followed by the peak limiter. Both work on the unclipped
+/// samples, so that the limiter can tame peaks that the gain pushed over full scale.
+struct Amplifier {
+    factor: f32,
+    limiter: Option<PeakLimiter>,
+}
+
+impl AudioFilter for Amplifier {
+    fn modify_stream(&self, data: &mut [i16]) {
+        let full_scale = -f32::from(i16::min_value());
+        for sample in data.iter_mut() {
+            let mut amplified = f32::from(*sample) * self.factor / full_scale;
+            if let Some(limiter) = self.limiter {
+                amplified = limiter.limit(amplified);
+            }
+            *sample = (amplified * full_scale)
+                .round()
+                .max(f32::from(i16::min_value()))
+                .min(f32::from(i16::max_value())) as i16;
+        }
+    }
+}
+
+/// One band of the equalizer: a peaking filter that boosts or cuts `gain_db` around
+/// `frequency`, over a width set by `q`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EqBand {
+    frequency: f64,
+    gain_db: f64,
+    q: f64,
+}
+
+impl EqBand {
+    /// The normalized biquad coefficients `[b0, b1, b2, a1, a2]`, following the peaking
+    /// EQ of the Audio EQ Cookbook.
+    fn coefficients(self) -> [f64; 5] {
+        let a = 10f64.powf(self.gain_db / 40.0);
+        let w0 = 2.0 * PI * self.frequency / SAMPLE_RATE;
+        let alpha = w0.sin() / (2.0 * self.q);
+        let a0 = 1.0 + alpha / a;
+        [
+            (1.0 + alpha * a) / a0,
+            -2.0 * w0.cos() / a0,
+            (1.0 - alpha * a) / a0,
+            -2.0 * w0.cos() / a0,
+            (1.0 - alpha / a) / a0,
+        ]
+    }
+}
+
+impl FromStr for EqBand {
+    type Err = ();
+
+    /// Parses `frequency/gain/q`, e.g. `80/-6/0.7` for a cut of 6 dB around 80 Hz.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split('/').map(|field| field.trim().parse::<f64>());
+        let band = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(Ok(frequency)), Some(Ok(gain_db)), Some(Ok(q)), None) => EqBand {
+                frequency,
+                gain_db,
+                q,
+            },
+            _ => return Err(()),
+        };
+        if (MIN_BAND_FREQUENCY..=MAX_BAND_FREQUENCY).contains(&band.frequency)
+            && (MIN_BAND_GAIN_DB..=MAX_BAND_GAIN_DB).contains(&band.gain_db)
+            && (MIN_BAND_Q..=MAX_BAND_Q).contains(&band.q)
+        {
+            Ok(band)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl fmt::Display for EqBand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.frequency, self.gain_db, self.q)
+    }
+}
+
+/// Parses a comma-separated list of equalizer bands.
+pub fn parse_eq_bands(s: &str) -> Result<Vec<EqBand>, ()> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|band| !band.is_empty())
+        .map(EqBand::from_str)
+        .collect()
+}
+
+/// The last two inputs and outputs of a biquad filter, for one channel.
+type History = [f64; 4];
+
+/// Runs the samples through one biquad filter per band. The filters keep their state
+/// between calls, as the stream arrives in chunks.
+struct Equalizer {
+    coefficients: Vec<[f64; 5]>,
+    history: Mutex<Vec<[History; CHANNELS]>>,
+}
+
+impl Equalizer {
+    fn new(bands: &[EqBand]) -> Equalizer {
+        Equalizer {
+            coefficients: bands.iter().map(|band| band.coefficients()).collect(),
+            history: Mutex::new(vec![[[0.0; 4]; CHANNELS]; bands.len()]),
+        }
+    }
+}
+
+impl AudioFilter for Equalizer {
+    fn modify_stream(&self, data: &mut [i16]) {
+        let mut history = self.history.lock().unwrap();
+        for frame in data.chunks_exact_mut(CHANNELS) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut x = f64::from(*sample);
+                for (c, band) in self.coefficients.iter().zip(history.iter_mut()) {
+                    let h = &mut band[channel];
+                    let y = c[0] * x + c[1] * h[0] + c[2] * h[1] - c[3] * h[2] - c[4] * h[3];
+                    *h = [x, h[0], y, h[2]];
+                    x = y;
+                }
+                *sample = x
+                    .round()
+                    .max(f64::from(i16::min_value()))
+                    .min(f64::from(i16::max_value())) as i16;
+            }
+        }
+    }
+}
+
+/// Ramps the volume up from silence when playback starts or resumes.
+struct FadeIn {
+    frames: usize,
+    state: Mutex<FadeState>,
+}
+
+struct FadeState {
+    /// How many frames of the current fade have been played.
+    position: usize,
+    last_samples: Option<Instant>,
+}
+
+impl FadeIn {
+    fn new(duration: Duration) -> FadeIn {
+        FadeIn {
+            frames: (duration.as_millis() as f64 * SAMPLE_RATE / 1000.0).round() as usize,
+            state: Mutex::new(FadeState {
+                position: 0,
+                last_samples: None,
+            }),
+        }
+    }
+
+    /// The gain of the frame at `position`, which reaches unity with the fade's last frame.
+    fn gain(&self, position: usize) -> f32 {
+        if position >= self.frames {
+            1.0
+        } else {
+            (position + 1) as f32 / self.frames as f32
+        }
+    }
+
+    fn modify_stream_at(&self, data: &mut [i16], now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(last) = state.last_samples {
+            if now.duration_since(last) >= FADE_IN_GAP {
+                state.position = 0;
+            }
+        }
+        state.last_samples = Some(now);
+        for frame in data.chunks_exact_mut(CHANNELS) {
+            if state.position >= self.frames {
+                break;
+            }
+            let gain = self.gain(state.position);
+            for sample in frame.iter_mut() {
+                *sample = (f32::from(*sample) * gain).round() as i16;
+            }
+            state.position += 1;
+        }
+    }
+}
+
+impl AudioFilter for FadeIn {
+    fn modify_stream(&self, data: &mut [i16]) {
+        self.modify_stream_at(data, Instant::now());
+    }
+}
+
+/// Replaces both channels with their average, for single speaker setups.
+struct MonoDownmix;
+
+impl AudioFilter for MonoDownmix {
+    fn modify_stream(&self, data: &mut [i16]) {
+        for frame in data.chunks_exact_mut(CHANNELS) {
+            let mono = ((i32::from(frame[0]) + i32::from(frame[1])) / 2) as i16;
+            frame[0] = mono;
+            frame[1] = mono;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mono_downmix() {
+        let mut data = [
+            100,
+            300,
+            -100,
+            100,
+            i16::max_value(),
+            i16::max_value(),
+            i16::min_value(),
+            i16::max_value(),
+        ];
+        MonoDownmix.modify_stream(&mut data);
+        assert_eq!(
+            data,
+            [200, 200, 0, 0, i16::max_value(), i16::max_value(), 0, 0]
+        );
+    }
+
+    #[test]
+    fn channel_swap() {
+        let swap = ChannelMap::from_str("R,L").unwrap();
+        let mut data = [1, 2, 3, 4, 5, 6];
+        swap.modify_stream(&mut data);
+        assert_eq!(data, [2, 1, 4, 3, 6, 5]);
+        assert_eq!(swap.to_string(), "R,L");
+
+        let left = ChannelMap::from_str("l, l").unwrap();
+        let mut data = [1, 2, 3, 4];
+        left.modify_stream(&mut data);
+        assert_eq!(data, [1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn channel_map_invalid() {
+        for map in &["", "R", "R,L,L", "L,C", "0,1"] {
+            assert!(ChannelMap::from_str(map).is_err(), "{:?}", map);
+        }
+    }
+
+    fn amplifier(gain: &str, limiter: Option<&str>) -> Amplifier {
+        Amplifier {
+            factor: Gain::from_str(gain).unwrap().factor(),
+            limiter: limiter.map(|threshold| PeakLimiter::from_str(threshold).unwrap()),
+        }
+    }
+
+    #[test]
+    fn gain_factor() {
+        let factor = |db: &str| Gain::from_str(db).unwrap().factor();
+        assert_eq!(factor("0"), 1.0);
+        assert!((factor("-6") - 0.501).abs() < 0.001);
+        assert!((factor("-20 dB") - 0.1).abs() < 0.0001);
+        assert!((factor("-60dB") - 0.001).abs() < 0.00001);
+        assert!((factor("6") - 1.995).abs() < 0.001);
+
+        let mut data = [1000, -1000, i16::max_value(), i16::min_value()];
+        amplifier("-20", None).modify_stream(&mut data);
+        assert_eq!(data, [100, -100, 3277, -3277]);
+
+        // Boosting saturates instead of wrapping around.
+        let mut data = [30000, -30000];
+        amplifier("6", None).modify_stream(&mut data);
+        assert_eq!(data, [i16::max_value(), i16::min_value()]);
+    }
+
+    #[test]
+    fn gain_safety_cap() {
+        assert!(Gain::from_str("6").is_ok());
+        for db in &["6.5", "20", "-61", "loud", "", "NaN"] {
+            assert!(Gain::from_str(db).is_err(), "{:?}", db);
+        }
+    }
+
+    #[test]
+    fn limiter_below_threshold() {
+        let limiter = PeakLimiter::from_str("-6").unwrap();
+        for &sample in &[0.0, 0.1, -0.3, 0.5, -0.5] {
+            assert_eq!(limiter.limit(sample), sample);
+        }
+
+        let mut data = [0, 1000, -16000, 16384, -16384];
+        amplifier("0", Some("-6")).modify_stream(&mut data);
+        assert_eq!(data, [0, 1000, -16000, 16384, -16384]);
+    }
+
+    #[test]
+    fn limiter_attenuates_peaks() {
+        let limiter = PeakLimiter::from_str("-6").unwrap();
+        let t = limiter.threshold;
+        let mut previous = t;
+        for i in 1..=30 {
+            let sample = t + i as f32 * 0.05;
+            let limited = limiter.limit(sample);
+            // Peaks are pulled down, but stay above the threshold and in order.
+            assert!(limited < sample && limited > previous, "{}", sample);
+            assert!(limited < 1.0, "{}", sample);
+            assert_eq!(limiter.limit(-sample), -limited);
+            previous = limited;
+        }
+        // There's no kink at the threshold.
+        let just_over = limiter.limit(t + 0.001);
+        assert!((just_over - (t + 0.001)).abs() < 0.0001);
+
+        // Boosted samples that would clip are limited instead.
+        let mut data = [20000, -20000];
+        amplifier("6", Some("-1")).modify_stream(&mut data);
+        assert!(data[0] < i16::max_value() && data[0] > 32000, "{:?}", data);
+        assert!(data[1] > i16::min_value() && data[1] < -32000, "{:?}", data);
+    }
+
+    #[test]
+    fn limiter_threshold() {
+        assert_eq!(PeakLimiter::default().to_string(), "-1");
+        assert_eq!(PeakLimiter::from_str("-3 dB").unwrap().to_string(), "-3");
+        for db in &["0.5", "-21", "soft", ""] {
+            assert!(PeakLimiter::from_str(db).is_err(), "{:?}", db);
+        }
+    }
+
+    /// Runs a stereo sine of `frequency` through `filter`, returning the peak of the
+    /// left channel once the filter has settled.
+    fn sine_peak(filter: &dyn AudioFilter, frequency: f64) -> i16 {
+        let mut data: Vec<i16> = (0..SAMPLE_RATE as usize)
+            .flat_map(|i| {
+                let sample =
+                    (8000.0 * (2.0 * PI * frequency * i as f64 / SAMPLE_RATE).sin()) as i16;
+                vec![sample, sample]
+            })
+            .collect();
+        // In chunks, like the player hands them over.
+        for chunk in data.chunks_mut(4096) {
+            filter.modify_stream(chunk);
+        }
+        data[data.len() / 2..]
+            .iter()
+            .step_by(CHANNELS)
+            .map(|sample| sample.abs())
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn eq_single_band() {
+        let bands = parse_eq_bands("1000/6/1").unwrap();
+        // The full gain at the center frequency...
+        let peak = sine_peak(&Equalizer::new(&bands), 1000.0);
+        assert!((15900..=16050).contains(&peak), "{}", peak);
+        // ...and next to none far away from it.
+        let peak = sine_peak(&Equalizer::new(&bands), 50.0);
+        assert!((7950..=8100).contains(&peak), "{}", peak);
+
+        let bands = parse_eq_bands("100/-6/0.7").unwrap();
+        let peak = sine_peak(&Equalizer::new(&bands), 100.0);
+        assert!((3950..=4060).contains(&peak), "{}", peak);
+    }
+
+    #[test]
+    fn eq_passthrough() {
+        assert_eq!(parse_eq_bands(""), Ok(vec![]));
+        let mut data = [0, 100, -100, 8000, i16::max_value(), i16::min_value()];
+        Equalizer::new(&[]).modify_stream(&mut data);
+        assert_eq!(
+            data,
+            [0, 100, -100, 8000, i16::max_value(), i16::min_value()]
+        );
+        // A band without gain doesn't change anything either.
+        let bands = parse_eq_bands("1000/0/1").unwrap();
+        assert_eq!(
+            sine_peak(&Equalizer::new(&bands), 1000.0),
+            sine_peak(&Equalizer::new(&[]), 1000.0)
+        );
+    }
+
+    #[test]
+    fn eq_bands_invalid() {
+        let bands = parse_eq_bands("80/-6/0.7, 3000/2/1.4").unwrap();
+        assert_eq!(bands.len(), 2);
+        assert_eq!(bands[1].to_string(), "3000/2/1.4");
+        for bands in &[
+            "80/-6",
+            "80/-6/0.7/1",
+            "bass/-6/0.7",
+            "10/-6/0.7",
+            "30000/-6/0.7",
+            "80/-30/0.7",
+            "80/20/0.7",
+            "80/-6/0",
+        ] {
+            assert!(parse_eq_bands(bands).is_err(), "{:?}", bands);
+        }
+    }
+
+    #[test]
+    fn fade_in_ramp() {
+        // 1ms is 44 frames.
+        let fade = FadeIn::new(Duration::from_millis(1));
+        assert_eq!(fade.frames, 44);
+        assert!(fade.gain(0) > 0.0 && fade.gain(0) < 0.05);
+        for position in 1..fade.frames {
+            assert!(fade.gain(position) > fade.gain(position - 1));
+        }
+        assert_eq!(fade.gain(fade.frames - 1), 1.0);
+        assert_eq!(fade.gain(fade.frames), 1.0);
+
+        // Split over two chunks, the ramp continues where the first one ended.
+        let now = Instant::now();
+        let mut first = vec![10000; 30 * CHANNELS];
+        let mut second = vec![10000; 30 * CHANNELS];
+        fade.modify_stream_at(&mut first, now);
+        fade.modify_stream_at(&mut second, now + Duration::from_millis(1));
+        let samples: Vec<i16> = first.into_iter().chain(second).collect();
+        assert_eq!(samples[0], 227);
+        assert_eq!(samples[0], samples[1]);
+        assert!(samples.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(samples[43 * CHANNELS], 10000);
+        assert!(samples[43 * CHANNELS..].iter().all(|&s| s == 10000));
+    }
+
+    #[test]
+    fn fade_in_after_pause() {
+        let fade = FadeIn::new(Duration::from_millis(1));
+        let now = Instant::now();
+        let mut data = vec![10000; 100 * CHANNELS];
+        fade.modify_stream_at(&mut data, now);
+        assert_eq!(data[0], 227);
+
+        // Samples that keep coming aren't faded again.
+        let mut data = vec![10000; 100 * CHANNELS];
+        fade.modify_stream_at(&mut data, now + Duration::from_millis(100));
+        assert_eq!(data[0], 10000);
+
+        let mut data = vec![10000; 100 * CHANNELS];
+        fade.modify_stream_at(&mut data, now + Duration::from_secs(5));
+        assert_eq!(data[0], 227);
+        assert_eq!(data[99 * CHANNELS], 10000);
+    }
+
+    #[test]
+    fn no_filters() {
+        assert!(FilterConfig::default().build(None).is_none());
+
+        let filter = FilterConfig {
+            mono: true,
+            ..Default::default()
+        }
+        .build(None)
+        .unwrap();
+        let mut data = [0, 10];
+        filter.modify_stream(&mut data);
+        assert_eq!(data, [5, 5]);
+    }
+}
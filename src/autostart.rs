@@ -0,0 +1,107 @@
+use futures::{future, Future};
+use librespot::core::{keymaster::get_token, session::Session};
+use log::{error, info};
+use rspotify::spotify::client::Spotify;
+use std::thread;
+
+const SCOPE: &str = "user-modify-playback-state";
+
+/// Playback to start on this device once the first session is up.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Autostart {
+    pub(crate) uri: String,
+    pub(crate) volume: Option<u8>,
+}
+
+/// Returns the playback to start, if `uri` is configured. As nobody is around to log
+/// in at that point, this only happens when the credentials come from the cache.
+pub(crate) fn autostart(
+    uri: Option<String>,
+    volume: Option<u8>,
+    cached_credentials: bool,
+) -> Option<Autostart> {
+    let uri = uri?;
+    if !cached_credentials {
+        info!(
+            "Not starting {} on startup, there are no cached credentials.",
+            uri
+        );
+        return None;
+    }
+    Some(Autostart { uri, volume })
+}
+
+/// Returns the `context_uri` and `uris` to start playback with: tracks are played on
+/// their own, everything else (albums, playlists, artists) as a context.
+fn playback_target(uri: String) -> (Option<String>, Option<Vec<String>>) {
+    if uri.starts_with("spotify:track:") {
+        (None, Some(vec![uri]))
+    } else {
+        (Some(uri), None)
+    }
+}
+
+/// Transfers playback to the device with the given id and starts playing.
+pub(crate) fn start(
+    session: &Session,
+    client_id: &str,
+    device_id: String,
+    autostart: Autostart,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    Box::new(
+        get_token(session, client_id, SCOPE)
+            .map_err(|e| error!("Couldn't get a token to start playback: {:?}", e))
+            .and_then(move |token| {
+                // The Web API client blocks, so keep it off the event loop.
+                thread::spawn(move || {
+                    let spotify = Spotify::default().access_token(&token.access_token).build();
+                    info!("Starting {} on this device.", autostart.uri);
+                    let (context_uri, uris) = playback_target(autostart.uri);
+                    if let Err(e) =
+                        spotify.start_playback(Some(device_id.clone()), context_uri, uris, None)
+                    {
+                        error!("Couldn't start playback: {}", e);
+                        return;
+                    }
+                    if let Some(volume) = autostart.volume {
+                        if let Err(e) = spotify.volume(volume, Some(device_id)) {
+                            error!("Couldn't set the volume to {}%: {}", volume, e);
+                        }
+                    }
+                });
+                future::ok(())
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn autostart_with_cached_credentials() {
+        let uri = "spotify:user:me:playlist:37i9dQZF1DX0yEZaMOXna3".to_string();
+        assert_eq!(
+            autostart(Some(uri.clone()), Some(30), true),
+            Some(Autostart {
+                uri,
+                volume: Some(30)
+            })
+        );
+        assert_eq!(autostart(None, Some(30), true), None);
+    }
+
+    #[test]
+    fn no_autostart_without_cached_credentials() {
+        let uri = "spotify:album:1DFixLWuPkv3KT3TnV35m3".to_string();
+        assert_eq!(autostart(Some(uri), None, false), None);
+    }
+
+    #[test]
+    fn track_or_context() {
+        let track = "spotify:track:6rqhFgbbKwnb9MLmUQDhG6".to_string();
+        assert_eq!(playback_target(track.clone()), (None, Some(vec![track])));
+        let album = "spotify:album:1DFixLWuPkv3KT3TnV35m3".to_string();
+        assert_eq!(playback_target(album.clone()), (Some(album), None));
+    }
+}
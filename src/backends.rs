@@ -0,0 +1,439 @@
+use librespot::playback::audio_backend::{Sink, BACKENDS};
+use log::{info, warn};
+use std::io;
+
+#[cfg(feature = "bluealsa_backend")]
+use crate::bluealsa_sink;
+#[cfg(feature = "rodio_backend")]
+use crate::rodio_sink;
+
+pub(crate) type SinkBuilder = fn(Option<String>) -> Box<dyn Sink>;
+
+/// Audio backends that can't select an output device.
+pub(crate) const BACKENDS_WITHOUT_DEVICE: &[&str] = &["pulseaudio"];
+
+/// All audio backends compiled into this binary: librespot's own, followed by the ones
+/// implemented by spotifyd. The first one is the default.
+pub(crate) fn backends() -> Vec<(&'static str, SinkBuilder)> {
+    #[allow(unused_mut)] // mut is needed behind the bluealsa and rodio flags.
+    let mut backends = BACKENDS.to_vec();
+    #[cfg(feature = "bluealsa_backend")]
+    backends.push(("bluealsa", bluealsa_sink::open as SinkBuilder));
+    #[cfg(feature = "rodio_backend")]
+    backends.push(("rodio", rodio_sink::open as SinkBuilder));
+    backends
+}
+
+/// Lists the backends compiled into this binary, marking the default.
+pub(crate) fn listing() -> String {
+    let mut listing = String::from("Available backends:\n");
+    for (i, (name, _)) in backends().into_iter().enumerate() {
+        let default = if i == 0 { " (default)" } else { "" };
+        listing.push_str(&format!("- {}{}\n", name, default));
+    }
+    listing
+}
+
+/// Splits a backend given as `name:device` into its name and its own device.
+pub(crate) fn split_device(entry: &str) -> (&str, Option<&str>) {
+    let mut parts = entry.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    (name, parts.next().filter(|device| !device.is_empty()))
+}
+
+/// The device each of `backends` opens: its own one if it has one, and otherwise `device`
+/// for the first backend that can select one. The others open their default device.
+pub(crate) fn assign_devices<'a>(
+    backends: &[(&str, Option<&'a str>)],
+    device: Option<&'a str>,
+) -> Vec<Option<&'a str>> {
+    let mut device = device;
+    backends
+        .iter()
+        .map(|&(name, own)| {
+            if BACKENDS_WITHOUT_DEVICE.contains(&name) {
+                None
+            } else {
+                own.or_else(|| device.take())
+            }
+        })
+        .collect()
+}
+
+/// The backends to play on, given by their names separated by `+`, each with the device
+/// it was given as `name:device`.
+#[derive(Clone)]
+pub struct Backend(Vec<(&'static str, SinkBuilder, Option<String>)>);
+
+impl Backend {
+    /// Opens the sinks, with the devices `assign_devices` picks for them. A backend that
+    /// panics instead of returning an error, as librespot's alsa backend does when it
+    /// can't open its device, takes all of them down, not only itself.
+    pub(crate) fn open(&self, device: Option<String>) -> Box<dyn Sink> {
+        let entries: Vec<_> = self
+            .0
+            .iter()
+            .map(|(name, _, own)| (*name, own.as_ref().map(String::as_str)))
+            .collect();
+        let devices: Vec<_> = assign_devices(&entries, device.as_ref().map(String::as_str))
+            .into_iter()
+            .map(|device| device.map(str::to_string))
+            .collect();
+        let mut sinks: Vec<_> = self
+            .0
+            .iter()
+            .zip(devices)
+            .map(|(&(name, builder, _), device)| (name, builder(device)))
+            .collect();
+        if sinks.len() == 1 {
+            sinks.pop().unwrap().1
+        } else {
+            Box::new(FanOut::new(sinks))
+        }
+    }
+
+    /// Picks the output device when none is configured, skipping blacklisted ones.
+    /// Returns `None` to leave the choice to the backend, which is what happens when
+    /// playing on several backends or one that can't list its devices.
+    pub(crate) fn select_device(&self, blacklist: &[String]) -> Option<String> {
+        if blacklist.is_empty() || self.0.len() != 1 || self.0[0].2.is_some() {
+            return None;
+        }
+        let (default, available) = output_devices(self.0[0].0)?;
+        let selected = pick_device(default.as_ref().map(String::as_str), &available, blacklist);
+        match selected {
+            Some(device) => info!("Selected the audio device {}.", device),
+            None => warn!(
+                "All audio devices are blacklisted, using the default one. Available devices: {}.",
+                available.join(", ")
+            ),
+        }
+        selected.map(str::to_string)
+    }
+}
+
+/// Whether `device` contains one of the entries of `blacklist`, ignoring case.
+fn is_blacklisted(device: &str, blacklist: &[String]) -> bool {
+    let device = device.to_lowercase();
+    blacklist
+        .iter()
+        .any(|entry| device.contains(&entry.to_lowercase()))
+}
+
+/// ALSA devices that stand for another one, which may well be a blacklisted one.
+const ALIASES: &[&str] = &["default", "sysdefault"];
+
+/// Picks the `default` device, unless it's blacklisted, and otherwise the first one of
+/// `available` that isn't. With a blacklist, aliases are skipped, since there's no
+/// telling which device they play on.
+fn pick_device<'a>(
+    default: Option<&'a str>,
+    available: &'a [String],
+    blacklist: &[String],
+) -> Option<&'a str> {
+    default
+        .into_iter()
+        .chain(available.iter().map(String::as_str))
+        .filter(|device| blacklist.is_empty() || !ALIASES.contains(device))
+        .find(|device| !is_blacklisted(device, blacklist))
+}
+
+/// The default output device of the backend `name` and all of them, for the backends
+/// that can list their devices.
+fn output_devices(name: &str) -> Option<(Option<String>, Vec<String>)> {
+    match name {
+        #[cfg(feature = "alsa_backend")]
+        "alsa" => Some((Some("default".to_string()), alsa_devices())),
+        #[cfg(feature = "rodio_backend")]
+        "rodio" => Some((
+            rodio::default_output_device().map(|output| output.name()),
+            rodio::output_devices()
+                .map(|output| output.name())
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Lists the ALSA playback devices, as `aplay -L` does. The null device, which discards
+/// everything, is left out.
+#[cfg(feature = "alsa_backend")]
+fn alsa_devices() -> Vec<String> {
+    use alsa::{device_name::HintIter, Direction};
+    use std::ffi::CString;
+
+    let pcm = CString::new("pcm").unwrap();
+    match HintIter::new(None, &pcm) {
+        Ok(hints) => hints
+            .filter(|hint| match hint.direction {
+                Some(Direction::Capture) => false,
+                _ => true,
+            })
+            .filter_map(|hint| hint.name)
+            .filter(|name| name != "null")
+            .collect(),
+        Err(e) => {
+            warn!("Couldn't list the ALSA devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub(crate) fn find_backend(name: Option<&str>) -> Backend {
+    let backends = backends();
+    match name {
+        Some(names) => Backend(
+            names
+                .split('+')
+                .map(|entry| {
+                    let (name, device) = split_device(entry);
+                    let &(name, builder) = backends
+                        .iter()
+                        .find(|backend| name == backend.0)
+                        .unwrap_or_else(|| panic!("Unknown backend: {}.", name));
+                    (name, builder, device.map(str::to_string))
+                })
+                .collect(),
+        ),
+        None => {
+            let &(name, back) = backends
+                .first()
+                .expect("No backends were enabled at build time");
+            info!("No backend specified, defaulting to: {}.", name);
+            Backend(vec![(name, back, None)])
+        }
+    }
+}
+
+/// Plays the same samples on several sinks. A sink that fails is skipped until playback
+/// is started again, while the others keep playing.
+struct FanOut {
+    sinks: Vec<(&'static str, Box<dyn Sink>, bool)>,
+}
+
+impl FanOut {
+    fn new(sinks: Vec<(&'static str, Box<dyn Sink>)>) -> FanOut {
+        FanOut {
+            sinks: sinks
+                .into_iter()
+                .map(|(name, sink)| (name, sink, false))
+                .collect(),
+        }
+    }
+
+    /// Runs `f` on every sink that hasn't failed, and fails only once all of them have.
+    fn each<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut dyn Sink) -> io::Result<()>,
+    {
+        let mut last_error = None;
+        for (name, sink, failed) in &mut self.sinks {
+            if *failed {
+                continue;
+            }
+            if let Err(e) = f(sink.as_mut()) {
+                warn!("The {} backend failed, continuing without it: {}", name, e);
+                *failed = true;
+                last_error = Some(e);
+            }
+        }
+        match last_error {
+            Some(e) if self.sinks.iter().all(|&(_, _, failed)| failed) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Sink for FanOut {
+    fn start(&mut self) -> io::Result<()> {
+        for (_, _, failed) in &mut self.sinks {
+            *failed = false;
+        }
+        self.each(|sink| sink.start())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.each(|sink| sink.stop())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.each(|sink| sink.write(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockSink;
+
+    fn mock_sink(fail_after: usize) -> (Box<dyn Sink>, MockSink) {
+        let sink = MockSink::failing_after(fail_after);
+        (Box::new(sink.clone()), sink)
+    }
+
+    #[test]
+    fn fan_out() {
+        let (first, first_written) = mock_sink(usize::max_value());
+        let (second, second_written) = mock_sink(usize::max_value());
+        let mut fan_out = FanOut::new(vec![("first", first), ("second", second)]);
+        fan_out.start().unwrap();
+        fan_out.write(&[1, 2, 3, 4]).unwrap();
+        fan_out.write(&[5, 6]).unwrap();
+        assert_eq!(first_written.written(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(second_written.written(), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn fan_out_failure() {
+        let (healthy, healthy_written) = mock_sink(usize::max_value());
+        let (failing, failing_written) = mock_sink(2);
+        let mut fan_out = FanOut::new(vec![("healthy", healthy), ("failing", failing)]);
+        fan_out.start().unwrap();
+        fan_out.write(&[1, 2]).unwrap();
+        fan_out.write(&[3, 4]).unwrap();
+        fan_out.write(&[5, 6]).unwrap();
+        assert_eq!(healthy_written.written(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(failing_written.written(), [1, 2]);
+
+        let (failing, _) = mock_sink(0);
+        let mut fan_out = FanOut::new(vec![("failing", failing)]);
+        assert!(fan_out.write(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn per_backend_devices() {
+        assert_eq!(split_device("alsa:hw:0"), ("alsa", Some("hw:0")));
+        assert_eq!(
+            split_device("bluealsa:00:1A:7D:DA:71:13"),
+            ("bluealsa", Some("00:1A:7D:DA:71:13"))
+        );
+        assert_eq!(split_device("pulseaudio"), ("pulseaudio", None));
+        assert_eq!(split_device("alsa:"), ("alsa", None));
+
+        // The configured device goes to the first backend without one of its own.
+        let backends = [
+            ("alsa", Some("hw:0")),
+            ("pulseaudio", None),
+            ("rodio", None),
+        ];
+        assert_eq!(
+            assign_devices(&backends, Some("Speakers")),
+            [Some("hw:0"), None, Some("Speakers")]
+        );
+        let backends = [("alsa", None), ("rodio", None)];
+        assert_eq!(
+            assign_devices(&backends, Some("hw:1")),
+            [Some("hw:1"), None]
+        );
+        assert_eq!(assign_devices(&backends, None), [None, None]);
+        assert_eq!(
+            assign_devices(&[("pulseaudio", Some("sink"))], None),
+            [None]
+        );
+    }
+
+    #[test]
+    fn device_selection() {
+        let available: Vec<String> = ["default", "hdmi:CARD=HDMI,DEV=0", "front:CARD=PCH,DEV=0"]
+            .iter()
+            .map(|device| device.to_string())
+            .collect();
+        let blacklist = vec!["HDMI".to_string()];
+        assert_eq!(
+            pick_device(Some("default"), &available, &blacklist),
+            Some("front:CARD=PCH,DEV=0")
+        );
+        assert_eq!(
+            pick_device(Some("hdmi:CARD=HDMI,DEV=0"), &available, &blacklist),
+            Some("front:CARD=PCH,DEV=0")
+        );
+        assert_eq!(
+            pick_device(Some("front:CARD=PCH,DEV=0"), &available, &blacklist),
+            Some("front:CARD=PCH,DEV=0")
+        );
+        assert_eq!(
+            pick_device(Some("default"), &available, &[]),
+            Some("default")
+        );
+
+        // The next available device is picked.
+        let blacklist = vec!["hdmi".to_string(), "default".to_string()];
+        assert_eq!(
+            pick_device(Some("default"), &available, &blacklist),
+            Some("front:CARD=PCH,DEV=0")
+        );
+        assert_eq!(pick_device(None, &available[..2], &blacklist), None);
+
+        // Without a blacklist, or with several backends, the backends pick their device.
+        let (name, builder) = backends()[0];
+        let backend = Backend(vec![(name, builder, None)]);
+        assert_eq!(backend.select_device(&[]), None);
+        let fan_out = Backend(vec![(name, builder, None), (name, builder, None)]);
+        assert_eq!(fan_out.select_device(&blacklist), None);
+        // Nor when the backend was given a device of its own.
+        let backend = Backend(vec![(name, builder, Some("hw:1".to_string()))]);
+        assert_eq!(backend.select_device(&blacklist), None);
+    }
+
+    #[test]
+    fn hdmi_default() {
+        // ALSA's default often plays on HDMI, so it's skipped along with the HDMI devices.
+        let available: Vec<String> = [
+            "default",
+            "sysdefault",
+            "sysdefault:CARD=HDMI",
+            "hdmi:CARD=HDMI,DEV=0",
+            "sysdefault:CARD=PCH",
+        ]
+        .iter()
+        .map(|device| device.to_string())
+        .collect();
+        let blacklist = vec!["hdmi".to_string()];
+        assert_eq!(
+            pick_device(Some("default"), &available, &blacklist),
+            Some("sysdefault:CARD=PCH")
+        );
+        assert_eq!(
+            pick_device(Some("default"), &available[..4], &blacklist),
+            None
+        );
+    }
+
+    #[test]
+    fn backend_listing() {
+        let listing = listing();
+        assert!(listing.starts_with("Available backends:\n"));
+        assert_eq!(listing.matches(" (default)").count(), 1);
+        for &(name, enabled) in &[
+            ("alsa", cfg!(feature = "alsa_backend")),
+            ("bluealsa", cfg!(feature = "bluealsa_backend")),
+            ("portaudio", cfg!(feature = "portaudio_backend")),
+            ("pulseaudio", cfg!(feature = "pulseaudio_backend")),
+            ("rodio", cfg!(feature = "rodio_backend")),
+        ] {
+            let listed = listing
+                .lines()
+                .any(|line| line.trim_start_matches("- ").trim_end_matches(" (default)") == name);
+            assert_eq!(listed, enabled, "{}", name);
+        }
+        if cfg!(feature = "alsa_backend") {
+            assert!(listing.contains("- alsa (default)\n"));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rodio_backend")]
+    fn rodio_is_available() {
+        assert!(backends().iter().any(|&(name, _)| name == "rodio"));
+    }
+
+    #[test]
+    #[cfg(feature = "rodio_backend")]
+    #[ignore] // Needs an audio output device.
+    fn rodio_default_device() {
+        let mut sink = find_backend(Some("rodio")).open(None);
+        sink.start().unwrap();
+        sink.write(&[0; 1024]).unwrap();
+        sink.stop().unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    cmp, process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Exponential backoff for retry loops: the n-th delay is `base * factor^n`, capped at
+/// `max`, and shortened by a random amount of up to `jitter` of itself so that retries
+/// of several clients don't line up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Backoff {
+    base: Duration,
+    factor: f64,
+    max: Duration,
+    jitter: f64,
+}
+
+impl Backoff {
+    /// `factor` is raised to at least 1 and `jitter` is clamped to a ratio between 0 and 1.
+    pub(crate) fn new(base: Duration, factor: f64, max: Duration, jitter: f64) -> Backoff {
+        Backoff {
+            base: cmp::min(base, max),
+            factor: factor.max(1.0),
+            max,
+            jitter: jitter.max(0.0).min(1.0),
+        }
+    }
+
+    /// Returns the delays to wait before each retry, starting with the first one.
+    pub(crate) fn delays(&self) -> Delays {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.delays_with_seed(
+            u64::from(now.subsec_nanos()) ^ now.as_secs() ^ u64::from(process::id()),
+        )
+    }
+
+    fn delays_with_seed(&self, seed: u64) -> Delays {
+        Delays {
+            backoff: *self,
+            next: duration_secs(self.base),
+            // Xorshift gets stuck at zero.
+            rng: seed | 1,
+        }
+    }
+}
+
+/// The endless sequence of delays of a `Backoff`.
+#[derive(Debug)]
+pub(crate) struct Delays {
+    backoff: Backoff,
+    /// The next delay before jitter, in seconds.
+    next: f64,
+    rng: u64,
+}
+
+impl Delays {
+    /// Returns a random number in `[0, 1)`, using xorshift64*.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let n = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (n >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Iterator for Delays {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let max = duration_secs(self.backoff.max);
+        let delay = self.next.min(max);
+        self.next = (self.next * self.backoff.factor).min(max);
+        let jittered = delay * (1.0 - self.backoff.jitter * self.random());
+        let secs = jittered.trunc();
+        Some(Duration::new(secs as u64, ((jittered - secs) * 1e9) as u32))
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn exponential_sequence() {
+        let backoff = Backoff::new(secs(1), 2.0, secs(60), 0.0);
+        let delays: Vec<_> = backoff.delays().take(5).collect();
+        assert_eq!(delays, vec![secs(1), secs(2), secs(4), secs(8), secs(16)]);
+
+        let backoff = Backoff::new(Duration::from_millis(500), 1.5, secs(60), 0.0);
+        let delays: Vec<_> = backoff.delays().take(3).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(500),
+                Duration::from_millis(750),
+                Duration::from_millis(1125)
+            ]
+        );
+    }
+
+    #[test]
+    fn capped_at_max() {
+        let backoff = Backoff::new(secs(1), 10.0, secs(30), 0.0);
+        let delays: Vec<_> = backoff.delays().take(5).collect();
+        assert_eq!(
+            delays,
+            vec![secs(1), secs(10), secs(30), secs(30), secs(30)]
+        );
+
+        // The cap holds for a base above it and keeps holding over many retries.
+        let backoff = Backoff::new(secs(100), 2.0, secs(30), 0.5);
+        assert!(backoff.delays().take(1000).all(|delay| delay <= secs(30)));
+    }
+
+    #[test]
+    fn constant_without_growth() {
+        // A factor below 1 is raised to 1, which gives a constant delay.
+        let backoff = Backoff::new(secs(2), 0.5, secs(30), 0.0);
+        assert!(backoff.delays().take(10).all(|delay| delay == secs(2)));
+    }
+
+    #[test]
+    fn jitter_within_ratio() {
+        let backoff = Backoff::new(secs(10), 1.0, secs(10), 0.25);
+        for seed in 0..100 {
+            for delay in backoff.delays_with_seed(seed).take(100) {
+                assert!(delay <= secs(10), "{:?}", delay);
+                assert!(delay >= Duration::from_millis(7500), "{:?}", delay);
+            }
+        }
+
+        // Some jitter is actually applied.
+        let delays: Vec<_> = backoff.delays_with_seed(42).take(10).collect();
+        assert!(delays.iter().any(|&delay| delay != secs(10)));
+
+        // The delay can shrink at most to zero.
+        let backoff = Backoff::new(secs(10), 1.0, secs(10), 3.0);
+        assert!(backoff.delays().take(100).all(|delay| delay <= secs(10)));
+    }
+
+    #[test]
+    fn jitter_is_random() {
+        let backoff = Backoff::new(secs(10), 2.0, secs(60), 0.5);
+        let first: Vec<_> = backoff.delays_with_seed(1).take(10).collect();
+        let second: Vec<_> = backoff.delays_with_seed(2).take(10).collect();
+        assert_ne!(first, second);
+    }
+}
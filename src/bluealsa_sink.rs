@@ -0,0 +1,190 @@
+use crate::backoff::{Backoff, Delays};
+use librespot::playback::audio_backend::{Sink, BACKENDS};
+use log::{error, info, warn};
+use std::{
+    io,
+    process::exit,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long to wait between attempts to reopen a speaker that went away, starting at 2s
+/// and backing off to at most 30s.
+fn retry_backoff() -> Backoff {
+    Backoff::new(Duration::from_secs(2), 2.0, Duration::from_secs(30), 0.1)
+}
+
+/// Returns the BlueALSA PCM for the bluetooth device with the given MAC address.
+pub(crate) fn pcm_name(mac: &str) -> Option<String> {
+    let octets: Vec<_> = mac.split(':').collect();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()));
+    if valid {
+        Some(format!(
+            "bluealsa:DEV={},PROFILE=a2dp",
+            mac.to_ascii_uppercase()
+        ))
+    } else {
+        None
+    }
+}
+
+/// Whether the speaker is currently reachable.
+#[derive(Debug, PartialEq)]
+enum Link {
+    Up,
+    Down { retry_at: Instant },
+}
+
+/// What to do with the next buffer of samples.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Write,
+    Wait(Duration),
+    Reconnect,
+}
+
+impl Link {
+    fn action(&self, now: Instant) -> Action {
+        match *self {
+            Link::Up => Action::Write,
+            Link::Down { retry_at } if retry_at > now => Action::Wait(retry_at - now),
+            Link::Down { .. } => Action::Reconnect,
+        }
+    }
+
+    fn lost(&mut self, now: Instant, delay: Duration) {
+        *self = Link::Down {
+            retry_at: now + delay,
+        };
+    }
+
+    fn reconnected(&mut self) {
+        *self = Link::Up;
+    }
+}
+
+/// Plays to a bluetooth speaker through BlueALSA. While the speaker is disconnected,
+/// playback is held back and resumes where it left off once the speaker is back.
+pub struct BlueAlsaSink {
+    sink: Box<dyn Sink>,
+    link: Link,
+    retry_delays: Delays,
+}
+
+impl BlueAlsaSink {
+    fn lost(&mut self) {
+        let delay = self.retry_delays.next().unwrap_or_default();
+        self.link.lost(Instant::now(), delay);
+    }
+}
+
+/// Opens the BlueALSA PCM of the speaker with the given MAC address.
+pub fn open(device: Option<String>) -> Box<dyn Sink> {
+    info!("Using BlueALSA sink.");
+    let pcm = match device.as_ref().and_then(|mac| pcm_name(mac)) {
+        Some(pcm) => pcm,
+        None => {
+            error!("The bluealsa backend needs the MAC address of the speaker as its device.");
+            exit(1);
+        }
+    };
+    let &(_, alsa) = BACKENDS
+        .iter()
+        .find(|&&(name, _)| name == "alsa")
+        .expect("The bluealsa backend needs the alsa backend");
+    Box::new(BlueAlsaSink {
+        sink: alsa(Some(pcm)),
+        link: Link::Up,
+        retry_delays: retry_backoff().delays(),
+    })
+}
+
+impl Sink for BlueAlsaSink {
+    fn start(&mut self) -> io::Result<()> {
+        if let Err(e) = self.sink.start() {
+            warn!("Couldn't open the bluetooth speaker, waiting for it: {}", e);
+            self.lost();
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if self.link == Link::Up {
+            self.sink.stop()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        loop {
+            match self.link.action(Instant::now()) {
+                Action::Write => match self.sink.write(data) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => {
+                        warn!("Lost the bluetooth speaker, pausing playback: {}", e);
+                        let _ = self.sink.stop();
+                        self.lost();
+                    }
+                },
+                Action::Wait(delay) => thread::sleep(delay),
+                Action::Reconnect => match self.sink.start() {
+                    Ok(()) => {
+                        info!("The bluetooth speaker is back, resuming playback.");
+                        self.link.reconnected();
+                        self.retry_delays = retry_backoff().delays();
+                    }
+                    Err(_) => self.lost(),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_device() {
+        assert_eq!(
+            pcm_name("00:1a:7d:da:71:13"),
+            Some("bluealsa:DEV=00:1A:7D:DA:71:13,PROFILE=a2dp".to_string())
+        );
+        for mac in &[
+            "",
+            "00:1A:7D:DA:71",
+            "00:1A:7D:DA:71:13:00",
+            "001A7DDA7113",
+            "00:1A:7D:DA:71:1G",
+            "hw:0",
+        ] {
+            assert_eq!(pcm_name(mac), None, "{:?}", mac);
+        }
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let start = Instant::now();
+        let retry = Duration::from_secs(2);
+        let mut link = Link::Up;
+        assert_eq!(link.action(start), Action::Write);
+
+        link.lost(start, retry);
+        assert_eq!(link.action(start), Action::Wait(retry));
+        assert_eq!(
+            link.action(start + Duration::from_secs(1)),
+            Action::Wait(retry - Duration::from_secs(1))
+        );
+        assert_eq!(link.action(start + retry), Action::Reconnect);
+
+        // A failed reconnect waits for the next delay.
+        link.lost(start + retry, 2 * retry);
+        assert_eq!(link.action(start + retry), Action::Wait(2 * retry));
+
+        link.reconnected();
+        assert_eq!(link.action(start + retry), Action::Write);
+    }
+}
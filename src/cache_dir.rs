@@ -0,0 +1,338 @@
+use futures::{Async, Stream};
+use log::{info, warn};
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio_core::reactor::{Handle, Interval};
+
+use crate::{context_volume, stats};
+
+/// The files librespot keeps in the cache directory.
+const CREDENTIALS: &str = "credentials.json";
+const VOLUME: &str = "volume";
+const AUDIO_FILES: &str = "files";
+
+/// Creates the cache directory, which holds the credentials, with `mode` or 0700.
+/// An existing directory is only changed if a mode is configured.
+pub(crate) fn create(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    if !path.is_dir() {
+        DirBuilder::new().recursive(true).create(path)?;
+    } else if mode.is_none() {
+        return Ok(());
+    }
+    // Set the mode explicitly, so that it isn't narrowed by the umask.
+    fs::set_permissions(path, Permissions::from_mode(mode.unwrap_or(0o700)))
+}
+
+/// Whether librespot cached credentials in the cache directory at `cache_path`.
+pub(crate) fn has_credentials(cache_path: &Path) -> bool {
+    cache_path.join(CREDENTIALS).is_file()
+}
+
+/// Removes the cached credentials and volume, so that the device has to be logged in
+/// again. The device ID comes from the device name, so Spotify still sees the same
+/// device. Cached audio is kept. Returns the paths that were removed.
+pub(crate) fn forget_device(cache_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for name in &[CREDENTIALS, VOLUME] {
+        let path = cache_path.join(name);
+        match fs::remove_file(&path) {
+            Ok(()) => removed.push(path),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// How often expired audio files are looked for after startup.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Whether a cached audio file last modified at `modified` is older than `max_age`. A
+/// file modified after `now`, by a clock that was set back, isn't.
+fn expired(modified: SystemTime, now: SystemTime, max_age: Duration) -> bool {
+    now.duration_since(modified)
+        .map_or(false, |age| age > max_age)
+}
+
+/// Removes the files below `path` that were modified longer than `max_age` before `now`,
+/// returning how many there were and their total size.
+fn remove_expired(path: &Path, now: SystemTime, max_age: Duration) -> io::Result<(u64, u64)> {
+    let mut total = (0, 0);
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_dir() {
+            let (files, bytes) = remove_expired(&path, now, max_age)?;
+            total = (total.0 + files, total.1 + bytes);
+        } else if expired(metadata.modified()?, now, max_age) {
+            fs::remove_file(&path)?;
+            total = (total.0 + 1, total.1 + metadata.len());
+        }
+    }
+    Ok(total)
+}
+
+/// Removes the cached audio files librespot downloaded longer than `max_age` ago. Cached
+/// credentials and the volume are kept.
+pub(crate) fn evict_audio(cache_path: &Path, max_age: Duration) -> io::Result<(u64, u64)> {
+    match remove_expired(&cache_path.join(AUDIO_FILES), SystemTime::now(), max_age) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok((0, 0)),
+        removed => removed,
+    }
+}
+
+fn log_eviction(cache_path: &Path, max_age: Duration) {
+    match evict_audio(cache_path, max_age) {
+        Ok((0, _)) => (),
+        Ok((files, bytes)) => info!(
+            "Removed {} expired cached audio file{}, {}.",
+            files,
+            if files == 1 { "" } else { "s" },
+            format_size(bytes)
+        ),
+        Err(e) => warn!("Couldn't remove expired cached audio: {}", e),
+    }
+}
+
+/// Removes expired audio from the cache on startup, and then periodically while nothing
+/// is playing, so that the file of the current track is never removed.
+pub(crate) struct Expiry {
+    cache_path: PathBuf,
+    max_age: Duration,
+    interval: Interval,
+    /// Set when the interval elapsed while a track was playing.
+    due: bool,
+}
+
+impl Expiry {
+    pub(crate) fn new(cache_path: PathBuf, max_age: Duration, handle: &Handle) -> Option<Expiry> {
+        log_eviction(&cache_path, max_age);
+        match Interval::new(EXPIRY_INTERVAL, handle) {
+            Ok(interval) => Some(Expiry {
+                cache_path,
+                max_age,
+                interval,
+                due: false,
+            }),
+            Err(e) => {
+                warn!("Couldn't schedule the cache expiry: {}", e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn poll(&mut self, playing: bool) {
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            self.due = true;
+        }
+        if self.due && !playing {
+            self.due = false;
+            log_eviction(&self.cache_path, self.max_age);
+        }
+    }
+}
+
+/// What an entry of the cache directory holds.
+fn describe(name: &str) -> &'static str {
+    match name {
+        CREDENTIALS => "cached login credentials",
+        VOLUME => "last volume",
+        AUDIO_FILES => "cached audio",
+        context_volume::FILE_NAME => "remembered context volumes",
+        stats::FILE_NAME => "playback stats",
+        _ => "unknown",
+    }
+}
+
+/// Returns the number of files below `path` and their total size.
+fn usage(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok((1, metadata.len()));
+    }
+    let mut total = (0, 0);
+    for entry in fs::read_dir(path)? {
+        let (files, bytes) = usage(&entry?.path())?;
+        total = (total.0 + files, total.1 + bytes);
+    }
+    Ok(total)
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit + 1 < UNITS.len() {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Lists what's stored in the cache directory, one entry per line.
+pub(crate) fn list(cache_path: &Path) -> io::Result<String> {
+    if !cache_path.exists() {
+        return Ok(format!("{}:\n  (not created yet)\n", cache_path.display()));
+    }
+    let mut names: Vec<String> = fs::read_dir(cache_path)?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<_>>()?;
+    names.sort();
+    let mut listing = format!("{}:\n", cache_path.display());
+    if names.is_empty() {
+        listing.push_str("  (empty)\n");
+    }
+    for name in names {
+        let path = cache_path.join(&name);
+        let (files, bytes) = usage(&path)?;
+        let name = if path.is_dir() {
+            format!("{}/", name)
+        } else {
+            name
+        };
+        let size = if path.is_dir() {
+            let plural = if files == 1 { "" } else { "s" };
+            format!("{} file{}, {}", files, plural, format_size(bytes))
+        } else {
+            format_size(bytes)
+        };
+        listing.push_str(&format!(
+            "  {:<20} {:<28} {}\n",
+            name,
+            describe(name.trim_end_matches('/')),
+            size
+        ));
+    }
+    Ok(listing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let path = temp_path(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("files").join("ab")).unwrap();
+        fs::write(path.join(CREDENTIALS), b"{}").unwrap();
+        fs::write(path.join(VOLUME), b"32768").unwrap();
+        fs::write(path.join("files").join("ab").join("cdef"), vec![0; 1500]).unwrap();
+        fs::write(path.join("files").join("ab").join("0123"), vec![0; 2500]).unwrap();
+        path
+    }
+
+    #[test]
+    fn create_mode() {
+        let dir = cache_dir("mode");
+        let cache = dir.join("cache");
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        create(&cache, None).unwrap();
+        assert_eq!(mode(&cache), 0o700);
+        fs::set_permissions(&cache, Permissions::from_mode(0o755)).unwrap();
+        create(&cache, None).unwrap();
+        assert_eq!(mode(&cache), 0o755);
+        create(&cache, Some(0o750)).unwrap();
+        assert_eq!(mode(&cache), 0o750);
+
+        assert!(!has_credentials(&cache));
+        fs::write(cache.join(CREDENTIALS), "{}").unwrap();
+        assert!(has_credentials(&cache));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forget() {
+        let path = cache_dir("forget");
+        let removed = forget_device(&path).unwrap();
+        assert_eq!(removed, vec![path.join(CREDENTIALS), path.join(VOLUME)]);
+        // The cached audio stays.
+        assert!(!path.join(CREDENTIALS).exists());
+        assert!(!path.join(VOLUME).exists());
+        assert!(path.join("files").join("ab").join("cdef").exists());
+        // Forgetting again is fine.
+        assert_eq!(forget_device(&path).unwrap(), Vec::<PathBuf>::new());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn listing() {
+        let path = cache_dir("list");
+        fs::write(
+            path.join(context_volume::FILE_NAME),
+            b"spotify:album:a\t40\n",
+        )
+        .unwrap();
+        assert_eq!(
+            list(&path).unwrap(),
+            format!(
+                "{}:\n  \
+                 context_volumes      remembered context volumes   19 B\n  \
+                 credentials.json     cached login credentials     2 B\n  \
+                 files/               cached audio                 2 files, 4.0 kB\n  \
+                 volume               last volume                  5 B\n",
+                path.display()
+            )
+        );
+
+        forget_device(&path).unwrap();
+        fs::remove_dir_all(path.join("files")).unwrap();
+        fs::remove_file(path.join(context_volume::FILE_NAME)).unwrap();
+        assert_eq!(
+            list(&path).unwrap(),
+            format!("{}:\n  (empty)\n", path.display())
+        );
+        fs::remove_dir_all(&path).unwrap();
+        assert_eq!(
+            list(&path).unwrap(),
+            format!("{}:\n  (not created yet)\n", path.display())
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(expired(now - 31 * day, now, 30 * day));
+        assert!(!expired(now - 29 * day, now, 30 * day));
+        assert!(!expired(now - 30 * day, now, 30 * day));
+        // Downloaded "after" now, as seen by a clock that was set back.
+        assert!(!expired(now + day, now, 30 * day));
+
+        let path = cache_dir("expiry");
+        let audio = path.join("files").join("ab");
+        // Both files were just written, so they're an hour old an hour from now.
+        let later = now + Duration::from_secs(60 * 60);
+        assert_eq!(
+            remove_expired(&audio, later, Duration::from_secs(2 * 60 * 60)).unwrap(),
+            (0, 0)
+        );
+        assert_eq!(
+            remove_expired(&path.join("files"), later, Duration::from_secs(60)).unwrap(),
+            (2, 4000)
+        );
+        assert!(!audio.join("cdef").exists());
+        assert!(path.join(CREDENTIALS).exists());
+
+        fs::remove_dir_all(path.join("files")).unwrap();
+        assert_eq!(evict_audio(&path, day).unwrap(), (0, 0));
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1500), "1.5 kB");
+        assert_eq!(format_size(250_000_000), "250.0 MB");
+        assert_eq!(format_size(12_000_000_000_000), "12000.0 GB");
+    }
+}
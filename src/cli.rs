@@ -1,5 +1,7 @@
 use getopts::Options;
-use librespot::playback::audio_backend::BACKENDS;
+
+use crate::backends;
+use crate::presets::{list_presets, preset_dirs};
 
 pub fn usage(program: &str, opts: &Options) -> String {
     let brief = format!("Usage: {} [options]", program);
@@ -7,18 +9,63 @@ pub fn usage(program: &str, opts: &Options) -> String {
 }
 
 pub fn print_backends() {
-    println!("Available backends:");
-    for &(name, _) in BACKENDS {
+    print!("{}", backends::listing());
+}
+
+pub fn print_presets() {
+    println!("Available presets:");
+    for name in list_presets(&preset_dirs()) {
         println!("- {}", name);
     }
 }
 
 pub fn command_line_argument_options() -> Options {
     let mut opts = Options::new();
-    opts.optopt("c", "config", "Path to a config file.", "CONFIG");
+    opts.optmulti(
+        "c",
+        "config",
+        "Path to a config file, or - to read it from stdin. Repeat to merge several, with \
+         later files overriding earlier ones.",
+        "CONFIG",
+    );
+    opts.optopt(
+        "",
+        "preset",
+        "Name of a preset that fills in options the config file leaves unset.",
+        "NAME",
+    );
     opts.optopt("u", "username", "Spotify user name.", "USERNAME");
+    opts.optflag(
+        "",
+        "trim_username",
+        "Strip whitespace around the username, which is otherwise used as given.",
+    );
+    opts.optopt(
+        "",
+        "allowed_users",
+        "Comma-separated Spotify accounts that may use the device through discovery.",
+        "USERS",
+    );
     opts.optopt("p", "password", "Spotify password.", "PASSWORD");
+    opts.optopt(
+        "",
+        "password_file",
+        "Path to a file containing the Spotify password.",
+        "PATH",
+    );
     opts.optopt("", "device", "Audio device, given by aplay -L.", "DEVICE");
+    opts.optopt(
+        "",
+        "device_blacklist",
+        "Comma-separated audio devices to skip when picking one, if device isn't set.",
+        "DEVICES",
+    );
+    opts.optopt(
+        "",
+        "device_close_delay",
+        "Keep the audio device open this long after playback stops, e.g. 30s.",
+        "DURATION",
+    );
     opts.optopt(
         "",
         "control",
@@ -26,11 +73,102 @@ pub fn command_line_argument_options() -> Options {
         "DEVICE",
     );
     opts.optopt("", "mixer", "Audio mixer", "DEVICE");
+    opts.optopt(
+        "",
+        "startup_mixer_volume",
+        "Set the alsa mixer to this volume percentage on startup, scaled like Spotify's volume.",
+        "PERCENT",
+    );
+    opts.optflag(
+        "",
+        "remember_context_volume",
+        "Restore the volume last used for a playlist, album or show when it plays again.",
+    );
+    opts.optflag(
+        "",
+        "persist_stats",
+        "Add up the playback stats of all sessions in the cache directory.",
+    );
+    opts.optflag(
+        "",
+        "resume_on_start",
+        "Pick up, paused, where playback was when spotifyd last ran.",
+    );
+    opts.optopt(
+        "",
+        "quiet_hours_start",
+        "Start of the daily window in which the volume is capped, e.g. 22:00.",
+        "HH:MM",
+    );
+    opts.optopt(
+        "",
+        "quiet_hours_end",
+        "End of the daily window in which the volume is capped, e.g. 07:00.",
+        "HH:MM",
+    );
+    opts.optopt(
+        "",
+        "quiet_hours_max_volume",
+        "Highest volume percentage allowed during quiet hours.",
+        "PERCENT",
+    );
     opts.optopt("", "bitrate", "Any of 96, 160, and 320.", "DEVICE");
     opts.optopt("", "pid", "Path to PID file.", "PID-FILE");
+    opts.optopt(
+        "",
+        "nice",
+        "Run at this niceness, from -20 (highest priority) to 19.",
+        "NICE",
+    );
+    opts.optopt(
+        "",
+        "rt_priority",
+        "Schedule the audio thread in real time (SCHED_FIFO) at this priority, 1 to 99.",
+        "PRIORITY",
+    );
+    opts.optflag(
+        "",
+        "mlock",
+        "Lock all memory into RAM, so that playback doesn't wait for it to be paged in.",
+    );
     opts.optopt("", "device_name", "Name of this Spotify device.", "DEVICE");
+    opts.optopt(
+        "",
+        "strip_hostname_domain",
+        "Leave the domain out of the hostname in the default device name: true or false.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "discovery_name",
+        "Name to advertise this device under on the local network, if not device_name.",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "instance_policy",
+        "What to do if another instance runs with the same device name: refuse, takeover or coexist.",
+        "POLICY",
+    );
+    opts.optflag(
+        "",
+        "announce_only_when_idle",
+        "Stop advertising the device on the local network while it's playing.",
+    );
     opts.optopt("", "backend", "Audio backend.", "BACKEND");
     opts.optopt("", "cache_path", "Path to cache location.", "PATH");
+    opts.optopt(
+        "",
+        "cache_dir_mode",
+        "Octal permissions for the cache directory, 0700 when it's created by default.",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "cache_expiry",
+        "Remove cached audio files older than this, e.g. 30d. 0 keeps them.",
+        "DURATION",
+    );
     opts.optflag(
         "",
         "volume-normalisation",
@@ -42,6 +180,64 @@ pub fn command_line_argument_options() -> Options {
         "dB of pregain for volume normalisation",
         "PREGAIN",
     );
+    opts.optopt(
+        "",
+        "fade_in_ms",
+        "Fade in over this many milliseconds when playback starts or resumes. 0 disables it.",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "fade_out_ms",
+        "Fade out over this many milliseconds before pausing. 0 disables it.",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "playback_speed",
+        "Play everything this much faster (or slower) without changing the pitch, 0.5 to 3.",
+        "SPEED",
+    );
+    opts.optopt(
+        "",
+        "eq_bands",
+        "Equalizer bands as comma-separated frequency/gain/Q triples, e.g. 80/-6/0.7.",
+        "BANDS",
+    );
+    opts.optopt(
+        "",
+        "master_gain",
+        "Gain in dB applied on top of the volume, e.g. -12 for a sensitive amplifier.",
+        "DB",
+    );
+    opts.optflag(
+        "",
+        "peak_limiter",
+        "Compress peaks above peak_limiter_threshold smoothly instead of clipping them.",
+    );
+    opts.optopt(
+        "",
+        "peak_limiter_threshold",
+        "Level in dB below full scale above which the peak limiter kicks in, -1 by default.",
+        "DB",
+    );
+    opts.optflag(
+        "",
+        "mono",
+        "Mix stereo output down to mono, for single speaker setups.",
+    );
+    opts.optopt(
+        "",
+        "channel_map",
+        "Input channels to play on the left and right output, e.g. R,L to swap them.",
+        "MAP",
+    );
+    opts.optopt(
+        "",
+        "max_track_length",
+        "Skip tracks longer than this, e.g. 10m. 0 disables skipping.",
+        "DURATION",
+    );
     opts.optopt(
         "",
         "onevent",
@@ -49,20 +245,231 @@ pub fn command_line_argument_options() -> Options {
          passed to the command.",
         "COMMAND",
     );
+    opts.optopt(
+        "",
+        "onevent_filter",
+        "Comma-separated list of events (change, start, stop) to run the onevent command \
+         for. All events if not given.",
+        "EVENTS",
+    );
+    opts.optopt(
+        "",
+        "onevent_max_procs",
+        "Run up to this many onevent commands at once, dropping events beyond that.",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "shell",
+        "Shell to run password_cmd and onevent with, instead of the user's shell.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "client_id",
+        "Spotify client id to request Web API tokens for, instead of the built-in one.",
+        "ID",
+    );
     opts.optopt(
         "",
         "volume-control",
         "Possible values are alsa, alsa_linear, and softvol.",
         "CONTROLLER",
     );
+    opts.optopt(
+        "",
+        "volume_backend",
+        "Whether volume changes drive software or hardware (mixer) volume.",
+        "BACKEND",
+    );
+    opts.optopt(
+        "",
+        "use_mpris",
+        "Whether to register the MPRIS interface on D-Bus: true, false or required.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "mpris_instance",
+        "Suffix for the MPRIS bus name, either pid or a custom name.",
+        "SUFFIX",
+    );
+    opts.optopt(
+        "",
+        "dbus_type",
+        "D-Bus bus to register the MPRIS interface on, session or system.",
+        "BUS",
+    );
+    opts.optopt(
+        "",
+        "artist_separator",
+        "Join the artists of a track with this for display, or with FIRST|REST.",
+        "SEPARATOR",
+    );
+    opts.optopt(
+        "",
+        "mpris_identity",
+        "The name media controls show for the MPRIS interface, the device name by default.",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "mpris_desktop_entry",
+        "The desktop entry media controls take the icon from, spotifyd by default.",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "max_reconnect_attempts",
+        "Give up after this many failed reconnection attempts.",
+        "ATTEMPTS",
+    );
+    opts.optopt(
+        "",
+        "reconnect_cooldown",
+        "Time between connection attempts, doubled after each failure up to 5m, e.g. 30s.",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "connect_timeout",
+        "Give up on a connection attempt after this long, e.g. 30s.",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "on_auth_failure",
+        "What to do when Spotify rejects the credentials: exit (default) or wait.",
+        "POLICY",
+    );
+    opts.optopt(
+        "",
+        "autostart_uri",
+        "Spotify URI to start playing on this device at startup, with cached credentials.",
+        "URI",
+    );
+    opts.optopt(
+        "",
+        "autostart_volume",
+        "Volume percentage to start autostart_uri at.",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "lastfm_api_key",
+        "The key of the Last.fm API account to scrobble with.",
+        "KEY",
+    );
+    opts.optopt(
+        "",
+        "lastfm_api_secret",
+        "The secret of the Last.fm API account to scrobble with.",
+        "SECRET",
+    );
+    opts.optopt(
+        "",
+        "lastfm_username",
+        "The Last.fm user to scrobble to.",
+        "USERNAME",
+    );
+    opts.optopt(
+        "",
+        "lastfm_password",
+        "The password of the Last.fm user to scrobble to.",
+        "PASSWORD",
+    );
     opts.optflag("v", "verbose", "Add debug information to log.");
+    opts.optopt(
+        "",
+        "log_color",
+        "Whether to color log lines when not running as a daemon: auto, always or never.",
+        "WHEN",
+    );
+    opts.optopt(
+        "",
+        "heartbeat_interval",
+        "Log that spotifyd is alive at this interval, e.g. 15m. 0 disables it.",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "log_rate_limit",
+        "Log the same line at most this many times per interval, e.g. 5/30s.",
+        "LINES",
+    );
     opts.optflag(
         "",
         "use-keyring",
         "Use the system's keyring to retrieve the password",
     );
+    opts.optopt(
+        "",
+        "keyring_service",
+        "The service of the keyring entry holding the password, spotifyd by default.",
+        "SERVICE",
+    );
+    opts.optopt(
+        "",
+        "keyring_account",
+        "The account of the keyring entry holding the password, the username by default.",
+        "ACCOUNT",
+    );
     opts.optflag("", "no-daemon", "Don't detach from console.");
+    opts.optopt(
+        "",
+        "once",
+        "Log in, play the given track URI and exit, with a non-zero status on failure.",
+        "URI",
+    );
+    opts.optopt(
+        "",
+        "once-duration",
+        "How long --once plays the track for, 30s by default.",
+        "DURATION",
+    );
+    opts.optflag(
+        "",
+        "verify-credentials",
+        "Log in once to check the configured credentials, and exit.",
+    );
+    opts.optflag(
+        "",
+        "doctor",
+        "Check the configuration, credentials, audio device, D-Bus, network and cache, and exit.",
+    );
+    opts.optflag(
+        "",
+        "forget-device",
+        "Remove the cached credentials and volume, so that the next start logs in anew, and exit.",
+    );
+    opts.optflag(
+        "",
+        "list-cache",
+        "List what's stored in the cache directory, and exit.",
+    );
+    opts.optflag(
+        "",
+        "dump-config",
+        "Print the effective configuration, with secrets redacted, and exit.",
+    );
+    opts.optflagopt(
+        "",
+        "migrate-config",
+        "Rewrite the configuration file in TOML, to PATH or stdout, and exit.",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "config-schema",
+        "Print a JSON Schema of the configuration file, and exit.",
+    );
     opts.optflag("", "backends", "List available audio backends.");
+    opts.optflag(
+        "",
+        "list-backends",
+        "List the audio backends compiled into this binary, marking the default.",
+    );
+    opts.optflag("", "list-presets", "List available presets.");
     opts.optflag("h", "help", "Print this help text.");
     opts.optflag("V", "version", "Print version number");
     opts
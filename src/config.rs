@@ -1,26 +1,61 @@
+use chrono::NaiveTime;
 use getopts::Matches;
 use ini::Ini;
 use librespot::{
     core::{cache::Cache, config::SessionConfig, version},
     playback::config::{Bitrate, PlayerConfig},
 };
-use log::info;
+use log::{info, warn};
 use sha1::{Digest, Sha1};
 use std::{
     convert::From,
-    fs::metadata,
+    fmt,
+    fs::{self, metadata},
+    io::{self, Read},
     mem::swap,
+    ops::RangeInclusive,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 use xdg;
 
+use crate::audio_filter::{parse_eq_bands, ChannelMap, FilterConfig, Gain, PeakLimiter};
+use crate::backends::{assign_devices, backends, split_device, BACKENDS_WITHOUT_DEVICE};
+#[cfg(feature = "bluealsa_backend")]
+use crate::bluealsa_sink::pcm_name;
+use crate::config_format::{self, Format};
 use crate::error::{Error, ErrorKind};
-use crate::process::run_program;
+use crate::instance_lock::InstancePolicy;
+use crate::presets::{load_preset, preset_dirs};
+use crate::process::{run_program, PLAYER_EVENTS};
+use crate::quiet_hours::QuietHours;
+use crate::reconnect::AuthFailurePolicy;
+use crate::scrobble::LastfmConfig;
 use crate::utils;
 
 const CONFIG_FILE: &str = "spotifyd.conf";
 
+/// Audio backends that can be compiled in, together with the cargo feature enabling them.
+const OPTIONAL_BACKENDS: &[(&str, &str)] = &[
+    ("alsa", "alsa_backend"),
+    ("bluealsa", "bluealsa_backend"),
+    ("portaudio", "portaudio_backend"),
+    ("pulseaudio", "pulseaudio_backend"),
+    ("rodio", "rodio_backend"),
+];
+
+/// The client id spotifyd requests Web API tokens for, unless `client_id` is set.
+pub(crate) const CLIENT_ID: &str = "2c1ea588dfbc4a989e2426f8385297c3";
+
+/// DNS labels, and with them zeroconf service names, are at most 63 bytes long.
+const MAX_ADVERTISED_NAME_LEN: usize = 63;
+
+/// The well-known bus name the MPRIS interface is registered under.
+pub(crate) const MPRIS_BUS_NAME: &str = "org.mpris.MediaPlayer2.spotifyd";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum VolumeController {
     Alsa { linear: bool },
     SoftVol,
@@ -43,42 +78,378 @@ impl FromStr for VolumeController {
     }
 }
 
+/// Which volume Spotify Connect's volume commands change: the samples', or the sound
+/// card's through its mixer control.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum VolumeBackend {
+    Software,
+    Hardware,
+}
+
+impl FromStr for VolumeBackend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "software" => Ok(VolumeBackend::Software),
+            "hardware" => Ok(VolumeBackend::Hardware),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for VolumeBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VolumeBackend::Software => write!(f, "software"),
+            VolumeBackend::Hardware => write!(f, "hardware"),
+        }
+    }
+}
+
+/// Picks the volume controller for `backend`, which takes precedence over
+/// `volume-control`. Hardware volume keeps the scaling `volume-control` asks for, and
+/// needs to know the mixer control to drive.
+fn select_volume_controller(
+    controller: VolumeController,
+    backend: Option<VolumeBackend>,
+    mixer: Option<&str>,
+) -> Result<VolumeController, Error> {
+    match backend {
+        None => Ok(controller),
+        Some(VolumeBackend::Software) => Ok(VolumeController::SoftVol),
+        Some(VolumeBackend::Hardware) if !cfg!(feature = "alsa_backend") => {
+            Err(ErrorKind::ConfigValueInvalid {
+                key: "volume_backend",
+                value: VolumeBackend::Hardware.to_string(),
+                expected: "software, as spotifyd was built without the alsa_backend feature",
+            }
+            .into())
+        }
+        Some(VolumeBackend::Hardware) if mixer.is_none() => Err(ErrorKind::ConfigMissing {
+            key: "mixer",
+            needed_by: "volume_backend = hardware",
+        }
+        .into()),
+        Some(VolumeBackend::Hardware) => match controller {
+            VolumeController::Alsa { linear } => Ok(VolumeController::Alsa { linear }),
+            VolumeController::SoftVol => Ok(VolumeController::Alsa { linear: false }),
+        },
+    }
+}
+
+/// The D-Bus bus the MPRIS interface is registered on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DBusType {
+    Session,
+    System,
+}
+
+impl FromStr for DBusType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "session" => Ok(DBusType::Session),
+            "system" => Ok(DBusType::System),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for DBusType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DBusType::Session => write!(f, "session"),
+            DBusType::System => write!(f, "system"),
+        }
+    }
+}
+
+/// The names MPRIS controllers show spotifyd under: `identity` as its name, and the icon
+/// of the desktop entry (the name of a .desktop file) `desktop_entry`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MprisIdentity {
+    pub(crate) identity: String,
+    pub(crate) desktop_entry: String,
+}
+
+/// How the artists of a track are joined into one string for display: `first` goes
+/// between the first two artists, `rest` between the others, as in "A feat. B & C".
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ArtistSeparator {
+    first: String,
+    rest: String,
+}
+
+impl ArtistSeparator {
+    pub(crate) fn join<S: AsRef<str>>(&self, artists: &[S]) -> String {
+        let mut joined = String::new();
+        for (i, artist) in artists.iter().enumerate() {
+            match i {
+                0 => (),
+                1 => joined.push_str(&self.first),
+                _ => joined.push_str(&self.rest),
+            }
+            joined.push_str(artist.as_ref());
+        }
+        joined
+    }
+}
+
+impl Default for ArtistSeparator {
+    fn default() -> Self {
+        ArtistSeparator {
+            first: ", ".to_string(),
+            rest: ", ".to_string(),
+        }
+    }
+}
+
+/// Parses a single separator, or the two `first|rest` ones.
+impl FromStr for ArtistSeparator {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '|');
+        let first = parts.next().unwrap_or_default();
+        let rest = parts.next().unwrap_or(first);
+        if first.is_empty() || rest.is_empty() {
+            return Err(());
+        }
+        Ok(ArtistSeparator {
+            first: first.to_string(),
+            rest: rest.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ArtistSeparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.first == self.rest {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}|{}", self.first, self.rest)
+        }
+    }
+}
+
+/// Whether log lines written to the terminal are colored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LogColor {
+    /// Color them if stderr is a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl LogColor {
+    pub(crate) fn colored(self, is_tty: bool) -> bool {
+        match self {
+            LogColor::Auto => is_tty,
+            LogColor::Always => true,
+            LogColor::Never => false,
+        }
+    }
+}
+
+impl FromStr for LogColor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "auto" => Ok(LogColor::Auto),
+            "always" => Ok(LogColor::Always),
+            "never" => Ok(LogColor::Never),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether to register the MPRIS interface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum UseMpris {
+    No,
+    /// Register it if the bus is available, and continue without it otherwise.
+    Yes,
+    /// Register it, and refuse to start if the bus is unavailable.
+    Required,
+}
+
+impl FromStr for UseMpris {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "false" => Ok(UseMpris::No),
+            "true" => Ok(UseMpris::Yes),
+            "required" => Ok(UseMpris::Required),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for UseMpris {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UseMpris::No => write!(f, "false"),
+            UseMpris::Yes => write!(f, "true"),
+            UseMpris::Required => write!(f, "required"),
+        }
+    }
+}
+
+/// Suffix appended to the MPRIS bus name so that several instances can run side by side.
+pub(crate) enum MprisInstance {
+    /// `instance<pid>`, as suggested by the MPRIS specification.
+    Pid,
+    Suffix(String),
+}
+
+impl MprisInstance {
+    /// Returns the full bus name, e.g. `org.mpris.MediaPlayer2.spotifyd.instance1234`.
+    ///
+    /// This must be called after daemonizing, as the pid changes when the process forks.
+    pub(crate) fn bus_name(&self) -> String {
+        match self {
+            MprisInstance::Pid => format!("{}.instance{}", MPRIS_BUS_NAME, std::process::id()),
+            MprisInstance::Suffix(suffix) => format!("{}.{}", MPRIS_BUS_NAME, suffix),
+        }
+    }
+}
+
+impl FromStr for MprisInstance {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "pid" {
+            return Ok(MprisInstance::Pid);
+        }
+        // Each element of a bus name may only contain "[A-Za-z0-9_-]", must not be empty
+        // and must not begin with a digit. The whole name is limited to 255 characters.
+        let is_valid_element = |element: &str| {
+            !element.is_empty()
+                && !element.starts_with(|c: char| c.is_ascii_digit())
+                && element
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if s.split('.').all(is_valid_element) && MPRIS_BUS_NAME.len() + 1 + s.len() <= 255 {
+            Ok(MprisInstance::Suffix(s.to_string()))
+        } else {
+            Err(ErrorKind::MprisInstanceInvalid(s.to_string()).into())
+        }
+    }
+}
+
 pub(crate) struct SpotifydConfig {
     pub(crate) username: Option<String>,
+    pub(crate) trim_username: bool,
+    /// The accounts that may log the device in through discovery, any if it's empty.
+    pub(crate) allowed_users: Vec<String>,
     pub(crate) password: Option<String>,
     pub(crate) use_keyring: bool,
+    /// The keyring entry to read the password from, by default `spotifyd` and the username.
+    pub(crate) keyring_service: String,
+    pub(crate) keyring_account: Option<String>,
     pub(crate) cache: Option<Cache>,
+    pub(crate) cache_path: Option<PathBuf>,
+    pub(crate) cache_dir_mode: Option<u32>,
+    /// Cached audio files are removed once they're older than this.
+    pub(crate) cache_expiry: Option<Duration>,
     pub(crate) backend: Option<String>,
     pub(crate) audio_device: Option<String>,
+    /// Devices to skip when picking one because `audio_device` isn't set.
+    pub(crate) device_blacklist: Vec<String>,
     pub(crate) control_device: Option<String>,
     pub(crate) mixer: Option<String>,
+    pub(crate) startup_mixer_volume: Option<u8>,
+    pub(crate) quiet_hours: Option<QuietHours>,
     pub(crate) volume_controller: VolumeController,
+    pub(crate) volume_backend: Option<VolumeBackend>,
     pub(crate) device_name: String,
+    /// Whether the domain is left out of the hostname in the default device name.
+    pub(crate) strip_hostname_domain: bool,
+    /// The name to advertise through zeroconf, if not `device_name`.
+    pub(crate) discovery_name: Option<String>,
+    pub(crate) instance_policy: InstancePolicy,
     pub(crate) player_config: PlayerConfig,
+    pub(crate) filters: FilterConfig,
     pub(crate) session_config: SessionConfig,
     pub(crate) onevent: Option<String>,
+    pub(crate) onevent_filter: Option<Vec<&'static str>>,
+    pub(crate) onevent_max_procs: Option<usize>,
     pub(crate) pid: Option<String>,
+    /// The niceness to run at, if not the one spotifyd was started with.
+    pub(crate) nice: Option<i32>,
+    /// The SCHED_FIFO priority of the audio thread, if it's scheduled in real time.
+    pub(crate) rt_priority: Option<i32>,
+    /// Whether all memory is locked into RAM.
+    pub(crate) mlock: bool,
     pub(crate) shell: String,
+    pub(crate) client_id: String,
+    pub(crate) use_mpris: UseMpris,
+    pub(crate) mpris_instance: Option<MprisInstance>,
+    pub(crate) dbus_type: DBusType,
+    pub(crate) artist_separator: ArtistSeparator,
+    /// The MPRIS `Identity`, if not the device name.
+    pub(crate) mpris_identity: Option<String>,
+    pub(crate) mpris_desktop_entry: String,
+    pub(crate) max_reconnect_attempts: Option<u32>,
+    pub(crate) reconnect_cooldown: Duration,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) on_auth_failure: AuthFailurePolicy,
+    pub(crate) heartbeat_interval: Option<Duration>,
+    pub(crate) max_track_length: Option<Duration>,
+    pub(crate) device_close_delay: Duration,
+    pub(crate) fade_out: Option<Duration>,
+    /// The speed everything is played at, if not 1.
+    pub(crate) playback_speed: Option<f64>,
+    pub(crate) remember_context_volume: bool,
+    /// Whether to keep the playback stats of all sessions in the cache directory.
+    pub(crate) persist_stats: bool,
+    /// Whether to pick up, paused, where playback was when spotifyd last ran.
+    pub(crate) resume_on_start: bool,
+    pub(crate) announce_only_when_idle: bool,
+    pub(crate) log_rate_limit: Option<(usize, Duration)>,
+    pub(crate) autostart_uri: Option<String>,
+    pub(crate) autostart_volume: Option<u8>,
+    pub(crate) lastfm: Option<LastfmConfig>,
 }
 
 impl Default for SpotifydConfig {
     fn default() -> SpotifydConfig {
         SpotifydConfig {
             username: None,
+            trim_username: false,
+            allowed_users: Vec::new(),
             password: None,
             use_keyring: false,
+            keyring_service: "spotifyd".to_string(),
+            keyring_account: None,
             cache: None,
+            cache_path: None,
+            cache_dir_mode: None,
+            cache_expiry: None,
             backend: None,
             audio_device: None,
+            device_blacklist: Vec::new(),
             control_device: None,
             mixer: None,
+            startup_mixer_volume: None,
+            quiet_hours: None,
             volume_controller: VolumeController::SoftVol,
+            volume_backend: None,
             device_name: "Spotifyd".to_string(),
+            strip_hostname_domain: true,
+            discovery_name: None,
+            instance_policy: InstancePolicy::Refuse,
             player_config: PlayerConfig {
                 bitrate: Bitrate::Bitrate160,
                 normalisation: false,
                 normalisation_pregain: 0.0,
             },
+            filters: FilterConfig::default(),
             session_config: SessionConfig {
                 user_agent: version::version_string(),
                 device_id: device_id("Spotifyd"),
@@ -86,12 +457,338 @@ impl Default for SpotifydConfig {
                 ap_port: Some(443),
             },
             onevent: None,
+            onevent_filter: None,
+            onevent_max_procs: None,
             pid: None,
-            shell: utils::get_shell().unwrap_or_else(|| {
-                info!("Unable to identify shell. Defaulting to \"sh\".");
-                "sh".to_string()
+            nice: None,
+            rt_priority: None,
+            mlock: false,
+            shell: utils::FALLBACK_SHELL.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            use_mpris: UseMpris::Yes,
+            mpris_instance: None,
+            dbus_type: DBusType::Session,
+            artist_separator: ArtistSeparator::default(),
+            mpris_identity: None,
+            mpris_desktop_entry: "spotifyd".to_string(),
+            max_reconnect_attempts: None,
+            reconnect_cooldown: Duration::from_secs(10),
+            connect_timeout: None,
+            on_auth_failure: AuthFailurePolicy::Exit,
+            heartbeat_interval: None,
+            max_track_length: None,
+            device_close_delay: Duration::from_secs(0),
+            fade_out: None,
+            playback_speed: None,
+            remember_context_volume: false,
+            persist_stats: false,
+            resume_on_start: false,
+            announce_only_when_idle: false,
+            log_rate_limit: None,
+            autostart_uri: None,
+            autostart_volume: None,
+            lastfm: None,
+        }
+    }
+}
+
+impl SpotifydConfig {
+    /// The name the device is advertised under on the local network, which Spotify apps
+    /// show for devices they haven't connected to yet.
+    pub(crate) fn advertised_name(&self) -> &str {
+        self.discovery_name.as_ref().unwrap_or(&self.device_name)
+    }
+
+    /// The bus name to register the MPRIS interface under.
+    pub(crate) fn mpris_bus_name(&self) -> String {
+        self.mpris_instance
+            .as_ref()
+            .map(MprisInstance::bus_name)
+            .unwrap_or_else(|| MPRIS_BUS_NAME.to_string())
+    }
+
+    pub(crate) fn mpris_identity(&self) -> MprisIdentity {
+        MprisIdentity {
+            identity: self
+                .mpris_identity
+                .clone()
+                .unwrap_or_else(|| self.device_name.clone()),
+            desktop_entry: self.mpris_desktop_entry.clone(),
+        }
+    }
+
+    /// Renders the effective configuration in the format of the config file, with secrets
+    /// replaced by `***`. Unset options are left out.
+    pub(crate) fn dump(&self) -> String {
+        fn quote(s: &str) -> String {
+            format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+
+        let mut entries: Vec<(&str, String)> = Vec::new();
+        let mut push = |key, value: Option<String>| {
+            if let Some(value) = value {
+                entries.push((key, value));
+            }
+        };
+        push("username", self.username.as_ref().map(|s| quote(s)));
+        push("trim_username", Some(self.trim_username.to_string()));
+        push(
+            "allowed_users",
+            if self.allowed_users.is_empty() {
+                None
+            } else {
+                Some(quote(&self.allowed_users.join(",")))
+            },
+        );
+        push("password", self.password.as_ref().map(|_| quote("***")));
+        push("use-keyring", Some(self.use_keyring.to_string()));
+        push("keyring_service", Some(quote(&self.keyring_service)));
+        push(
+            "keyring_account",
+            self.keyring_account.as_ref().map(|s| quote(s)),
+        );
+        push("backend", self.backend.as_ref().map(|s| quote(s)));
+        push("device", self.audio_device.as_ref().map(|s| quote(s)));
+        push(
+            "device_blacklist",
+            if self.device_blacklist.is_empty() {
+                None
+            } else {
+                Some(quote(&self.device_blacklist.join(",")))
+            },
+        );
+        push("control", self.control_device.as_ref().map(|s| quote(s)));
+        push("mixer", self.mixer.as_ref().map(|s| quote(s)));
+        push(
+            "startup_mixer_volume",
+            self.startup_mixer_volume.map(|percent| percent.to_string()),
+        );
+        if let Some(quiet_hours) = self.quiet_hours {
+            push(
+                "quiet_hours_start",
+                Some(quote(&quiet_hours.start.format("%H:%M").to_string())),
+            );
+            push(
+                "quiet_hours_end",
+                Some(quote(&quiet_hours.end.format("%H:%M").to_string())),
+            );
+            push(
+                "quiet_hours_max_volume",
+                Some(((u32::from(quiet_hours.max_volume) * 100 + 0x7FFF) / 0xFFFF).to_string()),
+            );
+        }
+        push(
+            "volume-control",
+            Some(quote(match self.volume_controller {
+                VolumeController::Alsa { linear: false } => "alsa",
+                VolumeController::Alsa { linear: true } => "alsa_linear",
+                VolumeController::SoftVol => "softvol",
+            })),
+        );
+        push(
+            "volume_backend",
+            self.volume_backend
+                .map(|backend| quote(&backend.to_string())),
+        );
+        push(
+            "strip_hostname_domain",
+            Some(self.strip_hostname_domain.to_string()),
+        );
+        push("device_name", Some(quote(&self.device_name)));
+        push(
+            "discovery_name",
+            self.discovery_name.as_ref().map(|s| quote(s)),
+        );
+        push(
+            "bitrate",
+            Some(
+                match self.player_config.bitrate {
+                    Bitrate::Bitrate96 => "96",
+                    Bitrate::Bitrate160 => "160",
+                    Bitrate::Bitrate320 => "320",
+                }
+                .to_string(),
+            ),
+        );
+        push(
+            "cache_path",
+            self.cache_path
+                .as_ref()
+                .map(|p| quote(&p.to_string_lossy())),
+        );
+        push(
+            "cache_dir_mode",
+            self.cache_dir_mode
+                .map(|mode| quote(&format!("{:04o}", mode))),
+        );
+        push(
+            "cache_expiry",
+            self.cache_expiry
+                .map(|expiry| quote(&format_duration(expiry))),
+        );
+        push(
+            "volume-normalisation",
+            Some(self.player_config.normalisation.to_string()),
+        );
+        push(
+            "normalisation-pregain",
+            Some(self.player_config.normalisation_pregain.to_string()),
+        );
+        push(
+            "fade_in_ms",
+            Some(
+                self.filters
+                    .fade_in
+                    .map_or(0, |fade| fade.as_millis())
+                    .to_string(),
+            ),
+        );
+        push(
+            "fade_out_ms",
+            Some(self.fade_out.map_or(0, |fade| fade.as_millis()).to_string()),
+        );
+        push(
+            "playback_speed",
+            Some(self.playback_speed.unwrap_or(1.0).to_string()),
+        );
+        if !self.filters.eq_bands.is_empty() {
+            let bands: Vec<_> = self
+                .filters
+                .eq_bands
+                .iter()
+                .map(|b| b.to_string())
+                .collect();
+            push("eq_bands", Some(quote(&bands.join(","))));
+        }
+        push(
+            "master_gain",
+            self.filters.gain.map(|gain| gain.to_string()),
+        );
+        push(
+            "peak_limiter",
+            Some(self.filters.peak_limiter.is_some().to_string()),
+        );
+        push(
+            "peak_limiter_threshold",
+            self.filters.peak_limiter.map(|limiter| limiter.to_string()),
+        );
+        push("mono", Some(self.filters.mono.to_string()));
+        push(
+            "channel_map",
+            self.filters.channel_map.map(|map| quote(&map.to_string())),
+        );
+        push("onevent", self.onevent.as_ref().map(|s| quote(s)));
+        push(
+            "onevent_filter",
+            self.onevent_filter
+                .as_ref()
+                .map(|events| quote(&events.join(","))),
+        );
+        push(
+            "onevent_max_procs",
+            self.onevent_max_procs.map(|max| max.to_string()),
+        );
+        push("pid", self.pid.as_ref().map(|s| quote(s)));
+        push("nice", self.nice.map(|nice| nice.to_string()));
+        push(
+            "rt_priority",
+            self.rt_priority.map(|priority| priority.to_string()),
+        );
+        push("mlock", Some(self.mlock.to_string()));
+        push("shell", Some(quote(&self.shell)));
+        push("client_id", Some(quote(&self.client_id)));
+        push("use_mpris", Some(quote(&self.use_mpris.to_string())));
+        push(
+            "mpris_instance",
+            self.mpris_instance.as_ref().map(|instance| match instance {
+                MprisInstance::Pid => quote("pid"),
+                MprisInstance::Suffix(suffix) => quote(suffix),
+            }),
+        );
+        push("dbus_type", Some(quote(&self.dbus_type.to_string())));
+        push(
+            "artist_separator",
+            Some(quote(&self.artist_separator.to_string())),
+        );
+        push(
+            "mpris_identity",
+            self.mpris_identity.as_ref().map(|s| quote(s)),
+        );
+        push(
+            "mpris_desktop_entry",
+            Some(quote(&self.mpris_desktop_entry)),
+        );
+        push(
+            "max_reconnect_attempts",
+            self.max_reconnect_attempts.map(|n| n.to_string()),
+        );
+        push(
+            "reconnect_cooldown",
+            Some(quote(&format_duration(self.reconnect_cooldown))),
+        );
+        push(
+            "connect_timeout",
+            self.connect_timeout
+                .map(|timeout| quote(&format_duration(timeout))),
+        );
+        push(
+            "on_auth_failure",
+            Some(quote(&self.on_auth_failure.to_string())),
+        );
+        push(
+            "heartbeat_interval",
+            self.heartbeat_interval
+                .map(|interval| quote(&format_duration(interval))),
+        );
+        push(
+            "log_rate_limit",
+            self.log_rate_limit.map(|(limit, interval)| {
+                quote(&format!("{}/{}", limit, format_duration(interval)))
             }),
+        );
+        push(
+            "instance_policy",
+            Some(quote(&self.instance_policy.to_string())),
+        );
+        push(
+            "announce_only_when_idle",
+            Some(self.announce_only_when_idle.to_string()),
+        );
+        push(
+            "remember_context_volume",
+            Some(self.remember_context_volume.to_string()),
+        );
+        push("persist_stats", Some(self.persist_stats.to_string()));
+        push("resume_on_start", Some(self.resume_on_start.to_string()));
+        push(
+            "device_close_delay",
+            Some(quote(&format_duration(self.device_close_delay))),
+        );
+        push(
+            "max_track_length",
+            self.max_track_length
+                .map(|length| quote(&format_duration(length))),
+        );
+        push(
+            "autostart_uri",
+            self.autostart_uri.as_ref().map(|s| quote(s)),
+        );
+        push(
+            "autostart_volume",
+            self.autostart_volume.map(|percent| percent.to_string()),
+        );
+        if let Some(ref lastfm) = self.lastfm {
+            push("lastfm_api_key", Some(quote("***")));
+            push("lastfm_api_secret", Some(quote("***")));
+            push("lastfm_username", Some(quote(&lastfm.username)));
+            push("lastfm_password", Some(quote("***")));
+        }
+
+        let mut dump = String::from("[global]\n");
+        for (key, value) in entries {
+            dump.push_str(&format!("{} = {}\n", key, value));
         }
+        dump
     }
 }
 
@@ -109,69 +806,551 @@ pub(crate) fn get_config_file() -> Option<PathBuf> {
     })
 }
 
+/// Names the device after the host, as far as the hostname makes a name Spotify apps
+/// accept.
+fn default_device_name(hostname: Option<&str>, strip_domain: bool) -> String {
+    const PREFIX: &str = "Spotifyd@";
+    let hostname = match hostname {
+        Some(hostname) => hostname,
+        None => return "Spotifyd".to_string(),
+    };
+    let max_len = MAX_ADVERTISED_NAME_LEN - PREFIX.len();
+    match utils::sanitize_hostname(hostname, strip_domain, max_len) {
+        Some(sanitized) => {
+            if sanitized != hostname {
+                info!(
+                    "Using {:?} for the hostname {:?} in the device name.",
+                    sanitized, hostname
+                );
+            }
+            format!("{}{}", PREFIX, sanitized)
+        }
+        None => {
+            warn!(
+                "The hostname {:?} can't be used in the device name, set device_name.",
+                hostname
+            );
+            "Spotifyd".to_string()
+        }
+    }
+}
+
+/// Checks that a device name can be shown and advertised.
+fn validate_device_name(key: &'static str, name: String) -> Result<String, Error> {
+    if name.trim().is_empty() || name.chars().any(char::is_control) {
+        return Err(ErrorKind::ConfigValueInvalid {
+            key,
+            value: name,
+            expected: "a name without control characters",
+        }
+        .into());
+    }
+    Ok(name)
+}
+
+/// Parses a Spotify client id: 32 hexadecimal digits, which may be written as a UUID.
+/// Returns them in lower case, without the dashes.
+fn parse_client_id(s: &str) -> Option<String> {
+    let s = s.trim();
+    let groups: Vec<usize> = s.split('-').map(str::len).collect();
+    if groups != [32] && groups != [8, 4, 4, 4, 12] {
+        return None;
+    }
+    let digits: String = s.chars().filter(|&c| c != '-').collect();
+    if digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(digits.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Parses the length of a fade in milliseconds, where 0 disables the fade.
+fn parse_fade(key: &'static str, value: Option<String>) -> Result<Option<Duration>, Error> {
+    match value {
+        Some(ms) => match ms.parse::<u64>() {
+            Ok(0) => Ok(None),
+            Ok(ms) => Ok(Some(Duration::from_millis(ms))),
+            Err(_) => Err(ErrorKind::ConfigValueInvalid {
+                key,
+                value: ms,
+                expected: "a number of milliseconds",
+            }
+            .into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parses the name of a desktop entry, which is the name of its file without a directory
+/// and optionally without the `.desktop` extension.
+fn parse_desktop_entry(s: &str) -> Option<String> {
+    let name = s.trim();
+    let name = if name.ends_with(".desktop") {
+        &name[..name.len() - ".desktop".len()]
+    } else {
+        name
+    };
+    if name.is_empty() || name.contains(|c: char| c == '/' || c.is_whitespace()) {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Parses a duration given as a number followed by an optional unit,
+/// e.g. `500ms`, `30s`, `5m`, `1h` or `7d`. Plain numbers are taken as seconds.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value = value.parse::<u64>().ok()?;
+    let secs = |factor: u64| value.checked_mul(factor).map(Duration::from_secs);
+    match unit.trim() {
+        "ms" => Some(Duration::from_millis(value)),
+        "" | "s" => Some(Duration::from_secs(value)),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        "d" => secs(24 * 60 * 60),
+        _ => None,
+    }
+}
+
+/// Parses a number of log lines per interval, e.g. `5/30s`. The interval defaults to a
+/// minute.
+fn parse_rate_limit(s: &str) -> Option<(usize, Duration)> {
+    let mut parts = s.splitn(2, '/');
+    let limit = parts
+        .next()?
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)?;
+    let interval = match parts.next() {
+        Some(interval) => parse_duration(interval).filter(|d| *d > Duration::from_secs(0))?,
+        None => Duration::from_secs(60),
+    };
+    Some((limit, interval))
+}
+
+/// Parses a file mode given in octal, e.g. `0700` or `750`.
+fn parse_mode(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim(), 8)
+        .ok()
+        .filter(|&mode| mode <= 0o777)
+}
+
+/// Returns the username to log in with. Its case is always kept, as logins can fail
+/// otherwise, but surrounding whitespace is removed if `trim` is set.
+fn normalize_username(username: String, trim: bool) -> String {
+    if trim {
+        username.trim().to_string()
+    } else {
+        if username.trim() != username {
+            warn!(
+                "The username {:?} has surrounding whitespace, set trim_username = true to \
+                 remove it.",
+                username
+            );
+        }
+        username
+    }
+}
+
+/// Reads the password from the file at `path`, warning if other users can access it.
+fn read_password_file(path: &Path) -> Result<String, Error> {
+    let path = utils::absolutize_path(path).unwrap_or_else(|_| path.to_path_buf());
+    let read = || -> io::Result<String> {
+        let mode = metadata(&path)?.permissions().mode();
+        if let Some(warning) = password_file_warning(&path, mode) {
+            warn!("{}", warning);
+        }
+        Ok(fs::read_to_string(&path)?.trim().to_string())
+    };
+    read().map_err(|e| {
+        ErrorKind::PasswordFile {
+            path: path.clone(),
+            msg: e.to_string(),
+        }
+        .into()
+    })
+}
+
+fn password_file_warning(path: &Path, mode: u32) -> Option<String> {
+    if mode & 0o077 == 0 {
+        return None;
+    }
+    Some(format!(
+        "The password file {:?} can be accessed by other users (mode {:04o}), \
+         restrict it to 0600.",
+        path,
+        mode & 0o777
+    ))
+}
+
+/// The shell to run password_cmd and onevent with when `shell` isn't set: the user's
+/// own, or `fallback`. Returns `None` if there's neither.
+fn default_shell(found: Option<String>, fallback: &Path) -> Option<String> {
+    if found.is_some() {
+        return found;
+    }
+    if is_executable(fallback) {
+        info!("Unable to identify shell. Defaulting to {:?}.", fallback);
+        Some(fallback.to_string_lossy().into_owned())
+    } else {
+        warn!(
+            "Unable to identify shell, and {:?} doesn't exist either. Set shell to run \
+             password_cmd and onevent.",
+            fallback
+        );
+        None
+    }
+}
+
+fn parse_in_range(
+    key: &'static str,
+    value: String,
+    range: RangeInclusive<i32>,
+    expected: &'static str,
+) -> Result<i32, Error> {
+    match value.trim().parse::<i32>() {
+        Ok(parsed) if range.contains(&parsed) => Ok(parsed),
+        _ => Err(ErrorKind::ConfigValueInvalid {
+            key,
+            value,
+            expected,
+        }
+        .into()),
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    metadata(path).map_or(false, |meta| {
+        meta.is_file() && meta.permissions().mode() & 0o111 != 0
+    })
+}
+
+/// Parses a comma-separated list of player event names. An empty list selects all events.
+fn parse_event_filter(s: &str) -> Result<Option<Vec<&'static str>>, Error> {
+    let mut events = Vec::new();
+    for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match PLAYER_EVENTS.iter().find(|&&event| event == name) {
+            Some(event) => events.push(*event),
+            None => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "onevent_filter",
+                    value: name.to_string(),
+                    expected: "a comma-separated list of the events change, start and stop",
+                }
+                .into())
+            }
+        }
+    }
+    Ok(if events.is_empty() {
+        None
+    } else {
+        Some(events)
+    })
+}
+
+/// The inverse of `parse_duration`.
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!(
+            "{}ms",
+            duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+        )
+    }
+}
+
 fn update<T>(r: &mut T, val: Option<T>) {
     if let Some(mut v) = val {
         swap(r, &mut v);
     }
 }
 
-pub(crate) fn get_config<P: AsRef<Path>>(
+/// Loads the config file, or the config piped to `stdin` if the path is `-`. A missing or
+/// unreadable file results in an empty config. Either can be in INI or TOML.
+fn read_config_file<P: AsRef<Path>, R: Read>(
     config_path: Option<P>,
+    mut stdin: R,
+) -> Result<Ini, Error> {
+    let config_path = match config_path {
+        Some(config_path) => config_path,
+        None => {
+            info!("Couldn't find config file, continuing with default configuration.");
+            return Ok(Ini::new());
+        }
+    };
+    let config_path = config_path.as_ref();
+    if config_path == Path::new("-") {
+        let mut contents = String::new();
+        stdin
+            .read_to_string(&mut contents)
+            .map_err(|err| ErrorKind::ConfigStdinInvalid(err.to_string()))?;
+        return config_format::detect(config_path, &contents)
+            .parse(&contents)
+            .map_err(|err| ErrorKind::ConfigStdinInvalid(err).into());
+    }
+    let parsed = match fs::read_to_string(config_path) {
+        Ok(contents) => match config_format::detect(config_path, &contents) {
+            Format::Ini => {
+                warn!("{}", ini_warning(config_path));
+                Ini::load_from_str(&contents).map_err(|err| err.to_string())
+            }
+            // Unlike INI, TOML is strict enough to tell a broken file from a valid one,
+            // so mistakes aren't silently read as the defaults.
+            Format::Toml => {
+                return config_format::parse_toml(&contents).map_err(|msg| {
+                    ErrorKind::ConfigFileInvalid {
+                        path: config_path.to_path_buf(),
+                        msg,
+                    }
+                    .into()
+                });
+            }
+        },
+        Err(err) => Err(err.to_string()),
+    };
+    match parsed {
+        Ok(ini_file) => Ok(ini_file),
+        Err(err) => {
+            info!(
+                "Couldn't read configuration file, continuing with default configuration: {}",
+                err
+            );
+            // Whenever we do not have a configuration file, we default to an empty one.
+            Ok(Ini::new())
+        }
+    }
+}
+
+fn ini_warning(path: &Path) -> String {
+    format!(
+        "{} is in the old INI format, run spotifyd --config {:?} --migrate-config to \
+         convert it to TOML.",
+        path.display(),
+        path
+    )
+}
+
+/// Builds the Last.fm account to scrobble to, which needs all four keys if any is set.
+fn parse_lastfm(
+    api_key: Option<String>,
+    api_secret: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<Option<LastfmConfig>, Error> {
+    match (api_key, api_secret, username, password) {
+        (None, None, None, None) => Ok(None),
+        (Some(api_key), Some(api_secret), Some(username), Some(password)) => {
+            Ok(Some(LastfmConfig {
+                api_key,
+                api_secret,
+                username,
+                password,
+            }))
+        }
+        (api_key, api_secret, username, _) => {
+            let key = if api_key.is_none() {
+                "lastfm_api_key"
+            } else if api_secret.is_none() {
+                "lastfm_api_secret"
+            } else if username.is_none() {
+                "lastfm_username"
+            } else {
+                "lastfm_password"
+            };
+            Err(ErrorKind::ConfigMissing {
+                key,
+                needed_by: "scrobbling to Last.fm",
+            }
+            .into())
+        }
+    }
+}
+
+/// Reads the config files one after another, with the keys of later files overriding
+/// those of earlier ones. Within each file `[spotifyd]` overrides `[global]`, so the
+/// merged keys all end up in `[global]`.
+fn read_config_files<P: AsRef<Path>, R: Read>(
+    config_paths: &[P],
+    mut stdin: R,
+) -> Result<Ini, Error> {
+    if config_paths.is_empty() {
+        return read_config_file(None::<&Path>, stdin);
+    }
+    let mut merged = Ini::new();
+    for path in config_paths {
+        let config_file = read_config_file(Some(path), &mut stdin)?;
+        for &section in &["global", "spotifyd"] {
+            if let Some(properties) = config_file.section(Some(section.to_owned())) {
+                for (key, value) in properties.iter() {
+                    merged
+                        .with_section(Some("global"))
+                        .set(key.as_str(), value.as_str());
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Builds the configuration from several config files, where later files override
+/// earlier ones, and the command line overrides them all.
+pub(crate) fn get_config<P: AsRef<Path>>(
+    config_paths: &[P],
+    matches: &Matches,
+) -> Result<SpotifydConfig, Error> {
+    let shell = default_shell(utils::get_shell(false), Path::new(utils::FALLBACK_SHELL));
+    get_config_with_shell(config_paths, matches, shell)
+}
+
+/// Builds the configuration like `get_config` does, running password_cmd and onevent
+/// with `default_shell` unless `shell` is set.
+fn get_config_with_shell<P: AsRef<Path>>(
+    config_paths: &[P],
     matches: &Matches,
+    default_shell: Option<String>,
 ) -> Result<SpotifydConfig, Error> {
     let mut config = SpotifydConfig::default();
 
-    let config_file = config_path
-        .or_else(|| {
-            info!("Couldn't find config file, continuing with default configuration.");
-            None
-        })
-        .and_then(|config_path| match Ini::load_from_file(config_path) {
-            Ok(ini_file) => Some(ini_file),
-            Err(err) => {
-                info!(
-                    "Couldn't read configuration file, continuing with default configuration: {}",
-                    err
-                );
-                None
-            }
-        })
-        .unwrap_or_else(|| {
-            // Whenever we do not have a configuration file, we default to an empty one.
-            ini::Ini::new()
-        });
+    let from_stdin = config_paths
+        .iter()
+        .any(|path| path.as_ref() == Path::new("-"));
+    // Reading from a terminal would block until someone types a config, which a daemon
+    // started from a shell would never get.
+    if from_stdin && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+        return Err(ErrorKind::ConfigStdinIsTerminal.into());
+    }
+    let config_file = read_config_files(config_paths, io::stdin())?;
 
     let global = config_file.section(Some("global".to_owned()));
     let spotifyd = config_file.section(Some("spotifyd".to_owned()));
 
+    // A preset fills in whatever the config file leaves unset.
+    let preset_file = match matches.opt_str("preset") {
+        Some(name) => load_preset(&name, &preset_dirs())?,
+        None => Ini::new(),
+    };
+    let preset_global = preset_file.section(Some("global".to_owned()));
+    let preset_spotifyd = preset_file.section(Some("spotifyd".to_owned()));
+    let from_preset = |field: &str| {
+        None.or_else(|| preset_spotifyd.and_then(|s| s.get(field).map(String::clone)))
+            .or_else(|| preset_global.and_then(|s| s.get(field).map(String::clone)))
+    };
+
     let lookup = |field| {
         if matches.opt_defined(field) {
             None.or_else(|| matches.opt_str(field))
                 .or_else(|| spotifyd.and_then(|s| s.get(field).map(String::clone)))
                 .or_else(|| global.and_then(|s| s.get(field).map(String::clone)))
+                .or_else(|| from_preset(field))
         } else {
             None.or_else(|| spotifyd.and_then(|s| s.get(field).map(String::clone)))
                 .or_else(|| global.and_then(|s| s.get(field).map(String::clone)))
+                .or_else(|| from_preset(field))
         }
     };
 
-    update(
-        &mut config.cache,
-        lookup("cache_path")
-            .map(PathBuf::from)
-            .and_then(|p| Some(Cache::new(p, true)))
-            .map(Some),
-    );
+    config.cache_path = lookup("cache_path").map(PathBuf::from);
+    config.cache_dir_mode = match lookup("cache_dir_mode") {
+        Some(mode) => Some(parse_mode(&mode).ok_or_else(|| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "cache_dir_mode",
+                value: mode.clone(),
+                expected: "an octal file mode such as 0700",
+            })
+        })?),
+        None => None,
+    };
+    config.cache_expiry = match lookup("cache_expiry") {
+        Some(expiry) => match parse_duration(&expiry) {
+            Some(expiry) if expiry == Duration::from_secs(0) => None,
+            Some(expiry) => Some(expiry),
+            None => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "cache_expiry",
+                    value: expiry,
+                    expected: "a duration such as \"30d\", or 0 to keep cached audio",
+                }
+                .into())
+            }
+        },
+        None => None,
+    };
 
-    config.username = lookup("username");
-    config.password = match lookup("password") {
-        Some(password) => Some(password),
-        None => match lookup("password_cmd") {
+    let mut shell_missing = false;
+    match lookup("shell") {
+        Some(shell) => {
+            if !is_executable(Path::new(&shell)) {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "shell",
+                    value: shell,
+                    expected: "the path to an executable",
+                }
+                .into());
+            }
+            config.shell = shell;
+        }
+        None => match default_shell {
+            Some(shell) => config.shell = shell,
+            None => shell_missing = true,
+        },
+    }
+    if let Some(client_id) = lookup("client_id") {
+        config.client_id =
+            parse_client_id(&client_id).ok_or_else(|| ErrorKind::ConfigValueInvalid {
+                key: "client_id",
+                value: client_id,
+                expected: "32 hexadecimal digits, optionally grouped like a UUID",
+            })?;
+    }
+
+    let trim_username = matches.opt_present("trim_username")
+        || lookup("trim_username").map_or(false, |value| value == "true");
+    config.trim_username = trim_username;
+    config.username =
+        lookup("username").map(|username| normalize_username(username, trim_username));
+    if let Some(users) = lookup("allowed_users") {
+        config.allowed_users = users
+            .split(',')
+            .map(str::trim)
+            .filter(|user| !user.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+    let password_file = lookup("password_file");
+    if password_file.is_some() {
+        for &other in &["password", "password_cmd"] {
+            if lookup(other).is_some() {
+                return Err(ErrorKind::ConfigConflict {
+                    key: "password_file",
+                    other,
+                }
+                .into());
+            }
+        }
+    }
+    config.password = match lookup("password") {
+        Some(password) => Some(password),
+        None => match lookup("password_cmd") {
+            Some(_) if shell_missing => {
+                return Err(ErrorKind::ConfigMissing {
+                    key: "shell",
+                    needed_by: "password_cmd",
+                }
+                .into())
+            }
             Some(ref cmd) => match run_program(&config.shell, cmd) {
                 Ok(s) => Some(s.trim().to_string()),
                 Err(e) => return Err(Error::subprocess_with_err(&config.shell, cmd, e)),
             },
-            None => None,
+            None => match password_file {
+                Some(path) => Some(read_password_file(Path::new(&path))?),
+                None => None,
+            },
         },
     };
     if let Some(ref value) = lookup("use-keyring") {
@@ -179,22 +1358,110 @@ pub(crate) fn get_config<P: AsRef<Path>>(
             config.use_keyring = true;
         }
     }
+    update(&mut config.keyring_service, lookup("keyring_service"));
+    config.keyring_account = lookup("keyring_account");
     config.backend = lookup("backend");
     config.audio_device = lookup("device");
+    if let Some(blacklist) = lookup("device_blacklist") {
+        config.device_blacklist = blacklist
+            .split(',')
+            .map(str::trim)
+            .filter(|device| !device.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
     config.control_device = lookup("control");
     config.mixer = lookup("mixer");
+    config.startup_mixer_volume = match lookup("startup_mixer_volume") {
+        Some(percent) => Some(parse_percent("startup_mixer_volume", percent)?),
+        None => None,
+    };
+    config.quiet_hours = parse_quiet_hours(
+        lookup("quiet_hours_start"),
+        lookup("quiet_hours_end"),
+        lookup("quiet_hours_max_volume"),
+    )?;
     update(
         &mut config.volume_controller,
         lookup("volume-control").and_then(|s| VolumeController::from_str(&*s).ok()),
     );
-    config.device_name = lookup("device_name").unwrap_or_else(|| {
-        if let Some(h) = utils::get_hostname() {
-            format!("Spotifyd@{}", h)
-        } else {
-            "Spotifyd".to_string()
+    if let Some(value) = lookup("volume_backend") {
+        config.volume_backend =
+            Some(
+                VolumeBackend::from_str(&value).map_err(|()| ErrorKind::ConfigValueInvalid {
+                    key: "volume_backend",
+                    value,
+                    expected: "software or hardware",
+                })?,
+            );
+    }
+    config.volume_controller = select_volume_controller(
+        config.volume_controller,
+        config.volume_backend,
+        config.mixer.as_ref().map(String::as_str),
+    )?;
+    config.strip_hostname_domain = match lookup("strip_hostname_domain") {
+        Some(ref value) if value == "true" => true,
+        Some(ref value) if value == "false" => false,
+        Some(value) => {
+            return Err(ErrorKind::ConfigValueInvalid {
+                key: "strip_hostname_domain",
+                value,
+                expected: "true or false",
+            }
+            .into())
+        }
+        None => true,
+    };
+    config.device_name = match lookup("device_name") {
+        Some(name) => validate_device_name("device_name", name)?,
+        None => default_device_name(
+            utils::get_hostname().as_ref().map(String::as_str),
+            config.strip_hostname_domain,
+        ),
+    };
+    config.discovery_name = match lookup("discovery_name") {
+        Some(name) => Some(validate_device_name("discovery_name", name)?),
+        None => None,
+    };
+    if config.advertised_name().len() > MAX_ADVERTISED_NAME_LEN {
+        return Err(ErrorKind::ConfigValueInvalid {
+            key: if config.discovery_name.is_some() {
+                "discovery_name"
+            } else {
+                "device_name"
+            },
+            value: config.advertised_name().to_string(),
+            expected: "at most 63 bytes long to be advertised, set a shorter discovery_name",
         }
-    });
+        .into());
+    }
     config.onevent = lookup("onevent");
+    if shell_missing && config.onevent.is_some() {
+        return Err(ErrorKind::ConfigMissing {
+            key: "shell",
+            needed_by: "onevent",
+        }
+        .into());
+    }
+    config.onevent_filter = match lookup("onevent_filter") {
+        Some(filter) => parse_event_filter(&filter)?,
+        None => None,
+    };
+    config.onevent_max_procs = match lookup("onevent_max_procs") {
+        Some(max) => match max.parse::<usize>() {
+            Ok(max) if max > 0 => Some(max),
+            _ => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "onevent_max_procs",
+                    value: max,
+                    expected: "a positive integer",
+                }
+                .into())
+            }
+        },
+        None => None,
+    };
     config.player_config.normalisation = matches.opt_present("volume-normalisation")
         || spotifyd
             .and_then(|s| s.get("volume-normalisation").map(String::clone))
@@ -209,6 +1476,74 @@ pub(crate) fn get_config<P: AsRef<Path>>(
         None => PlayerConfig::default().normalisation_pregain,
     };
 
+    config.filters.fade_in = parse_fade("fade_in_ms", lookup("fade_in_ms"))?;
+    config.fade_out = parse_fade("fade_out_ms", lookup("fade_out_ms"))?;
+    if let Some(speed) = lookup("playback_speed") {
+        config.playback_speed = match speed.parse::<f64>() {
+            Ok(parsed) if (parsed - 1.0).abs() < std::f64::EPSILON => None,
+            Ok(parsed) if (0.5..=3.0).contains(&parsed) => Some(parsed),
+            _ => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "playback_speed",
+                    value: speed,
+                    expected: "a speed between 0.5 and 3.0",
+                }
+                .into())
+            }
+        };
+    }
+    if let Some(bands) = lookup("eq_bands") {
+        config.filters.eq_bands =
+            parse_eq_bands(&bands).map_err(|()| ErrorKind::ConfigValueInvalid {
+                key: "eq_bands",
+                value: bands,
+                expected: "a comma-separated list of frequency/gain/Q bands, with a frequency \
+                           between 20 and 20000 Hz, a gain between -24 and 12 dB and a Q \
+                           between 0.1 and 10",
+            })?;
+    }
+    config.filters.gain = match lookup("master_gain") {
+        Some(gain) => Some(
+            Gain::from_str(&gain).map_err(|_| ErrorKind::ConfigValueInvalid {
+                key: "master_gain",
+                value: gain,
+                expected: "a gain in dB between -60 and 6",
+            })?,
+        ),
+        None => None,
+    };
+    let peak_limiter = matches.opt_present("peak_limiter")
+        || lookup("peak_limiter").map_or(false, |value| value == "true");
+    config.filters.peak_limiter = match lookup("peak_limiter_threshold") {
+        Some(threshold) if peak_limiter => Some(PeakLimiter::from_str(&threshold).map_err(
+            |_| ErrorKind::ConfigValueInvalid {
+                key: "peak_limiter_threshold",
+                value: threshold,
+                expected: "a level in dB between -20 and 0",
+            },
+        )?),
+        Some(_) => {
+            warn!("peak_limiter_threshold has no effect unless peak_limiter is enabled.");
+            None
+        }
+        None if peak_limiter => Some(PeakLimiter::default()),
+        None => None,
+    };
+    config.filters.mono =
+        matches.opt_present("mono") || lookup("mono").map_or(false, |value| value == "true");
+    config.filters.channel_map = match lookup("channel_map") {
+        Some(map) => {
+            Some(
+                ChannelMap::from_str(&map).map_err(|_| ErrorKind::ConfigValueInvalid {
+                    key: "channel_map",
+                    value: map,
+                    expected: "two of L and R, separated by a comma",
+                })?,
+            )
+        }
+        None => None,
+    };
+
     update(
         &mut config.player_config.bitrate,
         lookup("bitrate").and_then(|s| Bitrate::from_str(&*s).ok()),
@@ -216,5 +1551,1359 @@ pub(crate) fn get_config<P: AsRef<Path>>(
     update(&mut config.session_config.device_id, lookup("device_name"));
 
     config.pid = lookup("pid");
+    config.nice = match lookup("nice") {
+        Some(nice) => Some(parse_in_range(
+            "nice",
+            nice,
+            -20..=19,
+            "a niceness between -20 and 19",
+        )?),
+        None => None,
+    };
+    config.mlock =
+        matches.opt_present("mlock") || lookup("mlock").map_or(false, |value| value == "true");
+    config.rt_priority = match lookup("rt_priority") {
+        Some(priority) => Some(parse_in_range(
+            "rt_priority",
+            priority,
+            1..=99,
+            "a priority between 1 and 99",
+        )?),
+        None => None,
+    };
+
+    if let Some(value) = lookup("use_mpris") {
+        config.use_mpris = match UseMpris::from_str(&value) {
+            #[cfg(not(feature = "dbus_mpris"))]
+            Ok(UseMpris::Required) => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "use_mpris",
+                    value,
+                    expected: "true or false in a build without the dbus_mpris feature",
+                }
+                .into())
+            }
+            Ok(use_mpris) => use_mpris,
+            Err(()) => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "use_mpris",
+                    value,
+                    expected: "true, false or required",
+                }
+                .into())
+            }
+        };
+    }
+    config.mpris_instance = match lookup("mpris_instance") {
+        Some(instance) => Some(MprisInstance::from_str(&instance)?),
+        None => None,
+    };
+    if let Some(value) = lookup("dbus_type") {
+        config.dbus_type =
+            DBusType::from_str(&value).map_err(|()| ErrorKind::ConfigValueInvalid {
+                key: "dbus_type",
+                value,
+                expected: "session or system",
+            })?;
+    }
+    if let Some(separator) = lookup("artist_separator") {
+        config.artist_separator = ArtistSeparator::from_str(&separator).map_err(|_| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "artist_separator",
+                value: separator.clone(),
+                expected: "a separator, or two separated by |",
+            })
+        })?;
+    }
+    config.mpris_identity = match lookup("mpris_identity") {
+        Some(ref identity) if identity.trim().is_empty() => {
+            return Err(ErrorKind::ConfigValueInvalid {
+                key: "mpris_identity",
+                value: identity.clone(),
+                expected: "a name to show in media controls",
+            }
+            .into())
+        }
+        identity => identity,
+    };
+    if let Some(entry) = lookup("mpris_desktop_entry") {
+        config.mpris_desktop_entry = parse_desktop_entry(&entry).ok_or_else(|| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "mpris_desktop_entry",
+                value: entry.clone(),
+                expected: "the name of a .desktop file, such as \"spotifyd\"",
+            })
+        })?;
+    }
+
+    config.max_reconnect_attempts = match lookup("max_reconnect_attempts") {
+        Some(attempts) => Some(attempts.parse::<u32>().map_err(|_| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "max_reconnect_attempts",
+                value: attempts.clone(),
+                expected: "a non-negative integer",
+            })
+        })?),
+        None => None,
+    };
+    if let Some(cooldown) = lookup("reconnect_cooldown") {
+        config.reconnect_cooldown = parse_duration(&cooldown).ok_or_else(|| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "reconnect_cooldown",
+                value: cooldown.clone(),
+                expected: "a duration such as \"30s\" or \"5m\"",
+            })
+        })?;
+    }
+
+    config.connect_timeout = match lookup("connect_timeout") {
+        Some(timeout) => match parse_duration(&timeout) {
+            Some(duration) if duration > Duration::from_secs(0) => Some(duration),
+            _ => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "connect_timeout",
+                    value: timeout,
+                    expected: "a positive duration such as \"30s\"",
+                }
+                .into())
+            }
+        },
+        None => None,
+    };
+
+    if let Some(value) = lookup("on_auth_failure") {
+        config.on_auth_failure =
+            AuthFailurePolicy::from_str(&value).map_err(|()| ErrorKind::ConfigValueInvalid {
+                key: "on_auth_failure",
+                value,
+                expected: "exit or wait",
+            })?;
+    }
+
+    config.heartbeat_interval = match lookup("heartbeat_interval") {
+        Some(interval) => Some(parse_duration(&interval).ok_or_else(|| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "heartbeat_interval",
+                value: interval.clone(),
+                expected: "a duration such as \"15m\", or 0 to disable it",
+            })
+        })?),
+        None => None,
+    };
+
+    if let Some(value) = lookup("instance_policy") {
+        config.instance_policy =
+            InstancePolicy::from_str(&value).map_err(|()| ErrorKind::ConfigValueInvalid {
+                key: "instance_policy",
+                value,
+                expected: "refuse, takeover or coexist",
+            })?;
+    }
+
+    config.announce_only_when_idle = matches.opt_present("announce_only_when_idle")
+        || lookup("announce_only_when_idle").map_or(false, |value| value == "true");
+
+    config.remember_context_volume = matches.opt_present("remember_context_volume")
+        || lookup("remember_context_volume").map_or(false, |value| value == "true");
+
+    config.persist_stats = matches.opt_present("persist_stats")
+        || lookup("persist_stats").map_or(false, |value| value == "true");
+
+    config.resume_on_start = matches.opt_present("resume_on_start")
+        || lookup("resume_on_start").map_or(false, |value| value == "true");
+
+    if let Some(delay) = lookup("device_close_delay") {
+        config.device_close_delay = parse_duration(&delay).ok_or_else(|| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "device_close_delay",
+                value: delay.clone(),
+                expected: "a duration such as \"30s\", or 0 to close the device right away",
+            })
+        })?;
+    }
+
+    config.max_track_length = match lookup("max_track_length") {
+        Some(length) => match parse_duration(&length) {
+            Some(length) if length == Duration::from_secs(0) => None,
+            Some(length) => Some(length),
+            None => {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "max_track_length",
+                    value: length,
+                    expected: "a duration such as \"10m\", or 0 to disable it",
+                }
+                .into())
+            }
+        },
+        None => None,
+    };
+
+    config.log_rate_limit = match lookup("log_rate_limit") {
+        Some(limit) => Some(parse_rate_limit(&limit).ok_or_else(|| {
+            Error::from(ErrorKind::ConfigValueInvalid {
+                key: "log_rate_limit",
+                value: limit.clone(),
+                expected: "a positive number of lines, optionally per interval, e.g. \"5/30s\"",
+            })
+        })?),
+        None => None,
+    };
+
+    config.autostart_uri = lookup("autostart_uri");
+    config.autostart_volume = match lookup("autostart_volume") {
+        Some(percent) => Some(parse_percent("autostart_volume", percent)?),
+        None => None,
+    };
+
+    config.lastfm = parse_lastfm(
+        lookup("lastfm_api_key"),
+        lookup("lastfm_api_secret"),
+        lookup("lastfm_username"),
+        lookup("lastfm_password"),
+    )?;
+    if config.lastfm.is_some() && cfg!(not(feature = "scrobble")) {
+        warn!("Scrobbling to Last.fm needs a build with the scrobble feature, not scrobbling.");
+    }
+
+    let available: Vec<&str> = backends().iter().map(|&(name, _)| name).collect();
+    validate_backend(
+        config.backend.as_ref().map(String::as_ref),
+        &mut config.audio_device,
+        &available,
+    )?;
     Ok(config)
 }
+
+/// Parses a percentage between 0 and 100, with or without a trailing `%`.
+fn parse_percent(key: &'static str, value: String) -> Result<u8, Error> {
+    match value.trim_end_matches('%').parse::<u8>() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err(ErrorKind::ConfigValueInvalid {
+            key,
+            value,
+            expected: "a percentage between 0 and 100",
+        }
+        .into()),
+    }
+}
+
+fn parse_quiet_hours(
+    start: Option<String>,
+    end: Option<String>,
+    max_volume: Option<String>,
+) -> Result<Option<QuietHours>, Error> {
+    fn parse_time(key: &'static str, value: Option<String>) -> Result<NaiveTime, Error> {
+        let value = value.unwrap_or_default();
+        NaiveTime::parse_from_str(&value, "%H:%M").map_err(|_| {
+            ErrorKind::ConfigValueInvalid {
+                key,
+                value,
+                expected: "a time such as \"22:30\", set together with the other quiet_hours keys",
+            }
+            .into()
+        })
+    }
+
+    if start.is_none() && end.is_none() && max_volume.is_none() {
+        return Ok(None);
+    }
+    let start = parse_time("quiet_hours_start", start)?;
+    let end = parse_time("quiet_hours_end", end)?;
+    let max_volume = match max_volume {
+        Some(percent) => parse_percent("quiet_hours_max_volume", percent)?,
+        None => {
+            return Err(ErrorKind::ConfigValueInvalid {
+                key: "quiet_hours_max_volume",
+                value: String::new(),
+                expected: "a percentage, set together with the other quiet_hours keys",
+            }
+            .into())
+        }
+    };
+    Ok(Some(QuietHours {
+        start,
+        end,
+        max_volume: (u32::from(max_volume) * u32::from(u16::max_value()) / 100) as u16,
+    }))
+}
+
+/// Checks that every backend in `backend`, a list of names separated by `+`, is compiled in
+/// and drops the device if none of them uses it. Without an explicit backend the first
+/// available one is checked.
+fn validate_backend(
+    backend: Option<&str>,
+    device: &mut Option<String>,
+    available: &[&str],
+) -> Result<(), Error> {
+    let backend = match backend.or_else(|| available.first().cloned()) {
+        Some(backend) => backend,
+        None => return Ok(()),
+    };
+    let backends: Vec<(&str, Option<&str>)> = backend.split('+').map(split_device).collect();
+    for &(name, own) in &backends {
+        if !available.contains(&name) {
+            let feature = OPTIONAL_BACKENDS
+                .iter()
+                .find(|&&(optional, _)| optional == name)
+                .map(|&(_, feature)| feature);
+            return Err(ErrorKind::BackendUnavailable {
+                backend: name.to_string(),
+                feature,
+                available: available.iter().map(|name| name.to_string()).collect(),
+            }
+            .into());
+        }
+        if let (Some(own), true) = (own, BACKENDS_WITHOUT_DEVICE.contains(&name)) {
+            warn!(
+                "The {} backend doesn't support selecting a device, ignoring device {:?}.",
+                name, own
+            );
+        }
+    }
+    let devices = assign_devices(&backends, device.as_ref().map(String::as_str));
+    #[cfg(feature = "bluealsa_backend")]
+    {
+        for (&(name, _), mac) in backends.iter().zip(&devices) {
+            if name == "bluealsa" && mac.and_then(pcm_name).is_none() {
+                return Err(ErrorKind::ConfigValueInvalid {
+                    key: "device",
+                    value: mac.unwrap_or_default().to_string(),
+                    expected: "the MAC address of a bluetooth speaker for the bluealsa backend",
+                }
+                .into());
+            }
+        }
+    }
+    let used = devices
+        .iter()
+        .zip(&backends)
+        .any(|(assigned, &(_, own))| assigned.is_some() && own.is_none());
+    if !used {
+        if let Some(device) = device.take() {
+            if backends
+                .iter()
+                .all(|&(name, _)| BACKENDS_WITHOUT_DEVICE.contains(&name))
+            {
+                warn!(
+                    "The {} backend doesn't support selecting a device, ignoring device {:?}.",
+                    backend, device
+                );
+            } else {
+                warn!(
+                    "The backends in {} that can select a device have their own, ignoring device {:?}.",
+                    backend, device
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use std::fs::Permissions;
+
+    #[test]
+    fn mpris_instance_bus_name() {
+        let instance = MprisInstance::from_str("kitchen").unwrap();
+        assert_eq!(
+            instance.bus_name(),
+            "org.mpris.MediaPlayer2.spotifyd.kitchen"
+        );
+
+        let instance = MprisInstance::from_str("pid").unwrap();
+        assert_eq!(
+            instance.bus_name(),
+            format!(
+                "org.mpris.MediaPlayer2.spotifyd.instance{}",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn log_color() {
+        assert_eq!(LogColor::from_str("Never"), Ok(LogColor::Never));
+        assert_eq!(LogColor::from_str("colour"), Err(()));
+
+        // Plain output never depends on the terminal.
+        assert!(!LogColor::Never.colored(true));
+        assert!(!LogColor::Never.colored(false));
+        assert!(LogColor::Always.colored(false));
+        assert!(LogColor::Auto.colored(true));
+        assert!(!LogColor::Auto.colored(false));
+    }
+
+    #[test]
+    fn dbus_type_from_config() {
+        assert_eq!(DBusType::from_str("session"), Ok(DBusType::Session));
+        assert_eq!(DBusType::from_str("System"), Ok(DBusType::System));
+        assert_eq!(DBusType::from_str("starter"), Err(()));
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--dbus_type", "system"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.dbus_type, DBusType::System);
+
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.dbus_type, DBusType::Session);
+
+        // A typo is an error rather than quietly registering on the session bus.
+        let matches = opts.parse(&["--dbus_type", "sytem"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dbus_type must be session or system, got \"sytem\"."
+        );
+    }
+
+    #[test]
+    fn default_device_names() {
+        assert_eq!(default_device_name(None, true), "Spotifyd");
+        assert_eq!(
+            default_device_name(Some("kitchen-pi"), true),
+            "Spotifyd@kitchen-pi"
+        );
+        assert_eq!(
+            default_device_name(Some("kitchen-pi.local"), true),
+            "Spotifyd@kitchen-pi"
+        );
+        assert_eq!(
+            default_device_name(Some("kitchen-pi.local"), false),
+            "Spotifyd@kitchen-pi.local"
+        );
+        assert_eq!(
+            default_device_name(Some("fe80::1%eth0"), true),
+            "Spotifyd@fe80-1-eth0"
+        );
+        assert_eq!(default_device_name(Some("..."), true), "Spotifyd");
+        let long = default_device_name(Some(&"a".repeat(100)), true);
+        assert_eq!(long.len(), MAX_ADVERTISED_NAME_LEN);
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&[
+                "--strip_hostname_domain",
+                "false",
+                "--device_name",
+                "kitchen",
+            ])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert!(!config.strip_hostname_domain);
+        // An explicit device_name is used as is.
+        assert_eq!(config.device_name, "kitchen");
+        let matches = opts.parse(&["--strip_hostname_domain", "no"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert!(err.to_string().starts_with("strip_hostname_domain must be"));
+    }
+
+    #[test]
+    fn discovery_name() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--device_name", "kitchen"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.discovery_name, None);
+        assert_eq!(config.advertised_name(), "kitchen");
+
+        let long_name = "The speakers in the kitchen, next to the window facing the garden";
+        let matches = opts
+            .parse(&["--device_name", long_name, "--discovery_name", "kitchen"])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.device_name, long_name);
+        assert_eq!(config.advertised_name(), "kitchen");
+        assert!(config.dump().contains("discovery_name = \"kitchen\"\n"));
+
+        // Without a discovery_name, the long name would have to be advertised.
+        let matches = opts.parse(&["--device_name", long_name]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert!(
+            err.to_string().starts_with("device_name must be"),
+            "{}",
+            err
+        );
+
+        for name in &["", "  ", "kitchen\n"] {
+            let matches = opts.parse(&["--discovery_name", name]).unwrap();
+            let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+            assert!(
+                err.to_string().starts_with("discovery_name must be"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn mpris_identity() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--device_name", "Kitchen"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(
+            config.mpris_identity(),
+            MprisIdentity {
+                identity: "Kitchen".to_string(),
+                desktop_entry: "spotifyd".to_string(),
+            }
+        );
+        assert!(!config.dump().contains("mpris_identity"));
+
+        let matches = opts
+            .parse(&[
+                "--device_name",
+                "Kitchen",
+                "--mpris_identity",
+                "Spotify (kitchen)",
+                "--mpris_desktop_entry",
+                "spotify.desktop",
+            ])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(
+            config.mpris_identity(),
+            MprisIdentity {
+                identity: "Spotify (kitchen)".to_string(),
+                desktop_entry: "spotify".to_string(),
+            }
+        );
+        assert!(config
+            .dump()
+            .contains("mpris_desktop_entry = \"spotify\"\n"));
+
+        for &(key, value) in &[
+            ("--mpris_identity", " "),
+            ("--mpris_desktop_entry", ""),
+            ("--mpris_desktop_entry", ".desktop"),
+            ("--mpris_desktop_entry", "/usr/share/applications/spotify"),
+            ("--mpris_desktop_entry", "my player"),
+        ] {
+            let matches = opts.parse(&[key, value]).unwrap();
+            let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+            assert!(err.to_string().starts_with(&key[2..]), "{}", err);
+        }
+    }
+
+    #[test]
+    fn artist_separator() {
+        let artists = ["A", "B", "C", "D"];
+        let default = ArtistSeparator::default();
+        assert_eq!(default.join(&artists), "A, B, C, D");
+        assert_eq!(default.join(&["A"]), "A");
+        assert_eq!(default.join::<&str>(&[]), "");
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--artist_separator", " feat. | & "]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.artist_separator.join(&artists[..3]), "A feat. B & C");
+        assert_eq!(config.artist_separator.join(&artists[..2]), "A feat. B");
+        assert!(config
+            .dump()
+            .contains("artist_separator = \" feat. | & \"\n"));
+
+        let matches = opts.parse(&["--artist_separator", " / "]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.artist_separator.join(&artists), "A / B / C / D");
+
+        for separator in &["", "|", " feat. |"] {
+            let matches = opts.parse(&["--artist_separator", separator]).unwrap();
+            let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+            assert!(err.to_string().starts_with("artist_separator must be"));
+        }
+    }
+
+    #[test]
+    fn playback_speed() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--playback_speed", "1.25"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.playback_speed, Some(1.25));
+        assert!(config.dump().contains("playback_speed = 1.25\n"));
+
+        for speed in &["1", "1.0"] {
+            let matches = opts.parse(&["--playback_speed", speed]).unwrap();
+            let config = get_config::<PathBuf>(&[], &matches).unwrap();
+            assert_eq!(config.playback_speed, None);
+        }
+        for speed in &["0.5", "3"] {
+            let matches = opts.parse(&["--playback_speed", speed]).unwrap();
+            assert!(get_config::<PathBuf>(&[], &matches).is_ok());
+        }
+        for speed in &["0.4", "3.5", "-1", "fast", "NaN"] {
+            let matches = opts.parse(&["--playback_speed", speed]).unwrap();
+            let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+            assert!(err.to_string().starts_with("playback_speed must be"));
+        }
+    }
+
+    #[test]
+    fn scheduling_priority() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!((config.nice, config.rt_priority), (None, None));
+        assert!(!config.mlock);
+        let matches = opts.parse(&["--mlock"]).unwrap();
+        assert!(get_config::<PathBuf>(&[], &matches).unwrap().mlock);
+
+        let matches = opts
+            .parse(&["--nice", "-5", "--rt_priority", "50"])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!((config.nice, config.rt_priority), (Some(-5), Some(50)));
+        let dump = config.dump();
+        assert!(dump.contains("nice = -5\n"));
+        assert!(dump.contains("rt_priority = 50\n"));
+
+        for &(key, value) in &[
+            ("--nice", "-21"),
+            ("--nice", "20"),
+            ("--nice", "low"),
+            ("--rt_priority", "0"),
+            ("--rt_priority", "100"),
+        ] {
+            let matches = opts.parse(&[key, value]).unwrap();
+            let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("{} must be", &key[2..])),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn dump_config() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&[
+                "--device_name",
+                "kitchen",
+                "--password",
+                "hunter2",
+                "--connect_timeout",
+                "15s",
+                "--log_rate_limit",
+                "5",
+            ])
+            .unwrap();
+        let dump = get_config::<PathBuf>(&[], &matches).unwrap().dump();
+        assert!(dump.starts_with("[global]\n"));
+        assert!(dump.contains("device_name = \"kitchen\"\n"));
+        assert!(dump.contains("connect_timeout = \"15s\"\n"));
+        // The default interval is written out too.
+        assert!(dump.contains("log_rate_limit = \"5/60s\"\n"));
+        assert!(dump.contains("password = \"***\"\n"));
+        assert!(!dump.contains("hunter2"));
+        assert!(!dump.contains("username"));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30 s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(604800)));
+        assert_eq!(parse_duration("-1s"), None);
+        assert_eq!(parse_duration("1.5s"), None);
+        assert_eq!(parse_duration("ten"), None);
+        assert_eq!(parse_duration(""), None);
+        // Durations too long to be represented aren't valid.
+        for &(unit, secs) in &[("s", 1), ("m", 60), ("h", 60 * 60), ("d", 24 * 60 * 60)] {
+            let longest = u64::max_value() / secs;
+            assert_eq!(
+                parse_duration(&format!("{}{}", longest, unit)),
+                Some(Duration::from_secs(longest * secs))
+            );
+            if secs > 1 {
+                assert_eq!(parse_duration(&format!("{}{}", longest + 1, unit)), None);
+            }
+        }
+
+        for duration in &["500ms", "1500ms", "30s", "0s"] {
+            assert_eq!(
+                format_duration(parse_duration(duration).unwrap()),
+                *duration
+            );
+        }
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--connect_timeout", "15s"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.connect_timeout, Some(Duration::from_secs(15)));
+
+        for timeout in &["0s", "soon"] {
+            let matches = opts.parse(&["--connect_timeout", timeout]).unwrap();
+            let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+            assert!(
+                err.to_string().starts_with("connect_timeout must be"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn rate_limit() {
+        assert_eq!(parse_rate_limit("5"), Some((5, Duration::from_secs(60))));
+        assert_eq!(
+            parse_rate_limit("5/30s"),
+            Some((5, Duration::from_secs(30)))
+        );
+        for limit in &["0", "-1", "5/0s", "5/soon", "often"] {
+            assert_eq!(parse_rate_limit(limit), None, "{:?}", limit);
+        }
+    }
+
+    #[test]
+    fn cache_dir_mode() {
+        assert_eq!(parse_mode("0700"), Some(0o700));
+        assert_eq!(parse_mode("750"), Some(0o750));
+        for mode in &["", "0800", "1777", "rwx"] {
+            assert_eq!(parse_mode(mode), None, "{:?}", mode);
+        }
+
+        // Reading the configuration doesn't create the directory, setup does.
+        let dir = temp_path("cache");
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&["--cache_path", dir.to_str().unwrap()])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.cache_path, Some(dir.clone()));
+        assert!(config.cache.is_none());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn shell_override() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--shell", "/bin/sh"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.shell, "/bin/sh");
+
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(
+            config.shell,
+            utils::get_shell(false).unwrap_or_else(|| utils::FALLBACK_SHELL.to_string())
+        );
+    }
+
+    #[test]
+    fn shell_fallback() {
+        let bash = Some("/bin/bash".to_string());
+        assert_eq!(default_shell(bash.clone(), Path::new("/bin/sh")), bash);
+        assert_eq!(
+            default_shell(None, Path::new("/bin/sh")),
+            Some("/bin/sh".to_string())
+        );
+        assert_eq!(default_shell(None, Path::new("/no/such/shell")), None);
+
+        // Without any shell, a config is fine unless it has something to run.
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        assert!(get_config_with_shell::<PathBuf>(&[], &matches, None).is_ok());
+        let matches = opts.parse(&["--onevent", "notify-send"]).unwrap();
+        let err = get_config_with_shell::<PathBuf>(&[], &matches, None).unwrap_err();
+        assert_eq!(err.to_string(), "shell has to be set for onevent.");
+    }
+
+    #[test]
+    fn shell_must_be_executable() {
+        assert!(is_executable(Path::new("/bin/sh")));
+        assert!(!is_executable(Path::new("/bin")));
+        assert!(!is_executable(Path::new("/etc/passwd")));
+        assert!(!is_executable(Path::new("/no/such/shell")));
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--shell", "/no/such/shell"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "shell must be the path to an executable, got \"/no/such/shell\"."
+        );
+    }
+
+    #[test]
+    fn username_trimming() {
+        assert_eq!(
+            normalize_username(" JaneDoe\n".to_string(), true),
+            "JaneDoe"
+        );
+        assert_eq!(
+            normalize_username(" JaneDoe\n".to_string(), false),
+            " JaneDoe\n"
+        );
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&["--username", "JaneDoe \t", "--trim_username"])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(
+            config.username.as_ref().map(String::as_str),
+            Some("JaneDoe")
+        );
+    }
+
+    #[test]
+    fn username_case_preserved() {
+        assert_eq!(normalize_username("JaneDoe".to_string(), true), "JaneDoe");
+        assert_eq!(normalize_username("JaneDoe".to_string(), false), "JaneDoe");
+        assert_eq!(
+            normalize_username(" Jane Doe ".to_string(), true),
+            "Jane Doe"
+        );
+    }
+
+    #[test]
+    fn password_file() {
+        let path = temp_path("password");
+        fs::write(&path, "hunter2\n").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&["--password_file", path.to_str().unwrap()])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(
+            config.password.as_ref().map(String::as_str),
+            Some("hunter2")
+        );
+
+        let matches = opts
+            .parse(&["--password_file", "/no/such/password"])
+            .unwrap();
+        assert!(get_config::<PathBuf>(&[], &matches).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn password_file_permissions() {
+        let path = Path::new("/run/secrets/spotify");
+        assert_eq!(password_file_warning(path, 0o100600), None);
+        assert_eq!(password_file_warning(path, 0o100400), None);
+        assert_eq!(
+            password_file_warning(path, 0o100644).unwrap(),
+            "The password file \"/run/secrets/spotify\" can be accessed by other users \
+             (mode 0644), restrict it to 0600."
+        );
+        assert!(password_file_warning(path, 0o100640).is_some());
+    }
+
+    #[test]
+    fn password_file_conflicts() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&[
+                "--password_file",
+                "/run/secrets/spotify",
+                "--password",
+                "pass",
+            ])
+            .unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "password_file can't be combined with password, use only one of them."
+        );
+
+        let config =
+            "[global]\npassword_file = /run/secrets/spotify\npassword_cmd = pass spotify\n";
+        let path = temp_path("conflict");
+        fs::write(&path, config).unwrap();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let err = get_config(&[&path], &matches).unwrap_err();
+        assert!(err.to_string().contains("password_cmd"), "{}", err);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn event_filter() {
+        assert_eq!(parse_event_filter("").unwrap(), None);
+        assert_eq!(parse_event_filter(" , ").unwrap(), None);
+        assert_eq!(
+            parse_event_filter("change, stop").unwrap(),
+            Some(vec!["change", "stop"])
+        );
+
+        let err = parse_event_filter("change,pause").unwrap_err();
+        assert!(err.to_string().contains("\"pause\""), "{}", err);
+    }
+
+    #[test]
+    fn config_from_stdin() {
+        let stdin = "[global]\ndevice_name = piped\nbitrate = 320\n".as_bytes();
+        let ini = read_config_file(Some("-"), stdin).unwrap();
+        let global = ini.section(Some("global".to_owned())).unwrap();
+        assert_eq!(global.get("device_name").map(String::as_str), Some("piped"));
+
+        let err = read_config_file(Some("-"), "[global\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("stdin"), "{}", err);
+    }
+
+    #[test]
+    fn client_id() {
+        assert_eq!(
+            parse_client_id("2C1EA588DFBC4A989E2426F8385297C3"),
+            Some(CLIENT_ID.to_string())
+        );
+        assert_eq!(
+            parse_client_id(" 2c1ea588-dfbc-4a98-9e24-26f8385297c3 "),
+            Some(CLIENT_ID.to_string())
+        );
+        for invalid in &[
+            "",
+            "2c1ea588dfbc4a989e2426f8385297c",
+            "2c1ea588dfbc4a989e2426f8385297c3a",
+            "2c1ea588dfbc4a989e2426f8385297cg",
+            "2c1ea588-dfbc4a98-9e24-26f8385297c3",
+            "-2c1ea588dfbc4a989e2426f8385297c",
+        ] {
+            assert_eq!(parse_client_id(invalid), None, "{:?}", invalid);
+        }
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.client_id, CLIENT_ID);
+        let matches = opts
+            .parse(&["--client_id", "0123456789abcdef0123456789ABCDEF"])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.client_id, "0123456789abcdef0123456789abcdef");
+        let matches = opts.parse(&["--client_id", "spotifyd"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert!(err.to_string().starts_with("client_id must be"), "{}", err);
+    }
+
+    #[test]
+    fn config_chain() {
+        let dir = temp_path("chain");
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("base.conf");
+        let local = dir.join("local.toml");
+        fs::write(
+            &base,
+            "[global]\n\
+             device_name = Base\n\
+             bitrate = 96\n\
+             [spotifyd]\n\
+             onevent = base-hook\n",
+        )
+        .unwrap();
+        fs::write(
+            &local,
+            "[global]\n\
+             bitrate = 320\n\
+             mono = true\n\
+             onevent = \"local-hook\"\n",
+        )
+        .unwrap();
+        let opts = crate::cli::command_line_argument_options();
+
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config(&[&base, &local], &matches).unwrap();
+        assert_eq!(config.device_name, "Base");
+        assert_eq!(config.player_config.bitrate, Bitrate::Bitrate320);
+        assert!(config.filters.mono);
+        // Each file is resolved on its own first, so [global] of a later file beats
+        // [spotifyd] of an earlier one.
+        assert_eq!(
+            config.onevent.as_ref().map(String::as_str),
+            Some("local-hook")
+        );
+
+        // Later files win, and the command line wins over all of them.
+        let config = get_config(&[&local, &base], &matches).unwrap();
+        assert_eq!(config.player_config.bitrate, Bitrate::Bitrate96);
+        assert_eq!(
+            config.onevent.as_ref().map(String::as_str),
+            Some("base-hook")
+        );
+        let matches = opts.parse(&["--bitrate", "160"]).unwrap();
+        let config = get_config(&[&base, &local], &matches).unwrap();
+        assert_eq!(config.player_config.bitrate, Bitrate::Bitrate160);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn toml_config() {
+        let dir = temp_path("formats");
+        fs::create_dir_all(&dir).unwrap();
+        let ini = dir.join("spotifyd.conf");
+        let toml = dir.join("spotifyd.toml");
+        fs::write(
+            &ini,
+            "[global]\n\
+             device_name = Kitchen\n\
+             bitrate = 320\n\
+             onevent_filter = change,stop\n\
+             [spotifyd]\n\
+             volume-normalisation = true\n\
+             normalisation-pregain = -5\n",
+        )
+        .unwrap();
+        fs::write(
+            &toml,
+            "[global]\n\
+             device_name = \"Kitchen\"\n\
+             bitrate = 320\n\
+             onevent_filter = [\"change\", \"stop\"]\n\
+             [spotifyd]\n\
+             volume-normalisation = true\n\
+             normalisation-pregain = -5\n",
+        )
+        .unwrap();
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let from_ini = get_config(&[&ini], &matches).unwrap();
+        let from_toml = get_config(&[&toml], &matches).unwrap();
+        assert_eq!(from_ini.device_name, "Kitchen");
+        assert_eq!(from_toml.dump(), from_ini.dump());
+
+        fs::write(&toml, "[global]\ndevice_name = Kitchen\n").unwrap();
+        let err = get_config(&[&toml], &matches).unwrap_err();
+        assert!(err.to_string().contains("spotifyd.toml"), "{}", err);
+
+        // A value that only looks like an array keeps the file INI.
+        fs::write(
+            &ini,
+            "[global]\n\
+             onevent = [ \"$PLAYER_EVENT\" = change ] && notify-send \"$TRACK_ID\"\n",
+        )
+        .unwrap();
+        let config = get_config(&[&ini], &matches).unwrap();
+        assert_eq!(
+            config.onevent.as_ref().map(String::as_str),
+            Some("[ \"$PLAYER_EVENT\" = change ] && notify-send \"$TRACK_ID\"")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ini_deprecation() {
+        assert_eq!(
+            ini_warning(Path::new("/etc/spotifyd.conf")),
+            "/etc/spotifyd.conf is in the old INI format, run spotifyd --config \
+             \"/etc/spotifyd.conf\" --migrate-config to convert it to TOML."
+        );
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let some = |s: &str| Some(s.to_string());
+        let quiet_hours = parse_quiet_hours(some("22:30"), some("07:00"), some("50%"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(quiet_hours.start, NaiveTime::from_hms(22, 30, 0));
+        assert_eq!(quiet_hours.end, NaiveTime::from_hms(7, 0, 0));
+        assert_eq!(quiet_hours.max_volume, 0x7FFF);
+
+        assert_eq!(parse_quiet_hours(None, None, None).unwrap(), None);
+        let err = parse_quiet_hours(some("22:30"), None, some("50")).unwrap_err();
+        assert!(err.to_string().starts_with("quiet_hours_end"), "{}", err);
+        let err = parse_quiet_hours(some("25:00"), some("07:00"), some("50")).unwrap_err();
+        assert!(err.to_string().starts_with("quiet_hours_start"), "{}", err);
+    }
+
+    #[test]
+    fn preset() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--preset", "low-bandwidth"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.player_config.bitrate, Bitrate::Bitrate96);
+
+        let matches = opts
+            .parse(&["--preset", "low-bandwidth", "--bitrate", "320"])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.player_config.bitrate, Bitrate::Bitrate320);
+    }
+
+    #[test]
+    fn use_mpris() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--use_mpris", "false"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.use_mpris, UseMpris::No);
+
+        let matches = opts.parse(&["--use_mpris", "required"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches);
+        if cfg!(feature = "dbus_mpris") {
+            assert_eq!(config.unwrap().use_mpris, UseMpris::Required);
+        } else {
+            assert!(config.is_err());
+        }
+
+        let matches = opts.parse(&["--use_mpris", "maybe"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert!(err.to_string().starts_with("use_mpris must be"), "{}", err);
+    }
+
+    #[test]
+    fn on_auth_failure() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.on_auth_failure, AuthFailurePolicy::Exit);
+
+        let matches = opts.parse(&["--on_auth_failure", "wait"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.on_auth_failure, AuthFailurePolicy::Wait);
+
+        let matches = opts.parse(&["--on_auth_failure", "retry"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "on_auth_failure must be exit or wait, got \"retry\"."
+        );
+    }
+
+    #[test]
+    fn instance_policy() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.instance_policy, InstancePolicy::Refuse);
+
+        let matches = opts.parse(&["--instance_policy", "takeover"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.instance_policy, InstancePolicy::Takeover);
+
+        let matches = opts.parse(&["--instance_policy", "fight"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "instance_policy must be refuse, takeover or coexist, got \"fight\"."
+        );
+    }
+
+    #[test]
+    fn fade_in_ms() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--fade_in_ms", "250"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.filters.fade_in, Some(Duration::from_millis(250)));
+
+        let matches = opts.parse(&["--fade_in_ms", "0"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.filters.fade_in, None);
+
+        let matches = opts.parse(&["--fade_in_ms", "-5"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert!(err.to_string().starts_with("fade_in_ms must be"), "{}", err);
+
+        let matches = opts.parse(&["--fade_out_ms", "150"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.fade_out, Some(Duration::from_millis(150)));
+        assert_eq!(config.filters.fade_in, None);
+    }
+
+    #[test]
+    fn eq_bands() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&[] as &[&str]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert!(config.filters.eq_bands.is_empty());
+
+        let matches = opts
+            .parse(&["--eq_bands", "80/-6/0.7, 3000/2/1.4"])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.filters.eq_bands.len(), 2);
+        assert!(config
+            .dump()
+            .contains("eq_bands = \"80/-6/0.7,3000/2/1.4\"\n"));
+
+        let matches = opts.parse(&["--eq_bands", "80/-6"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert!(err.to_string().starts_with("eq_bands must be"), "{}", err);
+    }
+
+    #[test]
+    fn lastfm() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&[
+                "--lastfm_api_key",
+                "key",
+                "--lastfm_api_secret",
+                "secret",
+                "--lastfm_username",
+                "jane",
+                "--lastfm_password",
+                "hunter2",
+            ])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(config.lastfm.as_ref().unwrap().username, "jane");
+        let dump = config.dump();
+        assert!(dump.contains("lastfm_username = \"jane\"\n"));
+        assert!(!dump.contains("hunter2") && !dump.contains("secret"));
+
+        let matches = opts.parse(&["--lastfm_username", "jane"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "lastfm_api_key has to be set for scrobbling to Last.fm."
+        );
+    }
+
+    #[test]
+    fn volume_backend_selection() {
+        let alsa_linear = VolumeController::Alsa { linear: true };
+        let softvol = VolumeController::SoftVol;
+        assert_eq!(
+            select_volume_controller(alsa_linear, None, None).unwrap(),
+            alsa_linear
+        );
+        assert_eq!(
+            select_volume_controller(alsa_linear, Some(VolumeBackend::Software), Some("PCM"))
+                .unwrap(),
+            softvol
+        );
+        if cfg!(feature = "alsa_backend") {
+            assert_eq!(
+                select_volume_controller(softvol, Some(VolumeBackend::Hardware), Some("PCM"))
+                    .unwrap(),
+                VolumeController::Alsa { linear: false }
+            );
+            assert_eq!(
+                select_volume_controller(alsa_linear, Some(VolumeBackend::Hardware), Some("PCM"))
+                    .unwrap(),
+                alsa_linear
+            );
+        }
+    }
+
+    #[cfg(feature = "alsa_backend")]
+    #[test]
+    fn volume_backend_needs_mixer() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--volume_backend", "hardware"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "mixer has to be set for volume_backend = hardware."
+        );
+
+        let matches = opts
+            .parse(&["--volume_backend", "hardware", "--mixer", "PCM"])
+            .unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(
+            config.volume_controller,
+            VolumeController::Alsa { linear: false }
+        );
+        assert!(config.dump().contains("volume_backend = \"hardware\"\n"));
+    }
+
+    #[test]
+    fn channel_map() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts.parse(&["--channel_map", "R,L"]).unwrap();
+        let config = get_config::<PathBuf>(&[], &matches).unwrap();
+        assert_eq!(
+            config.filters.channel_map,
+            Some(ChannelMap::from_str("R,L").unwrap())
+        );
+
+        let matches = opts.parse(&["--channel_map", "L,R,R"]).unwrap();
+        let err = get_config::<PathBuf>(&[], &matches).unwrap_err();
+        assert!(
+            err.to_string().starts_with("channel_map must be"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "bluealsa_backend")]
+    fn bluealsa_device() {
+        let available = &["alsa", "bluealsa"];
+        let mut device = Some("00:1A:7D:DA:71:13".to_string());
+        assert!(validate_backend(Some("bluealsa"), &mut device, available).is_ok());
+
+        let err = validate_backend(Some("bluealsa"), &mut Some("hw:0".to_string()), available)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("device must be"), "{}", err);
+        assert!(validate_backend(Some("bluealsa"), &mut None, available).is_err());
+
+        // The MAC address can also be given with the backend, and device left for alsa.
+        let mut device = Some("hw:0".to_string());
+        let backend = Some("alsa+bluealsa:00:1A:7D:DA:71:13");
+        assert!(validate_backend(backend, &mut device, available).is_ok());
+        assert_eq!(device, Some("hw:0".to_string()));
+    }
+
+    #[test]
+    fn disabled_backend() {
+        let err = validate_backend(Some("pulseaudio"), &mut None, &["alsa", "pipe"]).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("pulseaudio_backend"), "{}", msg);
+        assert!(msg.contains("alsa, pipe"), "{}", msg);
+
+        let err = validate_backend(Some("jukebox"), &mut None, &["alsa"]).unwrap_err();
+        assert!(err.to_string().starts_with("Unknown backend"));
+
+        assert!(validate_backend(Some("pipe"), &mut None, &["alsa", "pipe"]).is_ok());
+        assert!(validate_backend(Some("alsa+pipe"), &mut None, &["alsa", "pipe"]).is_ok());
+        let err = validate_backend(Some("alsa+jukebox"), &mut None, &["alsa"]).unwrap_err();
+        assert!(err.to_string().contains("\"jukebox\""), "{}", err);
+        assert!(validate_backend(None, &mut None, &["alsa"]).is_ok());
+    }
+
+    #[test]
+    fn ignored_device() {
+        let mut device = Some("hw:0".to_string());
+        validate_backend(Some("pulseaudio"), &mut device, &["pulseaudio"]).unwrap();
+        assert_eq!(device, None);
+
+        let mut device = Some("hw:0".to_string());
+        validate_backend(None, &mut device, &["alsa", "pulseaudio"]).unwrap();
+        assert_eq!(device, Some("hw:0".to_string()));
+
+        // The device is still used by the alsa backend.
+        let mut device = Some("hw:0".to_string());
+        validate_backend(
+            Some("alsa+pulseaudio"),
+            &mut device,
+            &["alsa", "pulseaudio"],
+        )
+        .unwrap();
+        assert_eq!(device, Some("hw:0".to_string()));
+
+        // Unless every backend that could use it has a device of its own.
+        let mut device = Some("hw:0".to_string());
+        validate_backend(
+            Some("alsa:hw:1+pulseaudio"),
+            &mut device,
+            &["alsa", "pulseaudio"],
+        )
+        .unwrap();
+        assert_eq!(device, None);
+        let err = validate_backend(Some("alsa:hw:1+jukebox:x"), &mut None, &["alsa"]).unwrap_err();
+        assert!(err.to_string().contains("\"jukebox\""), "{}", err);
+    }
+
+    #[test]
+    fn mpris_instance_invalid() {
+        for suffix in &["", "1st", "two words", "trailing.", "k\u{fc}che", "a..b"] {
+            assert!(MprisInstance::from_str(suffix).is_err(), "{:?}", suffix);
+        }
+        assert!(MprisInstance::from_str(&"a".repeat(255)).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use ini::Ini;
+use std::{fmt, path::Path};
+use toml::Value;
+
+/// The formats a config file can be written in. Both end up as the same sections of
+/// keys and values, so it doesn't matter to the rest of the configuration which one a
+/// file uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    Ini,
+    Toml,
+}
+
+impl Format {
+    /// Reads `contents`, written in this format, into sections of keys and values.
+    pub(crate) fn parse(self, contents: &str) -> Result<Ini, String> {
+        match self {
+            Format::Ini => Ini::load_from_str(contents).map_err(|err| err.to_string()),
+            Format::Toml => parse_toml(contents),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Ini => write!(f, "INI"),
+            Format::Toml => write!(f, "TOML"),
+        }
+    }
+}
+
+/// Tells the format of a config file by its extension, or by its contents if that's
+/// not conclusive. Arrays only exist in TOML, but an INI value can start with `[` too,
+/// e.g. a shell test in `onevent`, so a file is only taken for TOML if it also parses as
+/// such. Anything else is INI.
+pub(crate) fn detect(path: &Path, contents: &str) -> Format {
+    if path
+        .extension()
+        .map_or(false, |extension| extension == "toml")
+    {
+        return Format::Toml;
+    }
+    let has_array = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#') && !line.starts_with(';'))
+        .filter_map(|line| line.splitn(2, '=').nth(1))
+        .any(|value| value.trim_start().starts_with('['));
+    if has_array && parse_toml(contents).is_ok() {
+        Format::Toml
+    } else {
+        Format::Ini
+    }
+}
+
+/// Reads a TOML config into the sections an INI config would have. Keys outside a
+/// table go into `[global]`, and arrays become comma-separated lists.
+pub(crate) fn parse_toml(contents: &str) -> Result<Ini, String> {
+    let table = match contents.parse::<Value>().map_err(|e| e.to_string())? {
+        Value::Table(table) => table,
+        _ => return Err("expected a table".to_string()),
+    };
+    let mut ini = Ini::new();
+    for (key, value) in table {
+        match value {
+            Value::Table(section) => {
+                for (field, value) in section {
+                    let value = scalar(&value).ok_or_else(|| nested(&field))?;
+                    ini.with_section(Some(key.as_str())).set(field, value);
+                }
+            }
+            value => {
+                let value = scalar(&value).ok_or_else(|| nested(&key))?;
+                ini.with_section(Some("global")).set(key, value);
+            }
+        }
+    }
+    Ok(ini)
+}
+
+fn nested(key: &str) -> String {
+    format!("{} can't be a table", key)
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Datetime(d) => Some(d.to_string()),
+        Value::Array(values) => values
+            .iter()
+            .map(scalar)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        Value::Table(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detection() {
+        let ini = "[global]\nusername = jane\n";
+        assert_eq!(detect(Path::new("spotifyd.conf"), ini), Format::Ini);
+        assert_eq!(detect(Path::new("spotifyd.toml"), ini), Format::Toml);
+        let toml = "[global]\n# eq = [1]\nonevent_filter = [\"change\", \"stop\"]\n";
+        assert_eq!(detect(Path::new("spotifyd.conf"), toml), Format::Toml);
+        // A shell test isn't an array.
+        let ini = "[global]\n\
+                   onevent = [ \"$PLAYER_EVENT\" = change ] && notify-send \"$TRACK_ID\"\n";
+        assert_eq!(detect(Path::new("spotifyd.conf"), ini), Format::Ini);
+    }
+
+    #[test]
+    fn toml_sections() {
+        let ini = parse_toml(
+            "bitrate = 320\n\
+             [global]\n\
+             username = \"jane\"\n\
+             volume_normalisation = true\n\
+             [spotifyd]\n\
+             normalisation-pregain = -5.5\n\
+             onevent_filter = [\"change\", \"stop\"]\n",
+        )
+        .unwrap();
+        let global = ini.section(Some("global".to_owned())).unwrap();
+        assert_eq!(global.get("bitrate").map(String::as_str), Some("320"));
+        assert_eq!(global.get("username").map(String::as_str), Some("jane"));
+        assert_eq!(
+            global.get("volume_normalisation").map(String::as_str),
+            Some("true")
+        );
+        let spotifyd = ini.section(Some("spotifyd".to_owned())).unwrap();
+        assert_eq!(
+            spotifyd.get("normalisation-pregain").map(String::as_str),
+            Some("-5.5")
+        );
+        assert_eq!(
+            spotifyd.get("onevent_filter").map(String::as_str),
+            Some("change,stop")
+        );
+    }
+
+    #[test]
+    fn toml_invalid() {
+        assert!(parse_toml("[global\n").is_err());
+        assert_eq!(
+            parse_toml("[global.mpris]\nenabled = true\n").unwrap_err(),
+            "mpris can't be a table"
+        );
+    }
+}
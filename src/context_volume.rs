@@ -0,0 +1,226 @@
+use futures::{future, Future};
+use librespot::core::{keymaster::get_token, session::Session};
+use log::{error, info, warn};
+use rspotify::spotify::client::Spotify;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+const SCOPE: &str = "user-read-playback-state,user-modify-playback-state";
+
+/// The name of the file in the cache directory that holds the volumes.
+pub(crate) const FILE_NAME: &str = "context_volumes";
+
+/// How many contexts to remember the volume of.
+const MAX_ENTRIES: usize = 200;
+
+/// The volume last used for each context (playlist, album, ...), most recent last.
+#[derive(Debug, PartialEq)]
+pub(crate) struct VolumeMemory {
+    path: PathBuf,
+    max_entries: usize,
+    entries: Vec<(String, u8)>,
+    /// The context of the track that started last.
+    current: Option<String>,
+}
+
+impl VolumeMemory {
+    /// Loads the volumes stored at `path`, starting out empty if there are none.
+    pub(crate) fn load(path: PathBuf) -> VolumeMemory {
+        VolumeMemory::load_with_limit(path, MAX_ENTRIES)
+    }
+
+    fn load_with_limit(path: PathBuf, max_entries: usize) -> VolumeMemory {
+        let mut entries = match fs::read_to_string(&path) {
+            Ok(contents) => parse_entries(&contents),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Couldn't read the context volumes from {:?}: {}", path, e);
+                Vec::new()
+            }
+        };
+        let excess = entries.len().saturating_sub(max_entries);
+        entries.drain(..excess);
+        VolumeMemory {
+            path,
+            max_entries,
+            entries,
+            current: None,
+        }
+    }
+
+    fn get(&self, context: &str) -> Option<u8> {
+        self.entries
+            .iter()
+            .find(|(uri, _)| uri == context)
+            .map(|&(_, volume)| volume)
+    }
+
+    /// Remembers the volume of `context`, forgetting the least recently used context if
+    /// there are too many.
+    fn remember(&mut self, context: &str, volume: u8) {
+        self.entries.retain(|(uri, _)| uri != context);
+        self.entries.push((context.to_string(), volume));
+        if self.entries.len() > self.max_entries {
+            let excess = self.entries.len() - self.max_entries;
+            self.entries.drain(..excess);
+        }
+    }
+
+    /// Records that a track of `context` started playing at `volume`. Returns the volume
+    /// to restore if playback switched to a context with a remembered volume.
+    pub(crate) fn track_started(&mut self, context: &str, volume: u8) -> Option<u8> {
+        let switched = self
+            .current
+            .as_ref()
+            .map_or(true, |current| current != context);
+        self.current = Some(context.to_string());
+        match self.get(context) {
+            Some(remembered) if switched && remembered != volume => {
+                self.remember(context, remembered);
+                Some(remembered)
+            }
+            _ => {
+                self.remember(context, volume);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn save(&self) -> io::Result<()> {
+        write_entries(&self.path, &self.entries)
+    }
+}
+
+/// Parses lines of a context URI and a volume percentage, separated by a tab.
+fn parse_entries(contents: &str) -> Vec<(String, u8)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(2, '\t');
+            let uri = fields.next()?;
+            let volume = fields.next()?.parse::<u8>().ok().filter(|&v| v <= 100)?;
+            Some((uri.to_string(), volume))
+        })
+        .collect()
+}
+
+fn write_entries(path: &Path, entries: &[(String, u8)]) -> io::Result<()> {
+    let contents: String = entries
+        .iter()
+        .map(|(uri, volume)| format!("{}\t{}\n", uri, volume))
+        .collect();
+    fs::write(path, contents)
+}
+
+/// Looks up the context and volume of the playback that just started on this device,
+/// restoring the volume last used for that context. The player only reports the track,
+/// so this goes through the Web API.
+pub(crate) fn track_started(
+    session: &Session,
+    client_id: &str,
+    device_id: String,
+    memory: Arc<Mutex<VolumeMemory>>,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    Box::new(
+        get_token(session, client_id, SCOPE)
+            .map_err(|e| error!("Couldn't get a token to look up the context: {:?}", e))
+            .and_then(move |token| {
+                // The Web API client blocks, so keep it off the event loop.
+                thread::spawn(move || {
+                    let spotify = Spotify::default().access_token(&token.access_token).build();
+                    let playback = match spotify.current_playback(None) {
+                        Ok(Some(playback)) => playback,
+                        Ok(None) => return,
+                        Err(e) => {
+                            warn!("Couldn't look up the current playback: {}", e);
+                            return;
+                        }
+                    };
+                    let context = match playback.context {
+                        Some(context) => context.uri,
+                        None => return,
+                    };
+                    let volume = playback.device.volume_percent.min(100) as u8;
+                    let mut memory = memory.lock().unwrap();
+                    if let Some(volume) = memory.track_started(&context, volume) {
+                        info!("Restoring the volume of {} to {}%.", context, volume);
+                        if let Err(e) = spotify.volume(volume, Some(device_id)) {
+                            error!("Couldn't set the volume to {}%: {}", volume, e);
+                        }
+                    }
+                    if let Err(e) = memory.save() {
+                        warn!("Couldn't save the context volumes: {}", e);
+                    }
+                });
+                future::ok(())
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    const PODCAST: &str = "spotify:show:5CfCWKI5pZ28U0uOzXkDHe";
+    const PLAYLIST: &str = "spotify:playlist:37i9dQZF1DX0yEZaMOXna3";
+
+    #[test]
+    fn per_context_round_trip() {
+        let path = temp_path("context-volumes");
+        let _ = fs::remove_file(&path);
+
+        let mut memory = VolumeMemory::load(path.clone());
+        assert_eq!(memory.track_started(PODCAST, 20), None);
+        assert_eq!(memory.track_started(PODCAST, 25), None);
+        assert_eq!(memory.track_started(PLAYLIST, 80), None);
+        // Back to the podcast, at the volume it was last played at.
+        assert_eq!(memory.track_started(PODCAST, 80), Some(25));
+        assert_eq!(memory.track_started(PODCAST, 25), None);
+        memory.save().unwrap();
+
+        let mut memory = VolumeMemory::load(path.clone());
+        assert_eq!(memory.get(PODCAST), Some(25));
+        assert_eq!(memory.get(PLAYLIST), Some(80));
+        assert_eq!(memory.track_started(PLAYLIST, 25), Some(80));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn evicts_oldest_contexts() {
+        let path = temp_path("context-volumes-evict");
+        let mut memory = VolumeMemory::load_with_limit(path.clone(), 3);
+        for (i, uri) in ["spotify:album:a", "spotify:album:b", "spotify:album:c"]
+            .iter()
+            .enumerate()
+        {
+            memory.track_started(uri, i as u8 * 10);
+        }
+        // Playing a context again makes it the most recent one...
+        memory.track_started("spotify:album:a", 0);
+        memory.track_started("spotify:album:d", 30);
+        // ...so the least recently played one goes.
+        assert_eq!(memory.get("spotify:album:b"), None);
+        assert_eq!(memory.get("spotify:album:a"), Some(0));
+        assert_eq!(memory.entries.len(), 3);
+
+        memory.save().unwrap();
+        let memory = VolumeMemory::load_with_limit(path.clone(), 3);
+        let uris: Vec<_> = memory.entries.iter().map(|(uri, _)| uri.as_str()).collect();
+        assert_eq!(
+            uris,
+            vec!["spotify:album:c", "spotify:album:a", "spotify:album:d"]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_lines_skipped() {
+        let entries = parse_entries("spotify:album:a\t40\nbroken\nspotify:album:b\t140\n");
+        assert_eq!(entries, vec![("spotify:album:a".to_string(), 40)]);
+    }
+}
@@ -1,14 +1,16 @@
 use chrono::prelude::*;
 use dbus::{
     arg::{RefArg, Variant},
+    stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged,
     tree::{Access, MethodErr},
-    BusType, Connection, MessageItem, MessageItemArray, NameFlag, Signature,
+    BusType, Connection, Message, MessageItem, MessageItemArray, NameFlag, Path, SignalArgs,
+    Signature,
 };
 use dbus_tokio::{
     tree::{AFactory, ATree, ATreeServer},
     AConnection,
 };
-use futures::{sync::oneshot, Async, Future, Poll, Stream};
+use futures::{future, sync::oneshot, Async, Future, Poll, Stream};
 use librespot::{
     connect::spirc::Spirc,
     core::{
@@ -16,14 +18,17 @@ use librespot::{
         mercury::MercuryError,
         session::Session,
     },
+    playback::config::Bitrate,
 };
-use log::{info, warn};
+use log::{error, info, warn};
 use rspotify::spotify::{
     client::Spotify, oauth2::TokenInfo as RspotifyToken, senum::*, util::datetime_to_timestamp,
 };
-use std::{collections::HashMap, rc::Rc, thread};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, thread};
 use tokio_core::reactor::Handle;
 
+use crate::config::{ArtistSeparator, DBusType, MprisIdentity};
+
 pub struct DbusServer {
     session: Session,
     handle: Handle,
@@ -32,9 +37,14 @@ pub struct DbusServer {
     token_request: Option<Box<Future<Item = LibrespotToken, Error = MercuryError>>>,
     dbus_future: Option<Box<Future<Item = (), Error = ()>>>,
     device_name: String,
+    bus_name: String,
+    dbus_type: DBusType,
+    bitrate: Bitrate,
+    client_id: String,
+    artist_separator: ArtistSeparator,
+    identity: MprisIdentity,
 }
 
-const CLIENT_ID: &str = "2c1ea588dfbc4a989e2426f8385297c3";
 const SCOPE: &str = "user-read-playback-state,user-read-private,user-read-birthdate,\
                      user-read-email,playlist-read-private,user-library-read,user-library-modify,\
                      user-top-read,playlist-read-collaborative,playlist-modify-public,\
@@ -43,11 +53,18 @@ const SCOPE: &str = "user-read-playback-state,user-read-private,user-read-birthd
                      user-read-recently-played";
 
 impl DbusServer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         session: Session,
         handle: Handle,
         spirc: Rc<Spirc>,
         device_name: String,
+        bus_name: String,
+        dbus_type: DBusType,
+        bitrate: Bitrate,
+        client_id: String,
+        artist_separator: ArtistSeparator,
+        identity: MprisIdentity,
     ) -> DbusServer {
         DbusServer {
             session,
@@ -57,6 +74,12 @@ impl DbusServer {
             token_request: None,
             dbus_future: None,
             device_name,
+            bus_name,
+            dbus_type,
+            bitrate,
+            client_id,
+            artist_separator,
+            identity,
         }
     }
 
@@ -87,11 +110,16 @@ impl Future for DbusServer {
                         self.api_token.clone(),
                         self.spirc.clone(),
                         self.device_name.clone(),
+                        &self.bus_name,
+                        self.dbus_type,
+                        self.bitrate,
+                        self.artist_separator.clone(),
+                        self.identity.clone(),
                     ));
                     got_new_token = true;
                 }
             } else {
-                self.token_request = Some(get_token(&self.session, CLIENT_ID, SCOPE));
+                self.token_request = Some(get_token(&self.session, &self.client_id, SCOPE));
             }
         } else if let Some(ref mut fut) = self.dbus_future {
             return fut.poll();
@@ -109,13 +137,139 @@ fn create_spotify_api(token: &RspotifyToken) -> Spotify {
     Spotify::default().access_token(&token.access_token).build()
 }
 
+/// The interface with the bitrate and sample format, which isn't part of MPRIS.
+const AUDIO_INTERFACE: &str = "io.github.spotifyd.Audio";
+
+/// librespot only decodes to signed 16 bit samples.
+const SAMPLE_FORMAT: &str = "S16";
+
+/// The bitrate librespot streams at, in kbit/s.
+fn bitrate_kbps(bitrate: Bitrate) -> u32 {
+    match bitrate {
+        Bitrate::Bitrate96 => 96,
+        Bitrate::Bitrate160 => 160,
+        Bitrate::Bitrate320 => 320,
+    }
+}
+
+/// Whether `date` is a release date as Spotify gives them, `1981`, `1981-12` or
+/// `1981-12-15` depending on how precisely it's known. Those are valid ISO 8601 dates,
+/// which is what `xesam:contentCreated` takes.
+fn is_release_date(date: &str) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    let lengths_ok = match parts.len() {
+        1 => parts[0].len() == 4,
+        2 | 3 => parts[0].len() == 4 && parts[1..].iter().all(|part| part.len() == 2),
+        _ => false,
+    };
+    lengths_ok
+        && parts
+            .iter()
+            .all(|part| part.chars().all(|c| c.is_ascii_digit()))
+        && parts[0] != "0000"
+}
+
+/// The track and disc number and the release date of a track, leaving out those that
+/// Spotify doesn't know. MPRIS takes the numbers as signed integers.
+fn release_metadata(
+    track_number: u32,
+    disc_number: i32,
+    release_date: Option<&str>,
+) -> Vec<(&'static str, MessageItem)> {
+    let mut metadata = Vec::new();
+    if track_number > 0 {
+        metadata.push(("xesam:trackNumber", MessageItem::Int32(track_number as i32)));
+    }
+    if disc_number > 0 {
+        metadata.push(("xesam:discNumber", MessageItem::Int32(disc_number)));
+    }
+    if let Some(date) = release_date.filter(|date| is_release_date(date)) {
+        metadata.push(("xesam:contentCreated", MessageItem::Str(date.to_string())));
+    }
+    metadata
+}
+
+/// The release date of the album played last, so that the album is only looked up again
+/// once another one plays. A failed lookup isn't retried for the same album.
+#[derive(Debug, Default)]
+struct ReleaseDate {
+    album_id: Option<String>,
+    date: Option<String>,
+}
+
+impl ReleaseDate {
+    /// The release date of the album `album_id`, which `lookup` gets unless it's the same
+    /// album as last time.
+    fn get<F: FnOnce() -> Option<String>>(&mut self, album_id: &str, lookup: F) -> Option<&str> {
+        if self.album_id.as_ref().map(String::as_str) != Some(album_id) {
+            self.date = lookup();
+            self.album_id = Some(album_id.to_string());
+        }
+        self.date.as_ref().map(String::as_str)
+    }
+}
+
+/// The PropertiesChanged signal with the current bitrate and sample format. MPRIS
+/// clients that were reading them from a previous connection pick up the values of the
+/// new one from this.
+fn audio_properties_changed(bitrate: Bitrate) -> Message {
+    let mut changed: HashMap<String, Variant<Box<RefArg>>> = HashMap::new();
+    changed.insert(
+        "Bitrate".to_string(),
+        Variant(Box::new(bitrate_kbps(bitrate)) as Box<RefArg>),
+    );
+    changed.insert(
+        "SampleFormat".to_string(),
+        Variant(Box::new(SAMPLE_FORMAT.to_string()) as Box<RefArg>),
+    );
+    PropertiesPropertiesChanged {
+        interface_name: AUDIO_INTERFACE.to_string(),
+        changed_properties: changed,
+        invalidated_properties: Vec::new(),
+    }
+    .to_emit_message(&Path::from("/org/mpris/MediaPlayer2"))
+}
+
+fn bus_type(dbus_type: DBusType) -> BusType {
+    match dbus_type {
+        DBusType::Session => BusType::Session,
+        DBusType::System => BusType::System,
+    }
+}
+
+/// Checks once whether the given bus can be connected to, and lets spotifyd own
+/// `bus_name`. The system bus only does so if a policy file allows it.
+pub(crate) fn check_bus(dbus_type: DBusType, bus_name: &str) -> Result<(), String> {
+    let c = Connection::get_private(bus_type(dbus_type)).map_err(|e| e.to_string())?;
+    // Don't take the name from another instance that owns it, that's only refused later.
+    c.register_name(bus_name, NameFlag::DoNotQueue as u32)
+        .map_err(|e| format!("the bus policy refused the name {}: {}", bus_name, e))?;
+    let _ = c.release_name(bus_name);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn create_dbus_server(
     handle: Handle,
     api_token: RspotifyToken,
     spirc: Rc<Spirc>,
     device_name: String,
+    bus_name: &str,
+    dbus_type: DBusType,
+    bitrate: Bitrate,
+    artist_separator: ArtistSeparator,
+    identity: MprisIdentity,
 ) -> Box<Future<Item = (), Error = ()>> {
-    let c = Rc::new(Connection::get_private(BusType::Session).unwrap());
+    let c = match Connection::get_private(bus_type(dbus_type)) {
+        Ok(c) => Rc::new(c),
+        Err(e) => {
+            error!(
+                "Couldn't connect to the D-Bus {} bus, MPRIS will not be available: {}",
+                dbus_type, e
+            );
+            return Box::new(future::empty());
+        }
+    };
 
     macro_rules! spotify_api_method {
         ([ $sp:ident, $device:ident $(, $m:ident: $t:ty)*] $f:expr) => {
@@ -154,14 +308,22 @@ fn create_dbus_server(
         }};
     }
 
-    c.register_name(
-        "org.mpris.MediaPlayer2.spotifyd",
-        NameFlag::ReplaceExisting as u32,
-    )
-    .unwrap();
+    if let Err(e) = c.register_name(bus_name, NameFlag::ReplaceExisting as u32) {
+        error!(
+            "The D-Bus {} bus policy refused the name {}, MPRIS will not be available: {}",
+            dbus_type, bus_name, e
+        );
+        return Box::new(future::empty());
+    }
 
     let spirc_quit = spirc.clone();
     let spirc_play_pause = spirc.clone();
+    let MprisIdentity {
+        identity,
+        desktop_entry,
+    } = identity;
+
+    let release_date = RefCell::new(ReleaseDate::default());
 
     let f = AFactory::new_afn::<()>();
     let tree = f.tree(ATree::new()).add(
@@ -345,6 +507,14 @@ fn create_dbus_server(
                                                     .map(|a| MessageItem::Str(a.name.to_string()))
                                                     .collect::<Vec<_>>(), Signature::new("as").unwrap()
                                             ).unwrap())) as Box<RefArg>));
+                                        let artists = track.artists
+                                            .iter()
+                                            .map(|a| a.name.as_str())
+                                            .collect::<Vec<_>>();
+                                        m.insert("spotifyd:artist".to_string(), Variant(Box::new(
+                                            MessageItem::Str(
+                                                artist_separator.join(&artists)
+                                            )) as Box<RefArg>));
                                         m.insert("xesam:albumArtist".to_string(), Variant(Box::new(
                                             MessageItem::Array(MessageItemArray::new(
                                                 track.album.artists
@@ -356,14 +526,19 @@ fn create_dbus_server(
                                             MessageItem::Double(
                                                 f64::from(track.popularity) / 100.0
                                             )) as Box<RefArg>));
-                                        m.insert("xesam:trackNumber".to_string(), Variant(Box::new(
-                                            MessageItem::UInt32(
-                                                track.track_number
-                                            )) as Box<RefArg>));
-                                        m.insert("xesam:discNumber".to_string(), Variant(Box::new(
-                                            MessageItem::Int32(
-                                                track.disc_number
-                                            )) as Box<RefArg>));
+                                        // Only the full album has the release date.
+                                        let mut release_date = release_date.borrow_mut();
+                                        let album_id = &track.album.id;
+                                        let date = release_date.get(album_id, || {
+                                            sp.album(album_id).ok().map(|album| album.release_date)
+                                        });
+                                        for (key, value) in release_metadata(
+                                            track.track_number,
+                                            track.disc_number,
+                                            date,
+                                        ) {
+                                            m.insert(key.to_string(), Variant(Box::new(value) as Box<RefArg>));
+                                        }
                                         m.insert("xesam:url".to_string(), Variant(Box::new(
                                             MessageItem::Str(
                                                 track.external_urls
@@ -450,8 +625,16 @@ fn create_dbus_server(
                     .add_p(
                         f.property::<String, _>("Identity", ())
                             .access(Access::Read)
-                            .on_get(|i, _| {
-                                i.append("Spotifyd".to_string());
+                            .on_get(move |i, _| {
+                                i.append(identity.clone());
+                                Ok(())
+                            }),
+                    )
+                    .add_p(
+                        f.property::<String, _>("DesktopEntry", ())
+                            .access(Access::Read)
+                            .on_get(move |i, _| {
+                                i.append(desktop_entry.clone());
                                 Ok(())
                             }),
                     )
@@ -463,10 +646,33 @@ fn create_dbus_server(
                                 Ok(())
                             }),
                     ),
+            )
+            .add(
+                f.interface(AUDIO_INTERFACE, ())
+                    .add_p(
+                        f.property::<u32, _>("Bitrate", ())
+                            .access(Access::Read)
+                            .on_get(move |i, _| {
+                                i.append(bitrate_kbps(bitrate));
+                                Ok(())
+                            }),
+                    )
+                    .add_p(
+                        f.property::<String, _>("SampleFormat", ())
+                            .access(Access::Read)
+                            .on_get(|i, _| {
+                                i.append(SAMPLE_FORMAT.to_string());
+                                Ok(())
+                            }),
+                    ),
             ),
     );
 
     tree.set_registered(&c, true).unwrap();
+    // The server is set up again on every new token, each time on a new connection.
+    if c.send(audio_properties_changed(bitrate)).is_err() {
+        warn!("Couldn't announce the bitrate and sample format over D-Bus.");
+    }
     let aconn = AConnection::new(c.clone(), handle).unwrap();
     let server = ATreeServer::new(c.clone(), Box::new(tree), aconn.messages().unwrap());
     Box::new(server.for_each(|m| {
@@ -474,3 +680,82 @@ fn create_dbus_server(
         Ok(())
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrate_property() {
+        assert_eq!(bitrate_kbps(Bitrate::Bitrate96), 96);
+        assert_eq!(bitrate_kbps(Bitrate::Bitrate160), 160);
+        assert_eq!(bitrate_kbps(Bitrate::Bitrate320), 320);
+    }
+
+    #[test]
+    fn audio_properties_signal() {
+        let message = audio_properties_changed(Bitrate::Bitrate320);
+        assert_eq!(&*message.path().unwrap(), "/org/mpris/MediaPlayer2");
+        assert_eq!(
+            &*message.interface().unwrap(),
+            "org.freedesktop.DBus.Properties"
+        );
+        assert_eq!(&*message.member().unwrap(), "PropertiesChanged");
+
+        let signal = PropertiesPropertiesChanged::from_message(&message).unwrap();
+        assert_eq!(signal.interface_name, AUDIO_INTERFACE);
+        assert!(signal.invalidated_properties.is_empty());
+        let changed = &signal.changed_properties;
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed["Bitrate"].0.as_u64(), Some(320));
+        assert_eq!(changed["SampleFormat"].0.as_str(), Some("S16"));
+    }
+
+    #[test]
+    fn release_fields() {
+        assert_eq!(
+            release_metadata(7, 2, Some("1981-12-15")),
+            vec![
+                ("xesam:trackNumber", MessageItem::Int32(7)),
+                ("xesam:discNumber", MessageItem::Int32(2)),
+                (
+                    "xesam:contentCreated",
+                    MessageItem::Str("1981-12-15".to_string())
+                ),
+            ]
+        );
+        assert_eq!(
+            release_metadata(1, 1, Some("1981"))[2],
+            ("xesam:contentCreated", MessageItem::Str("1981".to_string()))
+        );
+
+        // What Spotify doesn't know is left out.
+        assert_eq!(release_metadata(0, 0, None), vec![]);
+        for date in &["", "0000", "81", "1981-1", "1981-12-15T00:00", "unknown"] {
+            assert_eq!(release_metadata(3, 0, Some(date)).len(), 1, "{:?}", date);
+        }
+        assert!(is_release_date("1981-12"));
+    }
+
+    #[test]
+    fn release_date_per_album() {
+        let mut release_date = ReleaseDate::default();
+        let mut lookups = 0;
+        let mut get = |album_id, date: Option<&str>| {
+            release_date
+                .get(album_id, || {
+                    lookups += 1;
+                    date.map(str::to_string)
+                })
+                .map(str::to_string)
+        };
+        assert_eq!(get("a", Some("1981")), Some("1981".to_string()));
+        // The next track of the same album doesn't look it up again.
+        assert_eq!(get("a", Some("2000")), Some("1981".to_string()));
+        assert_eq!(get("b", None), None);
+        assert_eq!(get("b", Some("2000")), None);
+        assert_eq!(get("a", Some("1981")), Some("1981".to_string()));
+        drop(get);
+        assert_eq!(lookups, 3);
+    }
+}
@@ -0,0 +1,232 @@
+use librespot::playback::audio_backend::Sink;
+use log::{debug, error};
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Keeps track of when a stopped device is due to be closed.
+#[derive(Debug)]
+struct CloseTimer {
+    delay: Duration,
+    close_at: Option<Instant>,
+}
+
+impl CloseTimer {
+    fn new(delay: Duration) -> CloseTimer {
+        CloseTimer {
+            delay,
+            close_at: None,
+        }
+    }
+
+    /// Playback stopped at `now`. Returns whether the device is to be closed right away.
+    fn stopped(&mut self, now: Instant) -> bool {
+        if self.delay == Duration::from_secs(0) {
+            return true;
+        }
+        self.close_at = Some(now + self.delay);
+        false
+    }
+
+    /// Playback started. Returns whether the device has to be opened, or is still open
+    /// because its close was pending.
+    fn started(&mut self) -> bool {
+        self.close_at.take().is_none()
+    }
+
+    /// Returns whether the pending close is due at `now`, which clears it.
+    fn due(&mut self, now: Instant) -> bool {
+        match self.close_at {
+            Some(close_at) if close_at <= now => {
+                self.close_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How long to wait at `now` for the pending close, if there is one.
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.close_at.map(|close_at| {
+            if close_at > now {
+                close_at - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+}
+
+enum Command {
+    Start,
+    Stop,
+    Write(Vec<i16>),
+}
+
+/// Opens the sink with `open_sink`, closing it only once it has been stopped for
+/// `delay`. Starting playback again within `delay` keeps using the open device.
+/// `open_sink` runs on the thread that writes to the sink, so it can set up that thread.
+pub(crate) fn open<F>(open_sink: F, delay: Duration) -> Box<dyn Sink>
+where
+    F: FnOnce() -> Box<dyn Sink> + Send + 'static,
+{
+    if delay == Duration::from_secs(0) {
+        return open_sink();
+    }
+    let (commands, receiver) = mpsc::channel();
+    let (results, result) = mpsc::channel();
+    // The sink has to be stopped while nobody is writing to it, so it's owned by a thread
+    // of its own.
+    thread::spawn(move || {
+        let sink = open_sink();
+        run(sink, CloseTimer::new(delay), receiver, results);
+    });
+    Box::new(DelayedCloseSink { commands, result })
+}
+
+fn run(
+    mut sink: Box<dyn Sink>,
+    mut timer: CloseTimer,
+    commands: Receiver<Command>,
+    results: Sender<io::Result<()>>,
+) {
+    loop {
+        let command = match timer.timeout(Instant::now()) {
+            Some(timeout) => commands.recv_timeout(timeout),
+            None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let result = match command {
+            Ok(Command::Start) if timer.started() => sink.start(),
+            Ok(Command::Start) => {
+                debug!("Playback resumed, keeping the audio device open.");
+                Ok(())
+            }
+            Ok(Command::Stop) if timer.stopped(Instant::now()) => sink.stop(),
+            Ok(Command::Stop) => Ok(()),
+            Ok(Command::Write(data)) => sink.write(&data),
+            Err(RecvTimeoutError::Timeout) => {
+                if timer.due(Instant::now()) {
+                    debug!("Closing the audio device.");
+                    if let Err(e) = sink.stop() {
+                        error!("Couldn't close the audio device: {}", e);
+                    }
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                if timer.close_at.is_some() {
+                    let _ = sink.stop();
+                }
+                return;
+            }
+        };
+        if results.send(result).is_err() {
+            return;
+        }
+    }
+}
+
+/// Hands the samples to the thread owning the sink.
+struct DelayedCloseSink {
+    commands: Sender<Command>,
+    result: Receiver<io::Result<()>>,
+}
+
+impl DelayedCloseSink {
+    fn send(&self, command: Command) -> io::Result<()> {
+        let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "the audio device is gone");
+        self.commands.send(command).map_err(|_| gone())?;
+        self.result.recv().map_err(|_| gone())?
+    }
+}
+
+impl Sink for DelayedCloseSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.send(Command::Start)
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.send(Command::Stop)
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.send(Command::Write(data.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockSink;
+
+    #[test]
+    fn delayed_close() {
+        let start = Instant::now();
+        let delay = Duration::from_secs(10);
+        let mut timer = CloseTimer::new(delay);
+        assert!(timer.started());
+        assert_eq!(timer.timeout(start), None);
+
+        assert!(!timer.stopped(start));
+        assert_eq!(timer.timeout(start), Some(delay));
+        assert!(!timer.due(start + Duration::from_secs(9)));
+        assert_eq!(
+            timer.timeout(start + Duration::from_secs(9)),
+            Some(Duration::from_secs(1))
+        );
+        assert!(timer.due(start + delay));
+        assert_eq!(timer.timeout(start + delay), None);
+        assert!(!timer.due(start + 2 * delay));
+
+        // Once closed, starting again opens the device.
+        assert!(timer.started());
+    }
+
+    #[test]
+    fn resume_cancels_close() {
+        let start = Instant::now();
+        let mut timer = CloseTimer::new(Duration::from_secs(10));
+        assert!(timer.started());
+        assert!(!timer.stopped(start));
+        // The device is still open.
+        assert!(!timer.started());
+        assert_eq!(timer.timeout(start), None);
+        assert!(!timer.due(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn opened_on_the_writing_thread() {
+        for &delay in &[Duration::from_secs(10), Duration::from_secs(0)] {
+            let (opened_on, opened) = mpsc::channel();
+            let (written_on, written) = mpsc::channel();
+            let mut sink = open(
+                move || {
+                    opened_on.send(thread::current().id()).unwrap();
+                    Box::new(MockSink::reporting_threads(written_on)) as Box<dyn Sink>
+                },
+                delay,
+            );
+            sink.start().unwrap();
+            sink.write(&[0, 0]).unwrap();
+            let opened = opened.recv().unwrap();
+            assert_eq!(written.recv().unwrap(), opened);
+            // With a delay, the sink gets a thread of its own.
+            assert_eq!(
+                opened == thread::current().id(),
+                delay == Duration::from_secs(0)
+            );
+        }
+    }
+
+    #[test]
+    fn zero_delay_closes_immediately() {
+        let mut timer = CloseTimer::new(Duration::from_secs(0));
+        assert!(timer.started());
+        assert!(timer.stopped(Instant::now()));
+        assert_eq!(timer.timeout(Instant::now()), None);
+        assert!(timer.started());
+    }
+}
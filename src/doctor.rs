@@ -0,0 +1,333 @@
+use getopts::Matches;
+use std::{
+    fmt, fs,
+    net::{TcpStream, ToSocketAddrs},
+    panic,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::{backends, cache_dir, config, config_format};
+
+/// The access point librespot falls back to if it can't look one up.
+const ACCESS_POINT: &str = "ap.spotify.com:443";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Pass,
+    Fail,
+    /// The check doesn't apply, e.g. because an earlier one failed.
+    Skip,
+}
+
+/// The outcome of one check, with details on what was found.
+#[derive(Debug, PartialEq)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    details: String,
+}
+
+impl Check {
+    fn pass<S: Into<String>>(name: &'static str, details: S) -> Check {
+        Check {
+            name,
+            status: Status::Pass,
+            details: details.into(),
+        }
+    }
+
+    fn fail<S: Into<String>>(name: &'static str, details: S) -> Check {
+        Check {
+            name,
+            status: Status::Fail,
+            details: details.into(),
+        }
+    }
+
+    fn skip<S: Into<String>>(name: &'static str, details: S) -> Check {
+        Check {
+            name,
+            status: Status::Skip,
+            details: details.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Pass => "PASS",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        };
+        write!(f, "[{}] {}: {}", status, self.name, self.details)
+    }
+}
+
+/// Prints each check as soon as it's done, so that a check that hangs or takes the
+/// process down doesn't hide the ones before it.
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn add(&mut self, check: Check) {
+        println!("{}", check);
+        self.checks.push(check);
+    }
+
+    fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .count()
+    }
+
+    fn summary(&self) -> String {
+        match self.failed() {
+            0 => format!("All {} checks passed.", self.checks.len()),
+            failed => format!("{} of {} checks failed.", failed, self.checks.len()),
+        }
+    }
+}
+
+fn check_config_file(path: Option<&PathBuf>) -> Check {
+    const NAME: &str = "Config file";
+    match path {
+        None => Check::skip(NAME, "none found, using the defaults"),
+        Some(path) if path.as_path() == Path::new("-") => Check::skip(NAME, "read from stdin"),
+        Some(path) => {
+            let parsed = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| {
+                    let format = config_format::detect(path, &contents);
+                    format.parse(&contents).map(|_| format)
+                });
+            match parsed {
+                Ok(format) => Check::pass(NAME, format!("{} ({})", path.display(), format)),
+                Err(e) => Check::fail(NAME, format!("couldn't read {}: {}", path.display(), e)),
+            }
+        }
+    }
+}
+
+fn check_credentials(config: &config::SpotifydConfig) -> Check {
+    const NAME: &str = "Credentials";
+    let cached = config
+        .cache_path
+        .as_ref()
+        .map_or(false, |path| cache_dir::has_credentials(path));
+    match (config.username.as_ref(), config.password.is_some()) {
+        (Some(username), _) if username.is_empty() || username.trim() != username => {
+            Check::fail(NAME, format!("the username {:?} looks mistyped", username))
+        }
+        (Some(username), true) => Check::pass(NAME, format!("username {}", username)),
+        (Some(username), false) if config.use_keyring => Check::pass(
+            NAME,
+            format!("username {}, password from the keyring", username),
+        ),
+        (Some(_), false) if cached => Check::pass(NAME, "cached"),
+        (Some(_), false) => Check::fail(NAME, "a username, but no password"),
+        (None, _) if cached => Check::pass(NAME, "cached"),
+        (None, _) => Check::skip(NAME, "none, log in through Spotify Connect"),
+    }
+}
+
+/// Opens and closes the audio device, without playing anything. Backends that can't
+/// open the device may panic, which is caught here.
+fn check_backend(config: &config::SpotifydConfig) -> Check {
+    const NAME: &str = "Audio backend";
+    let backend = backends::find_backend(config.backend.as_ref().map(String::as_ref));
+    let device = config.audio_device.clone();
+    let opened = thread::spawn(move || {
+        let mut sink = backend.open(device);
+        sink.start()?;
+        sink.stop()
+    })
+    .join();
+    let name = config.backend.as_ref().map_or("default", String::as_str);
+    match opened {
+        Ok(Ok(())) => Check::pass(NAME, format!("{} opened the device", name)),
+        Ok(Err(e)) => Check::fail(NAME, format!("{} couldn't open the device: {}", name, e)),
+        Err(_) => Check::fail(NAME, format!("{} couldn't open the device", name)),
+    }
+}
+
+#[cfg(feature = "dbus_mpris")]
+fn check_dbus(config: &config::SpotifydConfig) -> Check {
+    const NAME: &str = "D-Bus";
+    if config.use_mpris == config::UseMpris::No {
+        return Check::skip(NAME, "MPRIS is disabled");
+    }
+    match crate::dbus_mpris::check_bus(config.dbus_type, &config.mpris_bus_name()) {
+        Ok(()) => Check::pass(NAME, format!("the {} bus is available", config.dbus_type)),
+        Err(e) => Check::fail(
+            NAME,
+            format!("the {} bus is unavailable: {}", config.dbus_type, e),
+        ),
+    }
+}
+
+#[cfg(not(feature = "dbus_mpris"))]
+fn check_dbus(_: &config::SpotifydConfig) -> Check {
+    Check::skip("D-Bus", "built without MPRIS support")
+}
+
+fn check_network() -> Check {
+    const NAME: &str = "Network";
+    let addr = match ACCESS_POINT.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => return Check::fail(NAME, format!("{} has no address", ACCESS_POINT)),
+        Err(e) => return Check::fail(NAME, format!("couldn't resolve {}: {}", ACCESS_POINT, e)),
+    };
+    match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+        Ok(_) => Check::pass(NAME, format!("reached {}", ACCESS_POINT)),
+        Err(e) => Check::fail(NAME, format!("couldn't reach {}: {}", ACCESS_POINT, e)),
+    }
+}
+
+fn check_cache(config: &config::SpotifydConfig) -> Check {
+    const NAME: &str = "Cache directory";
+    let path = match config.cache_path {
+        Some(ref path) => path,
+        None => return Check::skip(NAME, "no cache_path configured"),
+    };
+    if !path.exists() {
+        return Check::pass(NAME, format!("{} will be created", path.display()));
+    }
+    let probe = path.join(".spotifyd-doctor");
+    match fs::write(&probe, b"").and_then(|()| fs::remove_file(&probe)) {
+        Ok(()) => Check::pass(NAME, format!("{} is writable", path.display())),
+        Err(e) => Check::fail(NAME, format!("can't write to {}: {}", path.display(), e)),
+    }
+}
+
+/// Checks the setup and prints a report, without logging in or playing anything.
+/// Returns the exit code, which is non-zero if any check failed.
+pub(crate) fn run(config_paths: &[PathBuf], matches: &Matches) -> i32 {
+    let mut report = Report::default();
+    if config_paths.is_empty() {
+        report.add(check_config_file(None));
+    }
+    for path in config_paths {
+        report.add(check_config_file(Some(path)));
+    }
+    let config = match config::get_config(config_paths, matches) {
+        Ok(config) => {
+            report.add(Check::pass("Configuration", "valid"));
+            Some(config)
+        }
+        Err(e) => {
+            report.add(Check::fail("Configuration", e.to_string()));
+            None
+        }
+    };
+    match config {
+        Some(config) => {
+            report.add(check_credentials(&config));
+            report.add(check_dbus(&config));
+            report.add(check_network());
+            report.add(check_cache(&config));
+            // Keep the sink's own error output off the report.
+            let hook = panic::take_hook();
+            panic::set_hook(Box::new(|_| ()));
+            report.add(check_backend(&config));
+            panic::set_hook(hook);
+        }
+        None => {
+            for &name in &[
+                "Credentials",
+                "D-Bus",
+                "Network",
+                "Cache directory",
+                "Audio backend",
+            ] {
+                report.add(Check::skip(name, "the configuration is invalid"));
+            }
+        }
+    }
+    println!("{}", report.summary());
+    if report.failed() == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    #[test]
+    fn check_lines() {
+        assert_eq!(
+            Check::pass("Config file", "/etc/spotifyd.conf").to_string(),
+            "[PASS] Config file: /etc/spotifyd.conf"
+        );
+        assert_eq!(
+            Check::fail("Network", "couldn't reach ap.spotify.com:443").to_string(),
+            "[FAIL] Network: couldn't reach ap.spotify.com:443"
+        );
+        assert_eq!(
+            Check::skip("D-Bus", "MPRIS is disabled").to_string(),
+            "[SKIP] D-Bus: MPRIS is disabled"
+        );
+    }
+
+    #[test]
+    fn mixed_report() {
+        let mut report = Report::default();
+        report.add(Check::pass("Config file", "/etc/spotifyd.conf"));
+        report.add(Check::fail("Network", "unreachable"));
+        report.add(Check::skip("D-Bus", "MPRIS is disabled"));
+        report.add(Check::fail("Cache directory", "read-only"));
+        assert_eq!(report.failed(), 2);
+        assert_eq!(report.summary(), "2 of 4 checks failed.");
+
+        let mut report = Report::default();
+        report.add(Check::pass("Config file", "/etc/spotifyd.conf"));
+        report.add(Check::skip("D-Bus", "MPRIS is disabled"));
+        assert_eq!(report.summary(), "All 2 checks passed.");
+    }
+
+    #[test]
+    fn config_file_formats() {
+        let dir = temp_path("doctor");
+        fs::create_dir_all(&dir).unwrap();
+        let toml = dir.join("spotifyd.toml");
+        fs::write(&toml, "[global]\nbackend = \"alsa\"\n").unwrap();
+        let check = check_config_file(Some(&toml));
+        assert_eq!(check.status, Status::Pass);
+        assert!(check.details.ends_with("(TOML)"), "{}", check.details);
+
+        let ini = dir.join("spotifyd.conf");
+        fs::write(&ini, "[global]\nbackend = alsa\n").unwrap();
+        assert!(check_config_file(Some(&ini)).details.ends_with("(INI)"));
+
+        fs::write(&toml, "[global]\nbackend = \n").unwrap();
+        assert_eq!(check_config_file(Some(&toml)).status, Status::Fail);
+        let missing = dir.join("missing.conf");
+        assert_eq!(check_config_file(Some(&missing)).status, Status::Fail);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn credentials_form() {
+        let mut config = config::SpotifydConfig::default();
+        assert_eq!(check_credentials(&config).status, Status::Skip);
+        config.username = Some("JaneDoe".to_string());
+        assert_eq!(check_credentials(&config).status, Status::Fail);
+        config.password = Some("hunter2".to_string());
+        assert_eq!(
+            check_credentials(&config),
+            Check::pass("Credentials", "username JaneDoe")
+        );
+        config.username = Some("JaneDoe\n".to_string());
+        assert_eq!(check_credentials(&config).status, Status::Fail);
+    }
+}
@@ -1,4 +1,8 @@
-use std::fmt::{self, Display};
+use crate::config::DBusType;
+use std::{
+    fmt::{self, Display},
+    path::PathBuf,
+};
 
 /// This crate's error type.
 #[derive(Debug)]
@@ -64,6 +68,54 @@ pub(crate) enum ErrorKind {
         shell: String,
     },
     NormalisationPregainInvalid,
+    ConfigValueInvalid {
+        key: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    ConfigStdinInvalid(String),
+    ConfigFileInvalid {
+        path: PathBuf,
+        msg: String,
+    },
+    ConfigConflict {
+        key: &'static str,
+        other: &'static str,
+    },
+    ConfigMissing {
+        key: &'static str,
+        needed_by: &'static str,
+    },
+    PasswordFile {
+        path: PathBuf,
+        msg: String,
+    },
+    PasswordMissing,
+    ConfigStdinIsTerminal,
+    CacheDir {
+        path: PathBuf,
+        msg: String,
+    },
+    InstanceRunning {
+        pid: u32,
+    },
+    InstanceTakeover {
+        pid: u32,
+    },
+    InstanceLock {
+        path: PathBuf,
+        msg: String,
+    },
+    MprisInstanceInvalid(String),
+    MprisUnavailable {
+        bus: DBusType,
+        reason: String,
+    },
+    BackendUnavailable {
+        backend: String,
+        feature: Option<&'static str>,
+        available: Vec<String>,
+    },
 }
 
 impl Display for ErrorKind {
@@ -86,6 +138,87 @@ impl Display for ErrorKind {
                 f,
                 "normalisation-pregain must be a valid 32-bit floating point number."
             ),
+            ErrorKind::ConfigValueInvalid {
+                key,
+                value,
+                expected,
+            } => write!(f, "{} must be {}, got {:?}.", key, expected, value),
+            ErrorKind::ConfigStdinInvalid(e) => {
+                write!(f, "Couldn't read the configuration from stdin: {}", e)
+            }
+            ErrorKind::ConfigFileInvalid { path, msg } => {
+                write!(
+                    f,
+                    "Couldn't read the configuration file {:?}: {}",
+                    path, msg
+                )
+            }
+            ErrorKind::ConfigConflict { key, other } => {
+                write!(
+                    f,
+                    "{} can't be combined with {}, use only one of them.",
+                    key, other
+                )
+            }
+            ErrorKind::ConfigMissing { key, needed_by } => {
+                write!(f, "{} has to be set for {}.", key, needed_by)
+            }
+            ErrorKind::PasswordFile { path, msg } => {
+                write!(f, "Couldn't read the password file {:?}: {}", path, msg)
+            }
+            ErrorKind::PasswordMissing => write!(
+                f,
+                "A username is set, but no password was found for it. Set password, \
+                 password_cmd or password_file, or use the keyring."
+            ),
+            ErrorKind::ConfigStdinIsTerminal => write!(
+                f,
+                "--config - reads the configuration from stdin, but stdin is a terminal. \
+                 Pipe the configuration in instead."
+            ),
+            ErrorKind::CacheDir { path, msg } => {
+                write!(f, "Couldn't create the cache directory {:?}: {}", path, msg)
+            }
+            ErrorKind::InstanceRunning { pid } => write!(
+                f,
+                "Another spotifyd (PID {}) is already running with this device name. Stop it, \
+                 or set instance_policy to takeover or coexist.",
+                pid
+            ),
+            ErrorKind::InstanceTakeover { pid } => write!(
+                f,
+                "The spotifyd running with this device name (PID {}) didn't quit when asked to.",
+                pid
+            ),
+            ErrorKind::InstanceLock { path, msg } => {
+                write!(f, "Couldn't write the instance lock {:?}: {}", path, msg)
+            }
+            ErrorKind::MprisInstanceInvalid(suffix) => write!(
+                f,
+                "mpris_instance must be \"pid\" or a valid D-Bus bus name suffix, got {:?}.",
+                suffix
+            ),
+            ErrorKind::MprisUnavailable { bus, reason } => write!(
+                f,
+                "use_mpris is set to required, but the D-Bus {} bus is unavailable: {}",
+                bus, reason
+            ),
+            ErrorKind::BackendUnavailable {
+                backend,
+                feature,
+                available,
+            } => {
+                match feature {
+                    Some(feature) => write!(
+                        f,
+                        "The {:?} backend is not compiled into this build, rebuild with the {:?} \
+                         feature to use it.",
+                        backend, feature
+                    )?,
+                    None => write!(f, "Unknown backend {:?}.", backend)?,
+                }
+                write!(f, " Available backends: {}.", available.join(", "))
+            }
         }
     }
 }
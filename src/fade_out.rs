@@ -0,0 +1,152 @@
+use librespot::playback::audio_backend::Sink;
+use std::{collections::VecDeque, io, time::Duration};
+
+use crate::audio_filter::{CHANNELS, SAMPLE_RATE};
+
+/// The last stretch of the stream, held back from the sink so that it can still be
+/// faded out when playback stops.
+struct Tail {
+    frames: usize,
+    samples: VecDeque<i16>,
+}
+
+impl Tail {
+    fn new(duration: Duration) -> Tail {
+        let frames = (duration.as_millis() as f64 * SAMPLE_RATE / 1000.0).round() as usize;
+        Tail {
+            frames,
+            samples: VecDeque::with_capacity(frames * CHANNELS),
+        }
+    }
+
+    /// Adds `data` to the tail, returning the samples that no longer fit into it.
+    fn push(&mut self, data: &[i16]) -> Vec<i16> {
+        self.samples.extend(data);
+        let excess = self.samples.len().saturating_sub(self.frames * CHANNELS);
+        self.samples.drain(..excess).collect()
+    }
+
+    /// Empties the tail, ramping it down to silence by its last frame.
+    fn fade(&mut self) -> Vec<i16> {
+        let frames = self.samples.len() / CHANNELS;
+        self.samples
+            .drain(..)
+            .enumerate()
+            .map(|(i, sample)| {
+                let gain = (frames - i / CHANNELS - 1) as f32 / frames as f32;
+                (f32::from(sample) * gain).round() as i16
+            })
+            .collect()
+    }
+}
+
+/// Fades the audio out before the sink is stopped, so that pausing doesn't click.
+struct FadeOutSink {
+    sink: Box<dyn Sink>,
+    tail: Tail,
+}
+
+impl Sink for FadeOutSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let faded = self.tail.fade();
+        if !faded.is_empty() {
+            self.sink.write(&faded)?;
+        }
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let ready = self.tail.push(data);
+        if ready.is_empty() {
+            Ok(())
+        } else {
+            self.sink.write(&ready)
+        }
+    }
+}
+
+/// Fades `sink` out over `duration` when playback stops. As the fade runs over audio
+/// that was held back, everything reaches the device that much later.
+pub(crate) fn wrap(sink: Box<dyn Sink>, duration: Option<Duration>) -> Box<dyn Sink> {
+    match duration {
+        Some(duration) => Box::new(FadeOutSink {
+            sink,
+            tail: Tail::new(duration),
+        }),
+        None => sink,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{Call, MockSink};
+
+    #[test]
+    fn fade_ramp() {
+        // 1ms is 44 frames.
+        let mut tail = Tail::new(Duration::from_millis(1));
+        assert!(tail.push(&[10000; 30 * CHANNELS]).is_empty());
+        assert_eq!(
+            tail.push(&[10000; 30 * CHANNELS]),
+            vec![10000; 16 * CHANNELS]
+        );
+
+        let faded = tail.fade();
+        assert_eq!(faded.len(), 44 * CHANNELS);
+        assert_eq!(faded[0], 9773);
+        assert_eq!(faded[0], faded[1]);
+        assert!(faded.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert_eq!(faded[43 * CHANNELS..], [0, 0]);
+        assert!(tail.fade().is_empty());
+    }
+
+    #[test]
+    fn stop_writes_faded_tail() {
+        let recorder = MockSink::default();
+        let mut sink = wrap(Box::new(recorder.clone()), Some(Duration::from_millis(1)));
+        sink.start().unwrap();
+        sink.write(&[10000; 100 * CHANNELS]).unwrap();
+        assert_eq!(recorder.written().len(), 56 * CHANNELS);
+        sink.stop().unwrap();
+
+        let calls = recorder.calls();
+        assert_eq!(calls.first(), Some(&Call::Start));
+        assert_eq!(calls.last(), Some(&Call::Stop));
+        drop(calls);
+        let written = recorder.written();
+        assert_eq!(written.len(), 100 * CHANNELS);
+        assert_eq!(written[55 * CHANNELS], 10000);
+        assert_eq!(written[99 * CHANNELS], 0);
+    }
+
+    #[test]
+    fn resume_after_fade() {
+        let recorder = MockSink::default();
+        let mut sink = wrap(Box::new(recorder.clone()), Some(Duration::from_millis(1)));
+        sink.start().unwrap();
+        sink.write(&[10000; 20 * CHANNELS]).unwrap();
+        // Pausing this early fades what there is.
+        sink.stop().unwrap();
+        assert_eq!(recorder.written().len(), 20 * CHANNELS);
+
+        // Resuming starts over at full level, with nothing left of the fade.
+        sink.start().unwrap();
+        sink.write(&[10000; 100 * CHANNELS]).unwrap();
+        let written = recorder.written();
+        assert_eq!(written.len(), 76 * CHANNELS);
+        assert!(written[20 * CHANNELS..].iter().all(|&s| s == 10000));
+    }
+
+    #[test]
+    fn disabled() {
+        let recorder = MockSink::default();
+        let mut sink = wrap(Box::new(recorder.clone()), None);
+        sink.write(&[1, 2]).unwrap();
+        assert_eq!(recorder.written(), [1, 2]);
+    }
+}
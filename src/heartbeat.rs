@@ -0,0 +1,91 @@
+use futures::{Async, Stream};
+use log::{info, warn};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Interval};
+
+/// Logs a line at a fixed interval, so that unattended setups can tell from the logs
+/// that spotifyd is still alive.
+pub(crate) struct Heartbeat {
+    interval: Interval,
+    started: Instant,
+}
+
+impl Heartbeat {
+    /// Starts the heartbeat, or returns `None` if `interval` is zero.
+    pub(crate) fn new(interval: Duration, handle: &Handle) -> Option<Heartbeat> {
+        if interval == Duration::from_secs(0) {
+            return None;
+        }
+        match Interval::new(interval, handle) {
+            Ok(interval) => Some(Heartbeat {
+                interval,
+                started: Instant::now(),
+            }),
+            Err(e) => {
+                warn!("Couldn't start the heartbeat: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Logs a heartbeat if the interval has elapsed since the last one.
+    pub(crate) fn poll(&mut self, playing: bool) {
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            info!("{}", message(playing, self.started.elapsed()));
+        }
+    }
+}
+
+fn message(playing: bool, uptime: Duration) -> String {
+    format!(
+        "alive, state={}, uptime={}",
+        if playing { "play" } else { "pause" },
+        format_uptime(uptime)
+    )
+}
+
+/// Formats `uptime` in whole seconds, e.g. `1d2h0m5s`, leaving out leading zero units.
+pub(crate) fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = units
+        .iter()
+        .position(|&(value, _)| value > 0)
+        .unwrap_or(units.len() - 1);
+    units[first..]
+        .iter()
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn heartbeat_message() {
+        assert_eq!(
+            message(true, Duration::from_secs(42)),
+            "alive, state=play, uptime=42s"
+        );
+        assert_eq!(
+            message(false, Duration::from_millis(3_723_900)),
+            "alive, state=pause, uptime=1h2m3s"
+        );
+        assert_eq!(format_uptime(Duration::from_secs(0)), "0s");
+        assert_eq!(format_uptime(Duration::from_secs(86400 + 5)), "1d0h0m5s");
+    }
+
+    #[test]
+    fn zero_disables_heartbeat() {
+        let core = Core::new().unwrap();
+        assert!(Heartbeat::new(Duration::from_secs(0), &core.handle()).is_none());
+        assert!(Heartbeat::new(Duration::from_secs(60), &core.handle()).is_some());
+    }
+}
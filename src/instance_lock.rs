@@ -0,0 +1,357 @@
+use log::{info, warn};
+use std::{
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use crate::error::{Error, ErrorKind};
+
+/// How long to wait for the other instance to quit when taking over.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+const TAKEOVER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What to do when another instance already runs with the same device name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum InstancePolicy {
+    /// Refuse to start.
+    Refuse,
+    /// Ask the other instance to quit, and start once it has.
+    Takeover,
+    /// Start anyway, without a lock.
+    Coexist,
+}
+
+impl FromStr for InstancePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "refuse" => Ok(InstancePolicy::Refuse),
+            "takeover" => Ok(InstancePolicy::Takeover),
+            "coexist" => Ok(InstancePolicy::Coexist),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for InstancePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstancePolicy::Refuse => write!(f, "refuse"),
+            InstancePolicy::Takeover => write!(f, "takeover"),
+            InstancePolicy::Coexist => write!(f, "coexist"),
+        }
+    }
+}
+
+/// A lock file holding the PID of the instance using a device name. The file is removed
+/// when the lock is dropped; a file left behind by a crashed instance is taken over, as
+/// its PID doesn't belong to a running process anymore.
+#[derive(Debug)]
+pub(crate) struct InstanceLock {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Leave the file alone if another instance has taken over in the meantime.
+        if read_pid(&self.path) == Some(self.pid) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Where the lock file for `device_name` goes: the runtime directory if there is one,
+/// the temporary directory otherwise.
+pub(crate) fn lock_path(device_name: &str) -> PathBuf {
+    let name: String = device_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let name = format!("{}.lock", name);
+    xdg::BaseDirectories::with_prefix("spotifyd")
+        .ok()
+        .and_then(|dirs| dirs.place_runtime_file(&name).ok())
+        .unwrap_or_else(|| env::temp_dir().join(format!("spotifyd-{}", name)))
+}
+
+/// Locks `device_name` for this process, following `policy` if another instance holds
+/// the lock. Coexisting instances don't take a lock at all.
+pub(crate) fn acquire(
+    device_name: &str,
+    policy: InstancePolicy,
+) -> Result<Option<InstanceLock>, Error> {
+    if policy == InstancePolicy::Coexist {
+        return Ok(None);
+    }
+    acquire_with(
+        &lock_path(device_name),
+        policy,
+        process::id(),
+        is_spotifyd,
+        interrupt,
+    )
+    .map(Some)
+}
+
+/// How often creating the lock file is tried, removing the file in between if it's
+/// stale or its owner was taken over.
+const CREATE_ATTEMPTS: usize = 3;
+
+fn acquire_with<R, S>(
+    path: &Path,
+    policy: InstancePolicy,
+    pid: u32,
+    is_running: R,
+    mut stop: S,
+) -> Result<InstanceLock, Error>
+where
+    R: Fn(u32) -> bool,
+    S: FnMut(u32) -> io::Result<()>,
+{
+    let lock_error = |e: io::Error| ErrorKind::InstanceLock {
+        path: path.to_path_buf(),
+        msg: e.to_string(),
+    };
+    for attempt in 1..=CREATE_ATTEMPTS {
+        // Creating the file fails if it exists, so two instances starting at the same
+        // time can't both take the lock.
+        match create(path, pid) {
+            Ok(()) => {
+                return Ok(InstanceLock {
+                    path: path.to_path_buf(),
+                    pid,
+                })
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(lock_error(e).into()),
+        }
+        match read_pid(path) {
+            Some(other) if other != pid && is_running(other) => match policy {
+                InstancePolicy::Takeover => {
+                    info!("Asking the running instance (PID {}) to quit.", other);
+                    stop(other).map_err(lock_error)?;
+                    let mut waited = Duration::from_secs(0);
+                    while is_running(other) {
+                        if waited >= TAKEOVER_TIMEOUT {
+                            return Err(ErrorKind::InstanceTakeover { pid: other }.into());
+                        }
+                        thread::sleep(TAKEOVER_POLL_INTERVAL);
+                        waited += TAKEOVER_POLL_INTERVAL;
+                    }
+                }
+                _ => return Err(ErrorKind::InstanceRunning { pid: other }.into()),
+            },
+            Some(other) if other != pid => {
+                warn!("Removing the stale lock of PID {} at {:?}.", other, path);
+            }
+            Some(_) => (),
+            // Another instance may have just created the file and not written its PID
+            // yet, so give it a moment before treating the file as stale.
+            None if attempt == 1 => {
+                thread::sleep(TAKEOVER_POLL_INTERVAL);
+                continue;
+            }
+            None => warn!("Removing the unreadable lock at {:?}.", path),
+        }
+        match fs::remove_file(path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(lock_error(e).into()),
+        }
+    }
+    Err(lock_error(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "another instance keeps taking the lock",
+    ))
+    .into())
+}
+
+/// Creates the lock file with `pid` in it, failing if it already exists.
+fn create(path: &Path, pid: u32) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    writeln!(file, "{}", pid)
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists. EPERM means it does, but belongs
+    // to another user.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The name of the program `pid` runs, as /proc has it.
+fn program_name(pid: &str) -> Option<String> {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|comm| comm.trim_end().to_string())
+}
+
+/// Whether `pid` is a running spotifyd, i.e. runs the same program as this process. A
+/// PID left in a stale lock may have been reused by something else since. Without
+/// /proc the program can't be told, and any running process counts.
+fn is_spotifyd(pid: u32) -> bool {
+    match (program_name("self"), program_name(&pid.to_string())) {
+        (Some(own), Some(other)) => own == other,
+        (Some(_), None) => false,
+        (None, _) => is_running(pid),
+    }
+}
+
+/// Sends SIGINT, which makes spotifyd shut down cleanly. The PID is checked again right
+/// before, so that no other program is interrupted if spotifyd quit in the meantime.
+fn interrupt(pid: u32) -> io::Result<()> {
+    if !is_spotifyd(pid) {
+        return Ok(());
+    }
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGINT) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use std::cell::Cell;
+
+    const OTHER: u32 = 4242;
+
+    fn lock_file(name: &str) -> PathBuf {
+        temp_path(name).with_extension("lock")
+    }
+
+    fn never_stop(_: u32) -> io::Result<()> {
+        panic!("no other instance to stop");
+    }
+
+    #[test]
+    fn lock_acquisition() {
+        let path = lock_file("acquire");
+        let _ = fs::remove_file(&path);
+        let lock = acquire_with(&path, InstancePolicy::Refuse, 1, |_| false, never_stop).unwrap();
+        assert_eq!(read_pid(&path), Some(1));
+        drop(lock);
+        assert!(!path.exists());
+
+        // A lock left behind by an instance that's gone is taken over.
+        fs::write(&path, format!("{}\n", OTHER)).unwrap();
+        let lock = acquire_with(&path, InstancePolicy::Refuse, 1, |_| false, never_stop).unwrap();
+        assert_eq!(read_pid(&path), Some(1));
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refuse() {
+        let path = lock_file("refuse");
+        fs::write(&path, format!("{}\n", OTHER)).unwrap();
+        let error = acquire_with(
+            &path,
+            InstancePolicy::Refuse,
+            1,
+            |pid| pid == OTHER,
+            never_stop,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Another spotifyd (PID 4242) is already running with this device name. Stop it, \
+             or set instance_policy to takeover or coexist."
+        );
+        // The other instance's lock is left alone.
+        assert_eq!(read_pid(&path), Some(OTHER));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn takeover_signals_other_instance() {
+        let path = lock_file("takeover");
+        fs::write(&path, format!("{}\n", OTHER)).unwrap();
+        let running = Cell::new(true);
+        let signaled = Cell::new(None);
+        let lock = acquire_with(
+            &path,
+            InstancePolicy::Takeover,
+            1,
+            |pid| pid == OTHER && running.get(),
+            |pid| {
+                signaled.set(Some(pid));
+                running.set(false);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(signaled.get(), Some(OTHER));
+        assert_eq!(read_pid(&path), Some(1));
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn lock_file_in_use() {
+        // A lock another instance is still writing its PID to is left to it.
+        let path = lock_file("in-use");
+        fs::write(&path, "").unwrap();
+        let checked = Cell::new(0);
+        let lock = acquire_with(
+            &path,
+            InstancePolicy::Refuse,
+            1,
+            |_| {
+                checked.set(checked.get() + 1);
+                false
+            },
+            never_stop,
+        )
+        .unwrap();
+        assert_eq!(checked.get(), 0);
+        assert_eq!(read_pid(&path), Some(1));
+        drop(lock);
+
+        // The lock is only taken if creating the file succeeds.
+        fs::write(&path, format!("{}\n", OTHER)).unwrap();
+        assert!(create(&path, 1).is_err());
+        assert_eq!(read_pid(&path), Some(OTHER));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_spotifyd_is_interrupted() {
+        if program_name("self").is_none() {
+            return;
+        }
+        assert!(is_spotifyd(process::id()));
+        // A PID that was reused by another program isn't spotifyd's anymore.
+        let mut other = process::Command::new("sleep").arg("5").spawn().unwrap();
+        assert!(!is_spotifyd(other.id()));
+        interrupt(other.id()).unwrap();
+        assert!(other.try_wait().unwrap().is_none());
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+
+    #[test]
+    fn policies() {
+        for policy in &["refuse", "takeover", "coexist"] {
+            assert_eq!(
+                InstancePolicy::from_str(policy).unwrap().to_string(),
+                *policy
+            );
+        }
+        assert!(InstancePolicy::from_str("fight").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use futures::{future, Future, Stream};
+use log::{warn, Level, Log, Metadata, Record};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::{Handle, Interval};
+
+/// How many identical lines are let through per interval, 0 disables the limit. The
+/// logger is set up before the config is read, so these are filled in afterwards.
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+static INTERVAL_MS: AtomicUsize = AtomicUsize::new(0);
+
+/// Lets at most `limit` identical lines through per `interval`, from now on.
+pub(crate) fn set_rate_limit(limit: usize, interval: Duration) {
+    INTERVAL_MS.store(interval.as_millis() as usize, Ordering::Relaxed);
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Flushes the logger every `interval`, so that the summary of dropped lines is logged
+/// even if nothing else is.
+pub(crate) fn flush_every(
+    interval: Duration,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    match Interval::new(interval, handle) {
+        Ok(ticks) => Box::new(
+            ticks
+                .for_each(|()| {
+                    log::logger().flush();
+                    Ok(())
+                })
+                .map_err(|e| warn!("Stopped flushing the log: {}", e)),
+        ),
+        Err(e) => {
+            warn!("Couldn't start flushing the log: {}", e);
+            Box::new(future::ok(()))
+        }
+    }
+}
+
+/// Counts identical lines in a row.
+struct Limiter {
+    last: Option<(Level, String, String)>,
+    window_start: Instant,
+    count: usize,
+    suppressed: usize,
+}
+
+/// What to do with a line.
+#[derive(Debug, PartialEq)]
+struct Decision {
+    /// How many lines were dropped since the last one that was logged, to be reported
+    /// before this line.
+    summary: Option<usize>,
+    log: bool,
+}
+
+impl Limiter {
+    fn new() -> Self {
+        Limiter {
+            last: None,
+            window_start: Instant::now(),
+            count: 0,
+            suppressed: 0,
+        }
+    }
+
+    fn check(
+        &mut self,
+        line: (Level, &str, &str),
+        limit: usize,
+        interval: Duration,
+        now: Instant,
+    ) -> Decision {
+        let repeated = self
+            .last
+            .as_ref()
+            .map_or(false, |(level, target, message)| {
+                (*level, target.as_str(), message.as_str()) == line
+            });
+        if repeated && now.duration_since(self.window_start) < interval {
+            self.count += 1;
+            if self.count > limit {
+                self.suppressed += 1;
+                return Decision {
+                    summary: None,
+                    log: false,
+                };
+            }
+            return Decision {
+                summary: None,
+                log: true,
+            };
+        }
+        let summary = Some(self.suppressed).filter(|&suppressed| suppressed > 0);
+        let (level, target, message) = line;
+        self.last = Some((level, target.to_string(), message.to_string()));
+        self.window_start = now;
+        self.count = 1;
+        self.suppressed = 0;
+        Decision { summary, log: true }
+    }
+
+    /// Takes the number of lines dropped since the last one that was logged, along with the
+    /// level and target of that line, so that they're reported before the log is closed.
+    fn pending(&mut self) -> Option<(Level, String, usize)> {
+        let suppressed = self.suppressed;
+        self.suppressed = 0;
+        match self.last {
+            Some((level, ref target, _)) if suppressed > 0 => {
+                Some((level, target.clone(), suppressed))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The line that stands in for `suppressed` dropped ones.
+fn log_summary<L: Log>(inner: &L, level: Level, target: &str, suppressed: usize) {
+    inner.log(
+        &Record::builder()
+            .level(level)
+            .target(target)
+            .args(format_args!(
+                "... the previous message repeated {} more times",
+                suppressed
+            ))
+            .build(),
+    );
+}
+
+/// Wraps a logger, collapsing floods of identical lines into a single summary line.
+pub(crate) struct RateLimited<L> {
+    inner: L,
+    limiter: Mutex<Limiter>,
+}
+
+impl<L: Log> RateLimited<L> {
+    pub(crate) fn new(inner: L) -> Self {
+        RateLimited {
+            inner,
+            limiter: Mutex::new(Limiter::new()),
+        }
+    }
+}
+
+impl<L: Log> Log for RateLimited<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let limit = LIMIT.load(Ordering::Relaxed);
+        if limit == 0 {
+            return self.inner.log(record);
+        }
+        let interval = Duration::from_millis(INTERVAL_MS.load(Ordering::Relaxed) as u64);
+        let message = record.args().to_string();
+        let mut limiter = self.limiter.lock().unwrap();
+        let previous = limiter.last.clone();
+        let line = (record.level(), record.target(), message.as_str());
+        let decision = limiter.check(line, limit, interval, Instant::now());
+        if let (Some(suppressed), Some((level, target, _))) = (decision.summary, previous) {
+            log_summary(&self.inner, level, &target, suppressed);
+        }
+        if decision.log {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((level, target, suppressed)) = self.limiter.lock().unwrap().pending() {
+            log_summary(&self.inner, level, &target, suppressed);
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn collapse_repeated_lines() {
+        let mut limiter = Limiter::new();
+        let now = Instant::now();
+        let line = (Level::Error, "spotifyd", "Couldn't connect");
+        let logged = Decision {
+            summary: None,
+            log: true,
+        };
+        let dropped = Decision {
+            summary: None,
+            log: false,
+        };
+        assert_eq!(limiter.check(line, 2, SECOND, now), logged);
+        assert_eq!(limiter.check(line, 2, SECOND, now), logged);
+        assert_eq!(limiter.check(line, 2, SECOND, now), dropped);
+        assert_eq!(limiter.check(line, 2, SECOND, now), dropped);
+
+        // The next window reports what was dropped before letting the line through again.
+        assert_eq!(
+            limiter.check(line, 2, SECOND, now + SECOND),
+            Decision {
+                summary: Some(2),
+                log: true
+            }
+        );
+    }
+
+    #[test]
+    fn distinct_lines() {
+        let mut limiter = Limiter::new();
+        let now = Instant::now();
+        let first = (Level::Error, "spotifyd", "Couldn't connect");
+        let second = (Level::Info, "spotifyd", "Connected");
+        assert!(limiter.check(first, 1, SECOND, now).log);
+        assert!(!limiter.check(first, 1, SECOND, now).log);
+        assert_eq!(
+            limiter.check(second, 1, SECOND, now),
+            Decision {
+                summary: Some(1),
+                log: true
+            }
+        );
+        assert_eq!(
+            limiter.check(first, 1, SECOND, now),
+            Decision {
+                summary: None,
+                log: true
+            }
+        );
+
+        // The same message from another level or target isn't a repeat.
+        let warning = (Level::Warn, "spotifyd", "Couldn't connect");
+        assert!(limiter.check(warning, 1, SECOND, now).log);
+    }
+
+    /// Keeps the messages it's given.
+    struct Lines(Mutex<Vec<String>>);
+
+    impl Log for Lines {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn summary_on_flush() {
+        let mut limiter = Limiter::new();
+        let now = Instant::now();
+        let line = (Level::Error, "spotifyd", "Couldn't connect");
+        assert_eq!(limiter.pending(), None);
+        for _ in 0..4 {
+            limiter.check(line, 1, SECOND, now);
+        }
+        assert_eq!(
+            limiter.pending(),
+            Some((Level::Error, "spotifyd".to_string(), 3))
+        );
+        // Reported once, not again with the next line.
+        assert_eq!(limiter.pending(), None);
+        assert_eq!(limiter.check(line, 1, SECOND, now + SECOND).summary, None);
+
+        set_rate_limit(1, SECOND);
+        let logger = RateLimited::new(Lines(Mutex::new(Vec::new())));
+        for _ in 0..3 {
+            logger.log(
+                &Record::builder()
+                    .level(Level::Error)
+                    .args(format_args!("Couldn't connect"))
+                    .build(),
+            );
+        }
+        logger.flush();
+        assert_eq!(
+            *logger.inner.0.lock().unwrap(),
+            [
+                "Couldn't connect",
+                "... the previous message repeated 2 more times"
+            ]
+        );
+        set_rate_limit(0, SECOND);
+    }
+}
@@ -1,24 +1,92 @@
 #![cfg(unix)]
 
 use daemonize::Daemonize;
-use log::{error, info, LevelFilter};
-use std::{convert::From, error::Error, panic, path::PathBuf, process::exit};
+use log::{error, info, LevelFilter, Log, SetLoggerError};
+use std::{
+    convert::From,
+    io::{self, Write},
+    panic,
+    path::PathBuf,
+    process::exit,
+    str::FromStr,
+    time::Duration,
+};
 use tokio_core::reactor::Core;
 
 #[cfg(feature = "alsa_backend")]
 mod alsa_mixer;
+mod announce;
+mod audio_filter;
+mod autostart;
+mod backends;
+mod backoff;
+#[cfg(feature = "bluealsa_backend")]
+mod bluealsa_sink;
+mod cache_dir;
 mod cli;
 mod config;
+mod config_format;
+mod context_volume;
 #[cfg(feature = "dbus_mpris")]
 mod dbus_mpris;
+mod device_close;
+mod doctor;
 mod error;
+mod fade_out;
+mod heartbeat;
+mod instance_lock;
+mod log_limit;
 mod main_loop;
+mod memory_lock;
+mod migrate;
+mod once;
+mod presets;
+mod priority;
 mod process;
+mod quiet_hours;
+mod reconnect;
+mod resume;
+#[cfg(feature = "rodio_backend")]
+mod rodio_sink;
+mod schema;
+// The scrobbler is only started in builds with the scrobble feature.
+#[cfg_attr(not(feature = "scrobble"), allow(dead_code))]
+mod scrobble;
 mod setup;
+mod shutdown;
+mod stats;
+#[cfg(test)]
+mod test_utils;
+mod time_stretch;
+mod track_length;
 mod utils;
+mod verify;
+mod watchdog;
 #[macro_use]
 mod macros;
 
+/// Whether the `--no-daemon` log is colored. The terminal logger writes errors to stderr
+/// and everything else to stdout, so with `auto` both have to be terminals.
+fn log_colored(log_color: config::LogColor) -> bool {
+    let is_tty = |fd| unsafe { libc::isatty(fd) } == 1;
+    log_color.colored(is_tty(libc::STDOUT_FILENO) && is_tty(libc::STDERR_FILENO))
+}
+
+/// Logs plain lines, without any escape codes, to `out`.
+fn plain_logger<W: Write + Send + 'static>(
+    filter: LevelFilter,
+    out: W,
+) -> simplelog::WriteLogger<W> {
+    *simplelog::WriteLogger::new(filter, simplelog::Config::default(), out)
+}
+
+/// Installs `logger`, collapsing repeated lines into one as `log_rate_limit` asks.
+fn init_logger<L: Log + 'static>(logger: L, filter: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(log_limit::RateLimited::new(logger)))?;
+    log::set_max_level(filter);
+    Ok(())
+}
+
 fn main() {
     let opts = cli::command_line_argument_options();
     let args: Vec<String> = std::env::args().collect();
@@ -31,11 +99,21 @@ fn main() {
         }
     };
 
-    if matches.opt_present("backends") {
+    if matches.opt_present("backends") || matches.opt_present("list-backends") {
         cli::print_backends();
         exit(0);
     }
 
+    if matches.opt_present("config-schema") {
+        print!("{}", schema::json_schema());
+        exit(0);
+    }
+
+    if matches.opt_present("list-presets") {
+        cli::print_presets();
+        exit(0);
+    }
+
     if matches.opt_present("help") {
         println!("{}", cli::usage(&args[0], &opts));
         exit(0);
@@ -46,38 +124,82 @@ fn main() {
         exit(0)
     }
 
-    let is_daemon = !matches.opt_present("no-daemon");
+    let is_daemon = !matches.opt_present("no-daemon")
+        && !matches.opt_present("once")
+        && !matches.opt_present("verify-credentials")
+        && !matches.opt_present("doctor")
+        && !matches.opt_present("migrate-config")
+        && !matches.opt_present("forget-device")
+        && !matches.opt_present("list-cache");
 
+    let filter = if matches.opt_present("verbose") {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Info
+    };
     if is_daemon {
-        let filter = if matches.opt_present("verbose") {
-            LevelFilter::Trace
-        } else {
-            LevelFilter::Info
+        let formatter = syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_DAEMON,
+            hostname: None,
+            process: "Spotifyd".to_string(),
+            pid: std::process::id() as i32,
         };
-        syslog::init(syslog::Facility::LOG_DAEMON, filter, Some("Spotifyd"))
+        // Like syslog::init, fall back to a syslog daemon on localhost when there is no
+        // /dev/log, over TCP and then UDP.
+        let logger = syslog::unix(formatter.clone())
+            .or_else(|_| syslog::tcp(formatter.clone(), ("127.0.0.1", 601)))
+            .or_else(|_| syslog::udp(formatter, ("127.0.0.1", 0), ("127.0.0.1", 514)))
             .expect("Couldn't initialize logger");
+        init_logger(syslog::BasicLogger::new(logger), filter)
     } else {
-        let filter = if matches.opt_present("verbose") {
-            simplelog::LogLevelFilter::Trace
+        let log_color = match matches.opt_str("log_color") {
+            Some(color) => config::LogColor::from_str(&color).unwrap_or_else(|_| {
+                println!(
+                    "Error: --log_color must be auto, always or never, got {:?}.",
+                    color
+                );
+                exit(1)
+            }),
+            None => config::LogColor::Auto,
+        };
+        // The terminal logger colors the level of each line, the plain one doesn't.
+        let term_logger = if log_colored(log_color) {
+            simplelog::TermLogger::new(filter, simplelog::Config::default())
         } else {
-            simplelog::LogLevelFilter::Info
+            None
         };
-
-        simplelog::TermLogger::init(filter, simplelog::Config::default())
-            .map_err(Box::<dyn Error>::from)
-            .or_else(|_| {
-                simplelog::SimpleLogger::init(filter, simplelog::Config::default())
-                    .map_err(Box::<dyn Error>::from)
-            })
-            .expect("Couldn't initialize logger");
+        match term_logger {
+            Some(term_logger) => init_logger(*term_logger, filter),
+            None => init_logger(plain_logger(filter, io::stdout()), filter),
+        }
     }
+    .expect("Couldn't initialize logger");
 
-    let config_file = matches
-        .opt_str("config")
+    let mut config_files: Vec<PathBuf> = matches
+        .opt_strs("config")
+        .into_iter()
         .map(PathBuf::from)
-        .or_else(config::get_config_file);
+        .collect();
+    if config_files.is_empty() {
+        config_files.extend(config::get_config_file());
+    }
+
+    if matches.opt_present("doctor") {
+        exit(doctor::run(&config_files, &matches));
+    }
+
+    if matches.opt_present("migrate-config") {
+        if config_files.len() > 1 {
+            error!("--migrate-config migrates one config file at a time.");
+            exit(1);
+        }
+        exit(migrate::run(
+            config_files.pop(),
+            matches.opt_str("migrate-config"),
+        ));
+    }
 
-    let config = match config::get_config(config_file, &matches) {
+    let mut config = match config::get_config(&config_files, &matches) {
         Ok(config) => config,
         Err(e) => {
             error!("{}", e);
@@ -85,6 +207,72 @@ fn main() {
         }
     };
 
+    if let Some((limit, interval)) = config.log_rate_limit {
+        log_limit::set_rate_limit(limit, interval);
+    }
+
+    if matches.opt_present("dump-config") {
+        print!("{}", config.dump());
+        exit(0);
+    }
+
+    if matches.opt_present("forget-device") || matches.opt_present("list-cache") {
+        let cache_path = match config.cache_path {
+            Some(ref path) => path,
+            None => {
+                error!("--forget-device and --list-cache need a cache_path.");
+                exit(1);
+            }
+        };
+        if matches.opt_present("forget-device") {
+            match cache_dir::forget_device(cache_path) {
+                Ok(ref removed) if removed.is_empty() => info!("Nothing to forget."),
+                Ok(removed) => {
+                    for path in removed {
+                        info!("Removed {}.", path.display());
+                    }
+                }
+                Err(e) => {
+                    error!("Couldn't clear the cache: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        if matches.opt_present("list-cache") {
+            match cache_dir::list(cache_path) {
+                Ok(listing) => print!("{}", listing),
+                Err(e) => {
+                    error!("Couldn't list the cache: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        exit(0);
+    }
+
+    if let Err(e) = setup::open_cache(&mut config) {
+        error!("{}", e);
+        exit(1);
+    }
+
+    if matches.opt_present("verify-credentials") {
+        exit(verify::run(config).exit_code());
+    }
+
+    if let Some(uri) = matches.opt_str("once") {
+        let duration = match matches.opt_str("once-duration") {
+            Some(duration) => match config::parse_duration(&duration) {
+                Some(duration) => duration,
+                None => {
+                    error!("--once-duration must be a duration such as \"30s\".");
+                    exit(1);
+                }
+            },
+            None => Duration::from_secs(30),
+        };
+        exit(once::run(config, &uri, duration).exit_code());
+    }
+
     if is_daemon {
         let mut daemonize = Daemonize::new();
         if let Some(pid) = config.pid.as_ref() {
@@ -96,6 +284,21 @@ fn main() {
         };
     }
 
+    // Locked after daemonizing, which changes the PID.
+    let instance_lock = match instance_lock::acquire(&config.device_name, config.instance_policy) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    };
+
+    // Set before any threads are started, so that they all inherit it.
+    if let Some(nice) = config.nice {
+        priority::set_nice(nice);
+    }
+    memory_lock::lock(config.mlock);
+
     panic::set_hook(Box::new(|panic_info| {
         error!(
             "Caught panic with message: {}",
@@ -113,7 +316,55 @@ fn main() {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
+    if let Some((_, interval)) = config.log_rate_limit {
+        handle.spawn(log_limit::flush_every(interval, &handle));
+    }
     let initial_state = setup::initial_state(handle, config);
 
-    core.run(initial_state).unwrap();
+    let reason = core.run(initial_state).unwrap();
+    reason.log();
+    // exit() doesn't flush what a TCP syslog connection still buffers either.
+    log::logger().flush();
+    // exit() doesn't run destructors.
+    drop(instance_lock);
+    exit(reason.exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps what's written to it.
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plain_log() {
+        assert!(!log_colored(config::LogColor::Never));
+
+        let written = Written::default();
+        init_logger(
+            plain_logger(LevelFilter::Info, written.clone()),
+            LevelFilter::Info,
+        )
+        .unwrap();
+        info!("Connected");
+        error!("Couldn't connect");
+        let log = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        assert!(log.contains("Connected"), "{}", log);
+        assert!(log.contains("Couldn't connect"), "{}", log);
+        assert!(!log.contains('\u{1b}'), "{:?}", log);
+    }
 }
@@ -1,55 +1,134 @@
-use futures::{self, Async, Future, Poll, Stream};
+use futures::{self, future::Either, Async, Future, Poll, Stream};
 use librespot::{
     connect::{
         discovery::DiscoveryStream,
         spirc::{Spirc, SpircTask},
     },
     core::{
+        authentication::Credentials,
         cache::Cache,
         config::{ConnectConfig, DeviceType, SessionConfig},
         session::Session,
     },
     playback::{
-        audio_backend::Sink,
-        config::PlayerConfig,
+        config::{Bitrate, PlayerConfig},
         mixer::Mixer,
         player::{Player, PlayerEvent},
     },
 };
-use log::error;
-use std::{io, rc::Rc};
-use tokio_core::reactor::Handle;
+use log::{error, info, warn};
+use std::{
+    io,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::IoStream;
 
+use crate::announce::{Announcer, Change};
+use crate::audio_filter::FilterConfig;
+use crate::autostart::{self, Autostart};
+use crate::backends::Backend;
+use crate::cache_dir::Expiry;
+use crate::config::{ArtistSeparator, DBusType, MprisIdentity};
+use crate::context_volume::{self, VolumeMemory};
 #[cfg(feature = "dbus_mpris")]
 use crate::dbus_mpris::DbusServer;
-use crate::process::{spawn_program_on_event, Child};
+use crate::device_close;
+use crate::fade_out;
+use crate::heartbeat::Heartbeat;
+use crate::priority;
+use crate::process::{is_event_selected, EventPrograms};
+use crate::reconnect::{Next, Reconnect};
+use crate::resume::Resume;
+use crate::scrobble::Scrobbler;
+use crate::shutdown::ShutdownReason;
+use crate::stats::Stats;
+use crate::time_stretch;
+use crate::track_length;
+use crate::watchdog::{self, Verdict, Watchdog};
+
+/// How often to check whether an `onevent` program has exited while events wait for it.
+const EVENT_PROGRAMS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Starts connecting to Spotify, failing with `TimedOut` if there's no session after
+/// `timeout`.
+pub(crate) fn connect(
+    session_config: SessionConfig,
+    credentials: Credentials,
+    cache: Option<Cache>,
+    handle: &Handle,
+    timeout: Option<Duration>,
+) -> Box<dyn Future<Item = Session, Error = io::Error>> {
+    let connection = Session::connect(session_config, credentials, cache, handle.clone());
+    match timeout {
+        Some(timeout) => with_timeout(connection, timeout, handle),
+        None => connection,
+    }
+}
+
+fn with_timeout<T: 'static>(
+    future: Box<dyn Future<Item = T, Error = io::Error>>,
+    timeout: Duration,
+    handle: &Handle,
+) -> Box<dyn Future<Item = T, Error = io::Error>> {
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(futures::future::err(e)),
+    };
+    Box::new(future.select2(timer).then(move |result| match result {
+        Ok(Either::A((item, _))) => Ok(item),
+        Ok(Either::B(_)) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no response within {}ms", timeout.as_millis()),
+        )),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+    }))
+}
+
+/// Whether `username` may log the device in through discovery.
+fn is_allowed_user(allowed_users: &[String], username: &str) -> bool {
+    allowed_users.is_empty() || allowed_users.iter().any(|user| user == username)
+}
 
 pub struct LibreSpotConnection {
     connection: Box<dyn Future<Item = Session, Error = io::Error>>,
     spirc_task: Option<SpircTask>,
     spirc: Option<Rc<Spirc>>,
-    discovery_stream: DiscoveryStream,
+    session: Option<Session>,
+    /// `None` while the device isn't advertised.
+    discovery_stream: Option<DiscoveryStream>,
+    credentials: Option<Credentials>,
 }
 
 impl LibreSpotConnection {
     pub fn new(
         connection: Box<dyn Future<Item = Session, Error = io::Error>>,
         discovery_stream: DiscoveryStream,
+        credentials: Option<Credentials>,
     ) -> LibreSpotConnection {
         LibreSpotConnection {
             connection,
             spirc_task: None,
             spirc: None,
-            discovery_stream,
+            session: None,
+            discovery_stream: Some(discovery_stream),
+            credentials,
         }
     }
 }
 
 pub struct AudioSetup {
     pub mixer: Box<dyn FnMut() -> Box<dyn Mixer>>,
-    pub backend: fn(Option<String>) -> Box<dyn Sink>,
+    pub filters: FilterConfig,
+    pub backend: Backend,
     pub audio_device: Option<String>,
+    /// How long to keep the audio device open after playback stops.
+    pub device_close_delay: Duration,
+    pub fade_out: Option<Duration>,
+    pub playback_speed: Option<f64>,
+    pub rt_priority: Option<i32>,
 }
 
 pub struct SpotifydState {
@@ -59,30 +138,56 @@ pub struct SpotifydState {
     pub device_name: String,
     pub player_event_channel: Option<futures::sync::mpsc::UnboundedReceiver<PlayerEvent>>,
     pub player_event_program: Option<String>,
+    pub player_event_filter: Option<Vec<&'static str>>,
     pub dbus_mpris_server: Option<Box<dyn Future<Item = (), Error = ()>>>,
+    /// The bus name to register the MPRIS interface under, `None` when MPRIS is disabled.
+    pub mpris_bus_name: Option<String>,
+    pub dbus_type: DBusType,
+    pub artist_separator: ArtistSeparator,
+    pub mpris_identity: MprisIdentity,
 }
 
 #[cfg(feature = "dbus_mpris")]
+#[allow(clippy::too_many_arguments)]
 fn new_dbus_server(
     session: Session,
     handle: Handle,
     spirc: Rc<Spirc>,
     device_name: String,
+    bus_name: String,
+    dbus_type: DBusType,
+    bitrate: Bitrate,
+    client_id: String,
+    artist_separator: ArtistSeparator,
+    identity: MprisIdentity,
 ) -> Option<Box<Future<Item = (), Error = ()>>> {
     Some(Box::new(DbusServer::new(
         session,
         handle,
         spirc,
         device_name,
+        bus_name,
+        dbus_type,
+        bitrate,
+        client_id,
+        artist_separator,
+        identity,
     )))
 }
 
 #[cfg(not(feature = "dbus_mpris"))]
+#[allow(clippy::too_many_arguments)]
 fn new_dbus_server(
     _: Session,
     _: Handle,
     _: Rc<Spirc>,
     _: String,
+    _: String,
+    _: DBusType,
+    _: Bitrate,
+    _: String,
+    _: ArtistSeparator,
+    _: MprisIdentity,
 ) -> Option<Box<dyn Future<Item = (), Error = ()>>> {
     None
 }
@@ -95,68 +200,359 @@ pub(crate) struct MainLoopState {
     pub(crate) session_config: SessionConfig,
     pub(crate) handle: Handle,
     pub(crate) linear_volume: bool,
-    pub(crate) running_event_program: Option<Child>,
+    pub(crate) event_programs: EventPrograms,
+    pub(crate) event_programs_timeout: Option<Timeout>,
     pub(crate) shell: String,
+    /// The client id to request Web API tokens for.
+    pub(crate) client_id: String,
+    pub(crate) reconnect: Reconnect,
+    pub(crate) reconnect_timeout: Option<Timeout>,
+    pub(crate) connect_timeout: Option<Duration>,
+    /// Playback to start once the first session is up.
+    pub(crate) autostart: Option<Autostart>,
+    pub(crate) autostart_task: Option<Box<dyn Future<Item = (), Error = ()>>>,
+    /// Set if playback is picked up where it was after a restart.
+    pub(crate) resume: Option<Resume>,
+    pub(crate) resume_task: Option<Box<dyn Future<Item = (), Error = ()>>>,
+    pub(crate) heartbeat: Option<Heartbeat>,
+    /// Set if cached audio files expire.
+    pub(crate) cache_expiry: Option<Expiry>,
+    /// What was played, logged on a clean shutdown.
+    pub(crate) stats: Stats,
+    /// Whether a track is playing, as last reported by the player.
+    pub(crate) playing: bool,
+    /// Tracks longer than this are skipped.
+    pub(crate) max_track_length: Option<Duration>,
+    pub(crate) track_length_check: Option<Box<dyn Future<Item = bool, Error = ()>>>,
+    /// Set if the volume is remembered per context.
+    pub(crate) volume_memory: Option<Arc<Mutex<VolumeMemory>>>,
+    pub(crate) context_volume_task: Option<Box<dyn Future<Item = (), Error = ()>>>,
+    /// Set if the device is only advertised for discovery while nothing is playing.
+    pub(crate) announcer: Option<Announcer>,
+    pub(crate) announce_timeout: Option<Timeout>,
+    pub(crate) start_discovery: Box<dyn Fn() -> io::Result<DiscoveryStream>>,
+    /// Set if listened tracks are scrobbled to Last.fm.
+    pub(crate) scrobbler: Option<Scrobbler>,
+    pub(crate) watchdog: Watchdog,
+    /// The accounts that may log the device in through discovery, any if it's empty.
+    pub(crate) allowed_users: Vec<String>,
 }
 
-impl Future for MainLoopState {
-    type Error = ();
-    type Item = ();
+impl MainLoopState {
+    fn connect(&mut self, credentials: Credentials) {
+        self.librespot_connection.credentials = Some(credentials.clone());
+        self.librespot_connection.connection = connect(
+            self.session_config.clone(),
+            credentials,
+            self.spotifyd_state.cache.clone(),
+            &self.handle,
+            self.connect_timeout,
+        );
+        self.reconnect.attempt(Instant::now());
+    }
+
+    /// Tears down the crashed player with its session, and connects again to start
+    /// over. Returns why to shut down instead, if the player keeps crashing.
+    fn player_crashed(&mut self, error: String) -> Option<ShutdownReason> {
+        error!("The player crashed: {}", error);
+        self.librespot_connection.spirc = None;
+        self.librespot_connection.spirc_task = None;
+        self.librespot_connection.session = None;
+        self.spotifyd_state.player_event_channel = None;
+        self.spotifyd_state.dbus_mpris_server = None;
+        self.playing = false;
+        self.track_length_check = None;
+        self.context_volume_task = None;
+        self.resume_task = None;
+        if self.spotifyd_state.shutting_down {
+            return Some(ShutdownReason::Signal);
+        }
+        match self.watchdog.crashed(Instant::now()) {
+            Verdict::Restart => {
+                if let Some(creds) = self.librespot_connection.credentials.clone() {
+                    info!("Restarting the player with a fresh session.");
+                    self.connect(creds);
+                }
+                None
+            }
+            Verdict::GiveUp => Some(ShutdownReason::PlayerCrashed {
+                restarts: self.watchdog.restarts(),
+                error,
+            }),
+        }
+    }
 
-    fn poll(&mut self) -> Poll<(), ()> {
+    /// Runs everything spotifyd does, until it's time to shut down.
+    fn poll_loop(&mut self) -> Poll<ShutdownReason, ()> {
         loop {
-            if let Async::Ready(Some(creds)) =
-                self.librespot_connection.discovery_stream.poll().unwrap()
+            let discovered = match self
+                .librespot_connection
+                .discovery_stream
+                .as_mut()
+                .map(|stream| stream.poll().unwrap())
             {
+                Some(Async::Ready(Some(creds))) => Some(creds),
+                _ => None,
+            };
+            let discovered = discovered.filter(|creds| {
+                let allowed = is_allowed_user(&self.allowed_users, &creds.username);
+                if !allowed {
+                    warn!(
+                        "Ignoring a login through discovery by {:?}, who isn't in allowed_users.",
+                        creds.username
+                    );
+                }
+                allowed
+            });
+            if let Some(creds) = discovered {
                 if let Some(ref mut spirc) = self.librespot_connection.spirc {
                     spirc.shutdown();
                 }
-                let session_config = self.session_config.clone();
-                let cache = self.spotifyd_state.cache.clone();
-                let handle = self.handle.clone();
-                self.librespot_connection.connection =
-                    Session::connect(session_config, creds, cache, handle);
-            }
-
-            if let Some(mut child) = self.running_event_program.take() {
-                match child.try_wait() {
-                    // Still running...
-                    Ok(None) => self.running_event_program = Some(child),
-                    // Exited with error...
-                    Err(e) => error!("{}", e),
-                    // Exited without error...
-                    Ok(Some(_)) => (),
-                }
-            }
-            if self.running_event_program.is_none() {
-                if let Some(ref mut player_event_channel) = self.spotifyd_state.player_event_channel
-                {
-                    if let Async::Ready(Some(event)) = player_event_channel.poll().unwrap() {
-                        if let Some(ref cmd) = self.spotifyd_state.player_event_program {
-                            match spawn_program_on_event(&self.shell, cmd, event) {
-                                Ok(child) => self.running_event_program = Some(child),
-                                Err(e) => error!("{}", e),
+                // New credentials deserve a fresh set of attempts.
+                self.reconnect.succeeded();
+                self.reconnect_timeout = None;
+                self.connect(creds);
+            }
+
+            let reconnect_due = match self.reconnect_timeout.as_mut().map(|t| t.poll()) {
+                None | Some(Ok(Async::NotReady)) => false,
+                // The timer only fails if the reactor is gone, so don't wait any longer then.
+                Some(_) => true,
+            };
+            if reconnect_due {
+                self.reconnect_timeout = None;
+                if let Some(creds) = self.librespot_connection.credentials.clone() {
+                    info!("Reconnecting to Spotify.");
+                    self.stats.reconnected();
+                    self.connect(creds);
+                }
+            }
+
+            let mut started_track = None;
+            let mut playback_moved = false;
+            for e in self.event_programs.reap() {
+                error!("{}", e);
+            }
+            if let Some(ref cmd) = self.spotifyd_state.player_event_program {
+                if let Err(e) = self.event_programs.run_pending(&self.shell, cmd) {
+                    error!("{}", e);
+                }
+            }
+            if let Some(ref mut player_event_channel) = self.spotifyd_state.player_event_channel {
+                while let Async::Ready(Some(event)) = player_event_channel.poll().unwrap() {
+                    if let Some(ref mut scrobbler) = self.scrobbler {
+                        let session = self.librespot_connection.session.as_ref();
+                        scrobbler.player_event(&event, session);
+                    }
+                    self.stats.player_event(&event, Instant::now());
+                    match event {
+                        PlayerEvent::Started { track_id } => {
+                            self.playing = true;
+                            started_track = Some(track_id);
+                            playback_moved = true;
+                        }
+                        PlayerEvent::Stopped { .. } => {
+                            self.playing = false;
+                            playback_moved = true;
+                        }
+                        PlayerEvent::Changed { .. } => (),
+                    }
+                    let filter = self.spotifyd_state.player_event_filter.as_ref();
+                    match self.spotifyd_state.player_event_program {
+                        Some(ref cmd) if is_event_selected(filter.map(Vec::as_slice), &event) => {
+                            if let Err(e) = self.event_programs.spawn(&self.shell, cmd, event) {
+                                error!("{}", e);
                             }
                         }
+                        _ => (),
+                    }
+                }
+            }
+            // Nothing wakes the loop when a program exits, so check back for the events
+            // that wait for it.
+            if self.event_programs.has_pending() && self.event_programs_timeout.is_none() {
+                self.event_programs_timeout =
+                    Timeout::new(EVENT_PROGRAMS_INTERVAL, &self.handle).ok();
+            }
+            let event_programs_due = match self.event_programs_timeout.as_mut().map(|t| t.poll()) {
+                None | Some(Ok(Async::NotReady)) => false,
+                Some(_) => true,
+            };
+            if event_programs_due {
+                self.event_programs_timeout = None;
+                futures::task::current().notify();
+            }
+
+            if let (Some(track_id), Some(max_length)) = (started_track, self.max_track_length) {
+                if let Some(ref session) = self.librespot_connection.session {
+                    self.track_length_check =
+                        Some(track_length::check(session, track_id, max_length));
+                }
+            }
+            if let (Some(_), Some(ref memory)) = (started_track, &self.volume_memory) {
+                if let Some(ref session) = self.librespot_connection.session {
+                    self.context_volume_task = Some(context_volume::track_started(
+                        session,
+                        &self.client_id,
+                        self.session_config.device_id.clone(),
+                        memory.clone(),
+                    ));
+                }
+            }
+            if let (true, Some(ref resume)) = (playback_moved, &self.resume) {
+                if let Some(ref session) = self.librespot_connection.session {
+                    self.resume_task = Some(resume.save(session, &self.client_id));
+                }
+            }
+            let resume_done = match self.resume_task.as_mut().map(|t| t.poll()) {
+                None | Some(Ok(Async::NotReady)) => false,
+                Some(_) => true,
+            };
+            if resume_done {
+                self.resume_task = None;
+            }
+            if let Some(ref mut scrobbler) = self.scrobbler {
+                scrobbler.poll();
+            }
+            let context_volume_done = match self.context_volume_task.as_mut().map(|t| t.poll()) {
+                None | Some(Ok(Async::NotReady)) => false,
+                Some(_) => true,
+            };
+            if context_volume_done {
+                self.context_volume_task = None;
+            }
+            match self.track_length_check.as_mut().map(|check| check.poll()) {
+                None | Some(Ok(Async::NotReady)) => (),
+                Some(result) => {
+                    self.track_length_check = None;
+                    if let Ok(Async::Ready(true)) = result {
+                        if let Some(ref spirc) = self.librespot_connection.spirc {
+                            spirc.next();
+                        }
                     }
                 }
             }
 
+            if let Some(ref mut announcer) = self.announcer {
+                let now = Instant::now();
+                match announcer.update(self.playing, now) {
+                    Some(Change::Withdraw) => {
+                        info!("Playing, no longer advertising the device for discovery.");
+                        self.librespot_connection.discovery_stream = None;
+                    }
+                    Some(Change::Advertise) => match (self.start_discovery)() {
+                        Ok(stream) => {
+                            info!("Idle, advertising the device for discovery again.");
+                            self.librespot_connection.discovery_stream = Some(stream);
+                        }
+                        Err(e) => error!("Couldn't advertise the device for discovery: {}", e),
+                    },
+                    None => (),
+                }
+                if let (Some(deadline), None) = (announcer.deadline(), &self.announce_timeout) {
+                    self.announce_timeout = Timeout::new_at(deadline, &self.handle).ok();
+                }
+            }
+            let announce_due = match self.announce_timeout.as_mut().map(|t| t.poll()) {
+                None | Some(Ok(Async::NotReady)) => false,
+                Some(_) => true,
+            };
+            if announce_due {
+                self.announce_timeout = None;
+                // Let the announcer catch up on the next poll.
+                futures::task::current().notify();
+            }
+
+            if let Some(ref mut heartbeat) = self.heartbeat {
+                heartbeat.poll(self.playing);
+            }
+
+            if let Some(ref mut cache_expiry) = self.cache_expiry {
+                cache_expiry.poll(self.playing);
+            }
+
             if let Some(ref mut fut) = self.spotifyd_state.dbus_mpris_server {
                 let _ = fut.poll();
             }
 
-            if let Async::Ready(session) = self.librespot_connection.connection.poll().unwrap() {
+            let autostart_done = match self.autostart_task.as_mut().map(|t| t.poll()) {
+                None | Some(Ok(Async::NotReady)) => false,
+                Some(_) => true,
+            };
+            if autostart_done {
+                self.autostart_task = None;
+            }
+
+            let connection = match self.librespot_connection.connection.poll() {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.librespot_connection.connection = Box::new(futures::future::empty());
+                    match self.reconnect.failed_with(&e, Instant::now()) {
+                        Next::Retry(delay) => {
+                            info!(
+                                "Couldn't connect to Spotify: {}. Waiting {}s before reconnecting.",
+                                e,
+                                delay.as_secs()
+                            );
+                            match Timeout::new(delay, &self.handle) {
+                                Ok(timeout) => self.reconnect_timeout = Some(timeout),
+                                Err(e) => {
+                                    return Ok(Async::Ready(ShutdownReason::ReconnectFailed(
+                                        e.to_string(),
+                                    )));
+                                }
+                            }
+                        }
+                        Next::GiveUp => {
+                            return Ok(Async::Ready(ShutdownReason::GaveUpReconnecting {
+                                attempts: self.reconnect.failed_attempts(),
+                                error: e.to_string(),
+                            }));
+                        }
+                        Next::Exit => {
+                            return Ok(Async::Ready(ShutdownReason::AuthFailed(e.to_string())));
+                        }
+                        Next::Wait => {
+                            error!(
+                                "Spotify rejected the credentials: {}. Not reconnecting until \
+                                 new ones arrive through Spotify Connect.",
+                                e
+                            );
+                            self.librespot_connection.credentials = None;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            if let Async::Ready(session) = connection {
+                self.reconnect.succeeded();
                 let mixer = (self.audio_setup.mixer)();
-                let audio_filter = mixer.get_audio_filter();
+                let audio_filter = self.audio_setup.filters.build(mixer.get_audio_filter());
                 self.librespot_connection.connection = Box::new(futures::future::empty());
-                let backend = self.audio_setup.backend;
+                let backend = self.audio_setup.backend.clone();
                 let audio_device = self.audio_setup.audio_device.clone();
+                let close_delay = self.audio_setup.device_close_delay;
+                let fade_out = self.audio_setup.fade_out;
+                let playback_speed = self.audio_setup.playback_speed;
+                let rt_priority = self.audio_setup.rt_priority;
+                let underruns = self.stats.underruns();
                 let (player, event_channel) = Player::new(
                     self.player_config.clone(),
                     session.clone(),
                     audio_filter,
-                    move || (backend)(audio_device),
+                    move || {
+                        // Runs on the thread that writes to the device, which is the
+                        // player thread unless the device is closed with a delay.
+                        let open_sink = move || {
+                            if let Some(priority) = rt_priority {
+                                priority::set_rt_priority(priority);
+                            }
+                            underruns.count(backend.open(audio_device))
+                        };
+                        let sink = device_close::open(open_sink, close_delay);
+                        time_stretch::wrap(fade_out::wrap(sink, fade_out), playback_speed)
+                    },
                 );
 
                 self.spotifyd_state.player_event_channel = Some(event_channel);
@@ -175,32 +571,129 @@ impl Future for MainLoopState {
                 self.librespot_connection.spirc_task = Some(spirc_task);
                 let shared_spirc = Rc::new(spirc);
                 self.librespot_connection.spirc = Some(shared_spirc.clone());
+                self.librespot_connection.session = Some(session.clone());
 
-                self.spotifyd_state.dbus_mpris_server = new_dbus_server(
-                    session,
-                    self.handle.clone(),
-                    shared_spirc,
-                    self.spotifyd_state.device_name.clone(),
-                );
+                if let Some(autostart) = self.autostart.take() {
+                    self.autostart_task = Some(autostart::start(
+                        &session,
+                        &self.client_id,
+                        self.session_config.device_id.clone(),
+                        autostart,
+                    ));
+                }
+                if let Some(ref mut resume) = self.resume {
+                    self.resume_task = resume.restore(
+                        &session,
+                        &self.client_id,
+                        self.session_config.device_id.clone(),
+                    );
+                }
+
+                if let Some(ref bus_name) = self.spotifyd_state.mpris_bus_name {
+                    self.spotifyd_state.dbus_mpris_server = new_dbus_server(
+                        session,
+                        self.handle.clone(),
+                        shared_spirc,
+                        self.spotifyd_state.device_name.clone(),
+                        bus_name.clone(),
+                        self.spotifyd_state.dbus_type,
+                        self.player_config.bitrate,
+                        self.client_id.clone(),
+                        self.spotifyd_state.artist_separator.clone(),
+                        self.spotifyd_state.mpris_identity.clone(),
+                    );
+                }
             } else if let Async::Ready(_) = self.spotifyd_state.ctrl_c_stream.poll().unwrap() {
                 if !self.spotifyd_state.shutting_down {
                     if let Some(ref spirc) = self.librespot_connection.spirc {
                         spirc.shutdown();
                         self.spotifyd_state.shutting_down = true;
                     } else {
-                        return Ok(Async::Ready(()));
+                        return Ok(Async::Ready(ShutdownReason::Signal));
                     }
                 }
-            } else if let Some(Async::Ready(_)) = self
+            } else if let Some(polled) = self
                 .librespot_connection
                 .spirc_task
                 .as_mut()
-                .map(|ref mut st| st.poll().unwrap())
+                .map(watchdog::poll_catching)
             {
-                return Ok(Async::Ready(()));
+                let error = match polled {
+                    Ok(Ok(Async::Ready(()))) => {
+                        return Ok(Async::Ready(if self.spotifyd_state.shutting_down {
+                            ShutdownReason::Signal
+                        } else {
+                            ShutdownReason::SessionEnded
+                        }));
+                    }
+                    Ok(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+                    Ok(Err(())) => "the player task failed".to_string(),
+                    Err(panic) => panic,
+                };
+                if let Some(reason) = self.player_crashed(error) {
+                    return Ok(Async::Ready(reason));
+                }
             } else {
                 return Ok(Async::NotReady);
             }
         }
     }
 }
+
+impl Future for MainLoopState {
+    type Error = ();
+    type Item = ShutdownReason;
+
+    fn poll(&mut self) -> Poll<ShutdownReason, ()> {
+        let polled = self.poll_loop();
+        if let Ok(Async::Ready(ref reason)) = polled {
+            if let Some(scrobbler) = self.scrobbler.take() {
+                scrobbler.shut_down(Instant::now());
+            }
+            if *reason == ShutdownReason::Signal {
+                self.stats.shut_down(Instant::now());
+            }
+        }
+        polled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn connect_timeout() {
+        let mut core = Core::new().unwrap();
+        let stalled = Box::new(futures::future::empty::<(), io::Error>());
+        let err = core
+            .run(with_timeout(
+                stalled,
+                Duration::from_millis(10),
+                &core.handle(),
+            ))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let connected = Box::new(futures::future::ok::<_, io::Error>(42));
+        let result = core.run(with_timeout(
+            connected,
+            Duration::from_secs(10),
+            &core.handle(),
+        ));
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[test]
+    fn allowed_users() {
+        assert!(is_allowed_user(&[], "anyone"));
+        let allowed = vec!["jane".to_string(), "1234567890".to_string()];
+        assert!(is_allowed_user(&allowed, "jane"));
+        assert!(is_allowed_user(&allowed, "1234567890"));
+        assert!(!is_allowed_user(&allowed, "joe"));
+        // Usernames are compared as given, like they're logged in with.
+        assert!(!is_allowed_user(&allowed, "Jane"));
+        assert!(!is_allowed_user(&allowed, ""));
+    }
+}
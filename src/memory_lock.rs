@@ -0,0 +1,62 @@
+use log::{info, warn};
+use std::io;
+
+/// Locks all of spotifyd's memory, now and as it grows, so that the audio thread never
+/// waits for its pages to be read back in. Fails beyond `ulimit -l` without root or
+/// CAP_IPC_LOCK, in which case spotifyd warns and runs without it.
+pub(crate) fn lock(enabled: bool) -> bool {
+    lock_with(enabled, || {
+        if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    })
+}
+
+fn lock_with<F: FnOnce() -> io::Result<()>>(enabled: bool, mlockall: F) -> bool {
+    if !enabled {
+        return false;
+    }
+    match mlockall() {
+        Ok(()) => {
+            info!("Locked the memory of spotifyd.");
+            true
+        }
+        Err(e) => {
+            warn!(
+                "Couldn't lock the memory, which needs a higher ulimit -l, root or \
+                 CAP_IPC_LOCK: {}",
+                e
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn gated_by_config() {
+        let called = Cell::new(false);
+        assert!(!lock_with(false, || {
+            called.set(true);
+            Ok(())
+        }));
+        assert!(!called.get());
+        assert!(lock_with(true, || {
+            called.set(true);
+            Ok(())
+        }));
+        assert!(called.get());
+    }
+
+    #[test]
+    fn failure_is_not_fatal() {
+        let locked = lock_with(true, || Err(io::Error::from_raw_os_error(libc::ENOMEM)));
+        assert!(!locked);
+    }
+}
@@ -0,0 +1,267 @@
+use ini::Ini;
+use log::{error, info, warn};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use crate::config_format::{self, Format};
+use crate::schema;
+
+/// The sections `config::get_config` reads, in the order they're written.
+const SECTIONS: &[&str] = &["global", "spotifyd"];
+
+/// An INI config rewritten in TOML.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Migration {
+    pub(crate) toml: String,
+    /// Keys whose name was changed, with the name they were changed to.
+    pub(crate) renamed: Vec<(String, &'static str)>,
+    /// Keys that were left out, with the section they were in.
+    pub(crate) untranslated: Vec<(String, String)>,
+    /// Keys that were left out because the key they'd be renamed to is set as well,
+    /// with that key.
+    pub(crate) duplicates: Vec<(String, &'static str)>,
+}
+
+/// Rewrites the keys of `ini` in TOML, in the order of the schema. Keys that
+/// spotifyd doesn't know, and those outside the sections it reads, are left out.
+pub(crate) fn migrate(ini: &Ini) -> Migration {
+    let mut migration = Migration {
+        toml: String::new(),
+        renamed: Vec::new(),
+        untranslated: Vec::new(),
+        duplicates: Vec::new(),
+    };
+    for (section, properties) in ini.iter() {
+        let section = section.as_ref().map_or("", String::as_str);
+        if !SECTIONS.contains(&section) {
+            for (key, _) in properties.iter() {
+                migration
+                    .untranslated
+                    .push((key.to_string(), section.to_string()));
+            }
+        }
+    }
+    for &section in SECTIONS {
+        let properties = match ini.section(Some(section.to_owned())) {
+            Some(properties) => properties,
+            None => continue,
+        };
+        let mut keys: Vec<(&'static str, _)> = Vec::new();
+        for (key, value) in properties.iter() {
+            match schema::key_name(key) {
+                // spotifyd only reads the key as it's spelled in the schema, so that one
+                // is kept, or the first one of the other spellings.
+                Some(name)
+                    if name != key.as_str()
+                        && (properties.get(name).is_some()
+                            || keys.iter().any(|&(kept, _)| kept == name)) =>
+                {
+                    migration.duplicates.push((key.to_string(), name))
+                }
+                Some(name) => {
+                    if name != key.as_str() {
+                        migration.renamed.push((key.to_string(), name));
+                    }
+                    keys.push((name, value));
+                }
+                None => migration
+                    .untranslated
+                    .push((key.to_string(), section.to_string())),
+            }
+        }
+        keys.sort_by_key(|&(name, _)| schema::key_position(name));
+        if !migration.toml.is_empty() {
+            migration.toml.push('\n');
+        }
+        migration.toml.push_str(&format!("[{}]\n", section));
+        for (name, value) in keys {
+            migration
+                .toml
+                .push_str(&format!("{} = {}\n", name, schema::toml_value(name, value)));
+        }
+    }
+    migration.renamed.sort();
+    migration.untranslated.sort();
+    migration.duplicates.sort();
+    migration
+}
+
+/// Migrates the INI config at `config_path` to TOML, and writes it to `output` or
+/// prints it. Returns the exit code.
+pub(crate) fn run(config_path: Option<PathBuf>, output: Option<String>) -> i32 {
+    let config_path = match config_path {
+        Some(path) => path,
+        None => {
+            error!("There's no config file to migrate, pass one with --config.");
+            return 1;
+        }
+    };
+    let contents = match fs::read_to_string(&config_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Couldn't read {}: {}", config_path.display(), e);
+            return 1;
+        }
+    };
+    if config_format::detect(&config_path, &contents) == Format::Toml {
+        info!("{} is in TOML already.", config_path.display());
+        return 0;
+    }
+    let ini = match Ini::load_from_str(&contents) {
+        Ok(ini) => ini,
+        Err(e) => {
+            error!("Couldn't parse {}: {}", config_path.display(), e);
+            return 1;
+        }
+    };
+    let migration = migrate(&ini);
+    for (from, to) in &migration.renamed {
+        info!("Renamed {} to {}.", from, to);
+    }
+    for (key, kept) in &migration.duplicates {
+        warn!("Both {} and {} are set, keeping {}.", key, kept, kept);
+    }
+    for (key, section) in &migration.untranslated {
+        if section.is_empty() {
+            warn!(
+                "Couldn't translate {} outside a section, leaving it out.",
+                key
+            );
+        } else {
+            warn!(
+                "Couldn't translate {} in [{}], leaving it out.",
+                key, section
+            );
+        }
+    }
+    match output {
+        // Never overwrite a file, which could be the config that's being migrated.
+        Some(output) => {
+            let written = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&output)
+                .and_then(|mut file| file.write_all(migration.toml.as_bytes()));
+            match written {
+                Ok(()) => info!("Wrote the migrated config to {}.", output),
+                Err(e) => {
+                    error!("Couldn't write {}: {}", output, e);
+                    return 1;
+                }
+            }
+        }
+        None => print!("{}", migration.toml),
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_config() {
+        let ini = Ini::load_from_str(
+            "[global]\n\
+             password = \"hunter#2\"\n\
+             username = jane\n\
+             bitrate = 320\n\
+             use_keyring = true\n\
+             onevent_filter = change,stop\n\
+             [spotifyd]\n\
+             device-name = Kitchen\n\
+             volume-normalisation = true\n",
+        )
+        .unwrap();
+        let migration = migrate(&ini);
+        assert_eq!(
+            migration.toml,
+            "[global]\n\
+             username = \"jane\"\n\
+             password = \"hunter#2\"\n\
+             use-keyring = true\n\
+             bitrate = 320\n\
+             onevent_filter = \"change,stop\"\n\
+             \n\
+             [spotifyd]\n\
+             device_name = \"Kitchen\"\n\
+             volume-normalisation = true\n"
+        );
+        assert_eq!(
+            migration.renamed,
+            vec![
+                ("device-name".to_string(), "device_name"),
+                ("use_keyring".to_string(), "use-keyring"),
+            ]
+        );
+        assert!(migration.untranslated.is_empty());
+        assert!(migration.duplicates.is_empty());
+
+        // The result reads the same as the original.
+        let toml = config_format::parse_toml(&migration.toml).unwrap();
+        let global = toml.section(Some("global".to_owned())).unwrap();
+        assert_eq!(global.get("password").map(String::as_str), Some("hunter#2"));
+        assert_eq!(global.get("bitrate").map(String::as_str), Some("320"));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let ini = Ini::load_from_str(
+            "stray = 1\n\
+             [global]\n\
+             username = jane\n\
+             volume_controller = softvol\n\
+             [other]\n\
+             device = hw:0\n",
+        )
+        .unwrap();
+        let migration = migrate(&ini);
+        assert_eq!(migration.toml, "[global]\nusername = \"jane\"\n");
+        assert_eq!(
+            migration.untranslated,
+            vec![
+                ("device".to_string(), "other".to_string()),
+                ("stray".to_string(), "".to_string()),
+                ("volume_controller".to_string(), "global".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let ini = Ini::load_from_str(
+            "[global]\n\
+             device-name = Dashes\n\
+             device_name = Kitchen\n\
+             use_keyring = true\n\
+             [spotifyd]\n\
+             device-name = Living room\n\
+             device_name = Bedroom\n",
+        )
+        .unwrap();
+        let migration = migrate(&ini);
+        assert_eq!(
+            migration.toml,
+            "[global]\n\
+             use-keyring = true\n\
+             device_name = \"Kitchen\"\n\
+             \n\
+             [spotifyd]\n\
+             device_name = \"Bedroom\"\n"
+        );
+        assert_eq!(
+            migration.duplicates,
+            vec![
+                ("device-name".to_string(), "device_name"),
+                ("device-name".to_string(), "device_name"),
+            ]
+        );
+        assert_eq!(
+            migration.renamed,
+            vec![("use_keyring".to_string(), "use-keyring")]
+        );
+    }
+}
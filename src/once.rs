@@ -0,0 +1,226 @@
+use futures::{sync::mpsc::UnboundedReceiver, sync::oneshot, Async, Future, Poll, Stream};
+use librespot::{
+    core::{session::Session, spotify_id::SpotifyId},
+    playback::player::{Player, PlayerEvent},
+};
+use log::{error, info};
+use std::time::Duration;
+use tokio_core::reactor::{Core, Timeout};
+
+use crate::{backends, config::SpotifydConfig, setup};
+
+/// How a `--once` run ended.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Played,
+    PlaybackFailed,
+    ConnectionFailed,
+    InvalidUri,
+}
+
+impl Outcome {
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Played => 0,
+            Outcome::PlaybackFailed => 1,
+            Outcome::ConnectionFailed => 2,
+            Outcome::InvalidUri => 3,
+        }
+    }
+}
+
+/// Parses a track given as a Spotify URI (`spotify:track:<id>`) or an open.spotify.com link.
+fn parse_track_uri(uri: &str) -> Option<SpotifyId> {
+    let id = if uri.starts_with("spotify:track:") {
+        &uri["spotify:track:".len()..]
+    } else if uri.starts_with("https://open.spotify.com/track/") {
+        let id = &uri["https://open.spotify.com/track/".len()..];
+        id.split('?').next()?
+    } else {
+        return None;
+    };
+    SpotifyId::from_base62(id).ok()
+}
+
+/// Logs in, plays `uri` for `duration` (or until the track ends, if that's sooner)
+/// and reports how that went. Neither discovery nor MPRIS are started.
+pub(crate) fn run(config: SpotifydConfig, uri: &str, duration: Duration) -> Outcome {
+    let track = match parse_track_uri(uri) {
+        Some(track) => track,
+        None => {
+            error!("{:?} is not a Spotify track URI.", uri);
+            return Outcome::InvalidUri;
+        }
+    };
+    let credentials = match setup::credentials(&config) {
+        Ok(Some(credentials)) => credentials,
+        Err(e) => {
+            error!("{}", e);
+            return Outcome::ConnectionFailed;
+        }
+        Ok(None) => {
+            error!("No credentials found, a username and password are needed to log in.");
+            return Outcome::ConnectionFailed;
+        }
+    };
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let connection = Session::connect(
+        config.session_config,
+        credentials,
+        config.cache,
+        handle.clone(),
+    );
+    let session = match core.run(connection) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Couldn't connect to Spotify: {}", e);
+            return Outcome::ConnectionFailed;
+        }
+    };
+
+    let backend = backends::find_backend(config.backend.as_ref().map(String::as_ref));
+    let audio_device = config.audio_device;
+    let (player, events) = Player::new(config.player_config, session, None, move || {
+        backend.open(audio_device)
+    });
+    info!("Playing {} for {}s.", uri, duration.as_secs());
+    let playback = Playback {
+        events,
+        end_of_track: player.load(track, true, 0),
+        timeout: Timeout::new(duration, &handle).unwrap(),
+        started: false,
+    };
+    let outcome = core.run(playback).unwrap();
+    player.stop();
+    outcome
+}
+
+/// Resolves once the track has played for long enough, has ended or failed to play.
+struct Playback {
+    events: UnboundedReceiver<PlayerEvent>,
+    end_of_track: oneshot::Receiver<()>,
+    timeout: Timeout,
+    started: bool,
+}
+
+impl Playback {
+    fn outcome(&self) -> Outcome {
+        if self.started {
+            Outcome::Played
+        } else {
+            Outcome::PlaybackFailed
+        }
+    }
+}
+
+impl Future for Playback {
+    type Item = Outcome;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Outcome, ()> {
+        while let Ok(Async::Ready(Some(event))) = self.events.poll() {
+            if let PlayerEvent::Started { .. } = event {
+                self.started = true;
+            }
+        }
+        match self.end_of_track.poll() {
+            Ok(Async::Ready(())) => return Ok(Async::Ready(self.outcome())),
+            // The player drops the sender if the track couldn't be loaded.
+            Err(_) => return Ok(Async::Ready(Outcome::PlaybackFailed)),
+            Ok(Async::NotReady) => (),
+        }
+        match self.timeout.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            _ => Ok(Async::Ready(self.outcome())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_uri() {
+        let id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        assert_eq!(
+            parse_track_uri("spotify:track:4uLU6hMCjMI75M1A2tKUQC"),
+            Some(id)
+        );
+        assert_eq!(
+            parse_track_uri("https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC?si=abc"),
+            Some(id)
+        );
+        assert_eq!(
+            parse_track_uri("spotify:album:4uLU6hMCjMI75M1A2tKUQC"),
+            None
+        );
+        assert_eq!(parse_track_uri("spotify:track:"), None);
+    }
+
+    #[test]
+    fn once_arguments() {
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&[
+                "--once",
+                "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+                "--once-duration",
+                "5s",
+            ])
+            .unwrap();
+        assert_eq!(
+            matches.opt_str("once").as_ref().map(String::as_ref),
+            Some("spotify:track:4uLU6hMCjMI75M1A2tKUQC")
+        );
+        assert_eq!(matches.opt_str("once-duration"), Some("5s".to_string()));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(Outcome::Played.exit_code(), 0);
+        let failures = [
+            Outcome::PlaybackFailed,
+            Outcome::ConnectionFailed,
+            Outcome::InvalidUri,
+        ];
+        for (i, outcome) in failures.iter().enumerate() {
+            assert_ne!(outcome.exit_code(), 0);
+            for other in &failures[i + 1..] {
+                assert_ne!(outcome.exit_code(), other.exit_code());
+            }
+        }
+    }
+
+    /// Needs network access and a Spotify Premium account, given by the
+    /// SPOTIFYD_TEST_USERNAME and SPOTIFYD_TEST_PASSWORD environment variables.
+    #[cfg(feature = "network_tests")]
+    #[test]
+    fn play_once() {
+        use std::env;
+
+        let opts = crate::cli::command_line_argument_options();
+        let matches = opts
+            .parse(&[
+                "--username",
+                &env::var("SPOTIFYD_TEST_USERNAME").unwrap(),
+                "--password",
+                &env::var("SPOTIFYD_TEST_PASSWORD").unwrap(),
+                "--backend",
+                "pipe",
+                "--device",
+                "/dev/null",
+            ])
+            .unwrap();
+        let config = crate::config::get_config::<&str>(&[], &matches).unwrap();
+        let outcome = run(
+            config,
+            "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+            Duration::from_secs(5),
+        );
+        assert_eq!(outcome, Outcome::Played);
+    }
+}
@@ -0,0 +1,159 @@
+use ini::Ini;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config_format;
+use crate::error::{Error, ErrorKind};
+
+/// Presets shipped with spotifyd. A file with the same name in a preset directory
+/// takes precedence.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("party", "[global]\nbitrate = 320\n"),
+    ("low-bandwidth", "[global]\nbitrate = 96\n"),
+];
+
+/// The extensions of preset files, in the order they're looked for. Either can hold
+/// TOML or INI, which is told apart as for the config file.
+const EXTENSIONS: &[&str] = &["toml", "conf"];
+
+/// The directories presets are looked up in, most important first.
+pub(crate) fn preset_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(xdg_dirs) = xdg::BaseDirectories::with_prefix("spotifyd") {
+        dirs.push(xdg_dirs.get_config_home().join("presets"));
+    }
+    dirs.push(PathBuf::from("/etc/spotifyd/presets"));
+    dirs
+}
+
+/// Loads the preset with the given name, from `<dir>/<name>.toml`, `<dir>/<name>.conf`
+/// or the built-in ones.
+pub(crate) fn load_preset<P: AsRef<Path>>(name: &str, dirs: &[P]) -> Result<Ini, Error> {
+    let invalid = |expected| ErrorKind::ConfigValueInvalid {
+        key: "preset",
+        value: name.to_string(),
+        expected,
+    };
+    if name.is_empty() || name.contains('/') {
+        return Err(invalid("the name of a preset").into());
+    }
+    for dir in dirs {
+        for extension in EXTENSIONS {
+            let path = dir.as_ref().join(format!("{}.{}", name, extension));
+            if path.is_file() {
+                return fs::read_to_string(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|contents| config_format::detect(&path, &contents).parse(&contents))
+                    .map_err(|msg| ErrorKind::ConfigFileInvalid { path, msg }.into());
+            }
+        }
+    }
+    match BUILT_IN.iter().find(|&&(preset, _)| preset == name) {
+        Some(&(_, preset)) => Ok(Ini::load_from_str(preset).unwrap()),
+        None => Err(invalid("one of the presets listed by --list-presets").into()),
+    }
+}
+
+/// Returns the names of all presets, sorted.
+pub(crate) fn list_presets<P: AsRef<Path>>(dirs: &[P]) -> Vec<String> {
+    let mut presets: Vec<String> = BUILT_IN.iter().map(|&(name, _)| name.to_string()).collect();
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.extension().map_or(false, |extension| {
+                EXTENSIONS.iter().any(|&known| extension == known)
+            }) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    presets.push(name.to_string());
+                }
+            }
+        }
+    }
+    presets.sort();
+    presets.dedup();
+    presets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    fn preset_dir(name: &str) -> PathBuf {
+        let dir = temp_path(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn bitrate(preset: &Ini) -> Option<&str> {
+        preset
+            .section(Some("global".to_owned()))
+            .and_then(|global| global.get("bitrate"))
+            .map(String::as_str)
+    }
+
+    #[test]
+    fn preset_precedence() {
+        let none: &[PathBuf] = &[];
+        assert_eq!(bitrate(&load_preset("party", none).unwrap()), Some("320"));
+
+        let dir = preset_dir("precedence");
+        fs::write(dir.join("party.conf"), "[global]\nbitrate = 160\n").unwrap();
+        assert_eq!(
+            bitrate(&load_preset("party", &[&dir]).unwrap()),
+            Some("160")
+        );
+
+        for name in &["disco", "", "../party"] {
+            assert!(load_preset(name, &[&dir]).is_err(), "{:?}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn toml_presets() {
+        let dir = preset_dir("toml");
+        fs::write(dir.join("kitchen.toml"), "bitrate = 160\n").unwrap();
+        assert_eq!(
+            bitrate(&load_preset("kitchen", &[&dir]).unwrap()),
+            Some("160")
+        );
+        // Arrays make a .conf preset TOML as well.
+        fs::write(
+            dir.join("quiet.conf"),
+            "bitrate = 96\nonevent_filter = [\"stop\"]\n",
+        )
+        .unwrap();
+        assert_eq!(bitrate(&load_preset("quiet", &[&dir]).unwrap()), Some("96"));
+
+        fs::write(dir.join("broken.toml"), "bitrate = \n").unwrap();
+        let err = load_preset("broken", &[&dir]).unwrap_err().to_string();
+        assert!(err.contains("broken.toml"), "{}", err);
+        assert!(!err.contains("can be read and parsed"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn list() {
+        let dir = preset_dir("list");
+        fs::write(dir.join("kitchen.conf"), "[global]\n").unwrap();
+        fs::write(dir.join("party.conf"), "[global]\n").unwrap();
+        fs::write(dir.join("quiet.toml"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        let missing = dir.join("missing");
+        assert_eq!(
+            list_presets(&[&dir, &missing]),
+            ["kitchen", "low-bandwidth", "party", "quiet"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use log::{info, warn};
+use std::{io, mem};
+
+/// Runs `set`, which changes how spotifyd is scheduled, and warns if it fails, most
+/// likely for a lack of privileges. spotifyd keeps running as it was then. Returns
+/// whether it worked.
+fn apply<F: FnOnce() -> io::Result<()>>(what: &str, set: F) -> bool {
+    match set() {
+        Ok(()) => {
+            info!("Set the {}.", what);
+            true
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(
+                "Couldn't set the {}, which needs root or CAP_SYS_NICE: {}",
+                what, e
+            );
+            false
+        }
+        Err(e) => {
+            warn!("Couldn't set the {}: {}", what, e);
+            false
+        }
+    }
+}
+
+/// Sets the niceness of spotifyd, from -20 for the highest priority to 19. On Linux it
+/// only applies to the calling thread and the threads it starts afterwards, so this has
+/// to be called before any are started.
+pub(crate) fn set_nice(nice: i32) -> bool {
+    apply(&format!("niceness to {}", nice), || {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    })
+}
+
+/// Schedules the calling thread with SCHED_FIFO at `priority`, from 1 to 99, so that
+/// other processes can't keep it from filling the audio buffer.
+pub(crate) fn set_rt_priority(priority: i32) -> bool {
+    apply(
+        &format!("real-time priority of the audio thread to {}", priority),
+        || {
+            let mut param: libc::sched_param = unsafe { mem::zeroed() };
+            param.sched_priority = priority;
+            match unsafe {
+                libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param)
+            } {
+                0 => Ok(()),
+                e => Err(io::Error::from_raw_os_error(e)),
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn failures_are_not_fatal() {
+        assert!(apply("test priority", || Ok(())));
+        for &errno in &[libc::EPERM, libc::EINVAL] {
+            let failed = apply("test priority", || Err(io::Error::from_raw_os_error(errno)));
+            assert!(!failed);
+        }
+        // Out of range for SCHED_FIFO, which fails whatever the privileges.
+        let out_of_range = thread::spawn(|| set_rt_priority(1000)).join().unwrap();
+        assert!(!out_of_range);
+    }
+}
@@ -1,8 +1,8 @@
 use librespot::playback::player::PlayerEvent;
-use log::info;
+use log::{info, warn};
 use std::io::{self, Read, Write};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     process::{Command, ExitStatus, Stdio},
 };
 
@@ -44,36 +44,128 @@ fn spawn_program(shell: &str, cmd: &str, env: HashMap<&str, String>) -> Result<C
     Ok(child)
 }
 
+/// Names of the player events, as passed to the `onevent` program in `PLAYER_EVENT`.
+pub(crate) const PLAYER_EVENTS: &[&str] = &["change", "start", "stop"];
+
+fn player_event_name(event: &PlayerEvent) -> &'static str {
+    match event {
+        PlayerEvent::Changed { .. } => "change",
+        PlayerEvent::Started { .. } => "start",
+        PlayerEvent::Stopped { .. } => "stop",
+    }
+}
+
+/// Returns whether the `onevent` program should run for `event`, given the list of
+/// selected event names. No list selects all events.
+pub(crate) fn is_event_selected(filter: Option<&[&str]>, event: &PlayerEvent) -> bool {
+    filter.map_or(true, |names| names.contains(&player_event_name(event)))
+}
+
 /// Spawns provided command in a subprocess using the provided shell.
 /// Various environment variables are included in the subprocess's environment
 /// depending on the `PlayerEvent` that was passed in.
-pub(crate) fn spawn_program_on_event(
-    shell: &str,
-    cmd: &str,
-    event: PlayerEvent,
-) -> Result<Child, Error> {
+fn spawn_program_on_event(shell: &str, cmd: &str, event: PlayerEvent) -> Result<Child, Error> {
     let mut env = HashMap::new();
+    env.insert("PLAYER_EVENT", player_event_name(&event).to_string());
     match event {
         PlayerEvent::Changed {
             old_track_id,
             new_track_id,
         } => {
             env.insert("OLD_TRACK_ID", old_track_id.to_base62());
-            env.insert("PLAYER_EVENT", "change".to_string());
             env.insert("TRACK_ID", new_track_id.to_base62());
         }
-        PlayerEvent::Started { track_id } => {
-            env.insert("PLAYER_EVENT", "start".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-        }
-        PlayerEvent::Stopped { track_id } => {
-            env.insert("PLAYER_EVENT", "stop".to_string());
+        PlayerEvent::Started { track_id } | PlayerEvent::Stopped { track_id } => {
             env.insert("TRACK_ID", track_id.to_base62());
         }
     }
     spawn_program(shell, cmd, env)
 }
 
+/// The `onevent` programs that are currently running.
+pub(crate) struct EventPrograms {
+    children: Vec<Child>,
+    max_procs: Option<usize>,
+    /// Events waiting for the running program to exit, without `max_procs`.
+    pending: VecDeque<PlayerEvent>,
+}
+
+impl EventPrograms {
+    /// Without `max_procs`, programs run one at a time and events are held back while
+    /// one is running. With `max_procs`, up to that many programs run at once and events
+    /// arriving while all of them are busy are dropped.
+    pub(crate) fn new(max_procs: Option<usize>) -> Self {
+        Self {
+            children: Vec::new(),
+            max_procs,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.children.len() >= self.max_procs.unwrap_or(1)
+    }
+
+    /// Returns whether events are waiting for a program to exit.
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Forgets about programs that have exited, returning the errors of those that failed.
+    pub(crate) fn reap(&mut self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for mut child in std::mem::replace(&mut self.children, Vec::new()) {
+            match child.try_wait() {
+                // Still running...
+                Ok(None) => self.children.push(child),
+                // Exited with error...
+                Err(e) => errors.push(e),
+                // Exited without error...
+                Ok(Some(_)) => (),
+            }
+        }
+        errors
+    }
+
+    /// Runs `cmd` for `event`, unless too many programs are running already, in which
+    /// case the event waits or is dropped. Returns whether the program was started.
+    pub(crate) fn spawn(
+        &mut self,
+        shell: &str,
+        cmd: &str,
+        event: PlayerEvent,
+    ) -> Result<bool, Error> {
+        if self.max_procs.is_none() && (self.is_full() || self.has_pending()) {
+            self.pending.push_back(event);
+            return Ok(false);
+        }
+        if self.is_full() {
+            warn!(
+                "{} onevent commands are still running, dropping {} event.",
+                self.children.len(),
+                player_event_name(&event)
+            );
+            return Ok(false);
+        }
+        self.children
+            .push(spawn_program_on_event(shell, cmd, event)?);
+        Ok(true)
+    }
+
+    /// Runs `cmd` for the events that waited, as long as there's room.
+    pub(crate) fn run_pending(&mut self, shell: &str, cmd: &str) -> Result<(), Error> {
+        while !self.is_full() {
+            match self.pending.pop_front() {
+                Some(event) => self
+                    .children
+                    .push(spawn_program_on_event(shell, cmd, event)?),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Same as a `std::process::Child` except when this `Child` exits:
 /// * successfully: It writes the contents of it's stdout to the stdout of the main process.
 /// * unsuccesfully: It returns an error that includes the contents it's stderr as well as
@@ -164,3 +256,71 @@ impl From<Child> for std::process::Child {
         child.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot::core::spotify_id::SpotifyId;
+
+    #[test]
+    fn event_programs_cap() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let event = || PlayerEvent::Started { track_id };
+
+        let mut programs = EventPrograms::new(Some(2));
+        assert!(programs.spawn("sh", "sleep 1", event()).unwrap());
+        assert!(programs.spawn("sh", "sleep 1", event()).unwrap());
+        // Both slots are taken, so the event is dropped rather than waited for.
+        assert!(!programs.spawn("sh", "sleep 1", event()).unwrap());
+        assert!(!programs.has_pending());
+        assert_eq!(programs.children.len(), 2);
+
+        for mut child in programs.children.drain(..) {
+            child.wait().unwrap();
+        }
+        assert!(programs.reap().is_empty());
+        assert!(programs.spawn("sh", "exit 1", event()).unwrap());
+        programs.children[0].inner.wait().unwrap();
+        assert_eq!(programs.reap().len(), 1);
+    }
+
+    #[test]
+    fn event_programs_queue() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+
+        let mut programs = EventPrograms::new(None);
+        assert!(programs
+            .spawn("sh", "sleep 1", PlayerEvent::Started { track_id })
+            .unwrap());
+        // The slot is taken, so the event waits instead of being dropped.
+        assert!(!programs
+            .spawn("sh", "exit 1", PlayerEvent::Stopped { track_id })
+            .unwrap());
+        assert!(programs.has_pending());
+        programs.run_pending("sh", "exit 1").unwrap();
+        assert_eq!(programs.children.len(), 1);
+
+        programs.children[0].inner.wait().unwrap();
+        assert!(programs.reap().is_empty());
+        programs.run_pending("sh", "exit 1").unwrap();
+        assert!(!programs.has_pending());
+        programs.children[0].inner.wait().unwrap();
+        assert_eq!(programs.reap().len(), 1);
+    }
+
+    #[test]
+    fn event_filter() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let started = PlayerEvent::Started { track_id };
+        let changed = PlayerEvent::Changed {
+            old_track_id: track_id,
+            new_track_id: track_id,
+        };
+
+        assert!(is_event_selected(None, &started));
+        assert!(is_event_selected(None, &changed));
+        assert!(!is_event_selected(Some(&["change"]), &started));
+        assert!(is_event_selected(Some(&["change"]), &changed));
+        assert!(is_event_selected(Some(&["stop", "start"]), &started));
+    }
+}
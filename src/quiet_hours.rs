@@ -0,0 +1,162 @@
+use chrono::{Local, NaiveTime};
+use librespot::playback::mixer::{softmixer::SoftMixer, AudioFilter, Mixer, MixerConfig};
+use log::info;
+use std::{
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::Duration,
+};
+
+/// How often to check whether the quiet hours have started.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A daily window during which the volume is capped. The window starts at `start` and
+/// ends right before `end`, and crosses midnight if `end` is earlier than `start`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct QuietHours {
+    pub(crate) start: NaiveTime,
+    pub(crate) end: NaiveTime,
+    pub(crate) max_volume: u16,
+}
+
+impl QuietHours {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    fn clamp(&self, volume: u16, time: NaiveTime) -> u16 {
+        if self.contains(time) {
+            volume.min(self.max_volume)
+        } else {
+            volume
+        }
+    }
+}
+
+/// Caps the volume of another mixer during the quiet hours. Volume changes are clamped
+/// as they happen, and a louder volume is turned down when the quiet hours start.
+pub struct QuietMixer {
+    mixer: Arc<Mutex<Box<dyn Mixer>>>,
+    quiet_hours: QuietHours,
+}
+
+impl QuietMixer {
+    pub(crate) fn new(mixer: Box<dyn Mixer>, quiet_hours: QuietHours) -> QuietMixer {
+        let mixer = Arc::new(Mutex::new(mixer));
+        let watched = Arc::downgrade(&mixer);
+        thread::spawn(move || watch(&watched, quiet_hours));
+        QuietMixer { mixer, quiet_hours }
+    }
+}
+
+/// Turns the volume down whenever the quiet hours start, until the mixer is dropped.
+fn watch(watched: &Weak<Mutex<Box<dyn Mixer>>>, quiet_hours: QuietHours) {
+    let mut was_quiet = false;
+    while let Some(mixer) = watched.upgrade() {
+        let now = Local::now().time();
+        let is_quiet = quiet_hours.contains(now);
+        if is_quiet && !was_quiet {
+            let mixer = mixer.lock().unwrap();
+            let volume = mixer.volume();
+            if volume > quiet_hours.max_volume {
+                info!("Quiet hours started, turning the volume down.");
+                mixer.set_volume(quiet_hours.max_volume);
+            }
+        }
+        was_quiet = is_quiet;
+        drop(mixer);
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+impl Mixer for QuietMixer {
+    /// spotifyd wraps its mixers itself. This only satisfies the trait, with quiet hours
+    /// that never start.
+    fn open(config: Option<MixerConfig>) -> QuietMixer {
+        let midnight = NaiveTime::from_hms(0, 0, 0);
+        QuietMixer::new(
+            Box::new(SoftMixer::open(config)),
+            QuietHours {
+                start: midnight,
+                end: midnight,
+                max_volume: u16::max_value(),
+            },
+        )
+    }
+
+    fn start(&self) {
+        self.mixer.lock().unwrap().start()
+    }
+
+    fn stop(&self) {
+        self.mixer.lock().unwrap().stop()
+    }
+
+    fn volume(&self) -> u16 {
+        self.mixer.lock().unwrap().volume()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let clamped = self.quiet_hours.clamp(volume, Local::now().time());
+        if clamped < volume {
+            info!("Capping the volume during quiet hours.");
+        }
+        self.mixer.lock().unwrap().set_volume(clamped)
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
+        self.mixer.lock().unwrap().get_audio_filter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms(h, m, 0)
+    }
+
+    fn quiet_hours(start: NaiveTime, end: NaiveTime) -> QuietHours {
+        QuietHours {
+            start,
+            end,
+            max_volume: 0x4000,
+        }
+    }
+
+    #[test]
+    fn clamp_in_window() {
+        let quiet = quiet_hours(time(13, 0), time(15, 0));
+        assert_eq!(quiet.clamp(0xFFFF, time(14, 0)), 0x4000);
+        assert_eq!(quiet.clamp(0x1000, time(14, 0)), 0x1000);
+        assert_eq!(quiet.clamp(0xFFFF, time(16, 0)), 0xFFFF);
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        let quiet = quiet_hours(time(22, 0), time(7, 0));
+        assert!(quiet.contains(time(23, 30)));
+        assert!(quiet.contains(time(0, 0)));
+        assert!(quiet.contains(time(6, 59)));
+        assert!(!quiet.contains(time(12, 0)));
+        assert!(!quiet.contains(time(21, 59)));
+    }
+
+    #[test]
+    fn window_boundaries() {
+        for quiet in &[
+            quiet_hours(time(22, 0), time(7, 0)),
+            quiet_hours(time(1, 0), time(22, 0)),
+        ] {
+            assert!(quiet.contains(quiet.start), "{:?}", quiet);
+            assert!(!quiet.contains(quiet.end), "{:?}", quiet);
+        }
+        let never = quiet_hours(time(8, 0), time(8, 0));
+        assert!(!never.contains(time(8, 0)));
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    cmp, fmt, io,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::backoff::{Backoff, Delays};
+
+/// The longest wait between connection attempts while they keep failing, unless the
+/// configured cooldown is longer.
+const MAX_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+
+/// Waits `cooldown` before the first retry and doubles the wait after every failure.
+/// There's no jitter, so that `cooldown` stays the minimum time between attempts.
+pub(crate) fn backoff(cooldown: Duration) -> Backoff {
+    Backoff::new(cooldown, 2.0, cmp::max(cooldown, MAX_COOLDOWN), 0.0)
+}
+
+/// What to do when Spotify rejects the credentials, which retrying won't change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AuthFailurePolicy {
+    /// Exit with a distinct status.
+    Exit,
+    /// Keep running without retrying, until new credentials arrive through Spotify
+    /// Connect.
+    Wait,
+}
+
+impl FromStr for AuthFailurePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "exit" => Ok(AuthFailurePolicy::Exit),
+            "wait" => Ok(AuthFailurePolicy::Wait),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for AuthFailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthFailurePolicy::Exit => write!(f, "exit"),
+            AuthFailurePolicy::Wait => write!(f, "wait"),
+        }
+    }
+}
+
+/// Whether a failed connection attempt failed because Spotify rejected the credentials
+/// or the account, rather than because of the network. librespot reports those as
+/// PermissionDenied.
+pub(crate) fn is_auth_error(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::PermissionDenied
+}
+
+/// Keeps track of connection attempts, so that reconnecting after a failure never
+/// hammers Spotify's servers.
+pub(crate) struct Reconnect {
+    max_attempts: Option<u32>,
+    backoff: Backoff,
+    /// The waits before the next retries, started over once an attempt succeeds.
+    delays: Delays,
+    on_auth_failure: AuthFailurePolicy,
+    failed_attempts: u32,
+    last_attempt: Instant,
+}
+
+/// What to do after a failed connection attempt.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Next {
+    /// Start the next attempt after the given delay.
+    Retry(Duration),
+    /// Stop trying, the maximum number of attempts has been reached.
+    GiveUp,
+    /// Stop trying and exit, Spotify rejected the credentials.
+    Exit,
+    /// Stop trying, but wait for new credentials.
+    Wait,
+}
+
+impl Reconnect {
+    /// Creates a new `Reconnect`, assuming the first attempt is started right away.
+    pub(crate) fn new(
+        max_attempts: Option<u32>,
+        backoff: Backoff,
+        on_auth_failure: AuthFailurePolicy,
+    ) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            delays: backoff.delays(),
+            on_auth_failure,
+            failed_attempts: 0,
+            last_attempt: Instant::now(),
+        }
+    }
+
+    pub(crate) fn failed_attempts(&self) -> u32 {
+        self.failed_attempts
+    }
+
+    /// Records that a connection attempt was started at `now`.
+    pub(crate) fn attempt(&mut self, now: Instant) {
+        self.last_attempt = now;
+    }
+
+    /// Records that the current attempt failed at `now`. Consecutive attempts are started
+    /// at least the next delay of the backoff apart.
+    pub(crate) fn failed(&mut self, now: Instant) -> Next {
+        self.failed_attempts += 1;
+        if let Some(max_attempts) = self.max_attempts {
+            if self.failed_attempts > max_attempts {
+                return Next::GiveUp;
+            }
+        }
+        let delay = self.delays.next().unwrap_or_default();
+        let elapsed = now.duration_since(self.last_attempt);
+        Next::Retry(delay.checked_sub(elapsed).unwrap_or_default())
+    }
+
+    /// Records that the current attempt failed with `error` at `now`. Rejected
+    /// credentials aren't retried, as they'd only be rejected again.
+    pub(crate) fn failed_with(&mut self, error: &io::Error, now: Instant) -> Next {
+        if !is_auth_error(error) {
+            return self.failed(now);
+        }
+        self.failed_attempts += 1;
+        match self.on_auth_failure {
+            AuthFailurePolicy::Exit => Next::Exit,
+            AuthFailurePolicy::Wait => Next::Wait,
+        }
+    }
+
+    /// Records that the current attempt succeeded, which resets the attempt counter and
+    /// the backoff.
+    pub(crate) fn succeeded(&mut self) {
+        self.failed_attempts = 0;
+        self.delays = self.backoff.delays();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The same wait before every retry.
+    fn constant(secs: u64) -> Backoff {
+        let delay = Duration::from_secs(secs);
+        Backoff::new(delay, 1.0, delay, 0.0)
+    }
+
+    #[test]
+    fn attempt_counter() {
+        let mut reconnect = Reconnect::new(None, constant(0), AuthFailurePolicy::Exit);
+        let now = Instant::now();
+        for _ in 0..3 {
+            reconnect.attempt(now);
+            assert_eq!(reconnect.failed(now), Next::Retry(Duration::from_secs(0)));
+        }
+        assert_eq!(reconnect.failed_attempts(), 3);
+        reconnect.succeeded();
+        assert_eq!(reconnect.failed_attempts(), 0);
+    }
+
+    #[test]
+    fn cooldown() {
+        let mut reconnect = Reconnect::new(None, constant(10), AuthFailurePolicy::Exit);
+        let start = Instant::now();
+        reconnect.attempt(start);
+        assert_eq!(
+            reconnect.failed(start + Duration::from_secs(3)),
+            Next::Retry(Duration::from_secs(7))
+        );
+        // An attempt that took longer than the cooldown is retried right away.
+        reconnect.attempt(start + Duration::from_secs(10));
+        assert_eq!(
+            reconnect.failed(start + Duration::from_secs(25)),
+            Next::Retry(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn growing_cooldown() {
+        let mut reconnect = Reconnect::new(
+            None,
+            backoff(Duration::from_secs(10)),
+            AuthFailurePolicy::Exit,
+        );
+        let now = Instant::now();
+        let mut retries = Vec::new();
+        for _ in 0..7 {
+            reconnect.attempt(now);
+            match reconnect.failed(now) {
+                Next::Retry(delay) => retries.push(delay.as_secs()),
+                next => panic!("{:?}", next),
+            }
+        }
+        assert_eq!(retries, [10, 20, 40, 80, 160, 300, 300]);
+
+        // A successful attempt starts the backoff over.
+        reconnect.succeeded();
+        reconnect.attempt(now);
+        assert_eq!(reconnect.failed(now), Next::Retry(Duration::from_secs(10)));
+
+        // A cooldown above the cap is kept.
+        let mut reconnect = Reconnect::new(
+            None,
+            backoff(Duration::from_secs(600)),
+            AuthFailurePolicy::Exit,
+        );
+        reconnect.attempt(now);
+        assert_eq!(reconnect.failed(now), Next::Retry(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn give_up() {
+        let mut reconnect = Reconnect::new(Some(2), constant(1), AuthFailurePolicy::Exit);
+        let now = Instant::now();
+        reconnect.attempt(now);
+        assert_eq!(reconnect.failed(now), Next::Retry(Duration::from_secs(1)));
+        reconnect.attempt(now);
+        assert_eq!(reconnect.failed(now), Next::Retry(Duration::from_secs(1)));
+        reconnect.attempt(now);
+        assert_eq!(reconnect.failed(now), Next::GiveUp);
+    }
+
+    #[test]
+    fn error_classification() {
+        let error = |kind, message| io::Error::new(kind, message);
+        assert!(is_auth_error(&error(
+            io::ErrorKind::PermissionDenied,
+            "Authentication failed with reason: BadCredentials"
+        )));
+        assert!(is_auth_error(&error(
+            io::ErrorKind::PermissionDenied,
+            "Authentication failed with reason: PremiumAccountRequired"
+        )));
+        for &kind in &[
+            io::ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::TimedOut,
+            io::ErrorKind::UnexpectedEof,
+            io::ErrorKind::Other,
+        ] {
+            assert!(!is_auth_error(&error(kind, "network")), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn auth_failure_policy() {
+        let rejected = io::Error::new(io::ErrorKind::PermissionDenied, "Authentication failed");
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "Connection refused");
+        let now = Instant::now();
+
+        let mut reconnect = Reconnect::new(None, constant(1), AuthFailurePolicy::Exit);
+        reconnect.attempt(now);
+        assert_eq!(
+            reconnect.failed_with(&refused, now),
+            Next::Retry(Duration::from_secs(1))
+        );
+        assert_eq!(reconnect.failed_with(&rejected, now), Next::Exit);
+
+        let mut reconnect = Reconnect::new(Some(5), constant(1), AuthFailurePolicy::Wait);
+        reconnect.attempt(now);
+        assert_eq!(reconnect.failed_with(&rejected, now), Next::Wait);
+        assert_eq!(reconnect.failed_attempts(), 1);
+
+        assert_eq!(
+            AuthFailurePolicy::from_str("Wait"),
+            Ok(AuthFailurePolicy::Wait)
+        );
+        assert_eq!(AuthFailurePolicy::Exit.to_string(), "exit");
+        assert!(AuthFailurePolicy::from_str("retry").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use futures::{future, Future};
+use librespot::core::{keymaster::get_token, session::Session};
+use log::{error, info, warn};
+use rspotify::spotify::{client::Spotify, model::offset::Offset};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+};
+
+const SCOPE: &str = "user-read-playback-state,user-modify-playback-state";
+
+/// The name of the file in the cache directory that holds where playback was.
+pub(crate) const FILE_NAME: &str = "resume";
+
+/// Where playback was, to pick up from after a restart.
+#[derive(Clone, Debug, PartialEq)]
+struct Position {
+    /// The playlist, album or show the track was played from, if any.
+    context: Option<String>,
+    track: String,
+    position_ms: u32,
+}
+
+impl Position {
+    /// Parses lines of a name and a value, separated by a tab. Without a track, there's
+    /// nothing to resume.
+    fn parse(contents: &str) -> Option<Position> {
+        let mut context = None;
+        let mut track = None;
+        let mut position_ms = 0;
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, '\t');
+            match (fields.next(), fields.next()) {
+                (Some("context"), Some(uri)) => context = Some(uri.to_string()),
+                (Some("track"), Some(uri)) => track = Some(uri.to_string()),
+                (Some("position_ms"), Some(ms)) => position_ms = ms.parse().unwrap_or(0),
+                _ => (),
+            }
+        }
+        Some(Position {
+            context,
+            track: track?,
+            position_ms,
+        })
+    }
+
+    fn format(&self) -> String {
+        let mut contents = String::new();
+        if let Some(ref context) = self.context {
+            contents.push_str(&format!("context\t{}\n", context));
+        }
+        contents.push_str(&format!(
+            "track\t{}\nposition_ms\t{}\n",
+            self.track, self.position_ms
+        ));
+        contents
+    }
+}
+
+/// Reads the position saved at `path`, if there is one.
+fn load(path: &Path) -> Option<Position> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Position::parse(&contents),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Couldn't read where playback was from {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// What to start playback with: a track within a context, or the track on its own.
+#[derive(Debug, PartialEq)]
+struct Target {
+    context: Option<String>,
+    track: String,
+}
+
+/// The ways of getting back to `position`, most faithful first: the track within its
+/// context, then the track on its own, in case the context is gone, e.g. a playlist
+/// that was deleted.
+fn targets(position: &Position) -> Vec<Target> {
+    let mut targets = Vec::new();
+    if let Some(ref context) = position.context {
+        targets.push(Target {
+            context: Some(context.clone()),
+            track: position.track.clone(),
+        });
+    }
+    targets.push(Target {
+        context: None,
+        track: position.track.clone(),
+    });
+    targets
+}
+
+/// Starts the first of `targets` that `start` succeeds with, and returns it.
+fn start_first<F>(targets: Vec<Target>, mut start: F) -> Option<Target>
+where
+    F: FnMut(&Target) -> Result<(), String>,
+{
+    for target in targets {
+        match start(&target) {
+            Ok(()) => return Some(target),
+            Err(e) => warn!(
+                "Couldn't resume {}: {}",
+                target.context.as_ref().unwrap_or(&target.track),
+                e
+            ),
+        }
+    }
+    None
+}
+
+/// Keeps track of where playback is, to pick it up again, paused, after a restart.
+pub(crate) struct Resume {
+    path: PathBuf,
+    /// Where playback was when spotifyd last ran, until it's been resumed.
+    saved: Option<Position>,
+}
+
+impl Resume {
+    /// Reads where playback was from `path`. It's only resumed with `resume_saved`, as
+    /// nobody is around to log in at startup unless the credentials are cached.
+    pub(crate) fn new(path: PathBuf, resume_saved: bool) -> Resume {
+        let saved = if resume_saved { load(&path) } else { None };
+        Resume { path, saved }
+    }
+
+    /// Starts playback on the device with the given id where it was saved, and pauses it
+    /// there. This only happens once, so later sessions are left alone.
+    pub(crate) fn restore(
+        &mut self,
+        session: &Session,
+        client_id: &str,
+        device_id: String,
+    ) -> Option<Box<dyn Future<Item = (), Error = ()>>> {
+        let position = self.saved.take()?;
+        let path = self.path.clone();
+        Some(Box::new(
+            get_token(session, client_id, SCOPE)
+                .map_err(|e| error!("Couldn't get a token to resume playback: {:?}", e))
+                .and_then(move |token| {
+                    // The Web API client blocks, so keep it off the event loop.
+                    thread::spawn(move || {
+                        let spotify = Spotify::default().access_token(&token.access_token).build();
+                        let device = || Some(device_id.clone());
+                        let started = start_first(targets(&position), |target| {
+                            // Within a context, the track is where to start in it.
+                            let (uris, offset) = match target.context {
+                                Some(_) => (
+                                    None,
+                                    Some(Offset {
+                                        position: None,
+                                        uri: Some(target.track.clone()),
+                                    }),
+                                ),
+                                None => (Some(vec![target.track.clone()]), None),
+                            };
+                            spotify
+                                .start_playback(device(), target.context.clone(), uris, offset)
+                                .map_err(|e| e.to_string())
+                        });
+                        if started.is_none() {
+                            warn!("Forgetting where playback was, it can't be resumed.");
+                            if let Err(e) = fs::remove_file(&path) {
+                                warn!("Couldn't remove {:?}: {}", path, e);
+                            }
+                            return;
+                        }
+                        if let Err(e) = spotify.pause_playback(device()) {
+                            error!("Couldn't pause the resumed playback: {}", e);
+                        }
+                        if let Err(e) = spotify.seek_track(position.position_ms, device()) {
+                            error!("Couldn't seek to where playback was: {}", e);
+                        }
+                        info!(
+                            "Resumed {} at {}s, paused.",
+                            position.track,
+                            position.position_ms / 1000
+                        );
+                    });
+                    future::ok(())
+                }),
+        ))
+    }
+
+    /// Looks up where playback is through the Web API, as the player only reports the
+    /// track, and saves it.
+    pub(crate) fn save(
+        &self,
+        session: &Session,
+        client_id: &str,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        let path = self.path.clone();
+        Box::new(
+            get_token(session, client_id, SCOPE)
+                .map_err(|e| error!("Couldn't get a token to look up the playback: {:?}", e))
+                .and_then(move |token| {
+                    thread::spawn(move || {
+                        let spotify = Spotify::default().access_token(&token.access_token).build();
+                        let playback = match spotify.current_playback(None) {
+                            Ok(Some(playback)) => playback,
+                            Ok(None) => return,
+                            Err(e) => {
+                                warn!("Couldn't look up the current playback: {}", e);
+                                return;
+                            }
+                        };
+                        let track = match playback.item {
+                            Some(track) => track.uri,
+                            None => return,
+                        };
+                        let position = Position {
+                            context: playback.context.map(|context| context.uri),
+                            track,
+                            position_ms: playback.progress_ms.unwrap_or(0),
+                        };
+                        if let Err(e) = fs::write(&path, position.format()) {
+                            warn!("Couldn't save where playback is to {:?}: {}", path, e);
+                        }
+                    });
+                    future::ok(())
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    const ALBUM: &str = "spotify:album:1DFixLWuPkv3KT3TnV35m3";
+    const TRACK: &str = "spotify:track:6rqhFgbbKwnb9MLmUQDhG6";
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("resume");
+        let _ = fs::remove_file(&path);
+        assert_eq!(load(&path), None);
+        assert!(Resume::new(path.clone(), true).saved.is_none());
+
+        let position = Position {
+            context: Some(ALBUM.to_string()),
+            track: TRACK.to_string(),
+            position_ms: 93_500,
+        };
+        fs::write(&path, position.format()).unwrap();
+        assert_eq!(load(&path), Some(position.clone()));
+        assert_eq!(Resume::new(path.clone(), true).saved, Some(position));
+        // Without cached credentials nothing is resumed.
+        assert_eq!(Resume::new(path.clone(), false).saved, None);
+        fs::remove_file(path).unwrap();
+
+        let single = Position {
+            context: None,
+            track: TRACK.to_string(),
+            position_ms: 0,
+        };
+        assert_eq!(Position::parse(&single.format()), Some(single));
+        assert_eq!(Position::parse("context\tspotify:album:a\n"), None);
+    }
+
+    #[test]
+    fn missing_context() {
+        let position = Position {
+            context: Some(ALBUM.to_string()),
+            track: TRACK.to_string(),
+            position_ms: 1000,
+        };
+        let in_context = Target {
+            context: Some(ALBUM.to_string()),
+            track: TRACK.to_string(),
+        };
+        let alone = Target {
+            context: None,
+            track: TRACK.to_string(),
+        };
+        assert_eq!(
+            start_first(targets(&position), |_| Ok(())),
+            Some(in_context)
+        );
+
+        // The album is gone, so the track is played on its own.
+        let mut tried = 0;
+        let started = start_first(targets(&position), |target| {
+            tried += 1;
+            match target.context {
+                Some(_) => Err("Non existing id".to_string()),
+                None => Ok(()),
+            }
+        });
+        assert_eq!(started, Some(alone));
+        assert_eq!(tried, 2);
+        let single = Position {
+            context: None,
+            ..position.clone()
+        };
+        assert_eq!(targets(&single).len(), 1);
+
+        // And if the track is gone too, there's nothing left to resume.
+        assert_eq!(
+            start_first(targets(&position), |_| Err("Not found".to_string())),
+            None
+        );
+    }
+}
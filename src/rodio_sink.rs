@@ -0,0 +1,71 @@
+use librespot::playback::audio_backend::Sink;
+use log::{error, info};
+use std::{io, thread, time::Duration};
+
+/// How many of librespot's writes rodio may have queued up before `write` blocks. The
+/// player writes one decoded Vorbis packet at a time, about 20ms of audio, so this holds
+/// back roughly half a second.
+const MAX_QUEUED_WRITES: usize = 26;
+
+/// An audio backend built on rodio, which works wherever cpal does, without ALSA or PulseAudio.
+pub struct RodioSink {
+    /// Why the output device couldn't be opened, if it couldn't.
+    sink: Result<rodio::Sink, String>,
+}
+
+/// Opens the output device with the given name, or the default one. If there is no such
+/// device, the sink fails to start instead, so that neither the doctor nor the other
+/// backends of a fan-out are taken down with it.
+pub fn open(device: Option<String>) -> Box<dyn Sink> {
+    info!("Using rodio sink.");
+    let output = match device {
+        Some(name) => rodio::output_devices().find(|output| output.name() == name),
+        None => rodio::default_output_device(),
+    };
+    let sink = match output {
+        Some(output) => Ok(rodio::Sink::new(&output)),
+        None => {
+            let available = rodio::output_devices()
+                .map(|output| output.name())
+                .collect::<Vec<_>>();
+            let reason = format!(
+                "Couldn't find the audio output device. Available devices: {}.",
+                available.join(", ")
+            );
+            error!("{}", reason);
+            Err(reason)
+        }
+    };
+    Box::new(RodioSink { sink })
+}
+
+impl RodioSink {
+    fn sink(&self) -> io::Result<&rodio::Sink> {
+        self.sink
+            .as_ref()
+            .map_err(|reason| io::Error::new(io::ErrorKind::NotFound, reason.clone()))
+    }
+}
+
+impl Sink for RodioSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink()?.play();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.sink()?.pause();
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let sink = self.sink()?;
+        sink.append(rodio::buffer::SamplesBuffer::new(2, 44100, data));
+        // rodio plays whatever was appended in the background, so hold the player back
+        // once enough audio is queued up.
+        while sink.len() > MAX_QUEUED_WRITES {
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,718 @@
+/// The kind of value a config key takes. All values are strings in the config file, these
+/// describe what the strings have to look like.
+#[derive(Debug)]
+enum Type {
+    String,
+    Boolean,
+    Integer {
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    Number {
+        min: f64,
+        max: f64,
+    },
+    /// A duration such as "30s", see `config::parse_duration`.
+    Duration,
+    /// A string matching a regular expression.
+    Pattern(&'static str),
+    /// One of a fixed set of values. If they're all integers, so is the value.
+    Enum(&'static [&'static str]),
+}
+
+/// A key of the config file, as read by `config::get_config`.
+#[derive(Debug)]
+struct Key {
+    name: &'static str,
+    kind: Type,
+    default: Option<&'static str>,
+    description: &'static str,
+}
+
+const PERCENT: Type = Type::Integer {
+    min: Some(0),
+    max: Some(100),
+};
+const TIME: Type = Type::Pattern("^[0-9]{1,2}:[0-9]{2}$");
+
+macro_rules! key {
+    ($name:expr, $kind:expr, $default:expr, $description:expr) => {
+        Key {
+            name: $name,
+            kind: $kind,
+            default: $default,
+            description: $description,
+        }
+    };
+}
+
+const KEYS: &[Key] = &[
+    key!(
+        "username",
+        Type::String,
+        None,
+        "The Spotify account to log in with."
+    ),
+    key!(
+        "trim_username",
+        Type::Boolean,
+        Some("false"),
+        "Strip whitespace around the username."
+    ),
+    key!(
+        "allowed_users",
+        Type::String,
+        None,
+        "Comma-separated Spotify accounts that may use the device through discovery."
+    ),
+    key!(
+        "password",
+        Type::String,
+        None,
+        "The password of the Spotify account."
+    ),
+    key!(
+        "password_cmd",
+        Type::String,
+        None,
+        "A command that prints the password."
+    ),
+    key!(
+        "password_file",
+        Type::String,
+        None,
+        "A file holding the password."
+    ),
+    key!(
+        "use-keyring",
+        Type::Boolean,
+        Some("false"),
+        "Read the password from the system's keyring."
+    ),
+    key!(
+        "keyring_service",
+        Type::String,
+        Some("spotifyd"),
+        "The service of the keyring entry holding the password."
+    ),
+    key!(
+        "keyring_account",
+        Type::String,
+        None,
+        "The account of the keyring entry holding the password, the username by default."
+    ),
+    key!(
+        "backend",
+        Type::String,
+        None,
+        "The audio backend, see --backends. Several can be joined with +, each as name:device."
+    ),
+    key!("device", Type::String, None, "The audio device to play on."),
+    key!(
+        "device_blacklist",
+        Type::String,
+        None,
+        "Comma-separated devices to skip when picking one because device isn't set."
+    ),
+    key!(
+        "control",
+        Type::String,
+        None,
+        "The device for the mixer, if not the same as device."
+    ),
+    key!("mixer", Type::String, None, "The ALSA mixer to control."),
+    key!(
+        "startup_mixer_volume",
+        PERCENT,
+        None,
+        "Set the mixer to this percentage on startup."
+    ),
+    key!(
+        "volume-control",
+        Type::Enum(&["alsa", "alsa_linear", "softvol"]),
+        Some("softvol"),
+        "How the volume is controlled."
+    ),
+    key!(
+        "volume_backend",
+        Type::Enum(&["software", "hardware"]),
+        None,
+        "Which volume Spotify Connect changes, regardless of volume-control."
+    ),
+    key!(
+        "quiet_hours_start",
+        TIME,
+        None,
+        "When the quiet hours start, as HH:MM local time."
+    ),
+    key!(
+        "quiet_hours_end",
+        TIME,
+        None,
+        "When the quiet hours end, as HH:MM local time."
+    ),
+    key!(
+        "quiet_hours_max_volume",
+        PERCENT,
+        None,
+        "The highest volume percentage during the quiet hours."
+    ),
+    key!(
+        "device_name",
+        Type::String,
+        None,
+        "The name shown in Spotify Connect, Spotifyd@<hostname> by default."
+    ),
+    key!(
+        "strip_hostname_domain",
+        Type::Boolean,
+        Some("true"),
+        "Leave the domain out of the hostname in the default device name."
+    ),
+    key!(
+        "discovery_name",
+        Type::String,
+        None,
+        "The name advertised on the local network, device_name by default."
+    ),
+    key!(
+        "instance_policy",
+        Type::Enum(&["refuse", "takeover", "coexist"]),
+        Some("refuse"),
+        "What to do if another instance uses the device name."
+    ),
+    key!(
+        "announce_only_when_idle",
+        Type::Boolean,
+        Some("false"),
+        "Hide the device from discovery while it's playing."
+    ),
+    key!(
+        "bitrate",
+        Type::Enum(&["96", "160", "320"]),
+        Some("160"),
+        "The bitrate in kbit/s."
+    ),
+    key!(
+        "cache_path",
+        Type::String,
+        None,
+        "Where to cache audio files and credentials."
+    ),
+    key!(
+        "cache_dir_mode",
+        Type::Pattern("^0?[0-7]{3}$"),
+        None,
+        "Octal permissions of the cache directory, 0700 when it's created."
+    ),
+    key!(
+        "cache_expiry",
+        Type::Duration,
+        None,
+        "Remove cached audio files this long after they were downloaded."
+    ),
+    key!(
+        "onevent",
+        Type::String,
+        None,
+        "A command to run on playback events."
+    ),
+    key!(
+        "onevent_filter",
+        Type::String,
+        None,
+        "A comma-separated list of the events to run onevent for."
+    ),
+    key!(
+        "onevent_max_procs",
+        Type::Integer {
+            min: Some(1),
+            max: None
+        },
+        None,
+        "How many onevent commands may run at once."
+    ),
+    key!(
+        "volume-normalisation",
+        Type::Boolean,
+        Some("false"),
+        "Normalize the volume of tracks."
+    ),
+    key!(
+        "normalisation-pregain",
+        Type::Number {
+            min: -100.0,
+            max: 100.0
+        },
+        None,
+        "The pregain in dB applied by volume normalisation."
+    ),
+    key!(
+        "fade_in_ms",
+        Type::Integer {
+            min: Some(0),
+            max: None
+        },
+        Some("0"),
+        "Fade in over this many milliseconds when playback starts or resumes."
+    ),
+    key!(
+        "fade_out_ms",
+        Type::Integer {
+            min: Some(0),
+            max: None
+        },
+        Some("0"),
+        "Fade out over this many milliseconds before pausing."
+    ),
+    key!(
+        "playback_speed",
+        Type::Number { min: 0.5, max: 3.0 },
+        Some("1"),
+        "Plays everything at this speed, keeping the pitch."
+    ),
+    key!(
+        "eq_bands",
+        Type::Pattern(r"^\s*([0-9.]+/-?[0-9.]+/[0-9.]+\s*(,\s*|$))*$"),
+        None,
+        "Equalizer bands as comma-separated frequency/gain/Q triples."
+    ),
+    key!(
+        "master_gain",
+        Type::Number {
+            min: -60.0,
+            max: 6.0
+        },
+        None,
+        "A gain in dB applied on top of the volume."
+    ),
+    key!(
+        "peak_limiter",
+        Type::Boolean,
+        Some("false"),
+        "Soften peaks instead of letting them clip."
+    ),
+    key!(
+        "peak_limiter_threshold",
+        Type::Number {
+            min: -20.0,
+            max: 0.0
+        },
+        Some("-1"),
+        "The level in dB above which the peak limiter works."
+    ),
+    key!(
+        "mono",
+        Type::Boolean,
+        Some("false"),
+        "Mix both channels down."
+    ),
+    key!(
+        "channel_map",
+        Type::Enum(&["L,R", "R,L", "L,L", "R,R"]),
+        None,
+        "What to play on the left and right output."
+    ),
+    key!(
+        "max_track_length",
+        Type::Duration,
+        None,
+        "Skip tracks longer than this."
+    ),
+    key!(
+        "device_close_delay",
+        Type::Duration,
+        Some("0"),
+        "Keep the audio device open this long after pausing."
+    ),
+    key!(
+        "remember_context_volume",
+        Type::Boolean,
+        Some("false"),
+        "Restore the volume last used for each playlist, album or show."
+    ),
+    key!(
+        "persist_stats",
+        Type::Boolean,
+        Some("false"),
+        "Add up the playback stats of all sessions in the cache directory."
+    ),
+    key!(
+        "resume_on_start",
+        Type::Boolean,
+        Some("false"),
+        "Pick up, paused, where playback was when spotifyd last ran."
+    ),
+    key!("pid", Type::String, None, "Where to write the PID file."),
+    key!(
+        "nice",
+        Type::Integer {
+            min: Some(-20),
+            max: Some(19)
+        },
+        None,
+        "The niceness to run at, lower being a higher priority."
+    ),
+    key!(
+        "rt_priority",
+        Type::Integer {
+            min: Some(1),
+            max: Some(99)
+        },
+        None,
+        "The real-time (SCHED_FIFO) priority of the audio thread."
+    ),
+    key!(
+        "mlock",
+        Type::Boolean,
+        Some("false"),
+        "Lock all memory into RAM, so that none of it is paged out."
+    ),
+    key!(
+        "shell",
+        Type::String,
+        None,
+        "The shell to run password_cmd and onevent with."
+    ),
+    key!(
+        "client_id",
+        Type::Pattern("^([0-9a-fA-F]{32}|[0-9a-fA-F]{8}(-[0-9a-fA-F]{4}){3}-[0-9a-fA-F]{12})$"),
+        Some("2c1ea588dfbc4a989e2426f8385297c3"),
+        "The Spotify client id to request Web API tokens for."
+    ),
+    key!(
+        "use_mpris",
+        Type::Enum(&["true", "false", "required"]),
+        Some("true"),
+        "Whether to register the MPRIS interface on D-Bus."
+    ),
+    key!(
+        "mpris_instance",
+        Type::String,
+        None,
+        "\"pid\" or a suffix for the MPRIS bus name."
+    ),
+    key!(
+        "dbus_type",
+        Type::Enum(&["session", "system"]),
+        Some("session"),
+        "The bus to register MPRIS on."
+    ),
+    key!(
+        "artist_separator",
+        Type::String,
+        Some(", "),
+        "Joins the artists of a track for display, \"first|rest\" for two separators."
+    ),
+    key!(
+        "mpris_identity",
+        Type::String,
+        None,
+        "The name media controls show for the MPRIS interface, the device name by default."
+    ),
+    key!(
+        "mpris_desktop_entry",
+        Type::Pattern(r"^[^/\s]+$"),
+        Some("spotifyd"),
+        "The .desktop file media controls take the icon of the MPRIS interface from."
+    ),
+    key!(
+        "max_reconnect_attempts",
+        Type::Integer {
+            min: Some(0),
+            max: None
+        },
+        None,
+        "Give up after this many failed reconnects."
+    ),
+    key!(
+        "reconnect_cooldown",
+        Type::Duration,
+        Some("10s"),
+        "The time between connection attempts, doubled after each failure up to five minutes."
+    ),
+    key!(
+        "connect_timeout",
+        Type::Duration,
+        None,
+        "Retry connection attempts that stall for this long."
+    ),
+    key!(
+        "on_auth_failure",
+        Type::Enum(&["exit", "wait"]),
+        Some("exit"),
+        "What to do when Spotify rejects the credentials."
+    ),
+    key!(
+        "heartbeat_interval",
+        Type::Duration,
+        None,
+        "Log that spotifyd is alive this often."
+    ),
+    key!(
+        "log_rate_limit",
+        Type::Pattern(r"^[0-9]+(/[0-9]+ ?(ms|s|m|h|d)?)?$"),
+        None,
+        "How many log lines to write per interval, e.g. 5/30s."
+    ),
+    key!(
+        "autostart_uri",
+        Type::String,
+        None,
+        "A Spotify URI to play on startup."
+    ),
+    key!(
+        "autostart_volume",
+        PERCENT,
+        None,
+        "The volume percentage to play autostart_uri at."
+    ),
+    key!(
+        "lastfm_api_key",
+        Type::String,
+        None,
+        "The key of the Last.fm API account to scrobble with."
+    ),
+    key!(
+        "lastfm_api_secret",
+        Type::String,
+        None,
+        "The secret of the Last.fm API account to scrobble with."
+    ),
+    key!(
+        "lastfm_username",
+        Type::String,
+        None,
+        "The Last.fm user to scrobble to."
+    ),
+    key!(
+        "lastfm_password",
+        Type::String,
+        None,
+        "The password of the Last.fm user to scrobble to."
+    ),
+];
+
+/// Renders `s` as a JSON string, which is a valid TOML string as well.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Key {
+    /// The JSON Schema properties of the key's value.
+    fn properties(&self) -> Vec<(&'static str, String)> {
+        let mut properties = Vec::new();
+        // Whether values are written as JSON numbers or booleans rather than strings.
+        let mut literal = false;
+        match self.kind {
+            Type::String => properties.push(("type", quote("string"))),
+            Type::Boolean => {
+                properties.push(("type", quote("boolean")));
+                literal = true;
+            }
+            Type::Integer { min, max } => {
+                properties.push(("type", quote("integer")));
+                properties.extend(min.map(|min| ("minimum", min.to_string())));
+                properties.extend(max.map(|max| ("maximum", max.to_string())));
+                literal = true;
+            }
+            Type::Number { min, max } => {
+                properties.push(("type", quote("number")));
+                properties.push(("minimum", min.to_string()));
+                properties.push(("maximum", max.to_string()));
+                literal = true;
+            }
+            Type::Duration => {
+                properties.push(("type", quote("string")));
+                properties.push(("pattern", quote("^[0-9]+ ?(ms|s|m|h|d)?$")));
+            }
+            Type::Pattern(pattern) => {
+                properties.push(("type", quote("string")));
+                properties.push(("pattern", quote(pattern)));
+            }
+            Type::Enum(values) => {
+                literal = values.iter().all(|value| value.parse::<i64>().is_ok());
+                let kind = if literal { "integer" } else { "string" };
+                properties.push(("type", quote(kind)));
+                let values: Vec<_> = values
+                    .iter()
+                    .map(|&value| {
+                        if literal {
+                            value.to_string()
+                        } else {
+                            quote(value)
+                        }
+                    })
+                    .collect();
+                properties.push(("enum", format!("[{}]", values.join(", "))));
+            }
+        }
+        if let Some(default) = self.default {
+            let default = if literal {
+                default.to_string()
+            } else {
+                quote(default)
+            };
+            properties.push(("default", default));
+        }
+        properties.push(("description", quote(self.description)));
+        properties
+    }
+}
+
+/// Finds the key `name` stands for. Some keys are written with dashes and others with
+/// underscores, so names that mix the two up are matched as well.
+pub(crate) fn key_name(name: &str) -> Option<&'static str> {
+    let normalized = |name: &str| name.replace('-', "_");
+    KEYS.iter()
+        .find(|key| key.name == name)
+        .or_else(|| {
+            KEYS.iter()
+                .find(|key| normalized(key.name) == normalized(name))
+        })
+        .map(|key| key.name)
+}
+
+/// Where the key `name` comes in the list of keys, for listing keys in a familiar order.
+pub(crate) fn key_position(name: &str) -> Option<usize> {
+    KEYS.iter().position(|key| key.name == name)
+}
+
+/// Renders `value` of the key `name` as a TOML value: booleans and numbers as such,
+/// everything else as a string.
+pub(crate) fn toml_value(name: &str, value: &str) -> String {
+    let literal = match KEYS
+        .iter()
+        .find(|key| key.name == name)
+        .map(|key| &key.kind)
+    {
+        Some(Type::Boolean) => value == "true" || value == "false",
+        Some(Type::Integer { .. }) => value.parse::<i64>().is_ok(),
+        Some(Type::Number { .. }) => value.parse::<f64>().map_or(false, f64::is_finite),
+        Some(Type::Enum(_)) => value.parse::<i64>().is_ok(),
+        _ => false,
+    };
+    if literal {
+        value.to_string()
+    } else {
+        quote(value)
+    }
+}
+
+/// Describes the keys of the config file as a JSON Schema. The keys can be placed in the
+/// `[global]` as well as the `[spotifyd]` section.
+pub(crate) fn json_schema() -> String {
+    let keys: Vec<String> = KEYS
+        .iter()
+        .map(|key| {
+            let properties: Vec<String> = key
+                .properties()
+                .into_iter()
+                .map(|(name, value)| format!("      {}: {}", quote(name), value))
+                .collect();
+            format!(
+                "    {}: {{\n{}\n    }}",
+                quote(key.name),
+                properties.join(",\n")
+            )
+        })
+        .collect();
+    format!(
+        "{{\n  \"$schema\": \"http://json-schema.org/draft-07/schema#\",\n  \
+         \"title\": \"spotifyd configuration\",\n  \"type\": \"object\",\n  \
+         \"properties\": {{\n{}\n  }},\n  \"additionalProperties\": false\n}}\n",
+        keys.join(",\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> &'static Key {
+        KEYS.iter().find(|key| key.name == name).unwrap()
+    }
+
+    #[test]
+    fn known_keys() {
+        let schema = json_schema();
+        assert!(schema.contains(
+            "    \"bitrate\": {\n      \"type\": \"integer\",\n      \"enum\": [96, 160, 320],\n      \
+             \"default\": 160,\n"
+        ));
+        assert!(schema.contains(
+            "    \"mono\": {\n      \"type\": \"boolean\",\n      \"default\": false,\n"
+        ));
+        assert!(schema.contains(
+            "    \"dbus_type\": {\n      \"type\": \"string\",\n      \
+             \"enum\": [\"session\", \"system\"],\n      \"default\": \"session\",\n"
+        ));
+        assert!(schema.contains(
+            "    \"autostart_volume\": {\n      \"type\": \"integer\",\n      \"minimum\": 0,\n      \
+             \"maximum\": 100,\n"
+        ));
+        assert_eq!(key("reconnect_cooldown").default, Some("10s"));
+        assert_eq!(key("client_id").default, Some(crate::config::CLIENT_ID));
+        assert!(schema.ends_with("  \"additionalProperties\": false\n}\n"));
+    }
+
+    #[test]
+    fn key_names() {
+        assert_eq!(key_name("bitrate"), Some("bitrate"));
+        assert_eq!(key_name("use_keyring"), Some("use-keyring"));
+        assert_eq!(key_name("device-name"), Some("device_name"));
+        assert_eq!(key_name("volume_controller"), None);
+    }
+
+    #[test]
+    fn toml_values() {
+        assert_eq!(toml_value("bitrate", "320"), "320");
+        assert_eq!(toml_value("mono", "true"), "true");
+        assert_eq!(toml_value("mono", "yes"), "\"yes\"");
+        assert_eq!(toml_value("master_gain", "-3.5"), "-3.5");
+        assert_eq!(toml_value("dbus_type", "system"), "\"system\"");
+        assert_eq!(toml_value("device_name", "320"), "\"320\"");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("\"pid\" or a\\b\n"), "\"\\\"pid\\\" or a\\\\b\\n\"");
+        assert_eq!(quote("\u{1}"), "\"\\u0001\"");
+    }
+
+    /// Every key `get_config` looks up has to be in the schema, and every key in the
+    /// schema has to be looked up.
+    #[test]
+    fn in_sync_with_config() {
+        let source = include_str!("config.rs");
+        let mut looked_up: Vec<&str> = source
+            .split("lookup(\"")
+            .skip(1)
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect();
+        // This one is read from the sections directly.
+        looked_up.push("volume-normalisation");
+        for name in &looked_up {
+            assert!(
+                KEYS.iter().any(|key| key.name == *name),
+                "{} is missing",
+                name
+            );
+        }
+        for key in KEYS {
+            assert!(looked_up.contains(&key.name), "{} isn't read", key.name);
+        }
+    }
+}
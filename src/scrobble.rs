@@ -0,0 +1,714 @@
+use futures::{Async, Future};
+use librespot::{
+    core::{mercury::MercuryError, session::Session, spotify_id::SpotifyId},
+    metadata::{Album, Artist, Metadata},
+    playback::player::PlayerEvent,
+};
+use log::warn;
+use std::{
+    cmp,
+    collections::VecDeque,
+    sync::mpsc::Sender,
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::backoff::{Backoff, Delays};
+
+/// Last.fm ignores tracks shorter than this.
+const MIN_TRACK_LENGTH: Duration = Duration::from_secs(30);
+/// A track is scrobbled once it has played for half its length, or this long.
+const MAX_PLAY_TIME: Duration = Duration::from_secs(4 * 60);
+/// Last.fm accepts up to this many scrobbles per request.
+const BATCH_SIZE: usize = 50;
+/// How many scrobbles to keep while Last.fm can't be reached.
+const QUEUE_CAPACITY: usize = 1000;
+/// The longest to wait between attempts to reach Last.fm.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// The Last.fm account to scrobble to, and the API account to do it with.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LastfmConfig {
+    pub(crate) api_key: String,
+    pub(crate) api_secret: String,
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Track {
+    artist: String,
+    title: String,
+    album: String,
+    duration: Duration,
+}
+
+/// A track that was listened to long enough, started at `timestamp` in seconds since
+/// the epoch.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Scrobble {
+    track: Track,
+    timestamp: u64,
+}
+
+/// What the scrobbler thread hands to Last.fm.
+#[derive(Debug)]
+pub(crate) enum Message {
+    NowPlaying(Track),
+    Scrobble(Scrobble),
+}
+
+/// Whether a track of `duration` that has played for `played` can be scrobbled, following
+/// Last.fm's rules.
+fn eligible(duration: Duration, played: Duration) -> bool {
+    duration > MIN_TRACK_LENGTH && played >= cmp::min(duration / 2, MAX_PLAY_TIME)
+}
+
+/// How long the current track has played, across pauses.
+#[derive(Debug)]
+struct Listening {
+    track_id: SpotifyId,
+    timestamp: u64,
+    played: Duration,
+    /// When playback last resumed, if it's playing.
+    resumed: Option<Instant>,
+    /// Filled in once the metadata has been looked up.
+    track: Option<Track>,
+    /// Set once the scrobble was handed over, so it's only sent once.
+    scrobbled: bool,
+}
+
+impl Listening {
+    fn new(track_id: SpotifyId, timestamp: u64, now: Instant) -> Listening {
+        Listening {
+            track_id,
+            timestamp,
+            played: Duration::from_secs(0),
+            resumed: Some(now),
+            track: None,
+            scrobbled: false,
+        }
+    }
+
+    fn pause(&mut self, now: Instant) {
+        if let Some(resumed) = self.resumed.take() {
+            if now > resumed {
+                self.played += now - resumed;
+            }
+        }
+    }
+
+    fn resume(&mut self, now: Instant) {
+        self.resumed.get_or_insert(now);
+    }
+
+    /// The scrobble if the track has played long enough, the first time it's asked for.
+    fn scrobble(&mut self) -> Option<Scrobble> {
+        if self.scrobbled {
+            return None;
+        }
+        let track = self.track.as_ref()?;
+        if !eligible(track.duration, self.played) {
+            return None;
+        }
+        self.scrobbled = true;
+        Some(Scrobble {
+            track: track.clone(),
+            timestamp: self.timestamp,
+        })
+    }
+
+    /// Stops listening at `now`, returning the scrobble if the track played long enough.
+    fn finish(mut self, now: Instant) -> Option<Scrobble> {
+        self.pause(now);
+        self.scrobble()
+    }
+}
+
+/// Scrobbles that haven't been accepted yet, oldest first, and when to try sending them
+/// again after a failure.
+#[derive(Debug)]
+struct RetryQueue {
+    pending: VecDeque<Scrobble>,
+    backoff: Backoff,
+    delays: Delays,
+    retry_at: Option<Instant>,
+}
+
+impl RetryQueue {
+    fn new(backoff: Backoff) -> RetryQueue {
+        RetryQueue {
+            pending: VecDeque::new(),
+            backoff,
+            delays: backoff.delays(),
+            retry_at: None,
+        }
+    }
+
+    /// Queues a scrobble, dropping the oldest one if the queue is full.
+    fn push(&mut self, scrobble: Scrobble) {
+        if self.pending.len() == QUEUE_CAPACITY {
+            warn!("Too many scrobbles are waiting to be sent, dropping the oldest one.");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(scrobble);
+    }
+
+    /// The scrobbles to send at `now`, unless a retry is still pending.
+    fn batch(&self, now: Instant) -> Option<Vec<Scrobble>> {
+        if self.pending.is_empty() || self.retry_at.map_or(false, |at| at > now) {
+            return None;
+        }
+        Some(self.pending.iter().take(BATCH_SIZE).cloned().collect())
+    }
+
+    /// The first `count` scrobbles were accepted.
+    fn sent(&mut self, count: usize) {
+        self.pending.drain(..cmp::min(count, self.pending.len()));
+        self.delays = self.backoff.delays();
+        self.retry_at = None;
+    }
+
+    /// Sending failed at `now`, so try again after the next delay.
+    fn failed(&mut self, now: Instant) -> Duration {
+        let delay = self.delays.next().unwrap_or(MAX_RETRY_DELAY);
+        self.retry_at = Some(now + delay);
+        delay
+    }
+
+    /// How long to wait at `now` before sending again, if anything is waiting.
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(match self.retry_at {
+            Some(at) if at > now => at - now,
+            _ => Duration::from_secs(0),
+        })
+    }
+}
+
+fn retry_backoff() -> Backoff {
+    Backoff::new(Duration::from_secs(30), 2.0, MAX_RETRY_DELAY, 0.1)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the text of the first `name` element of a Last.fm XML response, such as
+/// `<error code="4">Invalid authentication token</error>`.
+#[cfg_attr(not(feature = "scrobble"), allow(dead_code))]
+fn element<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    let start = loop {
+        let tag = from + body[from..].find(&open)? + open.len();
+        let rest = &body[tag..];
+        // Skip elements whose name only starts with `name`.
+        if rest.starts_with('>') || rest.starts_with(char::is_whitespace) {
+            break tag + rest.find('>')? + 1;
+        }
+        from = tag;
+    };
+    let end = body[start..].find(&format!("</{}>", name))?;
+    Some(&body[start..start + end])
+}
+
+/// Looks up the artist, title and album of a track.
+fn track_info(session: &Session, track_id: SpotifyId) -> Box<dyn Future<Item = Track, Error = ()>> {
+    let session = session.clone();
+    Box::new(
+        librespot::metadata::Track::get(&session, track_id)
+            .and_then(move |track| {
+                let duration = Duration::from_millis(track.duration.max(0) as u64);
+                let artist = match track.artists.first() {
+                    Some(&artist) => Box::new(Artist::get(&session, artist).map(|a| a.name))
+                        as Box<dyn Future<Item = String, Error = MercuryError>>,
+                    None => Box::new(futures::future::ok(String::new())),
+                };
+                artist
+                    .join(Album::get(&session, track.album))
+                    .map(move |(artist, album)| Track {
+                        artist,
+                        title: track.name,
+                        album: album.name,
+                        duration,
+                    })
+            })
+            .map_err(|e| warn!("Couldn't look up the track to scrobble: {:?}", e)),
+    )
+}
+
+/// Follows the player's events, handing what's playing and what was listened to long
+/// enough to the thread talking to Last.fm.
+pub(crate) struct Scrobbler {
+    messages: Sender<Message>,
+    listening: Option<Listening>,
+    track_info: Option<Box<dyn Future<Item = Track, Error = ()>>>,
+    /// The thread talking to Last.fm, to wait for on shutdown.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scrobbler {
+    pub(crate) fn player_event(&mut self, event: &PlayerEvent, session: Option<&Session>) {
+        let now = Instant::now();
+        match *event {
+            PlayerEvent::Started { track_id } => match self.listening {
+                Some(ref mut listening) if listening.track_id == track_id => listening.resume(now),
+                _ => self.start(track_id, now, session),
+            },
+            PlayerEvent::Changed { new_track_id, .. } => self.start(new_track_id, now, session),
+            PlayerEvent::Stopped { track_id } => {
+                if let Some(ref mut listening) = self.listening {
+                    if listening.track_id == track_id {
+                        listening.pause(now);
+                        // Playback may not resume, e.g. at the end of the queue, so
+                        // don't wait for the next track to scrobble this one.
+                        if let Some(scrobble) = listening.scrobble() {
+                            let _ = self.messages.send(Message::Scrobble(scrobble));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, track_id: SpotifyId, now: Instant, session: Option<&Session>) {
+        if let Some(scrobble) = self.listening.take().and_then(|l| l.finish(now)) {
+            let _ = self.messages.send(Message::Scrobble(scrobble));
+        }
+        self.listening = Some(Listening::new(track_id, unix_time(), now));
+        self.track_info = session.map(|session| track_info(session, track_id));
+    }
+
+    /// Hands over the current track if it played long enough, then waits for the thread
+    /// talking to Last.fm to send what's queued.
+    pub(crate) fn shut_down(mut self, now: Instant) {
+        if let Some(scrobble) = self.listening.take().and_then(|l| l.finish(now)) {
+            let _ = self.messages.send(Message::Scrobble(scrobble));
+        }
+        drop(self.messages);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Polls the metadata lookup of the current track.
+    pub(crate) fn poll(&mut self) {
+        let result = match self.track_info.as_mut().map(|info| info.poll()) {
+            None | Some(Ok(Async::NotReady)) => return,
+            Some(result) => result,
+        };
+        self.track_info = None;
+        if let (Ok(Async::Ready(track)), Some(ref mut listening)) = (result, &mut self.listening) {
+            let _ = self.messages.send(Message::NowPlaying(track.clone()));
+            listening.track = Some(track);
+        }
+    }
+}
+
+#[cfg(feature = "scrobble")]
+pub(crate) use self::client::spawn;
+
+#[cfg(feature = "scrobble")]
+mod client {
+    use log::{error, info, warn};
+    use std::{
+        sync::mpsc::{self, Receiver, RecvTimeoutError},
+        thread,
+        time::Instant,
+    };
+
+    use super::{
+        element, retry_backoff, LastfmConfig, Message, RetryQueue, Scrobble, Scrobbler, Track,
+    };
+
+    const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+    enum Failure {
+        /// Worth trying again, e.g. because Last.fm couldn't be reached.
+        Retry(String),
+        /// Last.fm refused the request and won't accept it later either.
+        Rejected(String),
+        /// Last.fm refused the login, so nothing can be sent until the config is fixed.
+        AuthFailed(String),
+    }
+
+    struct Client {
+        http: reqwest::Client,
+        config: LastfmConfig,
+        session_key: Option<String>,
+    }
+
+    impl Client {
+        /// Calls `method`, signing the request as Last.fm requires.
+        fn call(&mut self, method: &str, params: Vec<(String, String)>) -> Result<String, Failure> {
+            let mut params = params;
+            params.push(("method".to_string(), method.to_string()));
+            params.push(("api_key".to_string(), self.config.api_key.clone()));
+            if let Some(ref key) = self.session_key {
+                params.push(("sk".to_string(), key.clone()));
+            }
+            params.sort();
+            let mut signed: String = params.iter().map(|(k, v)| format!("{}{}", k, v)).collect();
+            signed.push_str(&self.config.api_secret);
+            params.push((
+                "api_sig".to_string(),
+                format!("{:x}", md5::compute(signed.as_bytes())),
+            ));
+
+            let mut response = self
+                .http
+                .post(API_URL)
+                .form(&params)
+                .send()
+                .map_err(|e| Failure::Retry(e.to_string()))?;
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            if status.is_success() {
+                Ok(body)
+            } else {
+                let message = element(&body, "error").unwrap_or("no details").to_string();
+                // 9 means that the session key is no longer valid, so log in again.
+                if body.contains("code=\"9\"") && self.session_key.take().is_some() {
+                    return Err(Failure::Retry(message));
+                }
+                // 11 and 16 mean that the service is temporarily unavailable.
+                if status.is_server_error()
+                    || body.contains("code=\"11\"")
+                    || body.contains("code=\"16\"")
+                {
+                    Err(Failure::Retry(message))
+                } else {
+                    Err(Failure::Rejected(message))
+                }
+            }
+        }
+
+        fn authenticate(&mut self) -> Result<(), Failure> {
+            if self.session_key.is_some() {
+                return Ok(());
+            }
+            let body = self
+                .call(
+                    "auth.getMobileSession",
+                    vec![
+                        ("username".to_string(), self.config.username.clone()),
+                        ("password".to_string(), self.config.password.clone()),
+                    ],
+                )
+                .map_err(|failure| match failure {
+                    Failure::Rejected(e) => Failure::AuthFailed(e),
+                    failure => failure,
+                })?;
+            match element(&body, "key") {
+                Some(key) => {
+                    info!("Logged in to Last.fm as {}.", self.config.username);
+                    self.session_key = Some(key.to_string());
+                    Ok(())
+                }
+                None => Err(Failure::Retry("no session key in the response".to_string())),
+            }
+        }
+
+        fn now_playing(&mut self, track: &Track) -> Result<(), Failure> {
+            self.authenticate()?;
+            self.call(
+                "track.updateNowPlaying",
+                vec![
+                    ("artist".to_string(), track.artist.clone()),
+                    ("track".to_string(), track.title.clone()),
+                    ("album".to_string(), track.album.clone()),
+                    ("duration".to_string(), track.duration.as_secs().to_string()),
+                ],
+            )
+            .map(|_| ())
+        }
+
+        fn scrobble(&mut self, scrobbles: &[Scrobble]) -> Result<(), Failure> {
+            self.authenticate()?;
+            let mut params = Vec::new();
+            for (i, scrobble) in scrobbles.iter().enumerate() {
+                let track = &scrobble.track;
+                params.push((format!("artist[{}]", i), track.artist.clone()));
+                params.push((format!("track[{}]", i), track.title.clone()));
+                params.push((format!("album[{}]", i), track.album.clone()));
+                params.push((format!("timestamp[{}]", i), scrobble.timestamp.to_string()));
+                params.push((
+                    format!("duration[{}]", i),
+                    track.duration.as_secs().to_string(),
+                ));
+            }
+            self.call("track.scrobble", params).map(|_| ())
+        }
+    }
+
+    fn send(client: &mut Client, queue: &mut RetryQueue, batch: Vec<Scrobble>) {
+        match client.scrobble(&batch) {
+            Ok(()) => queue.sent(batch.len()),
+            Err(Failure::Retry(e)) => {
+                let delay = queue.failed(Instant::now());
+                warn!(
+                    "Couldn't scrobble to Last.fm, trying again in {}s: {}",
+                    delay.as_secs(),
+                    e
+                );
+            }
+            Err(Failure::Rejected(e)) => {
+                error!("Last.fm rejected {} scrobbles: {}", batch.len(), e);
+                queue.sent(batch.len());
+            }
+            // The scrobbles themselves weren't refused, so keep them for when the login
+            // works again.
+            Err(Failure::AuthFailed(e)) => {
+                let delay = queue.failed(Instant::now());
+                error!(
+                    "Couldn't log in to Last.fm, trying again in {}s: {}",
+                    delay.as_secs(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn run(mut client: Client, messages: Receiver<Message>) {
+        let mut queue = RetryQueue::new(retry_backoff());
+        loop {
+            let message = match queue.timeout(Instant::now()) {
+                Some(timeout) => messages.recv_timeout(timeout),
+                None => messages.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match message {
+                Ok(Message::NowPlaying(track)) => match client.now_playing(&track) {
+                    // Now playing is only of interest while the track plays.
+                    Err(Failure::Retry(e))
+                    | Err(Failure::Rejected(e))
+                    | Err(Failure::AuthFailed(e)) => {
+                        warn!("Couldn't update the track playing on Last.fm: {}", e)
+                    }
+                    Ok(()) => (),
+                },
+                Ok(Message::Scrobble(scrobble)) => queue.push(scrobble),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    // spotifyd is shutting down, so try what's queued right away, until
+                    // it's sent or sending fails.
+                    queue.retry_at = None;
+                    while let Some(batch) = queue.batch(Instant::now()) {
+                        send(&mut client, &mut queue, batch);
+                    }
+                    if !queue.pending.is_empty() {
+                        warn!(
+                            "Couldn't send {} scrobbles before quitting.",
+                            queue.pending.len()
+                        );
+                    }
+                    return;
+                }
+            }
+            if let Some(batch) = queue.batch(Instant::now()) {
+                send(&mut client, &mut queue, batch);
+            }
+        }
+    }
+
+    /// Starts the thread talking to Last.fm.
+    pub(crate) fn spawn(config: LastfmConfig) -> Scrobbler {
+        let (messages, receiver) = mpsc::channel();
+        let client = Client {
+            http: reqwest::Client::new(),
+            config,
+            session_key: None,
+        };
+        let thread = thread::spawn(move || run(client, receiver));
+        Scrobbler {
+            messages,
+            listening: None,
+            track_info: None,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(secs: u64) -> Track {
+        Track {
+            artist: "Artist".to_string(),
+            title: "Title".to_string(),
+            album: "Album".to_string(),
+            duration: Duration::from_secs(secs),
+        }
+    }
+
+    fn scrobble(timestamp: u64) -> Scrobble {
+        Scrobble {
+            track: track(200),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn eligibility() {
+        let secs = Duration::from_secs;
+        // Half of the track...
+        assert!(!eligible(secs(200), secs(99)));
+        assert!(eligible(secs(200), secs(100)));
+        // ...or four minutes, whichever comes first.
+        assert!(!eligible(secs(3600), secs(239)));
+        assert!(eligible(secs(3600), secs(240)));
+        // Short tracks are never scrobbled.
+        assert!(!eligible(secs(30), secs(30)));
+        assert!(eligible(secs(31), secs(16)));
+    }
+
+    #[test]
+    fn play_time_across_pauses() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+
+        let mut listening = Listening::new(track_id, 0, start);
+        listening.track = Some(track(200));
+        listening.pause(start + secs(60));
+        // Time spent paused doesn't count.
+        listening.resume(start + secs(600));
+        listening.resume(start + secs(610));
+        assert_eq!(
+            listening.finish(start + secs(640)),
+            Some(Scrobble {
+                track: track(200),
+                timestamp: 0,
+            })
+        );
+
+        let mut listening = Listening::new(track_id, 0, start);
+        listening.track = Some(track(200));
+        listening.pause(start + secs(60));
+        listening.resume(start + secs(600));
+        assert_eq!(listening.finish(start + secs(630)), None);
+
+        // Without metadata, there's nothing to scrobble.
+        let listening = Listening::new(track_id, 0, start);
+        assert_eq!(listening.finish(start + secs(600)), None);
+    }
+
+    fn queue() -> RetryQueue {
+        RetryQueue::new(Backoff::new(
+            Duration::from_secs(30),
+            2.0,
+            Duration::from_secs(120),
+            0.0,
+        ))
+    }
+
+    #[test]
+    fn retry_queue() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut queue = queue();
+        assert_eq!(queue.batch(start), None);
+        assert_eq!(queue.timeout(start), None);
+
+        queue.push(scrobble(1));
+        queue.push(scrobble(2));
+        assert_eq!(queue.timeout(start), Some(secs(0)));
+        assert_eq!(queue.batch(start), Some(vec![scrobble(1), scrobble(2)]));
+
+        // Failures back off, and new scrobbles wait for the retry.
+        assert_eq!(queue.failed(start), secs(30));
+        queue.push(scrobble(3));
+        assert_eq!(queue.batch(start + secs(29)), None);
+        assert_eq!(queue.timeout(start + secs(20)), Some(secs(10)));
+        assert_eq!(queue.batch(start + secs(30)).unwrap().len(), 3);
+        assert_eq!(queue.failed(start + secs(30)), secs(60));
+        assert_eq!(queue.failed(start + secs(90)), secs(120));
+        assert_eq!(queue.failed(start + secs(210)), secs(120));
+
+        // Once sent, the scrobbles are gone and the backoff starts over.
+        queue.sent(2);
+        assert_eq!(queue.batch(start + secs(210)), Some(vec![scrobble(3)]));
+        assert_eq!(queue.failed(start + secs(210)), secs(30));
+        queue.sent(1);
+        assert_eq!(queue.timeout(start + secs(210)), None);
+    }
+
+    #[test]
+    fn retry_queue_limits() {
+        let now = Instant::now();
+        let mut queue = queue();
+        for i in 0..QUEUE_CAPACITY as u64 + 10 {
+            queue.push(scrobble(i));
+        }
+        assert_eq!(queue.pending.len(), QUEUE_CAPACITY);
+        // The oldest scrobbles were dropped, and they're sent in batches.
+        let batch = queue.batch(now).unwrap();
+        assert_eq!(batch.len(), BATCH_SIZE);
+        assert_eq!(batch[0], scrobble(10));
+    }
+
+    #[test]
+    fn response_elements() {
+        let error = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<lfm status=\"failed\">\n  \
+                     <error code=\"4\">Invalid authentication token</error>\n</lfm>";
+        assert_eq!(
+            element(error, "error"),
+            Some("Invalid authentication token")
+        );
+        assert_eq!(element(error, "key"), None);
+
+        let session = "<lfm status=\"ok\"><session><name>jane</name><key>d580d57f</key>\
+                       <subscriber>0</subscriber></session></lfm>";
+        assert_eq!(element(session, "key"), Some("d580d57f"));
+        // session isn't taken for an element named sess.
+        assert_eq!(element(session, "sess"), None);
+    }
+
+    #[test]
+    fn scrobble_on_stop_and_shutdown() {
+        let a = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let b = SpotifyId::from_base62("6rqhFgbbKwnb9MLmUQDhG6").unwrap();
+        let start = Instant::now();
+        let (messages, received) = std::sync::mpsc::channel();
+        let mut scrobbler = Scrobbler {
+            messages,
+            listening: Some(Listening::new(a, 1, start)),
+            track_info: None,
+            thread: None,
+        };
+        let listening = scrobbler.listening.as_mut().unwrap();
+        listening.played = Duration::from_secs(150);
+        listening.track = Some(track(200));
+
+        // Stopping after the track played long enough scrobbles it right away, and only
+        // once, even if it's resumed and stopped again.
+        scrobbler.player_event(&PlayerEvent::Stopped { track_id: a }, None);
+        let sent: Vec<_> = received.try_iter().collect();
+        match sent.as_slice() {
+            [Message::Scrobble(sent)] => assert_eq!(*sent, scrobble(1)),
+            other => panic!("unexpected messages: {:?}", other),
+        }
+        scrobbler.player_event(&PlayerEvent::Started { track_id: a }, None);
+        scrobbler.player_event(&PlayerEvent::Stopped { track_id: a }, None);
+        scrobbler.player_event(&PlayerEvent::Started { track_id: b }, None);
+        assert_eq!(received.try_iter().count(), 0);
+
+        // Shutting down hands over a track that played long enough.
+        let listening = scrobbler.listening.as_mut().unwrap();
+        listening.timestamp = 2;
+        listening.played = Duration::from_secs(150);
+        listening.track = Some(track(200));
+        scrobbler.shut_down(Instant::now());
+        let sent: Vec<_> = received.iter().collect();
+        match sent.as_slice() {
+            [Message::Scrobble(sent)] => assert_eq!(*sent, scrobble(2)),
+            other => panic!("unexpected messages: {:?}", other),
+        }
+    }
+}
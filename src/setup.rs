@@ -1,52 +1,232 @@
 #[cfg(feature = "alsa_backend")]
 use crate::alsa_mixer;
-use crate::{config, main_loop};
+use crate::announce::Announcer;
+use crate::cache_dir::{self, Expiry};
+use crate::context_volume::{self, VolumeMemory};
+#[cfg(feature = "dbus_mpris")]
+use crate::dbus_mpris::check_bus;
+use crate::error::{Error, ErrorKind};
+use crate::heartbeat::Heartbeat;
+use crate::quiet_hours::QuietMixer;
+use crate::resume::{self, Resume};
+use crate::stats::{self, Stats};
+use crate::watchdog::{self, Watchdog};
+use crate::{
+    autostart, backends, config, main_loop,
+    process::EventPrograms,
+    reconnect::{self, Reconnect},
+};
 use futures::{self, Future};
 #[cfg(feature = "dbus_keyring")]
 use keyring::Keyring;
 use librespot::{
     connect::discovery::discovery,
     core::{
-        authentication::get_credentials,
+        authentication::{get_credentials, Credentials},
         cache::Cache,
         config::{ConnectConfig, DeviceType},
         session::Session,
     },
-    playback::{
-        audio_backend::{Sink, BACKENDS},
-        mixer::{self, Mixer},
-    },
+    playback::mixer::{self, Mixer},
+};
+use log::{error, info, warn};
+use std::{
+    io,
+    process::exit,
+    sync::{Arc, Mutex},
 };
-use log::{error, info};
-use std::{io, process::exit};
 use tokio_core::reactor::Handle;
 use tokio_signal::ctrl_c;
 
+/// Creates the cache directory, if one is configured, and opens the cache in it. Reading
+/// the configuration leaves this out, so that checking or dumping it creates nothing.
+pub(crate) fn open_cache(config: &mut config::SpotifydConfig) -> Result<(), Error> {
+    if let Some(ref path) = config.cache_path {
+        cache_dir::create(path, config.cache_dir_mode).map_err(|e| ErrorKind::CacheDir {
+            path: path.clone(),
+            msg: e.to_string(),
+        })?;
+        config.cache = Some(Cache::new(path.clone(), true));
+    }
+    Ok(())
+}
+
+/// Returns the credentials to log in with, taken from the config, the keyring or the cache.
+/// A username without a password is an error, which the caller turns into its exit code.
+pub(crate) fn credentials(config: &config::SpotifydConfig) -> Result<Option<Credentials>, Error> {
+    let username = config.username.clone();
+    #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
+    let mut password = config.password.clone();
+    #[cfg(feature = "dbus_keyring")]
+    {
+        // We only need to check if an actual user has been specified as
+        // spotifyd can run without being signed in too.
+        if let (Some(_), Some((service, account))) = (&username, keyring_entry(config)) {
+            info!("Checking keyring for password");
+            let keyring = Keyring::new(&service, &account);
+            password = prefer_keyring(keyring.get_password().ok(), password);
+        }
+    }
+
+    if let Some(ref username) = username {
+        info!(
+            "Using username {}.",
+            username_for_log(username, log::log_enabled!(log::Level::Trace))
+        );
+    }
+
+    let cached = config.cache.as_ref().and_then(Cache::credentials);
+    if password_missing(username.as_ref(), password.as_ref(), cached.as_ref()) {
+        return Err(ErrorKind::PasswordMissing.into());
+    }
+    // librespot only prompts for a password when there is none, which was ruled out above.
+    Ok(get_credentials(username, password, cached, |_| {
+        unreachable!("the password was checked")
+    }))
+}
+
+/// Whether librespot would have to prompt for a password: a username is set, but neither
+/// a password nor cached credentials for that username are there.
+fn password_missing(
+    username: Option<&String>,
+    password: Option<&String>,
+    cached: Option<&Credentials>,
+) -> bool {
+    match (username, password) {
+        (Some(username), None) => cached.map_or(true, |cached| &cached.username != username),
+        _ => false,
+    }
+}
+
+/// Returns the service and account of the keyring entry to read the password from, if
+/// the keyring is used.
+#[cfg_attr(not(feature = "dbus_keyring"), allow(dead_code))]
+fn keyring_entry(config: &config::SpotifydConfig) -> Option<(String, String)> {
+    if !config.use_keyring {
+        return None;
+    }
+    let account = config
+        .keyring_account
+        .as_ref()
+        .or_else(|| config.username.as_ref())?;
+    Some((config.keyring_service.clone(), account.clone()))
+}
+
+/// Takes the password from the keyring, falling back to the one in the config if the
+/// keyring has none.
+#[cfg_attr(not(feature = "dbus_keyring"), allow(dead_code))]
+fn prefer_keyring(keyring: Option<String>, config: Option<String>) -> Option<String> {
+    match keyring {
+        Some(password) => Some(password),
+        None => {
+            if config.is_some() {
+                info!("No password in the keyring, using the one from the config.");
+            }
+            config
+        }
+    }
+}
+
+/// Returns `username` for logging, redacted except for its first and last character
+/// unless `exact` is set.
+fn username_for_log(username: &str, exact: bool) -> String {
+    if exact {
+        return format!("{:?}", username);
+    }
+    let chars: Vec<char> = username.chars().collect();
+    if chars.len() > 2 {
+        format!("{}***{}", chars[0], chars[chars.len() - 1])
+    } else {
+        "***".to_string()
+    }
+}
+
+/// Without MPRIS support there is no bus to check, and `use_mpris = required` is
+/// rejected when the config is loaded.
+#[cfg(not(feature = "dbus_mpris"))]
+fn check_bus(_: config::DBusType, _: &str) -> Result<(), String> {
+    Ok(())
+}
+
+/// Returns the bus name to register the MPRIS interface under, if it's enabled and
+/// `check_bus` finds the bus available and the name allowed.
+fn mpris_bus_name<F>(config: &config::SpotifydConfig, check_bus: F) -> Result<Option<String>, Error>
+where
+    F: FnOnce(config::DBusType, &str) -> Result<(), String>,
+{
+    let bus_name = config.mpris_bus_name();
+    match config.use_mpris {
+        config::UseMpris::No => {
+            info!("MPRIS is disabled, not connecting to D-Bus.");
+            return Ok(None);
+        }
+        config::UseMpris::Yes => {
+            if let Err(reason) = check_bus(config.dbus_type, &bus_name) {
+                warn!(
+                    "The D-Bus {} bus is unavailable, continuing without MPRIS: {}",
+                    config.dbus_type, reason
+                );
+                return Ok(None);
+            }
+        }
+        config::UseMpris::Required => check_bus(config.dbus_type, &bus_name).map_err(|reason| {
+            ErrorKind::MprisUnavailable {
+                bus: config.dbus_type,
+                reason,
+            }
+        })?,
+    }
+    Ok(Some(bus_name))
+}
+
 pub(crate) fn initial_state(
     handle: Handle,
     config: config::SpotifydConfig,
 ) -> main_loop::MainLoopState {
+    let credentials = credentials(&config).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1);
+    });
+    let cached_credentials = config.cache.as_ref().and_then(Cache::credentials).is_some();
+    let autostart = autostart::autostart(
+        config.autostart_uri.clone(),
+        config.autostart_volume,
+        cached_credentials,
+    );
+    let mpris_bus_name = mpris_bus_name(&config, check_bus).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1);
+    });
+    let mpris_identity = config.mpris_identity();
+
     #[cfg(feature = "alsa_backend")]
-    let mut mixer = {
+    let mixer = {
         let local_audio_device = config.audio_device.clone();
         let local_control_device = config.control_device.clone();
         let local_mixer = config.mixer.clone();
         match config.volume_controller {
             config::VolumeController::Alsa { linear } => {
                 info!("Using alsa volume controller.");
+                let mut startup_volume =
+                    alsa_mixer::StartupVolume::new(config.startup_mixer_volume);
                 Box::new(move || {
-                    Box::new(alsa_mixer::AlsaMixer {
+                    let mixer = alsa_mixer::AlsaMixer {
                         device: local_control_device
                             .clone()
                             .or_else(|| local_audio_device.clone())
                             .unwrap_or_else(|| "default".to_string()),
                         mixer: local_mixer.clone().unwrap_or_else(|| "Master".to_string()),
                         linear_scaling: linear,
-                    }) as Box<dyn mixer::Mixer>
+                    };
+                    startup_volume.apply(|percent| mixer.set_volume_percent(percent));
+                    Box::new(mixer) as Box<dyn mixer::Mixer>
                 }) as Box<dyn FnMut() -> Box<dyn Mixer>>
             }
             config::VolumeController::SoftVol => {
                 info!("Using software volume controller.");
+                if config.startup_mixer_volume.is_some() {
+                    warn!("startup_mixer_volume only applies to the alsa volume controller.");
+                }
                 Box::new(|| Box::new(mixer::softmixer::SoftMixer::open(None)) as Box<dyn Mixer>)
                     as Box<dyn FnMut() -> Box<dyn Mixer>>
             }
@@ -54,12 +234,25 @@ pub(crate) fn initial_state(
     };
 
     #[cfg(not(feature = "alsa_backend"))]
-    let mut mixer = {
+    let mixer = {
         info!("Using software volume controller.");
+        if config.startup_mixer_volume.is_some() {
+            warn!("startup_mixer_volume only applies to the alsa volume controller.");
+        }
         Box::new(|| Box::new(mixer::softmixer::SoftMixer::open(None)) as Box<dyn Mixer>)
             as Box<dyn FnMut() -> Box<dyn Mixer>>
     };
 
+    let mut mixer = match config.quiet_hours {
+        Some(quiet_hours) => {
+            let mut inner = mixer;
+            Box::new(move || {
+                Box::new(QuietMixer::new(inner(), quiet_hours)) as Box<dyn mixer::Mixer>
+            }) as Box<dyn FnMut() -> Box<dyn Mixer>>
+        }
+        None => mixer,
+    };
+
     let cache = config.cache;
     let player_config = config.player_config;
     let session_config = config.session_config;
@@ -75,62 +268,96 @@ pub(crate) fn initial_state(
     #[cfg(not(feature = "alsa_backend"))]
     let linear_volume = false;
 
-    #[allow(clippy::or_fun_call)]
-    let discovery_stream = discovery(
-        &handle,
-        ConnectConfig {
-            name: config.device_name.clone(),
-            device_type: DeviceType::default(),
-            volume: mixer().volume(),
-            linear_volume,
-        },
-        device_id,
-        0,
-    )
-    .unwrap();
-
-    let username = config.username;
-    #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
-    let mut password = config.password;
-    #[cfg(feature = "dbus_keyring")]
-    {
-        // We only need to check if an actual user has been specified as
-        // spotifyd can run without being signed in too.
-        if username.is_some() && config.use_keyring {
-            info!("Checking keyring for password");
-            let keyring = Keyring::new("spotifyd", username.as_ref().unwrap());
-            let retrieved_password = keyring.get_password();
-            password = password.or_else(|| retrieved_password.ok());
-        }
-    }
+    let connect_config = ConnectConfig {
+        name: config.advertised_name().to_string(),
+        device_type: DeviceType::default(),
+        volume: mixer().volume(),
+        linear_volume,
+    };
+    let start_discovery = {
+        let handle = handle.clone();
+        move || discovery(&handle, connect_config.clone(), device_id.clone(), 0)
+    };
+    let discovery_stream = start_discovery().unwrap();
 
-    let connection = if let Some(credentials) = get_credentials(
-        username,
-        password,
-        cache.as_ref().and_then(Cache::credentials),
-        |_| {
-            error!("No password found.");
-            exit(1);
-        },
-    ) {
-        Session::connect(
+    let connection = if let Some(credentials) = credentials.clone() {
+        main_loop::connect(
             session_config.clone(),
             credentials,
             cache.clone(),
-            handle.clone(),
+            &handle,
+            config.connect_timeout,
         )
     } else {
         Box::new(futures::future::empty())
             as Box<dyn futures::Future<Item = Session, Error = io::Error>>
     };
 
-    let backend = find_backend(backend.as_ref().map(String::as_ref));
+    let backend = backends::find_backend(backend.as_ref().map(String::as_ref));
+    let audio_device = config
+        .audio_device
+        .clone()
+        .or_else(|| backend.select_device(&config.device_blacklist));
+    let volume_memory = match (config.remember_context_volume, config.cache_path.as_ref()) {
+        (true, Some(cache_path)) => Some(Arc::new(Mutex::new(VolumeMemory::load(
+            cache_path.join(context_volume::FILE_NAME),
+        )))),
+        (true, None) => {
+            warn!("remember_context_volume needs a cache_path to store the volumes in.");
+            None
+        }
+        (false, _) => None,
+    };
+    let heartbeat = config
+        .heartbeat_interval
+        .and_then(|interval| Heartbeat::new(interval, &handle));
+    let cache_expiry = match (config.cache_expiry, config.cache_path.clone()) {
+        (Some(max_age), Some(cache_path)) => Expiry::new(cache_path, max_age, &handle),
+        (Some(_), None) => {
+            warn!("cache_expiry needs a cache_path to remove audio files from.");
+            None
+        }
+        (None, _) => None,
+    };
+    let stats_path = match (config.persist_stats, config.cache_path.as_ref()) {
+        (true, Some(cache_path)) => Some(cache_path.join(stats::FILE_NAME)),
+        (true, None) => {
+            warn!("persist_stats needs a cache_path to store the stats in.");
+            None
+        }
+        (false, _) => None,
+    };
+    let resume = match (config.resume_on_start, config.cache_path.as_ref()) {
+        // Autostart takes over the playback, so there's nothing to resume then.
+        (true, Some(cache_path)) => Some(Resume::new(
+            cache_path.join(resume::FILE_NAME),
+            cached_credentials && autostart.is_none(),
+        )),
+        (true, None) => {
+            warn!("resume_on_start needs a cache_path to store the position in.");
+            None
+        }
+        (false, _) => None,
+    };
+    #[cfg(feature = "scrobble")]
+    let scrobbler = config.lastfm.map(crate::scrobble::spawn);
+    #[cfg(not(feature = "scrobble"))]
+    let scrobbler = None;
     main_loop::MainLoopState {
-        librespot_connection: main_loop::LibreSpotConnection::new(connection, discovery_stream),
+        librespot_connection: main_loop::LibreSpotConnection::new(
+            connection,
+            discovery_stream,
+            credentials,
+        ),
         audio_setup: main_loop::AudioSetup {
             mixer,
+            filters: config.filters,
             backend,
-            audio_device: config.audio_device.clone(),
+            audio_device,
+            device_close_delay: config.device_close_delay,
+            fade_out: config.fade_out,
+            playback_speed: config.playback_speed,
+            rt_priority: config.rt_priority,
         },
         spotifyd_state: main_loop::SpotifydState {
             ctrl_c_stream: Box::new(ctrl_c(&handle).flatten_stream()),
@@ -139,32 +366,170 @@ pub(crate) fn initial_state(
             device_name: config.device_name,
             player_event_channel: None,
             player_event_program: config.onevent,
+            player_event_filter: config.onevent_filter,
             dbus_mpris_server: None,
+            mpris_bus_name,
+            dbus_type: config.dbus_type,
+            artist_separator: config.artist_separator.clone(),
+            mpris_identity,
         },
         player_config,
         session_config,
         handle,
         linear_volume,
-        running_event_program: None,
+        event_programs: EventPrograms::new(config.onevent_max_procs),
+        event_programs_timeout: None,
         shell: config.shell,
+        client_id: config.client_id,
+        reconnect: Reconnect::new(
+            config.max_reconnect_attempts,
+            reconnect::backoff(config.reconnect_cooldown),
+            config.on_auth_failure,
+        ),
+        reconnect_timeout: None,
+        connect_timeout: config.connect_timeout,
+        autostart,
+        autostart_task: None,
+        resume,
+        resume_task: None,
+        heartbeat,
+        cache_expiry,
+        stats: Stats::new(stats_path),
+        playing: false,
+        max_track_length: config.max_track_length,
+        track_length_check: None,
+        announcer: if config.announce_only_when_idle {
+            Some(Announcer::new())
+        } else {
+            None
+        },
+        announce_timeout: None,
+        volume_memory,
+        context_volume_task: None,
+        start_discovery: Box::new(start_discovery),
+        scrobbler,
+        watchdog: Watchdog::new(watchdog::MAX_RESTARTS, watchdog::RESTART_WINDOW),
+        allowed_users: config.allowed_users,
     }
 }
 
-fn find_backend(name: Option<&str>) -> fn(Option<String>) -> Box<dyn Sink> {
-    match name {
-        Some(name) => {
-            BACKENDS
-                .iter()
-                .find(|backend| name == backend.0)
-                .unwrap_or_else(|| panic!("Unknown backend: {}.", name))
-                .1
-        }
-        None => {
-            let &(name, back) = BACKENDS
-                .first()
-                .expect("No backends were enabled at build time");
-            info!("No backend specified, defaulting to: {}.", name);
-            back
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn username_redacted() {
+        assert_eq!(username_for_log("JaneDoe ", true), "\"JaneDoe \"");
+        assert_eq!(username_for_log("JaneDoe", false), "J***e");
+        assert_eq!(username_for_log("JD", false), "***");
+    }
+
+    #[test]
+    fn missing_password() {
+        let jane = "jane".to_string();
+        let password = "hunter2".to_string();
+        let cached = Credentials::with_password(jane.clone(), password.clone());
+        assert!(password_missing(Some(&jane), None, None));
+        assert!(!password_missing(Some(&jane), Some(&password), None));
+        assert!(!password_missing(Some(&jane), None, Some(&cached)));
+        assert!(!password_missing(None, None, None));
+
+        // The cached credentials belong to someone else.
+        let john = "john".to_string();
+        assert!(password_missing(Some(&john), None, Some(&cached)));
+
+        let mut config = config::SpotifydConfig::default();
+        config.username = Some(jane);
+        let err = credentials(&config).unwrap_err();
+        assert!(err.to_string().contains("no password"), "{}", err);
+    }
+
+    #[test]
+    fn keyring_entries() {
+        let mut config = config::SpotifydConfig::default();
+        config.username = Some("jane".to_string());
+        assert_eq!(keyring_entry(&config), None);
+
+        config.use_keyring = true;
+        assert_eq!(
+            keyring_entry(&config),
+            Some(("spotifyd".to_string(), "jane".to_string()))
+        );
+        config.keyring_service = "spotify".to_string();
+        config.keyring_account = Some("jane@example.com".to_string());
+        assert_eq!(
+            keyring_entry(&config),
+            Some(("spotify".to_string(), "jane@example.com".to_string()))
+        );
+
+        config.username = None;
+        config.keyring_account = None;
+        assert_eq!(keyring_entry(&config), None);
+    }
+
+    #[test]
+    fn keyring_fallback() {
+        let password = |s: &str| Some(s.to_string());
+        assert_eq!(
+            prefer_keyring(password("from-keyring"), password("from-config")),
+            password("from-keyring")
+        );
+        assert_eq!(
+            prefer_keyring(None, password("from-config")),
+            password("from-config")
+        );
+        assert_eq!(
+            prefer_keyring(password("from-keyring"), None),
+            password("from-keyring")
+        );
+        assert_eq!(prefer_keyring(None, None), None);
+    }
+
+    #[test]
+    fn mpris_disabled() {
+        let mut config = config::SpotifydConfig::default();
+        assert_eq!(
+            mpris_bus_name(&config, |_, _| Ok(())).unwrap(),
+            Some(config::MPRIS_BUS_NAME.to_string())
+        );
+
+        config.use_mpris = config::UseMpris::No;
+        let bus_name = mpris_bus_name(&config, |_, _| panic!("the bus must not be checked"));
+        assert_eq!(bus_name.unwrap(), None);
+    }
+
+    #[test]
+    fn mpris_bus_unavailable() {
+        let config = config::SpotifydConfig::default();
+        let checks = Cell::new(0);
+        let bus_name = mpris_bus_name(&config, |_, name| {
+            assert_eq!(name, config::MPRIS_BUS_NAME);
+            checks.set(checks.get() + 1);
+            Err("no bus".to_string())
+        });
+        assert_eq!(bus_name.unwrap(), None);
+        assert_eq!(checks.get(), 1);
+    }
+
+    #[test]
+    fn mpris_required() {
+        let mut config = config::SpotifydConfig::default();
+        config.use_mpris = config::UseMpris::Required;
+        let err = mpris_bus_name(&config, |_, _| Err("no bus".to_string())).unwrap_err();
+        assert!(err.to_string().ends_with("unavailable: no bus"), "{}", err);
+
+        // The system bus refuses names that no policy file allows.
+        config.dbus_type = config::DBusType::System;
+        let refused = |_, name: &str| Err(format!("the bus policy refused the name {}", name));
+        let err = mpris_bus_name(&config, refused).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("refused the name org.mpris.MediaPlayer2.spotifyd"),
+            "{}",
+            err
+        );
+
+        assert!(mpris_bus_name(&config, |_, _| Ok(())).unwrap().is_some());
     }
 }
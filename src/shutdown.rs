@@ -0,0 +1,107 @@
+use log::{error, info};
+use std::fmt;
+
+/// Why the main loop ended.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ShutdownReason {
+    /// SIGINT was received.
+    Signal,
+    /// The connection to Spotify ended without being asked to.
+    SessionEnded,
+    /// Reconnecting failed more often than `max_reconnect_attempts` allows.
+    GaveUpReconnecting { attempts: u32, error: String },
+    /// The next reconnect couldn't be scheduled.
+    ReconnectFailed(String),
+    /// Spotify rejected the credentials, and `on_auth_failure` is `exit`.
+    AuthFailed(String),
+    /// The player crashed more often than the watchdog restarts it.
+    PlayerCrashed { restarts: usize, error: String },
+}
+
+impl ShutdownReason {
+    /// A clean shutdown exits with 0, everything else with a non-zero status so that
+    /// service managers can restart spotifyd.
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            ShutdownReason::Signal => 0,
+            ShutdownReason::SessionEnded => 1,
+            ShutdownReason::ReconnectFailed(_) => 1,
+            ShutdownReason::GaveUpReconnecting { .. } => 2,
+            ShutdownReason::AuthFailed(_) => 3,
+            ShutdownReason::PlayerCrashed { .. } => 4,
+        }
+    }
+
+    /// Logs the reason, as the last line spotifyd writes.
+    pub(crate) fn log(&self) {
+        if self.exit_code() == 0 {
+            info!("Shutting down: {}", self);
+        } else {
+            error!("Shutting down: {}", self);
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownReason::Signal => write!(f, "received SIGINT"),
+            ShutdownReason::SessionEnded => write!(f, "the Spotify session ended"),
+            ShutdownReason::GaveUpReconnecting { attempts, error } => write!(
+                f,
+                "couldn't connect to Spotify, giving up after {} attempts: {}",
+                attempts, error
+            ),
+            ShutdownReason::ReconnectFailed(e) => write!(f, "couldn't schedule reconnect: {}", e),
+            ShutdownReason::AuthFailed(e) => write!(f, "Spotify rejected the credentials: {}", e),
+            ShutdownReason::PlayerCrashed { restarts, error } => write!(
+                f,
+                "the player crashed again after {} restarts: {}",
+                restarts, error
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_and_exit_codes() {
+        let reasons = vec![
+            (ShutdownReason::Signal, "received SIGINT", 0),
+            (ShutdownReason::SessionEnded, "the Spotify session ended", 1),
+            (
+                ShutdownReason::GaveUpReconnecting {
+                    attempts: 5,
+                    error: "connection refused".to_string(),
+                },
+                "couldn't connect to Spotify, giving up after 5 attempts: connection refused",
+                2,
+            ),
+            (
+                ShutdownReason::ReconnectFailed("reactor gone".to_string()),
+                "couldn't schedule reconnect: reactor gone",
+                1,
+            ),
+            (
+                ShutdownReason::AuthFailed("Bad credentials".to_string()),
+                "Spotify rejected the credentials: Bad credentials",
+                3,
+            ),
+            (
+                ShutdownReason::PlayerCrashed {
+                    restarts: 3,
+                    error: "player thread gone".to_string(),
+                },
+                "the player crashed again after 3 restarts: player thread gone",
+                4,
+            ),
+        ];
+        for (reason, message, exit_code) in reasons {
+            assert_eq!(reason.to_string(), message);
+            assert_eq!(reason.exit_code(), exit_code, "{:?}", reason);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use librespot::{
+    core::spotify_id::SpotifyId,
+    playback::{audio_backend::Sink, player::PlayerEvent},
+};
+use log::{info, warn};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::heartbeat::format_uptime;
+
+/// The name of the file in the cache directory that holds the totals of all sessions.
+pub(crate) const FILE_NAME: &str = "stats";
+
+/// How much was listened to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Totals {
+    tracks: u64,
+    listening: Duration,
+    reconnects: u64,
+    underruns: u64,
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.tracks += other.tracks;
+        self.listening += other.listening;
+        self.reconnects += other.reconnects;
+        self.underruns += other.underruns;
+    }
+
+    /// Parses lines of a name and a number, separated by a tab, leaving out lines it
+    /// doesn't know.
+    fn parse(contents: &str) -> Totals {
+        let mut totals = Totals::default();
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, '\t');
+            let name = fields.next().unwrap_or("");
+            let value = match fields.next().and_then(|value| value.parse::<u64>().ok()) {
+                Some(value) => value,
+                None => continue,
+            };
+            match name {
+                "tracks" => totals.tracks = value,
+                "listening_secs" => totals.listening = Duration::from_secs(value),
+                "reconnects" => totals.reconnects = value,
+                "underruns" => totals.underruns = value,
+                _ => (),
+            }
+        }
+        totals
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "tracks\t{}\nlistening_secs\t{}\nreconnects\t{}\nunderruns\t{}\n",
+            self.tracks,
+            self.listening.as_secs(),
+            self.reconnects,
+            self.underruns
+        )
+    }
+}
+
+fn plural(count: u64, word: &str) -> String {
+    format!("{} {}{}", count, word, if count == 1 { "" } else { "s" })
+}
+
+impl fmt::Display for Totals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} listened, {}, {}",
+            plural(self.tracks, "track"),
+            format_uptime(self.listening),
+            plural(self.reconnects, "reconnect"),
+            plural(self.underruns, "underrun")
+        )
+    }
+}
+
+/// Counts what's played while spotifyd runs, to sum it up on shutdown. A track that's
+/// resumed after a pause isn't counted again.
+pub(crate) struct Stats {
+    session: Totals,
+    /// The track that started last.
+    track: Option<SpotifyId>,
+    /// When playback started, if it's playing.
+    playing_since: Option<Instant>,
+    /// Set if the totals of all sessions are kept.
+    path: Option<PathBuf>,
+    underruns: Underruns,
+}
+
+impl Stats {
+    pub(crate) fn new(path: Option<PathBuf>) -> Stats {
+        Stats {
+            session: Totals::default(),
+            track: None,
+            playing_since: None,
+            path,
+            underruns: Underruns::default(),
+        }
+    }
+
+    /// The counter for the sink to count underruns with.
+    pub(crate) fn underruns(&self) -> Underruns {
+        self.underruns.clone()
+    }
+
+    fn track_started(&mut self, track_id: SpotifyId) {
+        if self.track != Some(track_id) {
+            self.track = Some(track_id);
+            self.session.tracks += 1;
+        }
+    }
+
+    fn stop(&mut self, now: Instant) {
+        if let Some(since) = self.playing_since.take() {
+            self.session.listening += now.duration_since(since);
+        }
+    }
+
+    pub(crate) fn player_event(&mut self, event: &PlayerEvent, now: Instant) {
+        match *event {
+            PlayerEvent::Started { track_id } => {
+                self.track_started(track_id);
+                if self.playing_since.is_none() {
+                    self.playing_since = Some(now);
+                }
+            }
+            PlayerEvent::Changed { new_track_id, .. } => self.track_started(new_track_id),
+            PlayerEvent::Stopped { .. } => self.stop(now),
+        }
+    }
+
+    pub(crate) fn reconnected(&mut self) {
+        self.session.reconnects += 1;
+    }
+
+    /// Logs what was played during this session, adding it to the totals of all sessions
+    /// if they're kept.
+    pub(crate) fn shut_down(&mut self, now: Instant) {
+        self.stop(now);
+        self.session.underruns = (self.underruns.0).load(Ordering::Relaxed);
+        info!("This session: {}.", self.session);
+        if let Some(ref path) = self.path {
+            match add_to_file(path, self.session) {
+                Ok(totals) => info!("All sessions: {}.", totals),
+                Err(e) => warn!("Couldn't save the stats to {:?}: {}", path, e),
+            }
+        }
+    }
+}
+
+/// Counts underruns on the thread that writes to the audio device.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Underruns(Arc<AtomicU64>);
+
+impl Underruns {
+    /// Wraps `sink` to count the writes that fail. Backends that report underruns,
+    /// rather than recovering from them, do so as a failed write, e.g. with EPIPE.
+    pub(crate) fn count(self, sink: Box<dyn Sink>) -> Box<dyn Sink> {
+        Box::new(CountingSink {
+            sink,
+            underruns: self,
+        })
+    }
+}
+
+struct CountingSink {
+    sink: Box<dyn Sink>,
+    underruns: Underruns,
+}
+
+impl Sink for CountingSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let written = self.sink.write(data);
+        if written.is_err() {
+            (self.underruns.0).fetch_add(1, Ordering::Relaxed);
+        }
+        written
+    }
+}
+
+/// Adds `session` to the totals stored at `path`, returning the new totals.
+fn add_to_file(path: &Path, session: Totals) -> io::Result<Totals> {
+    let mut totals = match fs::read_to_string(path) {
+        Ok(contents) => Totals::parse(&contents),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Totals::default(),
+        Err(e) => return Err(e),
+    };
+    totals.add(session);
+    fs::write(path, totals.format())?;
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{temp_path, MockSink};
+
+    #[test]
+    fn accumulation() {
+        let a = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let b = SpotifyId::from_base62("6rqhFgbbKwnb9MLmUQDhG6").unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut stats = Stats::new(None);
+        stats.player_event(&PlayerEvent::Started { track_id: a }, at(0));
+        stats.player_event(&PlayerEvent::Stopped { track_id: a }, at(10));
+        // Resuming doesn't count the track again, nor the pause as listening.
+        stats.player_event(&PlayerEvent::Started { track_id: a }, at(20));
+        stats.player_event(
+            &PlayerEvent::Changed {
+                old_track_id: a,
+                new_track_id: b,
+            },
+            at(30),
+        );
+        stats.reconnected();
+        stats.player_event(&PlayerEvent::Started { track_id: b }, at(31));
+        stats.player_event(&PlayerEvent::Stopped { track_id: b }, at(50));
+        stats.player_event(&PlayerEvent::Started { track_id: a }, at(60));
+        let mut sink = stats
+            .underruns()
+            .count(Box::new(MockSink::failing_after(2)));
+        for _ in 0..3 {
+            let _ = sink.write(&[0, 0]);
+        }
+        stats.shut_down(at(65));
+        assert_eq!(
+            stats.session,
+            Totals {
+                tracks: 3,
+                listening: Duration::from_secs(45),
+                reconnects: 1,
+                underruns: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let totals = Totals {
+            tracks: 12,
+            listening: Duration::from_secs(3723),
+            reconnects: 1,
+            underruns: 4,
+        };
+        assert_eq!(
+            totals.to_string(),
+            "12 tracks, 1h2m3s listened, 1 reconnect, 4 underruns"
+        );
+        assert_eq!(
+            Totals::default().to_string(),
+            "0 tracks, 0s listened, 0 reconnects, 0 underruns"
+        );
+        assert_eq!(Totals::parse(&totals.format()), totals);
+        assert_eq!(Totals::parse("tracks\tmany\nother\t3\n"), Totals::default());
+
+        let path = temp_path("stats");
+        let _ = fs::remove_file(&path);
+        assert_eq!(add_to_file(&path, totals).unwrap(), totals);
+        let twice = add_to_file(&path, totals).unwrap();
+        assert_eq!(twice.tracks, 24);
+        assert_eq!(twice.listening, Duration::from_secs(7446));
+        fs::remove_file(path).unwrap();
+    }
+}
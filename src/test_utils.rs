@@ -0,0 +1,90 @@
+//! Helpers shared by the tests of several modules.
+
+use librespot::playback::audio_backend::Sink;
+use std::{
+    env, io,
+    path::PathBuf,
+    process,
+    sync::{mpsc::Sender, Arc, Mutex, MutexGuard},
+    thread::{self, ThreadId},
+};
+
+/// A path in the temporary directory for `name`, which no other test run uses.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("spotifyd-{}-{}", name, process::id()))
+}
+
+/// What a `MockSink` was asked to do.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Call {
+    Start,
+    Stop,
+    Write(Vec<i16>),
+}
+
+/// Records what's done with it, to be looked at through a clone.
+#[derive(Clone, Default)]
+pub(crate) struct MockSink {
+    calls: Arc<Mutex<Vec<Call>>>,
+    /// Writes fail once this many samples were written.
+    fail_after: Option<usize>,
+    /// Where to tell which thread each write comes from.
+    threads: Option<Sender<ThreadId>>,
+}
+
+impl MockSink {
+    pub(crate) fn failing_after(samples: usize) -> MockSink {
+        MockSink {
+            fail_after: Some(samples),
+            ..MockSink::default()
+        }
+    }
+
+    pub(crate) fn reporting_threads(threads: Sender<ThreadId>) -> MockSink {
+        MockSink {
+            threads: Some(threads),
+            ..MockSink::default()
+        }
+    }
+
+    pub(crate) fn calls(&self) -> MutexGuard<Vec<Call>> {
+        self.calls.lock().unwrap()
+    }
+
+    /// Everything written so far, in one piece.
+    pub(crate) fn written(&self) -> Vec<i16> {
+        let mut written = Vec::new();
+        for call in self.calls().iter() {
+            if let Call::Write(data) = call {
+                written.extend(data);
+            }
+        }
+        written
+    }
+}
+
+impl Sink for MockSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.calls().push(Call::Start);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.calls().push(Call::Stop);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        if let Some(ref threads) = self.threads {
+            let _ = threads.send(thread::current().id());
+        }
+        if self
+            .fail_after
+            .map_or(false, |samples| self.written().len() >= samples)
+        {
+            return Err(io::Error::new(io::ErrorKind::Other, "unplugged"));
+        }
+        self.calls().push(Call::Write(data.to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use librespot::playback::audio_backend::Sink;
+use std::{cmp, f32::consts::PI, io};
+
+use crate::audio_filter::CHANNELS;
+
+/// The length in frames of the windows the stream is cut into, 40ms. Consecutive
+/// windows overlap by half in the output.
+const WINDOW: usize = 1764;
+const HOP: usize = WINDOW / 2;
+/// How far in frames a window may be moved from where the speed puts it, 10ms, to line
+/// its waveform up with the previous window's.
+const TOLERANCE: usize = 441;
+
+/// Changes the speed of the stream without changing its pitch, by taking overlapping
+/// windows further apart (or closer together) than they're laid out in the output.
+/// Each window is moved a little to where it continues the previous one best (WSOLA),
+/// which keeps voices from sounding hollow.
+struct Stretcher {
+    speed: f64,
+    /// A Hann window, whose halves add up to 1 when overlapped.
+    window: Vec<f32>,
+    /// The input not consumed yet, interleaved, and mixed down to compare windows.
+    input: Vec<f32>,
+    mono: Vec<f32>,
+    /// Where the speed puts the next window, in frames into `input`.
+    position: f64,
+    /// Where the input continues the second half of the previous window, which is what
+    /// the next window should look like.
+    target: Option<usize>,
+    /// The second half of the previous window, to be added to the next one.
+    overlap: Vec<f32>,
+}
+
+impl Stretcher {
+    fn new(speed: f64) -> Stretcher {
+        let window = (0..WINDOW)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / WINDOW as f32).cos())
+            .collect();
+        Stretcher {
+            speed,
+            window,
+            input: Vec::new(),
+            mono: Vec::new(),
+            position: 0.0,
+            target: None,
+            overlap: vec![0.0; HOP * CHANNELS],
+        }
+    }
+
+    /// Finds the start of a window between `from` and `to` that's most similar to the
+    /// input at `target`, sampling every few frames to keep it cheap.
+    fn best_start(&self, target: usize, from: usize, to: usize) -> usize {
+        let mut best = (std::f32::MIN, from);
+        for start in (from..=to).step_by(2) {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            let candidate = self.mono[start..start + HOP].iter().step_by(4);
+            let wanted = self.mono[target..target + HOP].iter().step_by(4);
+            for (&sample, &other) in candidate.zip(wanted) {
+                correlation += sample * other;
+                energy += sample * sample;
+            }
+            let score = correlation / (energy + 1.0).sqrt();
+            if score > best.0 {
+                best = (score, start);
+            }
+        }
+        best.1
+    }
+
+    /// Adds `data` to the input, returning the output for all windows that are complete.
+    fn process(&mut self, data: &[i16]) -> Vec<i16> {
+        self.input
+            .extend(data.iter().map(|&sample| f32::from(sample)));
+        self.mono.extend(
+            data.chunks(CHANNELS)
+                .map(|frame| frame.iter().map(|&sample| f32::from(sample)).sum::<f32>()),
+        );
+        let mut output = Vec::new();
+        loop {
+            let nominal = self.position.round() as usize;
+            let (from, to) = (nominal.saturating_sub(TOLERANCE), nominal + TOLERANCE);
+            if to + WINDOW > self.mono.len() {
+                break;
+            }
+            let start = match self.target {
+                Some(target) => self.best_start(target, from, to),
+                None => nominal,
+            };
+            for i in 0..WINDOW {
+                for channel in 0..CHANNELS {
+                    let sample = self.input[(start + i) * CHANNELS + channel] * self.window[i];
+                    let index = (i % HOP) * CHANNELS + channel;
+                    if i < HOP {
+                        let mixed = self.overlap[index] + sample;
+                        output.push(mixed.round().max(-32768.0).min(32767.0) as i16);
+                    } else {
+                        self.overlap[index] = sample;
+                    }
+                }
+            }
+            self.position += HOP as f64 * self.speed;
+
+            // Drop the input that neither the next window nor its target can start in.
+            let target = start + HOP;
+            let next_from = (self.position.round() as usize).saturating_sub(TOLERANCE);
+            let consumed = cmp::min(target, next_from);
+            self.input.drain(..consumed * CHANNELS);
+            self.mono.drain(..consumed);
+            self.position -= consumed as f64;
+            self.target = Some(target - consumed);
+        }
+        output
+    }
+
+    /// Ends the stream, returning the rest of the last window. Input that doesn't fill a
+    /// window, at most 50ms, is dropped.
+    fn finish(&mut self) -> Vec<i16> {
+        let output = if self.target.is_some() {
+            self.overlap
+                .iter()
+                .map(|&sample| sample.round() as i16)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.input.clear();
+        self.mono.clear();
+        self.position = 0.0;
+        self.target = None;
+        self.overlap.iter_mut().for_each(|sample| *sample = 0.0);
+        output
+    }
+}
+
+/// Plays everything written to `sink` at a different speed.
+struct StretchSink {
+    sink: Box<dyn Sink>,
+    stretcher: Stretcher,
+}
+
+impl Sink for StretchSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let rest = self.stretcher.finish();
+        if !rest.is_empty() {
+            self.sink.write(&rest)?;
+        }
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let output = self.stretcher.process(data);
+        if output.is_empty() {
+            Ok(())
+        } else {
+            self.sink.write(&output)
+        }
+    }
+}
+
+/// Plays `sink` at `speed`, 1.25 being a quarter faster.
+pub(crate) fn wrap(sink: Box<dyn Sink>, speed: Option<f64>) -> Box<dyn Sink> {
+    match speed {
+        Some(speed) => Box::new(StretchSink {
+            sink,
+            stretcher: Stretcher::new(speed),
+        }),
+        None => sink,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockSink;
+
+    /// 440Hz, for a period of just over 100 frames.
+    fn sine(frames: usize) -> Vec<i16> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample = (10000.0 * (2.0 * PI * 440.0 * i as f32 / 44100.0).sin()) as i16;
+                vec![sample; CHANNELS]
+            })
+            .collect()
+    }
+
+    /// Counts the times the left channel goes from negative to positive.
+    fn rising_crossings(samples: &[i16]) -> usize {
+        samples
+            .iter()
+            .step_by(CHANNELS)
+            .collect::<Vec<_>>()
+            .windows(2)
+            .filter(|pair| *pair[0] < 0 && *pair[1] >= 0)
+            .count()
+    }
+
+    fn stretch(speed: f64, input: &[i16]) -> Vec<i16> {
+        let mut stretcher = Stretcher::new(speed);
+        let mut output = Vec::new();
+        // In chunks as librespot writes them.
+        for chunk in input.chunks(4096) {
+            output.extend(stretcher.process(chunk));
+        }
+        output.extend(stretcher.finish());
+        output
+    }
+
+    #[test]
+    fn output_length() {
+        let frames = 2 * 44100;
+        let input = sine(frames);
+        for &speed in &[0.5, 1.0, 1.25, 2.0, 3.0] {
+            let output = stretch(speed, &input);
+            assert_eq!(output.len() % CHANNELS, 0);
+            let expected = frames as f64 / speed;
+            let actual = (output.len() / CHANNELS) as f64;
+            // Up to a window and the tolerance of input is left over at the end, while the
+            // first and last windows end in a ramp.
+            let slack = (WINDOW + TOLERANCE) as f64 / speed + 2.0 * HOP as f64;
+            assert!(
+                (actual - expected).abs() <= slack,
+                "{}x: {} frames, expected {}",
+                speed,
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn pitch_is_kept() {
+        let input = sine(2 * 44100);
+        let input_rate = rising_crossings(&input) as f64 / (input.len() / CHANNELS) as f64;
+        for &speed in &[0.5, 1.5] {
+            let output = stretch(speed, &input);
+            // Leave out the ramp at the start.
+            let output = &output[WINDOW * CHANNELS..];
+            let output_rate = rising_crossings(output) as f64 / (output.len() / CHANNELS) as f64;
+            assert!(
+                (output_rate / input_rate - 1.0).abs() < 0.02,
+                "{}x: {} crossings per frame, expected {}",
+                speed,
+                output_rate,
+                input_rate
+            );
+        }
+    }
+
+    #[test]
+    fn sink() {
+        let recorder = MockSink::default();
+        let mut sink = wrap(Box::new(recorder.clone()), Some(2.0));
+        sink.start().unwrap();
+        sink.write(&sine(44100)).unwrap();
+        sink.stop().unwrap();
+        let written = recorder.written().len() / CHANNELS;
+        assert!(written > 20000 && written <= 22050, "{}", written);
+
+        let recorder = MockSink::default();
+        let mut sink = wrap(Box::new(recorder.clone()), None);
+        sink.write(&[1, 2]).unwrap();
+        assert_eq!(recorder.written(), [1, 2]);
+    }
+}
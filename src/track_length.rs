@@ -0,0 +1,58 @@
+use futures::Future;
+use librespot::{
+    core::{session::Session, spotify_id::SpotifyId},
+    metadata::{Metadata, Track},
+};
+use log::{info, warn};
+use std::time::Duration;
+
+/// Whether a track of the given length is longer than `max_length`. A zero maximum
+/// never skips anything.
+pub(crate) fn too_long(length: Duration, max_length: Duration) -> bool {
+    max_length > Duration::from_secs(0) && length > max_length
+}
+
+/// Looks up the length of the track, resolving to whether it should be skipped.
+pub(crate) fn check(
+    session: &Session,
+    track_id: SpotifyId,
+    max_length: Duration,
+) -> Box<dyn Future<Item = bool, Error = ()>> {
+    Box::new(
+        Track::get(session, track_id)
+            .map(move |track| {
+                let length = Duration::from_millis(track.duration.max(0) as u64);
+                let skip = too_long(length, max_length);
+                if skip {
+                    info!(
+                        "Skipping {:?}, its {}s are longer than max_track_length.",
+                        track.name,
+                        length.as_secs()
+                    );
+                }
+                skip
+            })
+            .map_err(|e| warn!("Couldn't look up the length of the track: {:?}", e)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_decision() {
+        let max = Duration::from_secs(600);
+        assert!(!too_long(Duration::from_secs(599), max));
+        assert!(!too_long(max, max));
+        assert!(too_long(Duration::from_secs(601), max));
+        assert!(too_long(Duration::from_millis(600_001), max));
+    }
+
+    #[test]
+    fn zero_disables_skipping() {
+        let zero = Duration::from_secs(0);
+        assert!(!too_long(Duration::from_secs(1200), zero));
+        assert!(!too_long(zero, zero));
+    }
+}
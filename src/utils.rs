@@ -1,6 +1,10 @@
 use std::cell::RefCell;
 use std::env;
 use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
 
 extern "C" {
     fn getlogin_r(buf: *mut libc::c_char, size: libc::size_t) -> libc::c_int;
@@ -25,7 +29,139 @@ pub(crate) fn get_hostname() -> Option<String> {
     })
 }
 
-pub(crate) fn get_shell() -> Option<String> {
+/// Cleans up a hostname for use in a device name: with `strip_domain`, the domain is
+/// dropped, everything but ASCII letters, digits, `.`, `-` and `_` becomes `-`, and the
+/// result is cut to `max_len`. IP addresses, which some machines report as their name,
+/// are kept whole. Returns `None` if nothing is left.
+pub(crate) fn sanitize_hostname(
+    hostname: &str,
+    strip_domain: bool,
+    max_len: usize,
+) -> Option<String> {
+    let hostname = hostname.trim();
+    let address = hostname.trim_start_matches('[').trim_end_matches(']');
+    let is_address = address
+        .split('%')
+        .next()
+        .map_or(false, |address| address.parse::<IpAddr>().is_ok());
+    let name = if is_address {
+        address
+    } else if strip_domain {
+        hostname.split('.').next().unwrap_or_default()
+    } else {
+        hostname
+    };
+    let mut sanitized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            sanitized.push(c);
+        } else if !sanitized.ends_with('-') {
+            sanitized.push('-');
+        }
+    }
+    let sanitized = sanitized.trim_start_matches(|c| c == '-' || c == '.');
+    let sanitized =
+        sanitized[..sanitized.len().min(max_len)].trim_end_matches(|c| c == '-' || c == '.');
+    if sanitized.is_empty() {
+        None
+    } else {
+        Some(sanitized.to_string())
+    }
+}
+
+/// Makes a relative `path` absolute by resolving it against the current directory.
+pub(crate) fn absolutize_path(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(env::current_dir()?.join(path))
+    }
+}
+
+/// Like `absolutize_path`, but a leading `~` is replaced by the home directory first,
+/// and the result is rejected if it ends up outside of `root`. `.` and `..` are resolved
+/// without looking at the file system, so `~/../etc/passwd` can't leave a `root` of `~`.
+// Nothing configures a root for its paths yet.
+#[allow(dead_code)]
+pub(crate) fn absolutize_path_confined(path: &Path, root: &Path) -> io::Result<PathBuf> {
+    let expand = |path: &Path| -> io::Result<PathBuf> {
+        match path.strip_prefix("~") {
+            Ok(rest) => {
+                let home = env::var_os("HOME")
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+                Ok(Path::new(&home).join(rest))
+            }
+            Err(_) => Ok(path.to_path_buf()),
+        }
+    };
+    let resolved = normalize_path(&absolutize_path(&expand(path)?)?);
+    let root = normalize_path(&absolutize_path(&expand(root)?)?);
+    if resolved.starts_with(&root) {
+        Ok(resolved)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:?} is outside of {:?}", path, root),
+        ))
+    }
+}
+
+/// Drops the `.` components of `path` and folds each `..` into the component before it.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The shell used if the user's can't be found, or isn't a login shell.
+pub(crate) const FALLBACK_SHELL: &str = "/bin/sh";
+
+/// Returns the user's shell. With `validate`, a shell that isn't listed in `/etc/shells`,
+/// such as `/usr/sbin/nologin`, is replaced by `FALLBACK_SHELL`.
+pub(crate) fn get_shell(validate: bool) -> Option<String> {
+    let shell = find_shell()?;
+    if validate {
+        Some(validate_shell(shell, Path::new("/etc/shells")))
+    } else {
+        Some(shell)
+    }
+}
+
+fn validate_shell(shell: String, shells: &Path) -> String {
+    // Without a list of login shells there is nothing to check against, so trust the shell.
+    let shells = match fs::read_to_string(shells) {
+        Ok(shells) => shells,
+        Err(e) => {
+            log::trace!("Couldn't read {:?}: {}", shells, e);
+            return shell;
+        }
+    };
+    let listed = shells
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .any(|line| line == shell);
+    if listed {
+        shell
+    } else {
+        log::info!(
+            "{:?} is not a login shell. Falling back to {:?}.",
+            shell,
+            FALLBACK_SHELL
+        );
+        FALLBACK_SHELL.to_string()
+    }
+}
+
+fn find_shell() -> Option<String> {
     // First look for the user's preferred shell using the SHELL environment variable...
     if let Ok(shell) = env::var("SHELL") {
         log::trace!("Found shell {:?} using SHELL environment variable.", shell);
@@ -101,6 +237,7 @@ fn get_username() -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::temp_path;
 
     #[test]
     fn it_works() {
@@ -110,11 +247,138 @@ mod tests {
 
         let _ = get_hostname().unwrap();
 
-        let _ = get_shell().unwrap();
+        let _ = get_shell(false).unwrap();
 
         if env::var("SHELL").is_ok() {
             env::remove_var("SHELL");
-            let _ = get_shell().unwrap();
+            let _ = get_shell(false).unwrap();
+        }
+    }
+
+    #[test]
+    fn hostnames() {
+        let cases = [
+            ("kitchen-pi", Some("kitchen-pi")),
+            ("kitchen-pi.local", Some("kitchen-pi")),
+            ("living room.example.com", Some("living-room")),
+            ("  media_box  ", Some("media_box")),
+            ("büro", Some("b-ro")),
+            ("--pi--", Some("pi")),
+            ("192.168.1.5", Some("192.168.1.5")),
+            ("fe80::1%eth0", Some("fe80-1-eth0")),
+            ("[2001:db8::7]", Some("2001-db8-7")),
+            (".local", None),
+            ("", None),
+        ];
+        for &(hostname, sanitized) in &cases {
+            assert_eq!(
+                sanitize_hostname(hostname, true, 54)
+                    .as_ref()
+                    .map(String::as_str),
+                sanitized,
+                "{:?}",
+                hostname
+            );
+        }
+        let long = "a".repeat(50) + "-b-" + &"c".repeat(50);
+        assert_eq!(sanitize_hostname(&long, true, 51).unwrap(), "a".repeat(50));
+        assert_eq!(sanitize_hostname(&long, true, 54).unwrap().len(), 54);
+    }
+
+    #[test]
+    fn hostname_domains() {
+        let sanitize = |hostname, strip_domain| sanitize_hostname(hostname, strip_domain, 54);
+        assert_eq!(sanitize("kitchen-pi.local", true).unwrap(), "kitchen-pi");
+        assert_eq!(
+            sanitize("kitchen-pi.local", false).unwrap(),
+            "kitchen-pi.local"
+        );
+        assert_eq!(sanitize("kitchen-pi", true).unwrap(), "kitchen-pi");
+        assert_eq!(sanitize("kitchen-pi", false).unwrap(), "kitchen-pi");
+        assert_eq!(sanitize("kitchen-pi.", false).unwrap(), "kitchen-pi");
+        // Addresses aren't cut at the first dot.
+        assert_eq!(sanitize("10.0.0.12", true).unwrap(), "10.0.0.12");
+        assert_eq!(sanitize("10.0.0.12", false).unwrap(), "10.0.0.12");
+        assert_eq!(sanitize("10.0.0.12.example", true).unwrap(), "10");
+    }
+
+    #[test]
+    fn confined_path_escaping() {
+        let root = Path::new("/var/cache/spotifyd");
+        for path in &[
+            "/var/cache/spotifyd/../../../etc/passwd",
+            "/var/cache/spotifyd-other",
+            "/etc/passwd",
+        ] {
+            assert!(
+                absolutize_path_confined(Path::new(path), root).is_err(),
+                "{:?}",
+                path
+            );
+        }
+        if env::var_os("HOME").is_some() {
+            let err =
+                absolutize_path_confined(Path::new("~/../etc/passwd"), Path::new("~")).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
         }
     }
+
+    #[test]
+    fn confined_path_inside() {
+        let root = Path::new("/var/cache/spotifyd");
+        assert_eq!(
+            absolutize_path_confined(Path::new("/var/cache/spotifyd/./audio/../files"), root)
+                .unwrap(),
+            Path::new("/var/cache/spotifyd/files")
+        );
+        if let Some(home) = env::var_os("HOME") {
+            assert_eq!(
+                absolutize_path_confined(Path::new("~/.cache/spotifyd"), Path::new("~")).unwrap(),
+                normalize_path(&Path::new(&home).join(".cache/spotifyd"))
+            );
+        }
+    }
+
+    fn shells_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    const SHELLS: &str = "# /etc/shells: valid login shells\n/bin/sh\n/bin/bash\n\n/usr/bin/zsh\n";
+
+    #[test]
+    fn valid_shell() {
+        let path = shells_file("valid-shell", SHELLS);
+        assert_eq!(validate_shell("/bin/bash".to_string(), &path), "/bin/bash");
+        assert_eq!(
+            validate_shell("/usr/bin/zsh".to_string(), &path),
+            "/usr/bin/zsh"
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn nologin_shell_falls_back() {
+        let path = shells_file("nologin-shell", SHELLS);
+        assert_eq!(
+            validate_shell("/usr/sbin/nologin".to_string(), &path),
+            FALLBACK_SHELL
+        );
+        // Comments aren't shells.
+        assert_eq!(
+            validate_shell("# /etc/shells: valid login shells".to_string(), &path),
+            FALLBACK_SHELL
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_shells_file() {
+        let path = env::temp_dir().join("spotifyd-no-such-shells-file");
+        assert_eq!(
+            validate_shell("/usr/sbin/nologin".to_string(), &path),
+            "/usr/sbin/nologin"
+        );
+    }
 }
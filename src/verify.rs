@@ -0,0 +1,135 @@
+use librespot::core::{authentication::Credentials, session::Session};
+use log::{error, info};
+use std::io;
+use tokio_core::reactor::Core;
+
+use crate::{config::SpotifydConfig, setup};
+
+/// How a `--verify-credentials` run ended.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verification {
+    Valid,
+    Rejected,
+    NoCredentials,
+    ConnectionFailed,
+}
+
+impl Verification {
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            Verification::Valid => 0,
+            Verification::Rejected => 1,
+            Verification::NoCredentials => 2,
+            Verification::ConnectionFailed => 3,
+        }
+    }
+}
+
+/// Logs in with `login` and reports whether Spotify accepted the credentials. Only the
+/// username is ever logged.
+fn verify<F>(credentials: Option<Credentials>, login: F) -> Verification
+where
+    F: FnOnce(Credentials) -> io::Result<()>,
+{
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            error!("No credentials found, set a username and password to verify them.");
+            return Verification::NoCredentials;
+        }
+    };
+    let username = credentials.username.clone();
+    match login(credentials) {
+        Ok(()) => {
+            info!("Logged in as {:?}, the credentials are valid.", username);
+            Verification::Valid
+        }
+        // librespot reports Spotify rejecting the credentials as PermissionDenied.
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            error!("Spotify rejected the credentials for {:?}: {}", username, e);
+            Verification::Rejected
+        }
+        Err(e) => {
+            error!(
+                "Couldn't connect to Spotify to verify the credentials: {}",
+                e
+            );
+            Verification::ConnectionFailed
+        }
+    }
+}
+
+/// Logs in once with the configured credentials and exits, without opening an audio
+/// backend or starting discovery or MPRIS.
+pub(crate) fn run(config: SpotifydConfig) -> Verification {
+    // A username without a password is as good as no credentials, not a rejection.
+    let credentials = match setup::credentials(&config) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            error!("{}", e);
+            return Verification::NoCredentials;
+        }
+    };
+    verify(credentials, |credentials| {
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        core.run(Session::connect(
+            config.session_config,
+            credentials,
+            config.cache,
+            handle,
+        ))
+        .map(|_| ())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> Option<Credentials> {
+        Some(Credentials::with_password(
+            "alice".to_string(),
+            "hunter2".to_string(),
+        ))
+    }
+
+    #[test]
+    fn valid_credentials() {
+        let verification = verify(credentials(), |credentials| {
+            assert_eq!(credentials.username, "alice");
+            Ok(())
+        });
+        assert_eq!(verification, Verification::Valid);
+        assert_eq!(verification.exit_code(), 0);
+    }
+
+    #[test]
+    fn rejected_credentials() {
+        let verification = verify(credentials(), |_| {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Authentication failed",
+            ))
+        });
+        assert_eq!(verification, Verification::Rejected);
+
+        let verification = verify(credentials(), |_| {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+        });
+        assert_eq!(verification, Verification::ConnectionFailed);
+
+        let verification = verify(None, |_| panic!("nothing to log in with"));
+        assert_eq!(verification, Verification::NoCredentials);
+
+        let codes: Vec<_> = [
+            Verification::Rejected,
+            Verification::NoCredentials,
+            Verification::ConnectionFailed,
+        ]
+        .iter()
+        .map(Verification::exit_code)
+        .collect();
+        assert!(!codes.contains(&0));
+    }
+}
@@ -0,0 +1,136 @@
+use futures::{Future, Poll};
+use std::{
+    any::Any,
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+/// How often the player may be restarted within `RESTART_WINDOW` before spotifyd gives
+/// up, as it's most likely going to crash again.
+pub(crate) const MAX_RESTARTS: usize = 3;
+pub(crate) const RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// What to do after the player crashed.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    /// Start over with a fresh session.
+    Restart,
+    /// Stop, the player crashed too often recently.
+    GiveUp,
+}
+
+/// Keeps track of player crashes, so that a player that crashes right away isn't
+/// restarted in a loop.
+pub(crate) struct Watchdog {
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(max_restarts: usize, window: Duration) -> Watchdog {
+        Watchdog {
+            max_restarts,
+            window,
+            restarts: VecDeque::with_capacity(max_restarts),
+        }
+    }
+
+    /// The number of restarts within the window.
+    pub(crate) fn restarts(&self) -> usize {
+        self.restarts.len()
+    }
+
+    /// Records that the player crashed at `now`.
+    pub(crate) fn crashed(&mut self, now: Instant) -> Verdict {
+        while let Some(&restart) = self.restarts.front() {
+            if now.duration_since(restart) < self.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return Verdict::GiveUp;
+        }
+        self.restarts.push_back(now);
+        Verdict::Restart
+    }
+}
+
+/// Polls `task`, returning the panic message instead if it panics. The task mustn't be
+/// polled again after that.
+pub(crate) fn poll_catching<F: Future>(task: &mut F) -> Result<Poll<F::Item, F::Error>, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| task.poll()))
+        .map_err(|payload| panic_message(&*payload))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Async;
+
+    /// Panics on its first poll if `broken`, and completes otherwise.
+    struct Task {
+        broken: bool,
+    }
+
+    impl Future for Task {
+        type Item = &'static str;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<&'static str, ()> {
+            if self.broken {
+                let channel: Option<()> = None;
+                channel.expect("player thread gone");
+            }
+            Ok(Async::Ready("done"))
+        }
+    }
+
+    #[test]
+    fn restart_on_panic() {
+        let mut watchdog = Watchdog::new(MAX_RESTARTS, RESTART_WINDOW);
+        let mut task = Task { broken: true };
+        assert_eq!(
+            poll_catching(&mut task),
+            Err("player thread gone".to_string())
+        );
+        assert_eq!(watchdog.crashed(Instant::now()), Verdict::Restart);
+        assert_eq!(watchdog.restarts(), 1);
+
+        // The fresh task runs as if nothing happened.
+        let mut task = Task { broken: false };
+        assert_eq!(poll_catching(&mut task), Ok(Ok(Async::Ready("done"))));
+
+        let formatted = panic::catch_unwind(|| panic!("track {} unavailable", 3)).unwrap_err();
+        assert_eq!(panic_message(&*formatted), "track 3 unavailable");
+    }
+
+    #[test]
+    fn crash_loop() {
+        let mut watchdog = Watchdog::new(3, Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(watchdog.crashed(at(0)), Verdict::Restart);
+        assert_eq!(watchdog.crashed(at(10)), Verdict::Restart);
+        assert_eq!(watchdog.crashed(at(20)), Verdict::Restart);
+        assert_eq!(watchdog.crashed(at(30)), Verdict::GiveUp);
+        // Once the first restart is out of the window, there's room for another.
+        assert_eq!(watchdog.crashed(at(60)), Verdict::Restart);
+        assert_eq!(watchdog.restarts(), 3);
+        assert_eq!(watchdog.crashed(at(65)), Verdict::GiveUp);
+        assert_eq!(watchdog.crashed(at(200)), Verdict::Restart);
+        assert_eq!(watchdog.restarts(), 1);
+    }
+}